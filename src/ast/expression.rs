@@ -1,4 +1,4 @@
-use super::{InputSource, Value};
+use super::{InputSource, LookupTable, SpatialOp, Value};
 use serde::{Deserialize, Serialize};
 use std::hash::Hash;
 
@@ -19,11 +19,89 @@ pub enum Expression {
     GreaterThanOrEqual(Box<Expression>, Box<Expression>),
     SmallerThan(Box<Expression>, Box<Expression>),
     SmallerThanOrEqual(Box<Expression>, Box<Expression>),
+    /// Step lookup of the operand in a table.
+    Table(Box<Expression>, LookupTable),
+    /// Piecewise-linear interpolation of the operand in a table.
+    Interpolate(Box<Expression>, LookupTable),
+    /// A call to a native function registered by the host, by name, with its arguments.
+    Call(String, Vec<Expression>),
+    /// A geometric operation over the fields of event instances. See `SpatialOp` for
+    /// the operands of each operation.
+    Spatial(SpatialOp, Vec<Expression>),
     Literal(Value),
     Input(InputSource),
     Reference(u64),
 }
 
+impl Expression {
+    /// The operands of this expression, left to right. Leaves and references have none.
+    pub fn children(&self) -> impl Iterator<Item = &Expression> {
+        let (first, second, rest) = match self {
+            Expression::Sum(l, r)
+            | Expression::Subtract(l, r)
+            | Expression::Multiply(l, r)
+            | Expression::Divide(l, r)
+            | Expression::And(l, r)
+            | Expression::Or(l, r)
+            | Expression::Xor(l, r)
+            | Expression::Equal(l, r)
+            | Expression::NotEqual(l, r)
+            | Expression::GreaterThan(l, r)
+            | Expression::GreaterThanOrEqual(l, r)
+            | Expression::SmallerThan(l, r)
+            | Expression::SmallerThanOrEqual(l, r) => (Some(l.as_ref()), Some(r.as_ref()), &[][..]),
+            Expression::Abs(v)
+            | Expression::Not(v)
+            | Expression::Table(v, _)
+            | Expression::Interpolate(v, _) => (Some(v.as_ref()), None, &[][..]),
+            Expression::Call(_, args) | Expression::Spatial(_, args) => {
+                (None, None, args.as_slice())
+            }
+            Expression::Literal(_) | Expression::Input(_) | Expression::Reference(_) => {
+                (None, None, &[][..])
+            }
+        };
+        first.into_iter().chain(second).chain(rest)
+    }
+
+    /// Returns the same operation with every operand replaced by `f(operand)`.
+    pub fn map_children(self, mut f: impl FnMut(Expression) -> Expression) -> Expression {
+        match self {
+            Expression::Call(name, args) => {
+                return Expression::Call(name, args.into_iter().map(f).collect());
+            }
+            Expression::Spatial(op, args) => {
+                return Expression::Spatial(op, args.into_iter().map(f).collect());
+            }
+            _ => {}
+        }
+        let mut f = |e: Box<Expression>| Box::new(f(*e));
+        match self {
+            Expression::Sum(l, r) => Expression::Sum(f(l), f(r)),
+            Expression::Subtract(l, r) => Expression::Subtract(f(l), f(r)),
+            Expression::Multiply(l, r) => Expression::Multiply(f(l), f(r)),
+            Expression::Divide(l, r) => Expression::Divide(f(l), f(r)),
+            Expression::Abs(v) => Expression::Abs(f(v)),
+            Expression::Not(v) => Expression::Not(f(v)),
+            Expression::And(l, r) => Expression::And(f(l), f(r)),
+            Expression::Or(l, r) => Expression::Or(f(l), f(r)),
+            Expression::Xor(l, r) => Expression::Xor(f(l), f(r)),
+            Expression::Equal(l, r) => Expression::Equal(f(l), f(r)),
+            Expression::NotEqual(l, r) => Expression::NotEqual(f(l), f(r)),
+            Expression::GreaterThan(l, r) => Expression::GreaterThan(f(l), f(r)),
+            Expression::GreaterThanOrEqual(l, r) => Expression::GreaterThanOrEqual(f(l), f(r)),
+            Expression::SmallerThan(l, r) => Expression::SmallerThan(f(l), f(r)),
+            Expression::SmallerThanOrEqual(l, r) => Expression::SmallerThanOrEqual(f(l), f(r)),
+            Expression::Table(v, table) => Expression::Table(f(v), table),
+            Expression::Interpolate(v, table) => Expression::Interpolate(f(v), table),
+            operation @ (Expression::Call(..) | Expression::Spatial(..)) => operation,
+            leaf @ (Expression::Literal(_) | Expression::Input(_) | Expression::Reference(_)) => {
+                leaf
+            }
+        }
+    }
+}
+
 #[cfg(feature = "debug-tools")]
 pub use display_impl::*;
 
@@ -87,68 +165,51 @@ mod display_impl {
                         InputSource::DynamicName { event, field } => {
                             format!("${}.{}", event, field)
                         }
+                        InputSource::Parameter { id } => format!("@Parameter [P{}]", id),
+                        InputSource::ParameterName { name } => format!("@{}", name),
                     };
                     writeln!(f, "Input: {}", display_str)?;
                 }
-                Expression::Not(v) => {
-                    writeln!(f, "notNode (NOT)")?;
-                    self.fmt_as_tree(v, f, &child_prefix, true)?;
-                }
-                Expression::Abs(v) => {
-                    writeln!(f, "absNode (ABS)")?;
-                    self.fmt_as_tree(v, f, &child_prefix, true)?;
-                }
-                Expression::Sum(l, r) => self.fmt_binary(f, "sumNode (+)", l, r, &child_prefix)?,
-                Expression::Subtract(l, r) => {
-                    self.fmt_binary(f, "subNode (-)", l, r, &child_prefix)?
-                }
-                Expression::Multiply(l, r) => {
-                    self.fmt_binary(f, "multNode (*)", l, r, &child_prefix)?
-                }
-                Expression::Divide(l, r) => {
-                    self.fmt_binary(f, "divideNode (/)", l, r, &child_prefix)?
-                }
-                Expression::And(l, r) => {
-                    self.fmt_binary(f, "andNode (AND)", l, r, &child_prefix)?
-                }
-                Expression::Or(l, r) => self.fmt_binary(f, "orNode (OR)", l, r, &child_prefix)?,
-                Expression::Xor(l, r) => {
-                    self.fmt_binary(f, "xorNode (XOR)", l, r, &child_prefix)?
-                }
-                Expression::Equal(l, r) => {
-                    self.fmt_binary(f, "eqNode (==)", l, r, &child_prefix)?
-                }
-                Expression::NotEqual(l, r) => {
-                    self.fmt_binary(f, "neqNode (!=)", l, r, &child_prefix)?
-                }
-                Expression::GreaterThan(l, r) => {
-                    self.fmt_binary(f, "gtNode (>)", l, r, &child_prefix)?
-                }
-                Expression::SmallerThan(l, r) => {
-                    self.fmt_binary(f, "stNode (<)", l, r, &child_prefix)?
-                }
-                Expression::GreaterThanOrEqual(l, r) => {
-                    self.fmt_binary(f, "gteqNode (>=)", l, r, &child_prefix)?
-                }
-                Expression::SmallerThanOrEqual(l, r) => {
-                    self.fmt_binary(f, "steqNode (<=)", l, r, &child_prefix)?
+                operation => {
+                    match operation {
+                        Expression::Call(name, _) => writeln!(f, "callNode ({}())", name)?,
+                        Expression::Spatial(op, _) => writeln!(f, "spatialNode ({})", op)?,
+                        _ => writeln!(f, "{}", operation_label(operation))?,
+                    }
+                    let children: Vec<_> = operation.children().collect();
+                    for (i, child) in children.iter().enumerate() {
+                        self.fmt_as_tree(child, f, &child_prefix, i + 1 == children.len())?;
+                    }
                 }
             }
             Ok(())
         }
+    }
 
-        fn fmt_binary(
-            &self,
-            f: &mut fmt::Formatter<'_>,
-            name: &str,
-            l: &Expression,
-            r: &Expression,
-            prefix: &str,
-        ) -> fmt::Result {
-            writeln!(f, "{}", name)?;
-            self.fmt_as_tree(l, f, prefix, false)?;
-            self.fmt_as_tree(r, f, prefix, true)?;
-            Ok(())
+    fn operation_label(expr: &Expression) -> &'static str {
+        match expr {
+            Expression::Sum(..) => "sumNode (+)",
+            Expression::Subtract(..) => "subNode (-)",
+            Expression::Multiply(..) => "multNode (*)",
+            Expression::Divide(..) => "divideNode (/)",
+            Expression::Abs(_) => "absNode (ABS)",
+            Expression::Not(_) => "notNode (NOT)",
+            Expression::And(..) => "andNode (AND)",
+            Expression::Or(..) => "orNode (OR)",
+            Expression::Xor(..) => "xorNode (XOR)",
+            Expression::Equal(..) => "eqNode (==)",
+            Expression::NotEqual(..) => "neqNode (!=)",
+            Expression::GreaterThan(..) => "gtNode (>)",
+            Expression::SmallerThan(..) => "stNode (<)",
+            Expression::GreaterThanOrEqual(..) => "gteqNode (>=)",
+            Expression::SmallerThanOrEqual(..) => "steqNode (<=)",
+            Expression::Table(..) => "tableNode (TABLE)",
+            Expression::Interpolate(..) => "interpolateNode (INTERPOLATE)",
+            Expression::Call(..) => "callNode (CALL)",
+            Expression::Spatial(..) => "spatialNode (SPATIAL)",
+            Expression::Literal(_) | Expression::Input(_) | Expression::Reference(_) => {
+                unreachable!("leaves and references are not operations")
+            }
         }
     }
 }
@@ -0,0 +1,458 @@
+//! An arena-allocated expression DAG.
+//!
+//! Every node lives in a single `Vec` owned by an [`ExpressionGraph`] and refers to its
+//! operands by [`NodeId`]. A graph created with [`ExpressionGraph::new`] is hash-consed:
+//! adding a node that is structurally equal to an existing one returns the existing id.
+//! Identical subexpressions are therefore stored once, rewriting a node never copies its
+//! subtree, and two ids are equal exactly when the expressions they denote are equal.
+//!
+//! `Expression` remains the convenient, owned tree form. [`ExpressionGraph::insert`]
+//! turns a tree into graph nodes, and [`ExpressionGraph::to_expression`] and
+//! [`ExpressionGraph::to_shared_expression`] turn graph nodes back into a tree.
+
+use super::{Expression, InputSource, LookupTable, SpatialOp, Value};
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use std::ops::Index;
+
+/// The index of a node within its `ExpressionGraph`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    /// The position of the node in the graph's arena.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A single operation in an `ExpressionGraph`. Operands are ids of other nodes.
+///
+/// The variants mirror `Expression`, except that there is no `Reference`: sharing is
+/// expressed by several nodes pointing at the same operand id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Node {
+    Sum(NodeId, NodeId),
+    Subtract(NodeId, NodeId),
+    Multiply(NodeId, NodeId),
+    Divide(NodeId, NodeId),
+    Abs(NodeId),
+    Not(NodeId),
+    And(NodeId, NodeId),
+    Or(NodeId, NodeId),
+    Xor(NodeId, NodeId),
+    Equal(NodeId, NodeId),
+    NotEqual(NodeId, NodeId),
+    GreaterThan(NodeId, NodeId),
+    GreaterThanOrEqual(NodeId, NodeId),
+    SmallerThan(NodeId, NodeId),
+    SmallerThanOrEqual(NodeId, NodeId),
+    Table(NodeId, LookupTable),
+    Interpolate(NodeId, LookupTable),
+    Call(String, Vec<NodeId>),
+    Spatial(SpatialOp, Vec<NodeId>),
+    Literal(Value),
+    Input(InputSource),
+}
+
+impl Node {
+    /// The operand ids of this node, left to right.
+    pub fn children(&self) -> impl Iterator<Item = NodeId> {
+        let (first, second, rest) = match *self {
+            Node::Sum(l, r)
+            | Node::Subtract(l, r)
+            | Node::Multiply(l, r)
+            | Node::Divide(l, r)
+            | Node::And(l, r)
+            | Node::Or(l, r)
+            | Node::Xor(l, r)
+            | Node::Equal(l, r)
+            | Node::NotEqual(l, r)
+            | Node::GreaterThan(l, r)
+            | Node::GreaterThanOrEqual(l, r)
+            | Node::SmallerThan(l, r)
+            | Node::SmallerThanOrEqual(l, r) => (Some(l), Some(r), &[][..]),
+            Node::Abs(v) | Node::Not(v) | Node::Table(v, _) | Node::Interpolate(v, _) => {
+                (Some(v), None, &[][..])
+            }
+            Node::Call(_, ref args) | Node::Spatial(_, ref args) => (None, None, args.as_slice()),
+            Node::Literal(_) | Node::Input(_) => (None, None, &[][..]),
+        };
+        first.into_iter().chain(second).chain(rest.iter().copied())
+    }
+
+    /// Returns the same operation with every operand id replaced by `f(id)`.
+    pub fn map_children(&self, mut f: impl FnMut(NodeId) -> NodeId) -> Node {
+        match self {
+            Node::Sum(l, r) => Node::Sum(f(*l), f(*r)),
+            Node::Subtract(l, r) => Node::Subtract(f(*l), f(*r)),
+            Node::Multiply(l, r) => Node::Multiply(f(*l), f(*r)),
+            Node::Divide(l, r) => Node::Divide(f(*l), f(*r)),
+            Node::Abs(v) => Node::Abs(f(*v)),
+            Node::Not(v) => Node::Not(f(*v)),
+            Node::And(l, r) => Node::And(f(*l), f(*r)),
+            Node::Or(l, r) => Node::Or(f(*l), f(*r)),
+            Node::Xor(l, r) => Node::Xor(f(*l), f(*r)),
+            Node::Equal(l, r) => Node::Equal(f(*l), f(*r)),
+            Node::NotEqual(l, r) => Node::NotEqual(f(*l), f(*r)),
+            Node::GreaterThan(l, r) => Node::GreaterThan(f(*l), f(*r)),
+            Node::GreaterThanOrEqual(l, r) => Node::GreaterThanOrEqual(f(*l), f(*r)),
+            Node::SmallerThan(l, r) => Node::SmallerThan(f(*l), f(*r)),
+            Node::SmallerThanOrEqual(l, r) => Node::SmallerThanOrEqual(f(*l), f(*r)),
+            Node::Table(v, table) => Node::Table(f(*v), table.clone()),
+            Node::Interpolate(v, table) => Node::Interpolate(f(*v), table.clone()),
+            Node::Call(name, args) => {
+                Node::Call(name.clone(), args.iter().map(|a| f(*a)).collect())
+            }
+            Node::Spatial(op, args) => Node::Spatial(*op, args.iter().map(|a| f(*a)).collect()),
+            leaf => leaf.clone(),
+        }
+    }
+
+    /// Returns true for literals and inputs.
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, Node::Literal(_) | Node::Input(_))
+    }
+}
+
+/// A node as the key of a hash-consed graph's index.
+///
+/// `Node`'s own equality compares numbers as floats, which would merge `0.0` with `-0.0`
+/// and never merge a NaN with itself. Two nodes denote the same expression only if their
+/// numbers have the same bits, so the key compares and hashes them that way.
+#[derive(Debug, Clone)]
+struct NodeKey(Node);
+
+impl PartialEq for NodeKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Node::Literal(a), Node::Literal(b)) => a.is_identical(b),
+            (Node::Table(a, t), Node::Table(b, u))
+            | (Node::Interpolate(a, t), Node::Interpolate(b, u)) => a == b && t.is_identical(u),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for NodeKey {}
+
+impl Hash for NodeKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Identical nodes are also equal as `Node`s, except for NaN literals.
+        match &self.0 {
+            Node::Literal(Value::Number(n)) => n.to_bits().hash(state),
+            node => node.hash(state),
+        }
+    }
+}
+
+/// An arena of expression nodes. See the module documentation.
+#[derive(Debug, Clone)]
+pub struct ExpressionGraph {
+    nodes: Vec<Node>,
+    index: AHashMap<NodeKey, NodeId>,
+    hash_consing: bool,
+}
+
+impl Default for ExpressionGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpressionGraph {
+    /// Creates an empty, hash-consed graph.
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            index: AHashMap::new(),
+            hash_consing: true,
+        }
+    }
+
+    /// Creates an empty graph that stores every added node separately, so nodes are only
+    /// shared where a caller explicitly reuses an id.
+    pub fn without_sharing() -> Self {
+        Self {
+            hash_consing: false,
+            ..Self::new()
+        }
+    }
+
+    /// Adds `node` and returns its id. In a hash-consed graph an existing equal node is reused.
+    pub fn add(&mut self, node: Node) -> NodeId {
+        let key = NodeKey(node);
+        if self.hash_consing
+            && let Some(id) = self.index.get(&key)
+        {
+            return *id;
+        }
+        let id = NodeId(u32::try_from(self.nodes.len()).expect("expression graph is full"));
+        self.nodes.push(key.0.clone());
+        if self.hash_consing {
+            self.index.insert(key, id);
+        }
+        id
+    }
+
+    /// Returns the node with the given id.
+    pub fn get(&self, id: NodeId) -> &Node {
+        &self.nodes[id.index()]
+    }
+
+    /// Number of nodes in the arena, including ones no longer reachable from any root.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Adds `expr` to the graph and returns the id of its root. `Reference` nodes are
+    /// resolved through `definitions`; every use of the same definition maps to one node.
+    ///
+    /// Returns the id of the first `Reference` that has no definition as the error.
+    pub fn insert(
+        &mut self,
+        expr: &Expression,
+        definitions: &AHashMap<u64, Expression>,
+    ) -> Result<NodeId, u64> {
+        self.insert_with(expr, definitions, &mut |source| source.clone())
+    }
+
+    /// Like [`Self::insert`], but passes every input through `map_input` first.
+    pub fn insert_with(
+        &mut self,
+        expr: &Expression,
+        definitions: &AHashMap<u64, Expression>,
+        map_input: &mut dyn FnMut(&InputSource) -> InputSource,
+    ) -> Result<NodeId, u64> {
+        let mut references = AHashMap::new();
+        self.insert_recursive(expr, definitions, map_input, &mut references)
+    }
+
+    fn insert_recursive(
+        &mut self,
+        expr: &Expression,
+        definitions: &AHashMap<u64, Expression>,
+        map_input: &mut dyn FnMut(&InputSource) -> InputSource,
+        references: &mut AHashMap<u64, NodeId>,
+    ) -> Result<NodeId, u64> {
+        match expr {
+            Expression::Reference(id) => {
+                if let Some(node) = references.get(id) {
+                    return Ok(*node);
+                }
+                let def = definitions.get(id).ok_or(*id)?;
+                let node = self.insert_recursive(def, definitions, map_input, references)?;
+                references.insert(*id, node);
+                return Ok(node);
+            }
+            Expression::Literal(v) => return Ok(self.add(Node::Literal(v.clone()))),
+            Expression::Input(source) => return Ok(self.add(Node::Input(map_input(source)))),
+            _ => {}
+        }
+
+        let mut child = |graph: &mut Self, e: &Expression| {
+            graph.insert_recursive(e, definitions, map_input, references)
+        };
+        let node = match expr {
+            Expression::Sum(l, r) => Node::Sum(child(self, l)?, child(self, r)?),
+            Expression::Subtract(l, r) => Node::Subtract(child(self, l)?, child(self, r)?),
+            Expression::Multiply(l, r) => Node::Multiply(child(self, l)?, child(self, r)?),
+            Expression::Divide(l, r) => Node::Divide(child(self, l)?, child(self, r)?),
+            Expression::Abs(v) => Node::Abs(child(self, v)?),
+            Expression::Not(v) => Node::Not(child(self, v)?),
+            Expression::And(l, r) => Node::And(child(self, l)?, child(self, r)?),
+            Expression::Or(l, r) => Node::Or(child(self, l)?, child(self, r)?),
+            Expression::Xor(l, r) => Node::Xor(child(self, l)?, child(self, r)?),
+            Expression::Equal(l, r) => Node::Equal(child(self, l)?, child(self, r)?),
+            Expression::NotEqual(l, r) => Node::NotEqual(child(self, l)?, child(self, r)?),
+            Expression::GreaterThan(l, r) => Node::GreaterThan(child(self, l)?, child(self, r)?),
+            Expression::GreaterThanOrEqual(l, r) => {
+                Node::GreaterThanOrEqual(child(self, l)?, child(self, r)?)
+            }
+            Expression::SmallerThan(l, r) => Node::SmallerThan(child(self, l)?, child(self, r)?),
+            Expression::SmallerThanOrEqual(l, r) => {
+                Node::SmallerThanOrEqual(child(self, l)?, child(self, r)?)
+            }
+            Expression::Table(v, table) => Node::Table(child(self, v)?, table.clone()),
+            Expression::Interpolate(v, table) => Node::Interpolate(child(self, v)?, table.clone()),
+            Expression::Call(name, args) => Node::Call(
+                name.clone(),
+                args.iter()
+                    .map(|a| child(self, a))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expression::Spatial(op, args) => Node::Spatial(
+                *op,
+                args.iter()
+                    .map(|a| child(self, a))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expression::Literal(_) | Expression::Input(_) | Expression::Reference(_) => {
+                unreachable!("leaves and references are handled above")
+            }
+        };
+        Ok(self.add(node))
+    }
+
+    /// Expands the subgraph rooted at `root` into a tree. Shared nodes are duplicated.
+    pub fn to_expression(&self, root: NodeId) -> Expression {
+        self.build_expression(root, &AHashMap::new())
+    }
+
+    /// Converts the subgraph rooted at `root` into a tree in which every shared operation
+    /// (see [`Self::shared_nodes`]) is replaced by a `Reference` to its definition.
+    ///
+    /// Definition ids are the positions in `shared_nodes`, so they are dense and
+    /// deterministic. A shared root is itself returned as a `Reference`.
+    pub fn to_shared_expression(&self, root: NodeId) -> (Expression, AHashMap<u64, Expression>) {
+        let shared = self.definition_ids(root);
+        let definitions = shared
+            .iter()
+            .map(|(node, id)| (*id, self.build_definition(*node, &shared)))
+            .collect();
+        (self.build_expression(root, &shared), definitions)
+    }
+
+    /// Maps every shared operation reachable from `root` to its definition id.
+    pub fn definition_ids(&self, root: NodeId) -> AHashMap<NodeId, u64> {
+        self.shared_nodes(root)
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| (node, i as u64))
+            .collect()
+    }
+
+    fn build_definition(&self, id: NodeId, shared: &AHashMap<NodeId, u64>) -> Expression {
+        self.build_node(self.get(id), shared)
+    }
+
+    fn build_expression(&self, id: NodeId, shared: &AHashMap<NodeId, u64>) -> Expression {
+        match shared.get(&id) {
+            Some(definition) => Expression::Reference(*definition),
+            None => self.build_node(self.get(id), shared),
+        }
+    }
+
+    fn build_node(&self, node: &Node, shared: &AHashMap<NodeId, u64>) -> Expression {
+        let child = |id: &NodeId| Box::new(self.build_expression(*id, shared));
+        match node {
+            Node::Sum(l, r) => Expression::Sum(child(l), child(r)),
+            Node::Subtract(l, r) => Expression::Subtract(child(l), child(r)),
+            Node::Multiply(l, r) => Expression::Multiply(child(l), child(r)),
+            Node::Divide(l, r) => Expression::Divide(child(l), child(r)),
+            Node::Abs(v) => Expression::Abs(child(v)),
+            Node::Not(v) => Expression::Not(child(v)),
+            Node::And(l, r) => Expression::And(child(l), child(r)),
+            Node::Or(l, r) => Expression::Or(child(l), child(r)),
+            Node::Xor(l, r) => Expression::Xor(child(l), child(r)),
+            Node::Equal(l, r) => Expression::Equal(child(l), child(r)),
+            Node::NotEqual(l, r) => Expression::NotEqual(child(l), child(r)),
+            Node::GreaterThan(l, r) => Expression::GreaterThan(child(l), child(r)),
+            Node::GreaterThanOrEqual(l, r) => Expression::GreaterThanOrEqual(child(l), child(r)),
+            Node::SmallerThan(l, r) => Expression::SmallerThan(child(l), child(r)),
+            Node::SmallerThanOrEqual(l, r) => Expression::SmallerThanOrEqual(child(l), child(r)),
+            Node::Table(v, table) => Expression::Table(child(v), table.clone()),
+            Node::Interpolate(v, table) => Expression::Interpolate(child(v), table.clone()),
+            Node::Call(name, args) => {
+                Expression::Call(name.clone(), args.iter().map(|a| *child(a)).collect())
+            }
+            Node::Spatial(op, args) => {
+                Expression::Spatial(*op, args.iter().map(|a| *child(a)).collect())
+            }
+            Node::Literal(v) => Expression::Literal(v.clone()),
+            Node::Input(source) => Expression::Input(source.clone()),
+        }
+    }
+
+    /// The nodes reachable from `root`, each listed once, operands before their users.
+    pub fn post_order(&self, root: NodeId) -> Vec<NodeId> {
+        fn visit(graph: &ExpressionGraph, id: NodeId, seen: &mut [bool], out: &mut Vec<NodeId>) {
+            if std::mem::replace(&mut seen[id.index()], true) {
+                return;
+            }
+            for child in graph.get(id).children() {
+                visit(graph, child, seen, out);
+            }
+            out.push(id);
+        }
+
+        let mut seen = vec![false; self.nodes.len()];
+        let mut out = Vec::new();
+        visit(self, root, &mut seen, &mut out);
+        out
+    }
+
+    /// The operations reachable from `root` that are used as an operand more than once,
+    /// in post-order. Shared literals and inputs are not included.
+    pub fn shared_nodes(&self, root: NodeId) -> Vec<NodeId> {
+        let order = self.post_order(root);
+        let mut uses = vec![0usize; self.nodes.len()];
+        for id in &order {
+            for child in self.get(*id).children() {
+                uses[child.index()] += 1;
+            }
+        }
+        order
+            .into_iter()
+            .filter(|id| uses[id.index()] > 1 && !self.get(*id).is_leaf())
+            .collect()
+    }
+
+    /// Copies the nodes reachable from `root` into a new, compact graph and returns it
+    /// with the new root. With `share` set to false the copy has no sharing at all: every
+    /// use of a shared node gets its own copy of the subtree.
+    pub fn extract(&self, root: NodeId, share: bool) -> (ExpressionGraph, NodeId) {
+        let mut graph = if share {
+            Self::new()
+        } else {
+            Self::without_sharing()
+        };
+        let mut copied = AHashMap::new();
+        let root = self.copy_into(root, &mut graph, share.then_some(&mut copied));
+        (graph, root)
+    }
+
+    fn copy_into(
+        &self,
+        id: NodeId,
+        target: &mut ExpressionGraph,
+        mut copied: Option<&mut AHashMap<NodeId, NodeId>>,
+    ) -> NodeId {
+        if let Some(new_id) = copied.as_ref().and_then(|c| c.get(&id)) {
+            return *new_id;
+        }
+        let node = self
+            .get(id)
+            .map_children(|child| self.copy_into(child, target, copied.as_deref_mut()));
+        let new_id = target.add(node);
+        if let Some(copied) = copied {
+            copied.insert(id, new_id);
+        }
+        new_id
+    }
+
+    /// Number of nodes in the tree expansion of `root`, i.e. counting shared nodes once
+    /// per use. Saturates instead of overflowing on very deep sharing.
+    pub fn tree_size(&self, root: NodeId) -> usize {
+        let mut sizes = vec![0usize; self.nodes.len()];
+        for id in self.post_order(root) {
+            sizes[id.index()] = self.get(id).children().fold(1usize, |acc, child| {
+                acc.saturating_add(sizes[child.index()])
+            });
+        }
+        sizes[root.index()]
+    }
+}
+
+impl Index<NodeId> for ExpressionGraph {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        self.get(id)
+    }
+}
@@ -1,7 +1,15 @@
 pub mod expression;
+pub mod graph;
+pub mod spatial;
+pub mod table;
 pub mod trace;
 pub mod value;
+pub mod visit;
 
 pub use expression::*;
+pub use graph::{ExpressionGraph, Node, NodeId};
+pub use spatial::SpatialOp;
+pub use table::LookupTable;
 pub use trace::*;
 pub use value::*;
+pub use visit::*;
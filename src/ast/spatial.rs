@@ -0,0 +1,99 @@
+use super::Value;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The field holding the X coordinate of an event's centre, across the board's width.
+pub const X_FIELD: &str = "X";
+/// The field holding the Y coordinate of an event's centre, along the board's length.
+pub const Y_FIELD: &str = "Y";
+/// The field holding an event's extent along X.
+pub const WIDTH_FIELD: &str = "Width";
+/// The field holding an event's extent along Y.
+pub const LENGTH_FIELD: &str = "Length";
+
+/// A geometric predicate or measure over event instances.
+///
+/// Coordinates are in board units with the origin at a corner of the board. An event is
+/// the axis-aligned box centred on its `X`/`Y` fields, `Width` wide and `Length` long.
+/// The operands of an `Expression::Spatial` are plain numbers, in the order listed for
+/// each operation, so both backends evaluate them like any other operands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SpatialOp {
+    /// The distance between the centres of two events: `ax, ay, bx, by`.
+    Distance,
+    /// Whether the boxes of two events overlap; touching edges do not count:
+    /// `ax, ay, aw, al, bx, by, bw, bl`.
+    Overlap,
+    /// Whether the centre of an event lies in a zone of the board, bounds included:
+    /// `x, y, min_x, min_y, max_x, max_y`.
+    InZone,
+    /// The gap between the box of an event and the nearest edge of the board, negative if
+    /// the box sticks out of it: `x, y, w, l, board_width, board_length`.
+    EdgeDistance,
+}
+
+impl SpatialOp {
+    pub const ALL: [SpatialOp; 4] = [
+        SpatialOp::Distance,
+        SpatialOp::Overlap,
+        SpatialOp::InZone,
+        SpatialOp::EdgeDistance,
+    ];
+
+    /// The most operands any operation takes.
+    pub const MAX_ARITY: usize = 8;
+
+    /// The number of operands the operation takes.
+    pub fn arity(self) -> usize {
+        match self {
+            SpatialOp::Distance => 4,
+            SpatialOp::Overlap => 8,
+            SpatialOp::InZone | SpatialOp::EdgeDistance => 6,
+        }
+    }
+
+    /// The name used in traces, exports and bytecode assembly.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            SpatialOp::Distance => "DISTANCE",
+            SpatialOp::Overlap => "OVERLAP",
+            SpatialOp::InZone => "IN_ZONE",
+            SpatialOp::EdgeDistance => "EDGE_DISTANCE",
+        }
+    }
+
+    /// The operation named `symbol`, the inverse of [`Self::symbol`].
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|op| op.symbol() == symbol)
+    }
+
+    /// Applies the operation to its operands, which must be exactly [`Self::arity`]
+    /// numbers.
+    pub fn apply(self, args: &[f64]) -> Value {
+        match (self, args) {
+            (SpatialOp::Distance, &[ax, ay, bx, by]) => Value::Number((ax - bx).hypot(ay - by)),
+            (SpatialOp::Overlap, &[ax, ay, aw, al, bx, by, bw, bl]) => {
+                Value::Bool(2.0 * (ax - bx).abs() < aw + bw && 2.0 * (ay - by).abs() < al + bl)
+            }
+            (SpatialOp::InZone, &[x, y, min_x, min_y, max_x, max_y]) => {
+                Value::Bool((min_x..=max_x).contains(&x) && (min_y..=max_y).contains(&y))
+            }
+            (SpatialOp::EdgeDistance, &[x, y, w, l, board_width, board_length]) => {
+                let (half_w, half_l) = (w / 2.0, l / 2.0);
+                Value::Number(
+                    (x - half_w)
+                        .min(board_width - x - half_w)
+                        .min(y - half_l)
+                        .min(board_length - y - half_l),
+                )
+            }
+            _ => Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for SpatialOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
@@ -0,0 +1,125 @@
+use super::Value;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// The breakpoints of a `tableNode` or `interpolateNode`: `(key, value)` rows with
+/// strictly increasing keys.
+///
+/// Inputs outside the table are clamped to its first or last row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LookupTable {
+    rows: Vec<(f64, f64)>,
+}
+
+// Manual implementation to handle f64, as for `Value`.
+impl Eq for LookupTable {}
+
+impl Hash for LookupTable {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // `0.0` and `-0.0` compare equal, so they must also hash equal.
+        let bits = |n: f64| if n == 0.0 { 0.0f64 } else { n }.to_bits();
+        for (key, value) in &self.rows {
+            bits(*key).hash(state);
+            bits(*value).hash(state);
+        }
+    }
+}
+
+impl LookupTable {
+    /// Creates a table from its rows. Fails unless there is at least one row, every
+    /// number is finite and the keys are strictly increasing.
+    pub fn new(rows: Vec<(f64, f64)>) -> Result<Self, String> {
+        if rows.is_empty() {
+            return Err("the table has no rows".to_string());
+        }
+        if let Some(row) = rows
+            .iter()
+            .position(|(k, v)| !k.is_finite() || !v.is_finite())
+        {
+            return Err(format!("row {} is not a pair of finite numbers", row));
+        }
+        if let Some(row) = rows.windows(2).position(|pair| pair[0].0 >= pair[1].0) {
+            return Err(format!(
+                "the keys must be strictly increasing, but row {} has key {} after {}",
+                row + 1,
+                rows[row + 1].0,
+                rows[row].0
+            ));
+        }
+        Ok(Self { rows })
+    }
+
+    pub fn rows(&self) -> &[(f64, f64)] {
+        &self.rows
+    }
+
+    /// Whether both tables have the same rows, comparing numbers by their bits like
+    /// `Value::is_identical`.
+    pub(crate) fn is_identical(&self, other: &LookupTable) -> bool {
+        let bits = |(k, v): &(f64, f64)| (k.to_bits(), v.to_bits());
+        self.rows.iter().map(bits).eq(other.rows.iter().map(bits))
+    }
+
+    /// Step lookup: the value of the last row whose key is at most `x`. Returns the value
+    /// and the row it was taken from.
+    pub fn step(&self, x: f64) -> (f64, &[(f64, f64)]) {
+        if x.is_nan() {
+            return (f64::NAN, &[]);
+        }
+        let row = self.upper_bound(x).saturating_sub(1);
+        (self.rows[row].1, &self.rows[row..=row])
+    }
+
+    /// Piecewise-linear interpolation between the two rows bracketing `x`. Returns the
+    /// value and the rows it was computed from: two inside the table, one at or beyond
+    /// its ends.
+    pub fn interpolate(&self, x: f64) -> (f64, &[(f64, f64)]) {
+        if x.is_nan() {
+            return (f64::NAN, &[]);
+        }
+        let upper = self.upper_bound(x);
+        if upper == 0 {
+            return (self.rows[0].1, &self.rows[..1]);
+        }
+        let (k0, v0) = self.rows[upper - 1];
+        if upper == self.rows.len() || k0 == x {
+            return (v0, &self.rows[upper - 1..upper]);
+        }
+        let (k1, v1) = self.rows[upper];
+        (
+            v0 + (x - k0) * (v1 - v0) / (k1 - k0),
+            &self.rows[upper - 1..=upper],
+        )
+    }
+
+    /// The number of rows whose key is at most `x`, found by binary search.
+    fn upper_bound(&self, x: f64) -> usize {
+        self.rows.partition_point(|(key, _)| *key <= x)
+    }
+
+    /// A total order over tables, used to sort otherwise equal expressions.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        self.rows
+            .iter()
+            .zip(&other.rows)
+            .map(|((ka, va), (kb, vb))| ka.total_cmp(kb).then(va.total_cmp(vb)))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| self.rows.len().cmp(&other.rows.len()))
+    }
+}
+
+impl fmt::Display for LookupTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_rows(&self.rows))
+    }
+}
+
+/// Formats rows as `10 -> 0.5, 20 -> 0.7`.
+pub fn format_rows(rows: &[(f64, f64)]) -> String {
+    rows.iter()
+        .map(|(key, value)| format!("{} -> {}", Value::Number(*key), Value::Number(*value)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
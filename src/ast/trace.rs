@@ -14,6 +14,19 @@ pub enum EvaluationTrace {
         child: Box<EvaluationTrace>,
         outcome: Value,
     },
+    /// A `TABLE` or `INTERPOLATE` lookup, with the rows that bracketed the input.
+    Lookup {
+        op_symbol: &'static str,
+        input: Box<EvaluationTrace>,
+        rows: Vec<(f64, f64)>,
+        outcome: Value,
+    },
+    /// A call to a native function, with the traces of its arguments.
+    Call {
+        function: String,
+        args: Vec<EvaluationTrace>,
+        outcome: Value,
+    },
     Leaf {
         source: String,
         value: Value,
@@ -26,6 +39,8 @@ impl EvaluationTrace {
         match self {
             EvaluationTrace::BinaryOp { outcome, .. } => outcome.clone(),
             EvaluationTrace::UnaryOp { outcome, .. } => outcome.clone(),
+            EvaluationTrace::Lookup { outcome, .. } => outcome.clone(),
+            EvaluationTrace::Call { outcome, .. } => outcome.clone(),
             EvaluationTrace::Leaf { value, .. } => value.clone(),
             EvaluationTrace::NotEvaluated => Value::Null,
         }
@@ -44,7 +59,10 @@ impl EvaluationTrace {
                 _ => 0,
             },
             EvaluationTrace::UnaryOp { .. } => 8,
-            EvaluationTrace::Leaf { .. } | EvaluationTrace::NotEvaluated => 9,
+            EvaluationTrace::Lookup { .. }
+            | EvaluationTrace::Call { .. }
+            | EvaluationTrace::Leaf { .. }
+            | EvaluationTrace::NotEvaluated => 9,
         }
     }
 }
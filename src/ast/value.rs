@@ -22,11 +22,24 @@ impl Default for Value {
 // Manual implementation to handle f64
 impl Eq for Value {}
 
-// Manual implementation to handle f64 by hashing its bits
+impl Value {
+    /// Whether both values are the same, comparing numbers by their bits. Unlike `==`,
+    /// this tells `0.0` and `-0.0` apart and considers a NaN identical to itself.
+    pub(crate) fn is_identical(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            _ => self == other,
+        }
+    }
+}
+
+// Manual implementation to handle f64 by hashing its bits.
+// `0.0` and `-0.0` compare equal, so they must also hash equal.
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
         core::mem::discriminant(self).hash(state);
         match self {
+            Value::Number(n) if *n == 0.0 => 0.0f64.to_bits().hash(state),
             Value::Number(n) => n.to_bits().hash(state),
             Value::Bool(b) => b.hash(state),
             Value::Null => {}
@@ -52,15 +65,41 @@ impl fmt::Display for Value {
 
 /// Defines the source of data for a leaf node in the AST.
 /// Supports both compilation-time string names and runtime IDs.
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum InputSource {
     // Runtime variants (used after string interning)
-    Static { id: InputId },
-    Dynamic { id: InputId },
+    Static {
+        id: InputId,
+    },
+    Dynamic {
+        id: InputId,
+    },
+    /// A recipe parameter: its default, unless overridden when evaluating.
+    Parameter {
+        id: InputId,
+    },
 
     // Compilation-time variants (used during initial AST building)
-    StaticName { name: String },
-    DynamicName { event: String, field: String },
+    StaticName {
+        name: String,
+    },
+    DynamicName {
+        event: String,
+        field: String,
+    },
+    ParameterName {
+        name: String,
+    },
+}
+
+impl InputSource {
+    /// Whether the input is a field of an event. It is null while the event has no instances.
+    pub fn is_dynamic(&self) -> bool {
+        matches!(
+            self,
+            InputSource::Dynamic { .. } | InputSource::DynamicName { .. }
+        )
+    }
 }
 
 impl fmt::Display for InputSource {
@@ -68,8 +107,10 @@ impl fmt::Display for InputSource {
         match self {
             InputSource::Static { id } => write!(f, "$Static[{}]", id),
             InputSource::Dynamic { id } => write!(f, "$Dynamic[{}]", id),
+            InputSource::Parameter { id } => write!(f, "@Parameter[{}]", id),
             InputSource::StaticName { name } => write!(f, "${}", name),
             InputSource::DynamicName { event, field } => write!(f, "${}.{}", event, field),
+            InputSource::ParameterName { name } => write!(f, "@{}", name),
         }
     }
 }
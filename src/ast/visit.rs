@@ -0,0 +1,93 @@
+//! Generic traversal of `Expression` trees.
+//!
+//! [`ExpressionVisitor`] walks a tree by reference and [`ExpressionFolder`] rebuilds it by
+//! value. Both traverse every operand by default, so an implementation only overrides
+//! the methods for the nodes it cares about and stays correct when variants are added.
+//!
+//! ```
+//! use hantei::ast::{Expression, ExpressionVisitor, InputSource};
+//!
+//! /// Collects the names of all static inputs.
+//! struct StaticNames(Vec<String>);
+//!
+//! impl ExpressionVisitor for StaticNames {
+//!     fn visit_input(&mut self, source: &InputSource) {
+//!         if let InputSource::StaticName { name } = source {
+//!             self.0.push(name.clone());
+//!         }
+//!     }
+//! }
+//!
+//! let expr = Expression::Not(Box::new(Expression::Input(InputSource::StaticName {
+//!     name: "Temp".to_string(),
+//! })));
+//! let mut names = StaticNames(Vec::new());
+//! names.visit_expression(&expr);
+//! assert_eq!(names.0, ["Temp"]);
+//! ```
+
+use super::{Expression, InputSource, Value};
+
+/// Read-only traversal of an `Expression` tree.
+///
+/// `Reference` nodes are reported through [`Self::visit_reference`] and not followed;
+/// resolve them against the definitions map there if the analysis needs to.
+pub trait ExpressionVisitor {
+    /// Called for every node. The default visits the node's operands or leaf.
+    fn visit_expression(&mut self, expr: &Expression) {
+        walk_expression(self, expr);
+    }
+
+    fn visit_literal(&mut self, _value: &Value) {}
+
+    fn visit_input(&mut self, _source: &InputSource) {}
+
+    fn visit_reference(&mut self, _id: u64) {}
+}
+
+/// The default traversal of [`ExpressionVisitor::visit_expression`]. Call it from an
+/// override to continue into the operands.
+pub fn walk_expression<V: ExpressionVisitor + ?Sized>(visitor: &mut V, expr: &Expression) {
+    match expr {
+        Expression::Literal(value) => visitor.visit_literal(value),
+        Expression::Input(source) => visitor.visit_input(source),
+        Expression::Reference(id) => visitor.visit_reference(*id),
+        _ => {
+            for child in expr.children() {
+                visitor.visit_expression(child);
+            }
+        }
+    }
+}
+
+/// Bottom-up rewriting of an `Expression` tree.
+pub trait ExpressionFolder {
+    /// Called for every node. The default folds the node's operands or leaf and keeps
+    /// the operation itself.
+    fn fold_expression(&mut self, expr: Expression) -> Expression {
+        fold_children(self, expr)
+    }
+
+    fn fold_literal(&mut self, value: Value) -> Expression {
+        Expression::Literal(value)
+    }
+
+    fn fold_input(&mut self, source: InputSource) -> Expression {
+        Expression::Input(source)
+    }
+
+    fn fold_reference(&mut self, id: u64) -> Expression {
+        Expression::Reference(id)
+    }
+}
+
+/// The default traversal of [`ExpressionFolder::fold_expression`]. Call it from an
+/// override to fold the operands before rewriting the node itself.
+pub fn fold_children<F: ExpressionFolder + ?Sized>(folder: &mut F, expr: Expression) -> Expression {
+    match expr {
+        Expression::Literal(value) => folder.fold_literal(value),
+        Expression::Input(source) => folder.fold_input(source),
+        Expression::Reference(id) => folder.fold_reference(id),
+        operation => operation.map_children(|child| folder.fold_expression(child)),
+    }
+}
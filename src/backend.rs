@@ -1,7 +1,10 @@
+use crate::ast::Value;
 use crate::compiler::CompilationArtifacts;
 use crate::error::{BackendError, EvaluationError};
+use crate::events::CombinationPolicy;
+use crate::functions::FunctionRegistry;
 use crate::interpreter::EvaluationResult;
-use crate::recipe::CompiledRecipe;
+use crate::recipe::{CompiledRecipe, RecipeParameter};
 use ahash::AHashMap;
 
 /// A compiled, runnable recipe that can be evaluated against data.
@@ -11,7 +14,11 @@ pub trait ExecutableRecipe: Send + Sync {
         &self,
         static_data: &AHashMap<String, f64>,
         dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>,
+        parameters: &AHashMap<String, Value>,
     ) -> Result<EvaluationResult, EvaluationError>;
+
+    /// The parameters of the recipe, indexed by id, with their defaults.
+    fn parameters(&self) -> &[RecipeParameter];
 }
 
 /// A trait for an evaluation backend that transforms ASTs into an `ExecutableRecipe`.
@@ -21,8 +28,13 @@ pub trait EvaluationBackend {
     fn compile(&self, artifacts: Vec<CompilationArtifacts>)
     -> Result<CompiledRecipe, BackendError>;
 
-    /// Loads a pre-compiled recipe and prepares it for execution.
-    fn load(&self, recipe: CompiledRecipe) -> Result<Box<dyn ExecutableRecipe>, BackendError>;
+    /// Loads a pre-compiled recipe and prepares it for execution, resolving the native
+    /// functions it calls against `functions`.
+    fn load(
+        &self,
+        recipe: CompiledRecipe,
+        functions: &FunctionRegistry,
+    ) -> Result<Box<dyn ExecutableRecipe>, BackendError>;
 }
 
 /// The available backends for evaluation.
@@ -33,3 +45,87 @@ pub enum BackendChoice {
     /// Compiles to custom bytecode and runs it on a stack-based VM. Faster.
     Bytecode,
 }
+
+/// Groups path indices by the decision they belong to, with decisions in order of first
+/// appearance and paths in their original order within each decision. Paths without a
+/// decision, i.e. outputs, are skipped.
+pub(crate) fn group_by_decision<'a>(
+    decisions: impl IntoIterator<Item = Option<&'a str>>,
+) -> Vec<(String, Vec<usize>)> {
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (index, decision) in decisions.into_iter().enumerate() {
+        let Some(decision) = decision else {
+            continue;
+        };
+        match groups.iter_mut().find(|(name, _)| name == decision) {
+            Some((_, indices)) => indices.push(index),
+            None => groups.push((decision.to_string(), vec![index])),
+        }
+    }
+    groups
+}
+
+/// The parameters of a recipe. Every artifact lists the parameters interned up to it, so
+/// the longest list contains all of them.
+pub(crate) fn recipe_parameters(artifacts: &[CompilationArtifacts]) -> Vec<RecipeParameter> {
+    artifacts
+        .iter()
+        .map(|a| &a.parameters)
+        .max_by_key(|parameters| parameters.len())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The combination policy of a recipe, which all of its artifacts share.
+pub(crate) fn recipe_combinations(artifacts: &[CompilationArtifacts]) -> CombinationPolicy {
+    artifacts
+        .first()
+        .map(|a| a.combinations)
+        .unwrap_or_default()
+}
+
+/// The value of every parameter by id: its default, unless `overrides` replaces it with a
+/// value of the same type.
+pub(crate) fn resolve_parameters(
+    parameters: &[RecipeParameter],
+    overrides: &AHashMap<String, Value>,
+) -> Result<Vec<Value>, EvaluationError> {
+    let mut values: Vec<Value> = parameters.iter().map(|p| p.default.clone()).collect();
+    for (name, value) in overrides {
+        let id = parameters
+            .iter()
+            .position(|p| p.name == *name)
+            .ok_or_else(|| EvaluationError::UnknownParameter(name.clone()))?;
+        let expected = match parameters[id].default {
+            Value::Number(_) => "Number",
+            Value::Bool(_) => "Bool",
+            Value::Null => "Null",
+        };
+        if std::mem::discriminant(value) != std::mem::discriminant(&parameters[id].default) {
+            return Err(EvaluationError::TypeMismatch {
+                operation: format!("override of parameter '{}'", name),
+                expected: expected.to_string(),
+                found: value.clone(),
+            });
+        }
+        values[id] = value.clone();
+    }
+    Ok(values)
+}
+
+/// The value of an output, given its value for every combination of the instances of the
+/// events it reads: the value they all agree on, or null if two of them differ. An output
+/// that reads no events, or only events without instances, has a single combination.
+pub(crate) fn agreed_output_value(
+    mut values: impl Iterator<Item = Result<Value, EvaluationError>>,
+) -> Result<Value, EvaluationError> {
+    let Some(first) = values.next().transpose()? else {
+        return Ok(Value::Null);
+    };
+    for value in values {
+        if value? != first {
+            return Ok(Value::Null);
+        }
+    }
+    Ok(first)
+}
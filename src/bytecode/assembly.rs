@@ -0,0 +1,530 @@
+//! A stable, human-readable text format for `BytecodeProgram`s.
+//!
+//! The disassembler and assembler are exact inverses: `assemble(&disassemble(p)) == p`
+//! for every program. This makes the format suitable for hand-written VM tests,
+//! for diffing compiled output between releases and for patching a program by hand.
+//!
+//! ```text
+//! .static "Temperature" 0
+//! .dynamic "hole.Diameter" 0
+//! .table 0 10.0:0.5 20.0:0.7
+//! .function 0 "hypot"
+//!
+//! .main
+//!     LoadStatic          R0, S0
+//!     LoadLiteral         R1, 25.0
+//!     GreaterThan         R0, R0, R1
+//!     Halt
+//!
+//! .sub 0
+//!     LoadDynamic         R0, D0
+//!     Return
+//! ```
+//!
+//! Operands are written as `R<n>` (register), `S<n>`/`D<n>` (static/dynamic input id),
+//! `P<n>` (parameter id), `T<n>` (table id), `F<n>` (native function id), `@<n>` (jump
+//! address), `#<n>` (subroutine id) or a literal value (`1.5`, `true`, `false`, `null`).
+//! A `.table` lists its rows as `key:value`. `CallNative` takes its argument count as a
+//! plain number, and `Spatial` its operation by name, as in `Spatial R0, DISTANCE, R1`.
+//! Everything after a `;` outside of a quoted string is a comment.
+//!
+//! `assemble` only accepts programs that pass `BytecodeProgram::validate`, so a program
+//! that names a missing register, jump target, subroutine, table or function is an error.
+
+use super::compiler::BytecodeProgram;
+use super::opcode::{Address, FunctionId, InputId, OpCode, Register, SubroutineId, TableId};
+use crate::ast::{LookupTable, SpatialOp, Value};
+use crate::error::AssemblyError;
+use ahash::AHashMap;
+use std::fmt::Write;
+
+/// Converts a program into its textual assembly form.
+///
+/// Maps and subroutines are emitted sorted by id, so the output is deterministic.
+pub fn disassemble(program: &BytecodeProgram) -> String {
+    let mut output = String::new();
+
+    let static_rev_map = sorted_by_id(&program.static_map);
+    let dynamic_rev_map = sorted_by_id(&program.dynamic_map);
+
+    for (id, name) in &static_rev_map {
+        writeln!(output, ".static {} {}", quote(name), id).unwrap();
+    }
+    for (id, name) in &dynamic_rev_map {
+        writeln!(output, ".dynamic {} {}", quote(name), id).unwrap();
+    }
+    for (id, table) in program.tables.iter().enumerate() {
+        let rows: Vec<_> = table
+            .rows()
+            .iter()
+            .map(|(key, value)| format!("{:?}:{:?}", key, value))
+            .collect();
+        writeln!(output, ".table {} {}", id, rows.join(" ")).unwrap();
+    }
+    for (id, name) in program.functions.iter().enumerate() {
+        writeln!(output, ".function {} {}", id, quote(name)).unwrap();
+    }
+    if !static_rev_map.is_empty()
+        || !dynamic_rev_map.is_empty()
+        || !program.tables.is_empty()
+        || !program.functions.is_empty()
+    {
+        writeln!(output).unwrap();
+    }
+
+    let static_names: AHashMap<InputId, &str> = static_rev_map.iter().copied().collect();
+    let dynamic_names: AHashMap<InputId, &str> = dynamic_rev_map.iter().copied().collect();
+    let names = ChunkNames {
+        static_names: &static_names,
+        dynamic_names: &dynamic_names,
+        functions: &program.functions,
+    };
+
+    writeln!(output, ".main").unwrap();
+    write_chunk(&mut output, &program.main, &names);
+
+    let mut subroutines: Vec<_> = program.subroutines.iter().collect();
+    subroutines.sort_by_key(|(id, _)| **id);
+    for (id, bytecode) in subroutines {
+        writeln!(output, "\n.sub {}", id).unwrap();
+        write_chunk(&mut output, bytecode, &names);
+    }
+
+    output
+}
+
+/// Parses textual assembly back into a `BytecodeProgram`, and validates it.
+pub fn assemble(source: &str) -> Result<BytecodeProgram, AssemblyError> {
+    let mut program = BytecodeProgram::default();
+    // `None` means the main section is active, `Some(id)` a subroutine.
+    let mut current: Option<Option<SubroutineId>> = None;
+    let mut seen_main = false;
+
+    for (idx, raw_line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = strip_comment(raw_line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(directive) = line.strip_prefix('.') {
+            let (keyword, rest) = split_first_word(directive);
+            match keyword {
+                "static" | "dynamic" => {
+                    let (name, id) = parse_map_entry(rest, line_no)?;
+                    let map = if keyword == "static" {
+                        &mut program.static_map
+                    } else {
+                        &mut program.dynamic_map
+                    };
+                    if map.insert(name.clone(), id).is_some() {
+                        return Err(AssemblyError::InvalidSyntax {
+                            line: line_no,
+                            message: format!("Duplicate .{} entry for '{}'", keyword, name),
+                        });
+                    }
+                }
+                "table" => {
+                    let (id, rows) = split_first_word(rest);
+                    let id: TableId = parse_number(id, line_no, "table id")?;
+                    if id as usize != program.tables.len() {
+                        return Err(AssemblyError::InvalidSyntax {
+                            line: line_no,
+                            message: format!(
+                                "Expected table {}, tables must be numbered in order",
+                                program.tables.len()
+                            ),
+                        });
+                    }
+                    program.tables.push(parse_table(rows, line_no)?);
+                }
+                "function" => {
+                    let (id, name) = split_first_word(rest);
+                    let id: FunctionId = parse_number(id, line_no, "function id")?;
+                    if id as usize != program.functions.len() {
+                        return Err(AssemblyError::InvalidSyntax {
+                            line: line_no,
+                            message: format!(
+                                "Expected function {}, functions must be numbered in order",
+                                program.functions.len()
+                            ),
+                        });
+                    }
+                    program.functions.push(parse_quoted(name.trim(), line_no)?);
+                }
+                "main" => {
+                    expect_no_operands(rest, line_no)?;
+                    if seen_main {
+                        return Err(AssemblyError::DuplicateSection {
+                            line: line_no,
+                            section: ".main".to_string(),
+                        });
+                    }
+                    seen_main = true;
+                    current = Some(None);
+                }
+                "sub" => {
+                    let id: SubroutineId = parse_number(rest.trim(), line_no, "subroutine id")?;
+                    if program.subroutines.insert(id, Vec::new()).is_some() {
+                        return Err(AssemblyError::DuplicateSection {
+                            line: line_no,
+                            section: format!(".sub {}", id),
+                        });
+                    }
+                    current = Some(Some(id));
+                }
+                other => {
+                    return Err(AssemblyError::InvalidSyntax {
+                        line: line_no,
+                        message: format!("Unknown directive '.{}'", other),
+                    });
+                }
+            }
+            continue;
+        }
+
+        let op = parse_instruction(line, line_no)?;
+        match current {
+            Some(None) => program.main.push(op),
+            Some(Some(id)) => program.subroutines.get_mut(&id).unwrap().push(op),
+            None => {
+                return Err(AssemblyError::InvalidSyntax {
+                    line: line_no,
+                    message: "Instruction appears before any .main or .sub section".to_string(),
+                });
+            }
+        }
+    }
+
+    if !seen_main {
+        return Err(AssemblyError::MissingMain);
+    }
+    program.validate()?;
+    Ok(program)
+}
+
+impl BytecodeProgram {
+    /// Returns the textual assembly form of this program. See [`disassemble`].
+    pub fn to_assembly(&self) -> String {
+        disassemble(self)
+    }
+
+    /// Parses a program from its textual assembly form. See [`assemble`].
+    pub fn from_assembly(source: &str) -> Result<Self, AssemblyError> {
+        assemble(source)
+    }
+}
+
+// --- Disassembler helpers ---
+
+fn sorted_by_id(map: &AHashMap<String, InputId>) -> Vec<(InputId, &str)> {
+    let mut entries: Vec<_> = map.iter().map(|(k, v)| (*v, k.as_str())).collect();
+    entries.sort();
+    entries
+}
+
+fn quote(name: &str) -> String {
+    serde_json::to_string(name).unwrap()
+}
+
+/// The names shown in the comments after instructions.
+struct ChunkNames<'a> {
+    static_names: &'a AHashMap<InputId, &'a str>,
+    dynamic_names: &'a AHashMap<InputId, &'a str>,
+    functions: &'a [String],
+}
+
+fn write_chunk(output: &mut String, bytecode: &[OpCode], names: &ChunkNames) {
+    for (i, op) in bytecode.iter().enumerate() {
+        let (mnemonic, operands) = format_instruction(op);
+        let annotation = match op {
+            OpCode::LoadStatic(_, id) => names.static_names.get(id).map(|n| format!(" ${}", n)),
+            OpCode::LoadDynamic(_, id) => names.dynamic_names.get(id).map(|n| format!(" ${}", n)),
+            OpCode::CallNative(_, id, _, _) => names
+                .functions
+                .get(*id as usize)
+                .map(|n| format!(" {}()", n)),
+            _ => None,
+        };
+        let text = format!("    {:<20}{}", mnemonic, operands);
+        writeln!(
+            output,
+            "{:<48}; {:04}{}",
+            text,
+            i,
+            annotation.unwrap_or_default()
+        )
+        .unwrap();
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        // `{:?}` is the shortest representation that parses back to the same bits.
+        Value::Number(n) => format!("{:?}", n),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => "null".to_string(),
+    }
+}
+
+fn format_instruction(op: &OpCode) -> (&'static str, String) {
+    let r = |reg: &Register| format!("R{}", reg);
+    let rrr = |d, a, b| format!("{}, {}, {}", r(d), r(a), r(b));
+    let rra = |a, b, addr: &Address| format!("{}, {}, @{}", r(a), r(b), addr);
+    match op {
+        OpCode::LoadLiteral(d, v) => ("LoadLiteral", format!("{}, {}", r(d), format_value(v))),
+        OpCode::LoadStatic(d, id) => ("LoadStatic", format!("{}, S{}", r(d), id)),
+        OpCode::LoadDynamic(d, id) => ("LoadDynamic", format!("{}, D{}", r(d), id)),
+        OpCode::LoadParameter(d, id) => ("LoadParameter", format!("{}, P{}", r(d), id)),
+        OpCode::Move(d, s) => ("Move", format!("{}, {}", r(d), r(s))),
+        OpCode::Add(d, a, b) => ("Add", rrr(d, a, b)),
+        OpCode::Subtract(d, a, b) => ("Subtract", rrr(d, a, b)),
+        OpCode::Multiply(d, a, b) => ("Multiply", rrr(d, a, b)),
+        OpCode::Divide(d, a, b) => ("Divide", rrr(d, a, b)),
+        OpCode::Xor(d, a, b) => ("Xor", rrr(d, a, b)),
+        OpCode::And(d, a, b) => ("And", rrr(d, a, b)),
+        OpCode::Or(d, a, b) => ("Or", rrr(d, a, b)),
+        OpCode::Abs(d, s) => ("Abs", format!("{}, {}", r(d), r(s))),
+        OpCode::Not(d, s) => ("Not", format!("{}, {}", r(d), r(s))),
+        OpCode::Lookup(d, s, t) => ("Lookup", format!("{}, {}, T{}", r(d), r(s), t)),
+        OpCode::Interpolate(d, s, t) => ("Interpolate", format!("{}, {}, T{}", r(d), r(s), t)),
+        OpCode::CallNative(d, f, first, count) => (
+            "CallNative",
+            format!("{}, F{}, {}, {}", r(d), f, r(first), count),
+        ),
+        OpCode::Spatial(d, op, first) => ("Spatial", format!("{}, {}, {}", r(d), op, r(first))),
+        OpCode::Equal(d, a, b) => ("Equal", rrr(d, a, b)),
+        OpCode::NotEqual(d, a, b) => ("NotEqual", rrr(d, a, b)),
+        OpCode::GreaterThan(d, a, b) => ("GreaterThan", rrr(d, a, b)),
+        OpCode::LessThan(d, a, b) => ("LessThan", rrr(d, a, b)),
+        OpCode::GreaterThanOrEqual(d, a, b) => ("GreaterThanOrEqual", rrr(d, a, b)),
+        OpCode::LessThanOrEqual(d, a, b) => ("LessThanOrEqual", rrr(d, a, b)),
+        OpCode::JumpIfEq(a, b, addr) => ("JumpIfEq", rra(a, b, addr)),
+        OpCode::JumpIfNeq(a, b, addr) => ("JumpIfNeq", rra(a, b, addr)),
+        OpCode::JumpIfGt(a, b, addr) => ("JumpIfGt", rra(a, b, addr)),
+        OpCode::JumpIfGte(a, b, addr) => ("JumpIfGte", rra(a, b, addr)),
+        OpCode::JumpIfLt(a, b, addr) => ("JumpIfLt", rra(a, b, addr)),
+        OpCode::JumpIfLte(a, b, addr) => ("JumpIfLte", rra(a, b, addr)),
+        OpCode::Jump(addr) => ("Jump", format!("@{}", addr)),
+        OpCode::JumpIfFalse(s, addr) => ("JumpIfFalse", format!("{}, @{}", r(s), addr)),
+        OpCode::JumpIfTrue(s, addr) => ("JumpIfTrue", format!("{}, @{}", r(s), addr)),
+        OpCode::Call(id) => ("Call", format!("#{}", id)),
+        OpCode::Return => ("Return", String::new()),
+        OpCode::Halt => ("Halt", String::new()),
+    }
+}
+
+// --- Assembler helpers ---
+
+/// Removes a trailing `; comment`, ignoring semicolons inside quoted strings.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_first_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, ""),
+    }
+}
+
+fn expect_no_operands(rest: &str, line: usize) -> Result<(), AssemblyError> {
+    if rest.trim().is_empty() {
+        Ok(())
+    } else {
+        Err(AssemblyError::InvalidSyntax {
+            line,
+            message: format!("Unexpected operands '{}'", rest.trim()),
+        })
+    }
+}
+
+fn parse_map_entry(rest: &str, line: usize) -> Result<(String, InputId), AssemblyError> {
+    let rest = rest.trim();
+    let (name_part, id_part) =
+        rest.rsplit_once(char::is_whitespace)
+            .ok_or_else(|| AssemblyError::InvalidSyntax {
+                line,
+                message: "Expected a quoted name followed by an id".to_string(),
+            })?;
+    let name = parse_quoted(name_part.trim(), line)?;
+    let id = parse_number(id_part, line, "input id")?;
+    Ok((name, id))
+}
+
+fn parse_quoted(name: &str, line: usize) -> Result<String, AssemblyError> {
+    serde_json::from_str(name).map_err(|e| AssemblyError::InvalidSyntax {
+        line,
+        message: format!("Invalid quoted name {}: {}", name, e),
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(
+    s: &str,
+    line: usize,
+    what: &str,
+) -> Result<T, AssemblyError> {
+    s.parse().map_err(|_| AssemblyError::InvalidSyntax {
+        line,
+        message: format!("Invalid {} '{}'", what, s),
+    })
+}
+
+fn parse_prefixed<T: std::str::FromStr>(
+    operand: &str,
+    prefix: char,
+    line: usize,
+    what: &str,
+) -> Result<T, AssemblyError> {
+    let digits = operand
+        .strip_prefix(prefix)
+        .ok_or_else(|| AssemblyError::InvalidSyntax {
+            line,
+            message: format!("Expected {} ('{}<n>'), found '{}'", what, prefix, operand),
+        })?;
+    parse_number(digits, line, what)
+}
+
+fn parse_value(operand: &str, line: usize) -> Result<Value, AssemblyError> {
+    match operand {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        "null" => Ok(Value::Null),
+        _ => parse_number(operand, line, "literal value").map(Value::Number),
+    }
+}
+
+fn parse_table(rows: &str, line: usize) -> Result<LookupTable, AssemblyError> {
+    let rows = rows
+        .split_whitespace()
+        .map(|row| {
+            let (key, value) = row
+                .split_once(':')
+                .ok_or_else(|| AssemblyError::InvalidSyntax {
+                    line,
+                    message: format!("Expected a table row 'key:value', found '{}'", row),
+                })?;
+            Ok((
+                parse_number(key, line, "table key")?,
+                parse_number(value, line, "table value")?,
+            ))
+        })
+        .collect::<Result<Vec<_>, AssemblyError>>()?;
+    LookupTable::new(rows).map_err(|message| AssemblyError::InvalidSyntax { line, message })
+}
+
+fn parse_instruction(line_text: &str, line: usize) -> Result<OpCode, AssemblyError> {
+    let (mnemonic, rest) = split_first_word(line_text);
+    let operands: Vec<&str> = if rest.trim().is_empty() {
+        Vec::new()
+    } else {
+        rest.split(',').map(str::trim).collect()
+    };
+
+    let expected = match mnemonic {
+        "Return" | "Halt" => 0,
+        "Jump" | "Call" => 1,
+        "LoadLiteral" | "LoadStatic" | "LoadDynamic" | "LoadParameter" | "Move" | "Abs" | "Not"
+        | "JumpIfFalse" | "JumpIfTrue" => 2,
+        "Add" | "Subtract" | "Multiply" | "Divide" | "Xor" | "And" | "Or" | "Equal"
+        | "NotEqual" | "GreaterThan" | "LessThan" | "GreaterThanOrEqual" | "LessThanOrEqual"
+        | "JumpIfEq" | "JumpIfNeq" | "JumpIfGt" | "JumpIfGte" | "JumpIfLt" | "JumpIfLte"
+        | "Lookup" | "Interpolate" | "Spatial" => 3,
+        "CallNative" => 4,
+        _ => {
+            return Err(AssemblyError::UnknownInstruction {
+                line,
+                mnemonic: mnemonic.to_string(),
+            });
+        }
+    };
+    if operands.len() != expected {
+        return Err(AssemblyError::InvalidSyntax {
+            line,
+            message: format!(
+                "{} expects {} operand(s), found {}",
+                mnemonic,
+                expected,
+                operands.len()
+            ),
+        });
+    }
+
+    let reg = |i: usize| parse_prefixed::<Register>(operands[i], 'R', line, "register");
+    let addr = |i: usize| parse_prefixed::<Address>(operands[i], '@', line, "address");
+    let table = |i: usize| parse_prefixed::<TableId>(operands[i], 'T', line, "table id");
+
+    let op = match mnemonic {
+        "LoadLiteral" => OpCode::LoadLiteral(reg(0)?, parse_value(operands[1], line)?),
+        "LoadStatic" => OpCode::LoadStatic(
+            reg(0)?,
+            parse_prefixed(operands[1], 'S', line, "static input id")?,
+        ),
+        "LoadDynamic" => OpCode::LoadDynamic(
+            reg(0)?,
+            parse_prefixed(operands[1], 'D', line, "dynamic input id")?,
+        ),
+        "LoadParameter" => OpCode::LoadParameter(
+            reg(0)?,
+            parse_prefixed(operands[1], 'P', line, "parameter id")?,
+        ),
+        "Move" => OpCode::Move(reg(0)?, reg(1)?),
+        "Add" => OpCode::Add(reg(0)?, reg(1)?, reg(2)?),
+        "Subtract" => OpCode::Subtract(reg(0)?, reg(1)?, reg(2)?),
+        "Multiply" => OpCode::Multiply(reg(0)?, reg(1)?, reg(2)?),
+        "Divide" => OpCode::Divide(reg(0)?, reg(1)?, reg(2)?),
+        "Xor" => OpCode::Xor(reg(0)?, reg(1)?, reg(2)?),
+        "And" => OpCode::And(reg(0)?, reg(1)?, reg(2)?),
+        "Or" => OpCode::Or(reg(0)?, reg(1)?, reg(2)?),
+        "Abs" => OpCode::Abs(reg(0)?, reg(1)?),
+        "Not" => OpCode::Not(reg(0)?, reg(1)?),
+        "Lookup" => OpCode::Lookup(reg(0)?, reg(1)?, table(2)?),
+        "Interpolate" => OpCode::Interpolate(reg(0)?, reg(1)?, table(2)?),
+        "CallNative" => OpCode::CallNative(
+            reg(0)?,
+            parse_prefixed(operands[1], 'F', line, "function id")?,
+            reg(2)?,
+            parse_number(operands[3], line, "argument count")?,
+        ),
+        "Spatial" => OpCode::Spatial(
+            reg(0)?,
+            SpatialOp::from_symbol(operands[1]).ok_or_else(|| AssemblyError::InvalidSyntax {
+                line,
+                message: format!("unknown spatial operation '{}'", operands[1]),
+            })?,
+            reg(2)?,
+        ),
+        "Equal" => OpCode::Equal(reg(0)?, reg(1)?, reg(2)?),
+        "NotEqual" => OpCode::NotEqual(reg(0)?, reg(1)?, reg(2)?),
+        "GreaterThan" => OpCode::GreaterThan(reg(0)?, reg(1)?, reg(2)?),
+        "LessThan" => OpCode::LessThan(reg(0)?, reg(1)?, reg(2)?),
+        "GreaterThanOrEqual" => OpCode::GreaterThanOrEqual(reg(0)?, reg(1)?, reg(2)?),
+        "LessThanOrEqual" => OpCode::LessThanOrEqual(reg(0)?, reg(1)?, reg(2)?),
+        "JumpIfEq" => OpCode::JumpIfEq(reg(0)?, reg(1)?, addr(2)?),
+        "JumpIfNeq" => OpCode::JumpIfNeq(reg(0)?, reg(1)?, addr(2)?),
+        "JumpIfGt" => OpCode::JumpIfGt(reg(0)?, reg(1)?, addr(2)?),
+        "JumpIfGte" => OpCode::JumpIfGte(reg(0)?, reg(1)?, addr(2)?),
+        "JumpIfLt" => OpCode::JumpIfLt(reg(0)?, reg(1)?, addr(2)?),
+        "JumpIfLte" => OpCode::JumpIfLte(reg(0)?, reg(1)?, addr(2)?),
+        "Jump" => OpCode::Jump(addr(0)?),
+        "JumpIfFalse" => OpCode::JumpIfFalse(reg(0)?, addr(1)?),
+        "JumpIfTrue" => OpCode::JumpIfTrue(reg(0)?, addr(1)?),
+        "Call" => OpCode::Call(parse_prefixed(operands[0], '#', line, "subroutine id")?),
+        "Return" => OpCode::Return,
+        "Halt" => OpCode::Halt,
+        _ => unreachable!(),
+    };
+    Ok(op)
+}
@@ -1,5 +1,6 @@
-use crate::ast::{Expression, InputSource};
-use crate::bytecode::opcode::{Address, InputId, OpCode, Register};
+use crate::ast::{Expression, ExpressionGraph, InputSource, LookupTable, Node, NodeId};
+use crate::bytecode::opcode::{Address, FunctionId, InputId, OpCode, Register, TableId};
+use crate::bytecode::vm::NUM_REGISTERS;
 use crate::error::BackendError;
 use ahash::AHashMap;
 use serde::{Deserialize, Serialize};
@@ -13,8 +14,12 @@ struct RegisterAllocator {
 }
 
 impl RegisterAllocator {
-    fn new() -> Self {
-        Default::default()
+    /// Creates an allocator that hands out registers from `base` upwards.
+    fn starting_at(base: Register) -> Self {
+        Self {
+            next_register: base,
+            free_registers: Vec::new(),
+        }
     }
 
     /// Allocates a new or recycled register.
@@ -23,13 +28,28 @@ impl RegisterAllocator {
             Ok(reg)
         } else {
             let reg = self.next_register;
-            self.next_register = self.next_register.checked_add(1).ok_or_else(|| {
-                BackendError::ResourceLimitExceeded("Register limit reached".to_string())
-            })?;
+            if reg as usize >= NUM_REGISTERS {
+                return Err(BackendError::ResourceLimitExceeded(
+                    "Register limit reached".to_string(),
+                ));
+            }
+            self.next_register += 1;
             Ok(reg)
         }
     }
 
+    /// Allocates `count` new, consecutive registers and returns the first of them.
+    fn alloc_block(&mut self, count: u8) -> Result<Register, BackendError> {
+        let first = self.next_register;
+        if first as usize + count as usize > NUM_REGISTERS {
+            return Err(BackendError::ResourceLimitExceeded(
+                "Register limit reached".to_string(),
+            ));
+        }
+        self.next_register += count;
+        Ok(first)
+    }
+
     /// Returns a register to the pool for reuse.
     fn free(&mut self, reg: Register) {
         // Simple check to avoid double-freeing, which can happen with complex liveness.
@@ -39,46 +59,93 @@ impl RegisterAllocator {
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct BytecodeProgram {
     pub main: Vec<OpCode>,
     pub subroutines: AHashMap<u64, Vec<OpCode>>,
     pub static_map: AHashMap<String, InputId>,
     pub dynamic_map: AHashMap<String, InputId>,
+    /// The tables read by `Lookup` and `Interpolate`, indexed by `TableId`.
+    pub tables: Vec<LookupTable>,
+    /// The names of the native functions called by `CallNative`, indexed by `FunctionId`.
+    /// They are resolved against the host's registry when the program is loaded.
+    pub functions: Vec<String>,
+}
+
+/// Where a compiled subroutine leaves its result and how far its registers reach.
+#[derive(Debug, Clone, Copy)]
+struct SubroutineRegisters {
+    result: Register,
+    /// One past the highest register used by the subroutine or anything it calls.
+    top: Register,
 }
 
+/// Lowers an expression graph to bytecode.
+///
+/// All code shares the VM's single register file, so a subroutine must not touch any
+/// register its callers still need. Every subroutine therefore gets a register window
+/// above the windows of all subroutines it calls, and `main` is placed above all of them.
+/// Subroutines are compiled callees first and return their result in their own window.
 pub struct BytecodeCompiler<'a> {
-    definitions: &'a AHashMap<u64, Expression>,
+    graph: &'a ExpressionGraph,
+    /// Shared nodes, which are compiled once as a subroutine and called from every use.
+    subroutine_ids: AHashMap<NodeId, u64>,
+    subroutine_registers: AHashMap<u64, SubroutineRegisters>,
     program: BytecodeProgram,
-    compiled_subroutines: AHashMap<u64, ()>,
     allocator: RegisterAllocator,
 }
 
+/// Compiles an expression tree whose `Reference` nodes point into `definitions`.
+///
+/// Every definition that is referenced more than once becomes a subroutine.
 pub fn compile_to_program(
     expr: &Expression,
     definitions: &AHashMap<u64, Expression>,
     static_map: &AHashMap<String, InputId>,
     dynamic_map: &AHashMap<String, InputId>,
 ) -> Result<BytecodeProgram, BackendError> {
+    // Without hash-consing, only the sharing expressed by `Reference` nodes is kept.
+    let mut graph = ExpressionGraph::without_sharing();
+    let root = graph
+        .insert(expr, definitions)
+        .map_err(|id| BackendError::InvalidLogic(format!("CSE Reference ID #{} not found", id)))?;
+    compile_graph(&graph, root, static_map, dynamic_map)
+}
+
+/// Compiles the expression rooted at `root`. Every operation node with more than one
+/// user becomes a subroutine, numbered like the definitions of
+/// `ExpressionGraph::to_shared_expression`.
+pub fn compile_graph(
+    graph: &ExpressionGraph,
+    root: NodeId,
+    static_map: &AHashMap<String, InputId>,
+    dynamic_map: &AHashMap<String, InputId>,
+) -> Result<BytecodeProgram, BackendError> {
+    let shared = graph.shared_nodes(root);
     let mut compiler = BytecodeCompiler {
-        definitions,
+        graph,
+        subroutine_ids: graph.definition_ids(root),
+        subroutine_registers: AHashMap::new(),
         program: BytecodeProgram {
             static_map: static_map.clone(),
             dynamic_map: dynamic_map.clone(),
             ..Default::default()
         },
-        compiled_subroutines: AHashMap::new(),
-        allocator: RegisterAllocator::new(),
+        allocator: RegisterAllocator::default(),
     };
-    compiler.compile_main(expr)?;
+    // `shared_nodes` is in post-order, so every subroutine is compiled after its callees.
+    for (id, node) in shared.into_iter().enumerate() {
+        compiler.compile_subroutine(node, id as u64)?;
+    }
+    compiler.compile_main(root)?;
     Ok(compiler.program)
 }
 
 impl<'a> BytecodeCompiler<'a> {
-    fn compile_main(&mut self, expr: &Expression) -> Result<(), BackendError> {
-        self.allocator = RegisterAllocator::new();
+    fn compile_main(&mut self, root: NodeId) -> Result<(), BackendError> {
+        self.allocator = RegisterAllocator::starting_at(self.window_base(root));
         let mut main_bc = Vec::new();
-        let final_reg = self.compile_recursive(expr, &mut main_bc, &HashSet::new())?;
+        let final_reg = self.compile_recursive(root, &mut main_bc, &HashSet::new())?;
         // The final result must be in R0 for the VM.
         if final_reg != 0 {
             main_bc.push(OpCode::Move(0, final_reg));
@@ -89,43 +156,72 @@ impl<'a> BytecodeCompiler<'a> {
         Ok(())
     }
 
-    fn compile_subroutine(&mut self, id: u64) -> Result<(), BackendError> {
-        if self.compiled_subroutines.contains_key(&id) {
-            return Ok(());
-        }
-        let expr = self.definitions.get(&id).ok_or_else(|| {
-            BackendError::InvalidLogic(format!("CSE Reference ID #{} not found", id))
-        })?;
-        self.compiled_subroutines.insert(id, ());
+    fn compile_subroutine(&mut self, node: NodeId, id: u64) -> Result<(), BackendError> {
+        self.allocator = RegisterAllocator::starting_at(self.window_base(node));
         let mut subroutine_bc = Vec::new();
-        self.allocator = RegisterAllocator::new();
-        let final_reg = self.compile_recursive(expr, &mut subroutine_bc, &HashSet::new())?;
-        if final_reg != 0 {
-            subroutine_bc.push(OpCode::Move(0, final_reg));
-        }
-        self.allocator.free(final_reg);
+        let result = self.compile_node(node, &mut subroutine_bc, &HashSet::new())?;
         subroutine_bc.push(OpCode::Return);
         self.program.subroutines.insert(id, subroutine_bc);
+        self.subroutine_registers.insert(
+            id,
+            SubroutineRegisters {
+                result,
+                top: self.allocator.next_register,
+            },
+        );
         Ok(())
     }
 
+    /// The first register that the code for `node` may use: one past the windows of every
+    /// subroutine it calls directly, which in turn lie above the subroutines they call.
+    fn window_base(&self, node: NodeId) -> Register {
+        let mut base = 0;
+        let mut stack: Vec<NodeId> = self.graph[node].children().collect();
+        let mut seen = HashSet::new();
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            match self.subroutine_ids.get(&id) {
+                Some(subroutine) => base = base.max(self.subroutine_registers[subroutine].top),
+                None => stack.extend(self.graph[id].children()),
+            }
+        }
+        base
+    }
+
+    /// Compiles a use of `id`, which is a call if the node is shared.
     fn compile_recursive(
         &mut self,
-        expr: &Expression,
+        id: NodeId,
         bytecode: &mut Vec<OpCode>,
         live_after: &HashSet<Register>,
     ) -> Result<Register, BackendError> {
-        match expr {
-            Expression::Literal(val) => {
+        match self.subroutine_ids.get(&id) {
+            Some(subroutine) => self.compile_call(*subroutine, bytecode),
+            None => self.compile_node(id, bytecode, live_after),
+        }
+    }
+
+    /// Compiles the operation of `id` itself.
+    fn compile_node(
+        &mut self,
+        id: NodeId,
+        bytecode: &mut Vec<OpCode>,
+        live_after: &HashSet<Register>,
+    ) -> Result<Register, BackendError> {
+        match &self.graph[id] {
+            Node::Literal(val) => {
                 let dest = self.allocator.alloc()?;
                 bytecode.push(OpCode::LoadLiteral(dest, val.clone()));
                 Ok(dest)
             }
-            Expression::Input(source) => {
+            Node::Input(source) => {
                 let dest = self.allocator.alloc()?;
                 let op = match source {
                     InputSource::Static { id } => OpCode::LoadStatic(dest, *id),
                     InputSource::Dynamic { id } => OpCode::LoadDynamic(dest, *id),
+                    InputSource::Parameter { id } => OpCode::LoadParameter(dest, *id),
                     _ => {
                         return Err(BackendError::InvalidLogic(
                             "Encountered uninterned InputSource".to_string(),
@@ -135,18 +231,130 @@ impl<'a> BytecodeCompiler<'a> {
                 bytecode.push(op);
                 Ok(dest)
             }
-            Expression::Reference(id) => self.compile_call(id, bytecode, live_after),
-            Expression::Not(v) => self.compile_unary(v, OpCode::Not, bytecode, live_after),
-            Expression::Abs(v) => self.compile_unary(v, OpCode::Abs, bytecode, live_after),
-            Expression::And(l, r) => self.compile_short_circuit(l, r, false, bytecode, live_after),
-            Expression::Or(l, r) => self.compile_short_circuit(l, r, true, bytecode, live_after),
-            _ => self.compile_binary_fallback(expr, bytecode, live_after),
+            Node::Not(v) => self.compile_unary(*v, OpCode::Not, bytecode, live_after),
+            Node::Abs(v) => self.compile_unary(*v, OpCode::Abs, bytecode, live_after),
+            Node::Table(v, table) => {
+                let table = self.table_id(table)?;
+                let op = move |dest, src| OpCode::Lookup(dest, src, table);
+                self.compile_unary(*v, op, bytecode, live_after)
+            }
+            Node::Interpolate(v, table) => {
+                let table = self.table_id(table)?;
+                let op = move |dest, src| OpCode::Interpolate(dest, src, table);
+                self.compile_unary(*v, op, bytecode, live_after)
+            }
+            Node::Call(name, args) => self.compile_native_call(name, args, bytecode, live_after),
+            Node::Spatial(op, args) => {
+                let op = *op;
+                if args.len() != op.arity() {
+                    return Err(BackendError::InvalidLogic(format!(
+                        "{} takes {} operands, but has {}",
+                        op,
+                        op.arity(),
+                        args.len()
+                    )));
+                }
+                self.compile_block_op(args, bytecode, live_after, |dest, first, _| {
+                    OpCode::Spatial(dest, op, first)
+                })
+            }
+            Node::And(l, r) => self.compile_short_circuit(*l, *r, false, bytecode, live_after),
+            Node::Or(l, r) => self.compile_short_circuit(*l, *r, true, bytecode, live_after),
+            _ => self.compile_binary_fallback(id, bytecode, live_after),
         }
     }
 
+    /// The id of `table` in the program, adding it on first use.
+    fn table_id(&mut self, table: &LookupTable) -> Result<TableId, BackendError> {
+        let tables = &mut self.program.tables;
+        let index = match tables.iter().position(|t| t.is_identical(table)) {
+            Some(index) => index,
+            None => {
+                tables.push(table.clone());
+                tables.len() - 1
+            }
+        };
+        TableId::try_from(index)
+            .map_err(|_| BackendError::ResourceLimitExceeded("Table limit reached".to_string()))
+    }
+
+    /// The id of the native function `name` in the program, adding it on first use.
+    fn function_id(&mut self, name: &str) -> Result<FunctionId, BackendError> {
+        let functions = &mut self.program.functions;
+        let index = match functions.iter().position(|f| f == name) {
+            Some(index) => index,
+            None => {
+                functions.push(name.to_string());
+                functions.len() - 1
+            }
+        };
+        FunctionId::try_from(index)
+            .map_err(|_| BackendError::ResourceLimitExceeded("Function limit reached".to_string()))
+    }
+
+    /// Compiles a call of native function `name` with the arguments `args`.
+    fn compile_native_call(
+        &mut self,
+        name: &str,
+        args: &[NodeId],
+        bytecode: &mut Vec<OpCode>,
+        live_after: &HashSet<Register>,
+    ) -> Result<Register, BackendError> {
+        let function = self.function_id(name)?;
+        if u8::try_from(args.len()).is_err() {
+            return Err(BackendError::ResourceLimitExceeded(format!(
+                "Native function '{}' is called with more than {} arguments",
+                name,
+                u8::MAX
+            )));
+        }
+        self.compile_block_op(args, bytecode, live_after, |dest, first, count| {
+            OpCode::CallNative(dest, function, first, count)
+        })
+    }
+
+    /// Compiles every operand, moves them into a block of consecutive registers and
+    /// emits `op_builder(dest, first, count)` over that block. The result replaces the
+    /// first operand. At most `u8::MAX` operands are supported.
+    fn compile_block_op<F>(
+        &mut self,
+        args: &[NodeId],
+        bytecode: &mut Vec<OpCode>,
+        live_after: &HashSet<Register>,
+        op_builder: F,
+    ) -> Result<Register, BackendError>
+    where
+        F: FnOnce(Register, Register, u8) -> OpCode,
+    {
+        let count = args.len() as u8;
+        let mut live = live_after.clone();
+        let mut arg_regs = Vec::with_capacity(args.len());
+        for arg in args {
+            let reg = self.compile_recursive(*arg, bytecode, &live)?;
+            live.insert(reg);
+            arg_regs.push(reg);
+        }
+
+        let first = self.allocator.alloc_block(count.max(1))?;
+        for (offset, reg) in arg_regs.iter().enumerate() {
+            bytecode.push(OpCode::Move(first + offset as Register, *reg));
+        }
+        bytecode.push(op_builder(first, first, count));
+
+        for reg in arg_regs {
+            if !live_after.contains(&reg) {
+                self.allocator.free(reg);
+            }
+        }
+        for offset in 1..count {
+            self.allocator.free(first + offset);
+        }
+        Ok(first)
+    }
+
     fn compile_unary<F>(
         &mut self,
-        expr: &Expression,
+        operand: NodeId,
         op_builder: F,
         bytecode: &mut Vec<OpCode>,
         live_after: &HashSet<Register>,
@@ -154,7 +362,7 @@ impl<'a> BytecodeCompiler<'a> {
     where
         F: Fn(Register, Register) -> OpCode,
     {
-        let src = self.compile_recursive(expr, bytecode, live_after)?;
+        let src = self.compile_recursive(operand, bytecode, live_after)?;
         // Optimization: if the source register is not live after this operation,
         // we can perform the operation in-place.
         let dest = if !live_after.contains(&src) {
@@ -168,26 +376,26 @@ impl<'a> BytecodeCompiler<'a> {
 
     fn compile_binary_fallback(
         &mut self,
-        expr: &Expression,
+        id: NodeId,
         bytecode: &mut Vec<OpCode>,
         live_after: &HashSet<Register>,
     ) -> Result<Register, BackendError> {
         let (l, r, op_builder): (
-            &Expression,
-            &Expression,
+            NodeId,
+            NodeId,
             Box<dyn Fn(Register, Register, Register) -> OpCode>,
-        ) = match expr {
-            Expression::Sum(l, r) => (l, r, Box::new(OpCode::Add)),
-            Expression::Subtract(l, r) => (l, r, Box::new(OpCode::Subtract)),
-            Expression::Multiply(l, r) => (l, r, Box::new(OpCode::Multiply)),
-            Expression::Divide(l, r) => (l, r, Box::new(OpCode::Divide)),
-            Expression::Equal(l, r) => (l, r, Box::new(OpCode::Equal)),
-            Expression::NotEqual(l, r) => (l, r, Box::new(OpCode::NotEqual)),
-            Expression::GreaterThan(l, r) => (l, r, Box::new(OpCode::GreaterThan)),
-            Expression::SmallerThan(l, r) => (l, r, Box::new(OpCode::LessThan)),
-            Expression::GreaterThanOrEqual(l, r) => (l, r, Box::new(OpCode::GreaterThanOrEqual)),
-            Expression::SmallerThanOrEqual(l, r) => (l, r, Box::new(OpCode::LessThanOrEqual)),
-            Expression::Xor(l, r) => (l, r, Box::new(OpCode::Xor)),
+        ) = match self.graph[id] {
+            Node::Sum(l, r) => (l, r, Box::new(OpCode::Add)),
+            Node::Subtract(l, r) => (l, r, Box::new(OpCode::Subtract)),
+            Node::Multiply(l, r) => (l, r, Box::new(OpCode::Multiply)),
+            Node::Divide(l, r) => (l, r, Box::new(OpCode::Divide)),
+            Node::Equal(l, r) => (l, r, Box::new(OpCode::Equal)),
+            Node::NotEqual(l, r) => (l, r, Box::new(OpCode::NotEqual)),
+            Node::GreaterThan(l, r) => (l, r, Box::new(OpCode::GreaterThan)),
+            Node::SmallerThan(l, r) => (l, r, Box::new(OpCode::LessThan)),
+            Node::GreaterThanOrEqual(l, r) => (l, r, Box::new(OpCode::GreaterThanOrEqual)),
+            Node::SmallerThanOrEqual(l, r) => (l, r, Box::new(OpCode::LessThanOrEqual)),
+            Node::Xor(l, r) => (l, r, Box::new(OpCode::Xor)),
             _ => {
                 return Err(BackendError::UnsupportedAstNode(
                     "Unsupported binary expression".to_string(),
@@ -224,23 +432,23 @@ impl<'a> BytecodeCompiler<'a> {
 
     fn compile_call(
         &mut self,
-        id: &u64,
+        id: u64,
         bytecode: &mut Vec<OpCode>,
-        _live_after: &HashSet<Register>,
     ) -> Result<Register, BackendError> {
-        self.compile_subroutine(*id)?;
+        let registers = self.subroutine_registers.get(&id).copied().ok_or_else(|| {
+            BackendError::InvalidLogic(format!("Subroutine #{} called before it was compiled", id))
+        })?;
         let dest = self.allocator.alloc()?;
-        bytecode.push(OpCode::Call(*id));
-        // The result of a subroutine is always placed in R0 by convention.
-        bytecode.push(OpCode::Move(dest, 0));
+        bytecode.push(OpCode::Call(id));
+        bytecode.push(OpCode::Move(dest, registers.result));
         Ok(dest)
     }
 
     /// Compiles the short circuit. It compiles the left and right sides onlye once.
     fn compile_short_circuit(
         &mut self,
-        l: &Expression,
-        r: &Expression,
+        l: NodeId,
+        r: NodeId,
         is_or: bool,
         bytecode: &mut Vec<OpCode>,
         live_after: &HashSet<Register>,
@@ -265,8 +473,13 @@ impl<'a> BytecodeCompiler<'a> {
         live_for_r.insert(result_reg);
         let reg_r = self.compile_recursive(r, bytecode, &live_for_r)?;
 
-        // 4. Move the result of the RHS into our final result register.
-        bytecode.push(OpCode::Move(result_reg, reg_r));
+        // 4. Combine both sides into our final result register. The left side can still be
+        // null (unknown), in which case the right side alone does not decide the result.
+        bytecode.push(if is_or {
+            OpCode::Or(result_reg, result_reg, reg_r)
+        } else {
+            OpCode::And(result_reg, result_reg, reg_r)
+        });
         if !live_after.contains(&reg_r) {
             self.allocator.free(reg_r);
         }
@@ -1,18 +1,24 @@
+pub mod assembly;
 pub mod compiler;
 pub mod opcode;
 pub mod visualizer;
 pub mod vm;
 
-use crate::ast::Value;
-use crate::backend::{EvaluationBackend, ExecutableRecipe};
-use crate::compiler::CompilationArtifacts;
-use crate::error::{BackendError, EvaluationError};
-use crate::interpreter::EvaluationResult;
-use crate::recipe::{CompiledPathBytecode, CompiledRecipe};
-use ahash::AHashMap;
+use crate::ast::{InputId, Value};
+use crate::backend::{
+    EvaluationBackend, ExecutableRecipe, agreed_output_value, group_by_decision,
+    recipe_combinations, recipe_parameters, resolve_parameters,
+};
+use crate::compiler::{CompilationArtifacts, PathKind};
+use crate::error::{BackendError, EvaluationError, VmError};
+use crate::events::{CombinationPolicy, EventCombinations, PathSplit, event_names};
+use crate::functions::{FunctionRegistry, NativeFunction};
+use crate::interpreter::{DecisionResult, EvaluationResult};
+use crate::recipe::{CompiledPathBytecode, CompiledRecipe, RecipeParameter};
+use ahash::{AHashMap, AHashSet};
 use compiler::BytecodeProgram;
 use itertools::Itertools;
-use std::collections::HashSet;
+use opcode::OpCode;
 use vm::Vm;
 
 pub struct BytecodeBackend;
@@ -22,119 +28,307 @@ impl EvaluationBackend for BytecodeBackend {
         &self,
         artifacts: Vec<CompilationArtifacts>,
     ) -> Result<CompiledRecipe, BackendError> {
+        let parameters = recipe_parameters(&artifacts);
+        let combinations = recipe_combinations(&artifacts);
         let bytecode_programs = artifacts
             .into_iter()
             .map(|a| {
-                // We have to get rid of the ast here because we no longer need it.
-                let program = compiler::compile_to_program(
-                    &a.ast,
-                    &a.definitions,
-                    &a.static_map,
-                    &a.dynamic_map,
-                )?;
+                let compile =
+                    |root| compiler::compile_graph(&a.graph, root, &a.static_map, &a.dynamic_map);
+                let program = compile(a.root)?;
+                let split = a.split.clone().try_map(compile)?;
 
                 Ok(CompiledPathBytecode {
+                    kind: a.kind,
+                    decision: a.decision,
                     priority: a.priority,
                     name: a.name,
                     program,
+                    split,
                 })
             })
             .collect::<Result<Vec<_>, BackendError>>()?;
 
-        Ok(CompiledRecipe::new(None, Some(bytecode_programs)))
+        Ok(CompiledRecipe::new(
+            None,
+            Some(bytecode_programs),
+            parameters,
+            combinations,
+        ))
     }
 
-    fn load(&self, recipe: CompiledRecipe) -> Result<Box<dyn ExecutableRecipe>, BackendError> {
+    fn load(
+        &self,
+        recipe: CompiledRecipe,
+        functions: &FunctionRegistry,
+    ) -> Result<Box<dyn ExecutableRecipe>, BackendError> {
         let programs = recipe.bytecode_programs.ok_or_else(|| {
             BackendError::InvalidLogic(
                 "Recipe file does not contain bytecode artifacts".to_string(),
             )
         })?;
 
-        let compiled_artifacts = programs
-            .into_iter()
-            .map(|p| (p.priority, p.name, p.program))
+        let decisions = group_by_decision(
+            programs
+                .iter()
+                .map(|p| (p.kind == PathKind::Quality).then_some(p.decision.as_str())),
+        );
+        let outputs = programs
+            .iter()
+            .positions(|p| p.kind == PathKind::Output)
             .collect();
+        let load = |program: BytecodeProgram, path: &str| {
+            program
+                .validate()
+                .map_err(|error| BackendError::InvalidBytecode {
+                    path: path.to_string(),
+                    error,
+                })?;
+            let native_functions = program
+                .functions
+                .iter()
+                .map(|name| functions.resolve(name))
+                .collect::<Result<_, BackendError>>()?;
+            Ok(LoadedProgram {
+                program,
+                functions: native_functions,
+            })
+        };
+        let paths = programs
+            .into_iter()
+            .map(|p| {
+                let combined_events = match &p.split.residual {
+                    Some(residual) => event_names(&residual.dynamic_map, &dynamic_ids(residual)),
+                    None => AHashSet::new(),
+                };
+                Ok(LoadedPath {
+                    priority: p.priority,
+                    combined_events,
+                    program: load(p.program, &p.name)?,
+                    split: p.split.try_map(|program| load(program, &p.name))?,
+                    name: p.name,
+                })
+            })
+            .collect::<Result<_, BackendError>>()?;
+
+        Ok(Box::new(BytecodeExecutable {
+            paths,
+            decisions,
+            outputs,
+            parameters: recipe.parameters,
+            combinations: recipe.combinations,
+        }))
+    }
+}
+
+/// A validated program with the native functions it calls, indexed by `FunctionId`.
+struct LoadedProgram {
+    program: BytecodeProgram,
+    functions: Vec<NativeFunction>,
+}
+
+impl LoadedProgram {
+    fn run(
+        &self,
+        static_vec: &[Value],
+        context: &AHashMap<&str, &AHashMap<String, f64>>,
+        parameter_values: &[Value],
+    ) -> Result<Value, EvaluationError> {
+        let dynamic_vec = prepare_dynamic_context(&self.program, context);
+        // SAFETY: `BytecodeBackend::load` validated the program.
+        unsafe { Vm::new_unchecked(&self.program, static_vec, &dynamic_vec) }
+            .with_parameters(parameter_values)
+            .with_functions(&self.functions)
+            .run()
+            .map_err(evaluation_error)
+    }
 
-        Ok(Box::new(BytecodeExecutable { compiled_artifacts }))
+    fn holds(
+        &self,
+        static_vec: &[Value],
+        context: &AHashMap<&str, &AHashMap<String, f64>>,
+        parameter_values: &[Value],
+    ) -> Result<bool, EvaluationError> {
+        Ok(self.run(static_vec, context, parameter_values)? == Value::Bool(true))
     }
 }
 
+struct LoadedPath {
+    priority: i32,
+    name: String,
+    program: LoadedProgram,
+    /// The parts of `program` checked before and while combining event instances.
+    split: PathSplit<LoadedProgram>,
+    /// The events the residual of `split` relates, the only ones whose instances are
+    /// combined.
+    combined_events: AHashSet<String>,
+}
+
 struct BytecodeExecutable {
-    compiled_artifacts: Vec<(i32, String, BytecodeProgram)>,
+    paths: Vec<LoadedPath>,
+    /// The indices into `paths` of each decision, in priority order.
+    decisions: Vec<(String, Vec<usize>)>,
+    /// The indices into `paths` of the outputs.
+    outputs: Vec<usize>,
+    parameters: Vec<RecipeParameter>,
+    combinations: CombinationPolicy,
 }
 
 impl ExecutableRecipe for BytecodeExecutable {
+    fn parameters(&self) -> &[RecipeParameter] {
+        &self.parameters
+    }
+
     fn evaluate(
         &self,
         static_data: &AHashMap<String, f64>,
         dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>,
+        parameters: &AHashMap<String, Value>,
     ) -> Result<EvaluationResult, EvaluationError> {
-        let prepared_static_data = prepare_all_static_data(&self.compiled_artifacts, static_data)?;
+        let parameter_values = resolve_parameters(&self.parameters, parameters)?;
+        let prepared_static_data = prepare_all_static_data(&self.paths, static_data)?;
 
-        let maybe_result = self.compiled_artifacts.iter().enumerate().find_map(
-            |(prog_idx, (priority, name, program))| {
-                let (event_names, event_instances) = prepare_dynamic_events(program, dynamic_data);
+        let decisions = self
+            .decisions
+            .iter()
+            .map(|(decision, indices)| {
+                let maybe_result = indices.iter().find_map(|&i| {
+                    let path = &self.paths[i];
+                    let result = self.matches(
+                        path,
+                        &prepared_static_data[i],
+                        dynamic_data,
+                        &parameter_values,
+                    );
+                    match result {
+                        Ok(true) => Some(Ok(DecisionResult {
+                            name: decision.clone(),
+                            quality_name: Some(path.name.clone()),
+                            quality_priority: Some(path.priority),
+                            reason: format!(
+                                "Bytecode evaluation for '{}' returned true",
+                                path.name
+                            ),
+                        })),
+                        Ok(false) => None,
+                        Err(e) => Some(Err(e)),
+                    }
+                });
 
-                if event_instances.iter().any(|v| v.is_empty()) {
-                    return None; // If any required event type has no instances, we can't match.
+                match maybe_result {
+                    Some(Ok(result)) => Ok(result),
+                    Some(Err(e)) => Err(e),
+                    None => Ok(DecisionResult::not_triggered(decision)),
                 }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
-                // If there are no dynamic events required, we still need one empty context to run against.
-                let combinations_iterator: Box<dyn Iterator<Item = Vec<&AHashMap<String, f64>>>> =
-                    if event_instances.is_empty() {
-                        Box::new(std::iter::once(Vec::new()))
-                    } else {
-                        Box::new(event_instances.into_iter().multi_cartesian_product())
-                    };
-
-                let static_vec = &prepared_static_data[prog_idx];
-
-                for combination in combinations_iterator {
-                    // Build the context map for this single combination
-                    let context_map: AHashMap<&str, _> = event_names
-                        .iter()
-                        .map(|s| s.as_str())
-                        .zip(combination.into_iter())
-                        .collect();
-
-                    let dynamic_vec = prepare_dynamic_context(program, &context_map);
-                    let mut vm = Vm::new(program, static_vec, &dynamic_vec);
-                    match vm.run() {
-                        Ok(Value::Bool(true)) => {
-                            return Some(Ok(EvaluationResult {
-                                quality_name: Some(name.clone()),
-                                quality_priority: Some(*priority),
-                                reason: format!("Bytecode evaluation for '{}' returned true", name),
-                            }));
-                        }
-                        Ok(_) => continue,
-                        Err(e) => return Some(Err(EvaluationError::BackendError(e.to_string()))),
-                    }
-                }
-                None
-            },
-        );
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|&i| {
+                let path = &self.paths[i];
+                let events = EventCombinations::new(
+                    &path.program.program.dynamic_map,
+                    dynamic_data,
+                    self.combinations.pairing,
+                );
+                events.check_limit(&path.name, self.combinations.max_combinations)?;
+                // An output has a value only if every combination of instances agrees on it.
+                let values = events.iter().map(|context_map| {
+                    path.program
+                        .run(&prepared_static_data[i], &context_map, &parameter_values)
+                });
+                Ok((path.name.clone(), agreed_output_value(values)?))
+            })
+            .collect::<Result<_, EvaluationError>>()?;
+
+        Ok(EvaluationResult::new(decisions, outputs))
+    }
+}
+
+impl BytecodeExecutable {
+    /// Whether some combination of event instances triggers a quality path.
+    ///
+    /// The conjuncts of the path's split are checked first: those that read no events
+    /// once, and those that read a single event once per instance, dropping the instances
+    /// they reject. Only the remaining combinations are run.
+    fn matches(
+        &self,
+        path: &LoadedPath,
+        static_vec: &[Value],
+        dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>,
+        parameter_values: &[Value],
+    ) -> Result<bool, EvaluationError> {
+        let PathSplit {
+            guard,
+            filters,
+            residual,
+        } = &path.split;
+
+        if let Some(guard) = guard
+            && !guard.holds(static_vec, &AHashMap::new(), parameter_values)?
+        {
+            return Ok(false);
+        }
+
+        let dynamic_map = &path.program.program.dynamic_map;
+        let mut events =
+            EventCombinations::new(dynamic_map, dynamic_data, self.combinations.pairing);
+        for (event, filter) in filters {
+            events.retain(event, |instance| {
+                let context = AHashMap::from_iter(instance.map(|i| (event.as_str(), i)));
+                filter.holds(static_vec, &context, parameter_values)
+            })?;
+        }
+        events.combine_only(&path.combined_events);
+        events.check_limit(&path.name, self.combinations.max_combinations)?;
+
+        // A path without dynamic events still runs once, with an empty context.
+        for context_map in events.iter() {
+            if residual.as_ref().unwrap_or(&path.program).holds(
+                static_vec,
+                &context_map,
+                parameter_values,
+            )? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// The ids of the dynamic inputs `program` loads.
+fn dynamic_ids(program: &BytecodeProgram) -> AHashSet<InputId> {
+    std::iter::once(&program.main)
+        .chain(program.subroutines.values())
+        .flatten()
+        .filter_map(|op| match op {
+            OpCode::LoadDynamic(_, id) => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
 
-        match maybe_result {
-            Some(Ok(result)) => Ok(result),
-            Some(Err(e)) => Err(e),
-            None => Ok(EvaluationResult {
-                quality_name: None,
-                quality_priority: None,
-                reason: "No quality triggered".to_string(),
-            }),
+/// Reports a failing native function the same way as the interpreter, and any other VM
+/// error as a backend error.
+fn evaluation_error(error: VmError) -> EvaluationError {
+    match error {
+        VmError::NativeFunction { name, message } => {
+            EvaluationError::NativeFunction { name, message }
         }
+        other => EvaluationError::BackendError(other.to_string()),
     }
 }
 
 fn prepare_all_static_data(
-    artifacts: &[(i32, String, BytecodeProgram)],
+    paths: &[LoadedPath],
     static_data: &AHashMap<String, f64>,
 ) -> Result<Vec<Vec<Value>>, EvaluationError> {
-    artifacts
+    paths
         .iter()
-        .map(|(_, _, program)| {
+        .map(|LoadedPath { program, .. }| {
+            let program = &program.program;
             let mut static_vec = vec![Value::Null; program.static_map.len()];
             for (name, &id) in &program.static_map {
                 let value = static_data
@@ -152,7 +346,13 @@ fn prepare_dynamic_context(
     program: &BytecodeProgram,
     context: &AHashMap<&str, &AHashMap<String, f64>>,
 ) -> Vec<Value> {
-    let mut dynamic_vec = vec![Value::Null; program.dynamic_map.len()];
+    // Ids need not be contiguous, since every path only keeps the events it reads.
+    let len = program
+        .dynamic_map
+        .values()
+        .max()
+        .map_or(0, |&id| id as usize + 1);
+    let mut dynamic_vec = vec![Value::Null; len];
     for (key, &id) in &program.dynamic_map {
         let (event_name, field_name) = key.split_once('.').unwrap();
         if let Some(instance) = context.get(event_name) {
@@ -163,31 +363,3 @@ fn prepare_dynamic_context(
     }
     dynamic_vec
 }
-
-fn prepare_dynamic_events<'a>(
-    program: &BytecodeProgram,
-    dynamic_data: &'a AHashMap<String, Vec<AHashMap<String, f64>>>,
-) -> (Vec<String>, Vec<Vec<&'a AHashMap<String, f64>>>) {
-    let mut required_events = HashSet::new();
-    for key in program.dynamic_map.keys() {
-        required_events.insert(key.split_once('.').unwrap().0);
-    }
-    if required_events.is_empty() {
-        return (Vec::new(), Vec::new());
-    }
-
-    let event_names: Vec<String> = required_events.into_iter().map(|s| s.to_string()).collect();
-    let mut event_instances = Vec::with_capacity(event_names.len());
-
-    for event_name in &event_names {
-        match dynamic_data.get(event_name) {
-            Some(instances) => {
-                event_instances.push(instances.iter().collect());
-            }
-            None => {
-                event_instances.push(Vec::new());
-            }
-        }
-    }
-    (event_names, event_instances)
-}
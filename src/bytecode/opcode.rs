@@ -1,10 +1,12 @@
 pub use crate::ast::InputId;
-use crate::ast::Value;
+use crate::ast::{SpatialOp, Value};
 use serde::{Deserialize, Serialize};
 
 pub type Register = u8;
 pub type Address = u16; // Up to 65536 instructions per chunk
 pub type SubroutineId = u64;
+pub type TableId = u16; // Index into `BytecodeProgram::tables`
+pub type FunctionId = u16; // Index into `BytecodeProgram::functions`
 
 /// An instruction for the register-based virtual machine.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -61,4 +63,29 @@ pub enum OpCode {
 
     // VM Control (28)
     Halt,
+
+    // Opcodes below were added later. Serialized programs refer to opcodes by index, so
+    // new ones are always appended.
+
+    // Parameters (29)
+    // src, dest
+    LoadParameter(Register, InputId),
+
+    // Table Lookup (30-31), by binary search over the table's keys
+    // dest, src, table
+    Lookup(Register, Register, TableId),
+    Interpolate(Register, Register, TableId),
+
+    // Native Functions (32), called with the registers first..first + count as arguments
+    // dest, function, first, count
+    CallNative(Register, FunctionId, Register, u8),
+
+    // Spatial Predicates (33), over the registers first..first + the operation's arity
+    // dest, operation, first
+    Spatial(Register, SpatialOp, Register),
+
+    // Kleene Logic (34-35), with null as unknown: null AND false is false, null OR true is true
+    // dest, src1, src2
+    And(Register, Register, Register),
+    Or(Register, Register, Register),
 }
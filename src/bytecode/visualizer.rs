@@ -60,6 +60,7 @@ fn format_bytecode_chunk(
                 let name = dynamic_rev_map.get(id).map(|s| s.as_str()).unwrap_or("?");
                 format!("{:<20} R{}, ${} [D{}]", "LoadDynamic", r, name, id)
             }
+            OpCode::LoadParameter(r, id) => format!("{:<20} R{}, P{}", "LoadParameter", r, id),
             // --- Standard formatting for other opcodes ---
             OpCode::LoadLiteral(r, v) => format!("{:<20} R{}, {}", "LoadLiteral", r, v),
             OpCode::Move(d, s) => format!("{:<20} R{}, R{}", "Move", d, s),
@@ -68,8 +69,20 @@ fn format_bytecode_chunk(
             OpCode::Multiply(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "Multiply", d, s1, s2),
             OpCode::Divide(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "Divide", d, s1, s2),
             OpCode::Xor(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "Xor", d, s1, s2),
+            OpCode::And(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "And", d, s1, s2),
+            OpCode::Or(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "Or", d, s1, s2),
             OpCode::Abs(d, s) => format!("{:<20} R{}, R{}", "Abs", d, s),
             OpCode::Not(d, s) => format!("{:<20} R{}, R{}", "Not", d, s),
+            OpCode::Lookup(d, s, t) => format!("{:<20} R{}, R{}, T{}", "Lookup", d, s, t),
+            OpCode::Interpolate(d, s, t) => {
+                format!("{:<20} R{}, R{}, T{}", "Interpolate", d, s, t)
+            }
+            OpCode::CallNative(d, f, first, count) => {
+                format!("{:<20} R{}, F{}, R{}, {}", "CallNative", d, f, first, count)
+            }
+            OpCode::Spatial(d, op, first) => {
+                format!("{:<20} R{}, {}, R{}", "Spatial", d, op, first)
+            }
             OpCode::Equal(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "Equal", d, s1, s2),
             OpCode::NotEqual(d, s1, s2) => format!("{:<20} R{}, R{}, R{}", "NotEqual", d, s1, s2),
             OpCode::GreaterThan(d, s1, s2) => {
@@ -1,12 +1,13 @@
-use crate::ast::Value;
+use crate::ast::{LookupTable, SpatialOp, Value};
 use crate::bytecode::compiler::BytecodeProgram;
-use crate::bytecode::opcode::{OpCode, Register};
-use crate::error::VmError;
+use crate::bytecode::opcode::{Address, FunctionId, OpCode, Register, TableId};
+use crate::error::{ProgramError, VmError};
+use crate::functions::NativeFunction;
 
 /// Number of registers in the VM.
 /// This is a fixed size for simplicity, but could be made dynamic if needed.
 /// Must be <= 256 to fit in a single byte for register encoding.
-const NUM_REGISTERS: usize = 64;
+pub(crate) const NUM_REGISTERS: usize = 64;
 
 macro_rules! binary_op {
     ($self:ident, $dest:ident, $src1:ident, $src2:ident, $op:tt) => {{
@@ -17,6 +18,10 @@ macro_rules! binary_op {
                 unsafe { $self.set_reg_unchecked($dest, Value::Number(*l $op *r)) };
                 Ok(())
             }
+            (Value::Null, _) | (_, Value::Null) => {
+                unsafe { $self.set_reg_unchecked($dest, Value::Null) };
+                Ok(())
+            }
             (l, _r) => Err(VmError::TypeMismatch { expected: "Number".to_string(), found: l.clone() }),
         }
     }};
@@ -31,6 +36,10 @@ macro_rules! comparison_op {
                 unsafe { $self.set_reg_unchecked($dest, Value::Bool(*l $op *r)) };
                 Ok(())
             }
+            (Value::Null, _) | (_, Value::Null) => {
+                unsafe { $self.set_reg_unchecked($dest, Value::Null) };
+                Ok(())
+            }
             (l, _r) => Err(VmError::TypeMismatch { expected: "Number".to_string(), found: l.clone() }),
         }
     }};
@@ -45,11 +54,155 @@ macro_rules! logical_op {
                 unsafe { $self.set_reg_unchecked($dest, Value::Bool(*l $op *r)) };
                 Ok(())
             }
+            (Value::Bool(_) | Value::Null, Value::Bool(_) | Value::Null) => {
+                unsafe { $self.set_reg_unchecked($dest, Value::Null) };
+                Ok(())
+            }
             (l, _) => Err(VmError::TypeMismatch { expected: "Bool".to_string(), found: l.clone() }),
         }
     }};
 }
 
+impl BytecodeProgram {
+    /// Checks that the VM can run this program: every register, jump target, subroutine,
+    /// table and function it uses exists, and every chunk ends with `Halt` or `Return`,
+    /// so execution never runs past the end of a chunk.
+    ///
+    /// The VM reads registers and instructions without bounds checks and relies on this.
+    pub fn validate(&self) -> Result<(), ProgramError> {
+        self.validate_chunk(&self.main, ".main")?;
+        let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(id, _)| **id);
+        for (id, bytecode) in subroutines {
+            self.validate_chunk(bytecode, &format!(".sub {}", id))?;
+        }
+        Ok(())
+    }
+
+    fn validate_chunk(&self, bytecode: &[OpCode], chunk: &str) -> Result<(), ProgramError> {
+        for (index, op) in bytecode.iter().enumerate() {
+            let reg = |register: usize| {
+                if register < NUM_REGISTERS {
+                    Ok(())
+                } else {
+                    Err(ProgramError::InvalidRegister {
+                        chunk: chunk.to_string(),
+                        index,
+                        register,
+                    })
+                }
+            };
+            // The registers first..first + len, read as arguments.
+            let block = |first: Register, len: usize| {
+                reg(first as usize)?;
+                reg(first as usize + len.saturating_sub(1))
+            };
+            let jump = |address: Address| {
+                if (address as usize) < bytecode.len() {
+                    Ok(())
+                } else {
+                    Err(ProgramError::JumpOutOfBounds {
+                        chunk: chunk.to_string(),
+                        index,
+                        address,
+                    })
+                }
+            };
+            let table = |id: TableId| {
+                if (id as usize) < self.tables.len() {
+                    Ok(())
+                } else {
+                    Err(ProgramError::UnknownTable {
+                        chunk: chunk.to_string(),
+                        index,
+                        id,
+                    })
+                }
+            };
+
+            match *op {
+                OpCode::LoadLiteral(d, _)
+                | OpCode::LoadStatic(d, _)
+                | OpCode::LoadDynamic(d, _)
+                | OpCode::LoadParameter(d, _) => reg(d as usize)?,
+                OpCode::Move(d, s) | OpCode::Abs(d, s) | OpCode::Not(d, s) => {
+                    reg(d as usize)?;
+                    reg(s as usize)?;
+                }
+                OpCode::Add(d, a, b)
+                | OpCode::Subtract(d, a, b)
+                | OpCode::Multiply(d, a, b)
+                | OpCode::Divide(d, a, b)
+                | OpCode::Xor(d, a, b)
+                | OpCode::And(d, a, b)
+                | OpCode::Or(d, a, b)
+                | OpCode::Equal(d, a, b)
+                | OpCode::NotEqual(d, a, b)
+                | OpCode::GreaterThan(d, a, b)
+                | OpCode::LessThan(d, a, b)
+                | OpCode::GreaterThanOrEqual(d, a, b)
+                | OpCode::LessThanOrEqual(d, a, b) => {
+                    reg(d as usize)?;
+                    reg(a as usize)?;
+                    reg(b as usize)?;
+                }
+                OpCode::Lookup(d, s, t) | OpCode::Interpolate(d, s, t) => {
+                    reg(d as usize)?;
+                    reg(s as usize)?;
+                    table(t)?;
+                }
+                OpCode::CallNative(d, id, first, count) => {
+                    reg(d as usize)?;
+                    block(first, count as usize)?;
+                    if id as usize >= self.functions.len() {
+                        return Err(ProgramError::UnknownFunction {
+                            chunk: chunk.to_string(),
+                            index,
+                            id,
+                        });
+                    }
+                }
+                OpCode::Spatial(d, op, first) => {
+                    reg(d as usize)?;
+                    block(first, op.arity())?;
+                }
+                OpCode::JumpIfEq(a, b, addr)
+                | OpCode::JumpIfNeq(a, b, addr)
+                | OpCode::JumpIfGt(a, b, addr)
+                | OpCode::JumpIfGte(a, b, addr)
+                | OpCode::JumpIfLt(a, b, addr)
+                | OpCode::JumpIfLte(a, b, addr) => {
+                    reg(a as usize)?;
+                    reg(b as usize)?;
+                    jump(addr)?;
+                }
+                OpCode::JumpIfFalse(r, addr) | OpCode::JumpIfTrue(r, addr) => {
+                    reg(r as usize)?;
+                    jump(addr)?;
+                }
+                OpCode::Jump(addr) => jump(addr)?,
+                OpCode::Call(id) => {
+                    if !self.subroutines.contains_key(&id) {
+                        return Err(ProgramError::UnknownSubroutine {
+                            chunk: chunk.to_string(),
+                            index,
+                            id,
+                        });
+                    }
+                }
+                OpCode::Return | OpCode::Halt => {}
+            }
+        }
+
+        match bytecode.last() {
+            Some(OpCode::Halt | OpCode::Return) => Ok(()),
+            _ => Err(ProgramError::MissingTerminator {
+                chunk: chunk.to_string(),
+            }),
+        }
+    }
+}
+
 pub struct Vm<'a> {
     program: &'a BytecodeProgram,
     ip: usize,
@@ -58,13 +211,33 @@ pub struct Vm<'a> {
     call_stack: Vec<(usize, &'a [OpCode])>,
     static_data: &'a [Value],
     dynamic_context: &'a [Value],
+    parameters: &'a [Value],
+    functions: &'a [NativeFunction],
 }
 
 impl<'a> Vm<'a> {
+    /// Creates a VM for `program`, which is checked with [`BytecodeProgram::validate`].
     pub fn new(
         program: &'a BytecodeProgram,
         static_data: &'a [Value],
         dynamic_context: &'a [Value],
+    ) -> Result<Self, VmError> {
+        program.validate()?;
+        // SAFETY: the program was validated above.
+        Ok(unsafe { Self::new_unchecked(program, static_data, dynamic_context) })
+    }
+
+    /// Creates a VM for `program` without validating it, for programs that are run many
+    /// times and were validated once when they were loaded.
+    ///
+    /// # Safety
+    ///
+    /// `program.validate()` must succeed. `run` indexes registers and instructions
+    /// without bounds checks, so running an invalid program is undefined behaviour.
+    pub unsafe fn new_unchecked(
+        program: &'a BytecodeProgram,
+        static_data: &'a [Value],
+        dynamic_context: &'a [Value],
     ) -> Self {
         Self {
             program,
@@ -74,9 +247,23 @@ impl<'a> Vm<'a> {
             call_stack: Vec::with_capacity(8),
             static_data,
             dynamic_context,
+            parameters: &[],
+            functions: &[],
         }
     }
 
+    /// Sets the parameter values read by `LoadParameter`, indexed by parameter id.
+    pub fn with_parameters(mut self, parameters: &'a [Value]) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Sets the native functions called by `CallNative`, indexed by function id.
+    pub fn with_functions(mut self, functions: &'a [NativeFunction]) -> Self {
+        self.functions = functions;
+        self
+    }
+
     /// Unsafe, unchecked, and always-inlined register access.
     #[inline(always)]
     unsafe fn get_reg_unchecked(&self, reg: Register) -> &Value {
@@ -91,6 +278,97 @@ impl<'a> Vm<'a> {
         }
     }
 
+    /// The number in `src`, looked up by a table instruction, or `None` if it is null.
+    #[inline(always)]
+    fn lookup_input(&self, src: Register) -> Result<Option<f64>, VmError> {
+        match unsafe { self.get_reg_unchecked(src) } {
+            Value::Number(n) => Ok(Some(*n)),
+            Value::Null => Ok(None),
+            other => Err(VmError::TypeMismatch {
+                expected: "Number".to_string(),
+                found: other.clone(),
+            }),
+        }
+    }
+
+    #[inline(always)]
+    fn table(&self, id: TableId) -> Result<&'a LookupTable, VmError> {
+        self.program
+            .tables
+            .get(id as usize)
+            .ok_or(VmError::UnknownTable(id))
+    }
+
+    /// Calls native function `id` with the `count` registers from `first` as arguments.
+    fn call_native(&self, id: FunctionId, first: Register, count: u8) -> Result<Value, VmError> {
+        let function = self
+            .functions
+            .get(id as usize)
+            .ok_or(VmError::UnknownFunction(id))?;
+        let args = self
+            .registers
+            .get(first as usize..first as usize + count as usize)
+            .ok_or(VmError::InvalidRegister(first))?;
+        function(args).map_err(|message| VmError::NativeFunction {
+            name: self
+                .program
+                .functions
+                .get(id as usize)
+                .cloned()
+                .unwrap_or_default(),
+            message,
+        })
+    }
+
+    /// Applies `op` to the registers from `first`, which must all hold numbers. The result
+    /// is null if any of them is.
+    fn spatial(&self, op: SpatialOp, first: Register) -> Result<Value, VmError> {
+        let args = self
+            .registers
+            .get(first as usize..first as usize + op.arity())
+            .ok_or(VmError::InvalidRegister(first))?;
+        let mut numbers = [0.0; SpatialOp::MAX_ARITY];
+        let mut missing = false;
+        for (number, arg) in numbers.iter_mut().zip(args) {
+            *number = match arg {
+                Value::Number(n) => *n,
+                Value::Null => {
+                    missing = true;
+                    0.0
+                }
+                other => {
+                    return Err(VmError::TypeMismatch {
+                        expected: "Number".to_string(),
+                        found: other.clone(),
+                    });
+                }
+            };
+        }
+        if missing {
+            return Ok(Value::Null);
+        }
+        Ok(op.apply(&numbers[..args.len()]))
+    }
+
+    /// `src1 AND src2`, or `src1 OR src2` if `is_or`, where null is unknown: unknown AND
+    /// false is false, unknown OR true is true, and any other unknown operand leaves the
+    /// result unknown.
+    #[inline(always)]
+    fn kleene(&self, src1: Register, src2: Register, is_or: bool) -> Result<Value, VmError> {
+        match unsafe { (self.get_reg_unchecked(src1), self.get_reg_unchecked(src2)) } {
+            (Value::Bool(l), Value::Bool(r)) => {
+                Ok(Value::Bool(if is_or { *l || *r } else { *l && *r }))
+            }
+            (Value::Null, Value::Bool(r)) if *r == is_or => Ok(Value::Bool(is_or)),
+            (Value::Bool(l), Value::Null) if *l == is_or => Ok(Value::Bool(is_or)),
+            (Value::Bool(_) | Value::Null, Value::Bool(_) | Value::Null) => Ok(Value::Null),
+            (l, _) => Err(VmError::TypeMismatch {
+                expected: "Bool".to_string(),
+                found: l.clone(),
+            }),
+        }
+    }
+
     #[inline(always)]
     pub fn run(&mut self) -> Result<Value, VmError> {
         loop {
@@ -116,6 +394,13 @@ impl<'a> Vm<'a> {
                         .ok_or(VmError::InputIdOutOfBounds(id))?;
                     unsafe { self.set_reg_unchecked(dest, val.clone()) };
                 }
+                OpCode::LoadParameter(dest, id) => {
+                    let val = self
+                        .parameters
+                        .get(id as usize)
+                        .ok_or(VmError::InputIdOutOfBounds(id))?;
+                    unsafe { self.set_reg_unchecked(dest, val.clone()) };
+                }
                 OpCode::Move(dest, src) => {
                     let val = unsafe { self.get_reg_unchecked(src) }.clone();
                     unsafe { self.set_reg_unchecked(dest, val) };
@@ -125,9 +410,19 @@ impl<'a> Vm<'a> {
                 OpCode::Multiply(dest, src1, src2) => binary_op!(self, dest, src1, src2, *)?,
                 OpCode::Divide(dest, src1, src2) => binary_op!(self, dest, src1, src2, /)?,
                 OpCode::Xor(dest, src1, src2) => logical_op!(self, dest, src1, src2, ^)?,
+                OpCode::And(dest, src1, src2) => {
+                    let value = self.kleene(src1, src2, false)?;
+                    unsafe { self.set_reg_unchecked(dest, value) };
+                }
+                OpCode::Or(dest, src1, src2) => {
+                    let value = self.kleene(src1, src2, true)?;
+                    unsafe { self.set_reg_unchecked(dest, value) };
+                }
                 OpCode::Abs(dest, src) => {
                     if let Value::Number(n) = unsafe { self.get_reg_unchecked(src) } {
                         unsafe { self.set_reg_unchecked(dest, Value::Number(n.abs())) };
+                    } else if let Value::Null = unsafe { self.get_reg_unchecked(src) } {
+                        unsafe { self.set_reg_unchecked(dest, Value::Null) };
                     } else {
                         return Err(VmError::TypeMismatch {
                             expected: "Number".to_string(),
@@ -138,6 +433,8 @@ impl<'a> Vm<'a> {
                 OpCode::Not(dest, src) => {
                     if let Value::Bool(b) = unsafe { self.get_reg_unchecked(src) } {
                         unsafe { self.set_reg_unchecked(dest, Value::Bool(!*b)) };
+                    } else if let Value::Null = unsafe { self.get_reg_unchecked(src) } {
+                        unsafe { self.set_reg_unchecked(dest, Value::Null) };
                     } else {
                         return Err(VmError::TypeMismatch {
                             expected: "Bool".to_string(),
@@ -145,15 +442,45 @@ impl<'a> Vm<'a> {
                         });
                     }
                 }
+                OpCode::Lookup(dest, src, table) => {
+                    let value = match self.lookup_input(src)? {
+                        Some(x) => Value::Number(self.table(table)?.step(x).0),
+                        None => Value::Null,
+                    };
+                    unsafe { self.set_reg_unchecked(dest, value) };
+                }
+                OpCode::Interpolate(dest, src, table) => {
+                    let value = match self.lookup_input(src)? {
+                        Some(x) => Value::Number(self.table(table)?.interpolate(x).0),
+                        None => Value::Null,
+                    };
+                    unsafe { self.set_reg_unchecked(dest, value) };
+                }
+                OpCode::CallNative(dest, function, first, count) => {
+                    let value = self.call_native(function, first, count)?;
+                    unsafe { self.set_reg_unchecked(dest, value) };
+                }
+                OpCode::Spatial(dest, op, first) => {
+                    let value = self.spatial(op, first)?;
+                    unsafe { self.set_reg_unchecked(dest, value) };
+                }
                 OpCode::Equal(dest, src1, src2) => {
-                    let are_equal =
-                        unsafe { self.get_reg_unchecked(src1) == self.get_reg_unchecked(src2) };
-                    unsafe { self.set_reg_unchecked(dest, Value::Bool(are_equal)) };
+                    let value = match unsafe {
+                        (self.get_reg_unchecked(src1), self.get_reg_unchecked(src2))
+                    } {
+                        (Value::Null, _) | (_, Value::Null) => Value::Null,
+                        (l, r) => Value::Bool(l == r),
+                    };
+                    unsafe { self.set_reg_unchecked(dest, value) };
                 }
                 OpCode::NotEqual(dest, src1, src2) => {
-                    let are_not_equal =
-                        unsafe { self.get_reg_unchecked(src1) != self.get_reg_unchecked(src2) };
-                    unsafe { self.set_reg_unchecked(dest, Value::Bool(are_not_equal)) };
+                    let value = match unsafe {
+                        (self.get_reg_unchecked(src1), self.get_reg_unchecked(src2))
+                    } {
+                        (Value::Null, _) | (_, Value::Null) => Value::Null,
+                        (l, r) => Value::Bool(l != r),
+                    };
+                    unsafe { self.set_reg_unchecked(dest, value) };
                 }
                 OpCode::GreaterThan(dest, src1, src2) => comparison_op!(self, dest, src1, src2, >)?,
                 OpCode::LessThan(dest, src1, src2) => comparison_op!(self, dest, src1, src2, <)?,
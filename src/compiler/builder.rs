@@ -1,41 +1,59 @@
 use ahash::AHashMap;
 
 use crate::ast::{Expression, InputSource, Value};
-use crate::compiler::parsing::NodeParser;
+use crate::compiler::parsing::{FanIn, NodeOutputs, NodeParser};
+use crate::compiler::{PARAMETER_NODE_TYPE, parameter_definition};
 use crate::error::AstBuildError;
-use crate::recipe::{FlowDefinition, FlowNodeDefinition};
+use crate::events::check_event_alias;
+use crate::recipe::{FlowDefinition, FlowNodeDefinition, SubFlowDefinition};
 
 /// Responsible for building the initial, unoptimized AST from a `FlowDefinition`.
 pub(super) struct AstBuilder<'a> {
     flow: &'a FlowDefinition,
     registry: &'a AHashMap<String, Box<dyn NodeParser>>,
-    ast_cache: &'a mut AHashMap<String, Expression>,
+    sub_flows: &'a AHashMap<String, SubFlowDefinition>,
+    ast_cache: &'a mut AHashMap<String, NodeOutputs>,
     connections: AHashMap<String, AHashMap<u32, Vec<(String, u32)>>>,
+    /// The nodes currently being built, from the quality node down to the innermost one.
+    path: Vec<String>,
+    max_depth: usize,
+    /// How deep the composite node this flow is expanded for is nested, if it is a sub-flow.
+    depth_offset: usize,
+    /// The expressions fed into the `subFlowInputNode`s of a sub-flow, by node id.
+    bindings: AHashMap<String, Expression>,
+    /// The sub-flows being expanded, from the outermost one down to this flow.
+    sub_flow_stack: Vec<String>,
 }
 
 impl<'a> AstBuilder<'a> {
     pub(super) fn new(
         flow: &'a FlowDefinition,
         registry: &'a AHashMap<String, Box<dyn NodeParser>>,
-        ast_cache: &'a mut AHashMap<String, Expression>,
+        sub_flows: &'a AHashMap<String, SubFlowDefinition>,
+        ast_cache: &'a mut AHashMap<String, NodeOutputs>,
+        max_depth: usize,
     ) -> Self {
         let mut connections: AHashMap<String, AHashMap<u32, Vec<(String, u32)>>> = AHashMap::new();
         for edge in &flow.edges {
-            let target_handle_idx = Self::parse_handle_index(&edge.target_handle);
-            let source_handle_idx = Self::parse_handle_index(&edge.source_handle);
             connections
                 .entry(edge.target.clone())
                 .or_default()
-                .entry(target_handle_idx)
+                .entry(edge.target_handle)
                 .or_default()
-                .push((edge.source.clone(), source_handle_idx));
+                .push((edge.source.clone(), edge.source_handle));
         }
 
         Self {
             flow,
             registry,
+            sub_flows,
             ast_cache,
             connections,
+            path: Vec::new(),
+            max_depth,
+            depth_offset: 0,
+            bindings: AHashMap::new(),
+            sub_flow_stack: Vec::new(),
         }
     }
 
@@ -46,7 +64,10 @@ impl<'a> AstBuilder<'a> {
         node_id: &str,
     ) -> Result<AHashMap<u32, Expression>, AstBuildError> {
         let node = self.find_node(node_id, "N/A")?;
-        let mut expressions = self.gather_connected_inputs(node_id)?;
+        self.enter(node_id)?;
+        let expressions = self.gather_connected_inputs(node);
+        self.path.pop();
+        let mut expressions = expressions?;
 
         if let Some(values) = &node.literal_values {
             for (i, val) in values.iter().enumerate() {
@@ -65,14 +86,64 @@ impl<'a> AstBuilder<'a> {
         Ok(expressions)
     }
 
-    /// Recursively builds the AST for a single node, handling caching.
-    fn build_ast(&mut self, node_id: &str, source_id: &str) -> Result<Expression, AstBuildError> {
-        if let Some(cached) = self.ast_cache.get(node_id) {
-            return Ok(cached.clone());
+    /// Marks `node_id` as being built. Fails if it is already being built further up,
+    /// i.e. the flow loops back into it, or if the flow is nested too deeply.
+    fn enter(&mut self, node_id: &str) -> Result<(), AstBuildError> {
+        if let Some(start) = self.path.iter().position(|id| id == node_id) {
+            let mut cycle = self.path[start..].to_vec();
+            cycle.push(node_id.to_string());
+            return Err(AstBuildError::CycleDetected { cycle });
         }
+        if self.depth_offset + self.path.len() >= self.max_depth {
+            return Err(AstBuildError::MaxDepthExceeded {
+                node_id: node_id.to_string(),
+                max_depth: self.max_depth,
+            });
+        }
+        self.path.push(node_id.to_string());
+        Ok(())
+    }
 
+    /// Builds the AST of output handle `handle` of a node, handling caching.
+    fn build_ast(
+        &mut self,
+        node_id: &str,
+        source_id: &str,
+        handle: u32,
+    ) -> Result<Expression, AstBuildError> {
+        if !self.ast_cache.contains_key(node_id) {
+            let outputs = self.build_outputs(node_id, source_id)?;
+            self.ast_cache.insert(node_id.to_string(), outputs);
+        }
+        self.ast_cache[node_id].get(handle).cloned().ok_or_else(|| {
+            AstBuildError::UnknownOutputHandle {
+                node_id: node_id.to_string(),
+                handle,
+                target_node_id: source_id.to_string(),
+            }
+        })
+    }
+
+    /// Recursively builds the outputs of a single node.
+    fn build_outputs(
+        &mut self,
+        node_id: &str,
+        source_id: &str,
+    ) -> Result<NodeOutputs, AstBuildError> {
         let node = self.find_node(node_id, source_id)?;
+        if node.operation_type == "subFlowInputNode" {
+            let input = self.bindings.get(node_id).cloned();
+            return input
+                .map(NodeOutputs::Single)
+                .ok_or_else(|| AstBuildError::UnboundSubFlowInput(node_id.to_string()));
+        }
+        if node.operation_type == PARAMETER_NODE_TYPE {
+            return build_parameter_expr(node).map(NodeOutputs::Single);
+        }
         let expressions_map = self.build_asts_for_node(node_id)?;
+        if let Some(sub_flow) = self.sub_flows.get(&node.operation_type) {
+            return self.expand_sub_flow(node, sub_flow, expressions_map);
+        }
 
         let mut sorted_expressions: Vec<_> = expressions_map.into_iter().collect();
         sorted_expressions.sort_by_key(|(idx, _)| *idx);
@@ -88,17 +159,85 @@ impl<'a> AstBuilder<'a> {
             }
         })?;
 
-        let expression = parser.parse(node, inputs)?;
-        self.ast_cache
-            .insert(node_id.to_string(), expression.clone());
-        Ok(expression)
+        parser.parse_outputs(node, inputs)
+    }
+
+    /// Builds the outputs of the composite node `node` by expanding `sub_flow` with the
+    /// node's inputs bound to the sub-flow's input nodes.
+    fn expand_sub_flow(
+        &mut self,
+        node: &FlowNodeDefinition,
+        sub_flow: &'a SubFlowDefinition,
+        mut inputs: AHashMap<u32, Expression>,
+    ) -> Result<NodeOutputs, AstBuildError> {
+        if let Some(start) = self.sub_flow_stack.iter().position(|n| *n == sub_flow.name) {
+            let mut chain = self.sub_flow_stack[start..].to_vec();
+            chain.push(sub_flow.name.clone());
+            return Err(AstBuildError::RecursiveSubFlow { chain });
+        }
+        let mismatch = |message: String| AstBuildError::SubFlowSignatureMismatch {
+            node_id: node.id.clone(),
+            sub_flow: sub_flow.name.clone(),
+            message,
+        };
+        if let Some(handle) = inputs
+            .keys()
+            .filter(|handle| **handle as usize >= sub_flow.inputs.len())
+            .min()
+        {
+            return Err(mismatch(format!(
+                "input handle {} is connected, but the sub-flow has {} inputs",
+                handle,
+                sub_flow.inputs.len()
+            )));
+        }
+
+        let mut bindings = AHashMap::new();
+        for (handle, input_node_id) in sub_flow.inputs.iter().enumerate() {
+            let is_input_node = sub_flow
+                .flow
+                .nodes
+                .iter()
+                .any(|n| n.id == *input_node_id && n.operation_type == "subFlowInputNode");
+            if !is_input_node {
+                return Err(mismatch(format!(
+                    "input {} refers to '{}', which is not a subFlowInputNode",
+                    handle, input_node_id
+                )));
+            }
+            let expr = inputs
+                .remove(&(handle as u32))
+                .ok_or_else(|| mismatch(format!("input handle {} is not connected", handle)))?;
+            bindings.insert(input_node_id.clone(), expr);
+        }
+
+        let mut ast_cache = AHashMap::new();
+        let mut builder = AstBuilder::new(
+            &sub_flow.flow,
+            self.registry,
+            self.sub_flows,
+            &mut ast_cache,
+            self.max_depth,
+        );
+        builder.depth_offset = self.depth_offset + self.path.len();
+        builder.bindings = bindings;
+        builder.sub_flow_stack = self.sub_flow_stack.clone();
+        builder.sub_flow_stack.push(sub_flow.name.clone());
+
+        let mut outputs = AHashMap::new();
+        for (handle, output) in sub_flow.outputs.iter().enumerate() {
+            let expr = builder.build_ast(&output.node_id, &node.id, output.handle)?;
+            outputs.insert(handle as u32, expr);
+        }
+        Ok(NodeOutputs::PerHandle(outputs))
     }
 
     /// Gathers all incoming connected expressions for a node.
     fn gather_connected_inputs(
         &mut self,
-        node_id: &str,
+        node: &FlowNodeDefinition,
     ) -> Result<AHashMap<u32, Expression>, AstBuildError> {
+        let node_id = node.id.as_str();
         let mut expressions: AHashMap<u32, Expression> = AHashMap::new();
 
         // Clone the connection data to iterate over, releasing the borrow on `self`.
@@ -116,14 +255,30 @@ impl<'a> AstBuilder<'a> {
                     self.build_input_source_expr(source_node, *source_handle_idx)?
                 } else {
                     // This mutable call is now safe.
-                    self.build_ast(source_node_id, node_id)?
+                    self.build_ast(source_node_id, node_id, *source_handle_idx)?
                 };
                 source_expressions.push(expr);
             }
 
+            let combine = if sources.len() == 1 {
+                Expression::Or // Never called for a single expression.
+            } else {
+                match self.fan_in(node, target_handle_idx)? {
+                    FanIn::Or => Expression::Or,
+                    FanIn::And => Expression::And,
+                    FanIn::Sum => Expression::Sum,
+                    FanIn::Reject => {
+                        return Err(AstBuildError::IllegalFanIn {
+                            node_id: node_id.to_string(),
+                            handle: target_handle_idx,
+                            sources: sources.into_iter().map(|(id, _)| id).collect(),
+                        });
+                    }
+                }
+            };
             if let Some(combined) = source_expressions
                 .into_iter()
-                .reduce(|acc, expr| Expression::Or(Box::new(acc), Box::new(expr)))
+                .reduce(|acc, expr| combine(Box::new(acc), Box::new(expr)))
             {
                 expressions.insert(target_handle_idx, combined);
             }
@@ -131,6 +286,24 @@ impl<'a> AstBuilder<'a> {
         Ok(expressions)
     }
 
+    /// How edges into input handle `handle` of `node` are combined. Every quality of the
+    /// quality node is triggered by any of the conditions wired into it.
+    fn fan_in(&self, node: &FlowNodeDefinition, handle: u32) -> Result<FanIn, AstBuildError> {
+        if node.operation_type == "setQualityNode" {
+            return Ok(FanIn::Or);
+        }
+        if self.sub_flows.contains_key(&node.operation_type) {
+            return Ok(FanIn::Reject);
+        }
+        let parser = self.registry.get(&node.operation_type).ok_or_else(|| {
+            AstBuildError::InvalidNodeType {
+                node_id: node.id.clone(),
+                type_name: node.operation_type.clone(),
+            }
+        })?;
+        Ok(parser.fan_in(handle))
+    }
+
     /// Creates an `Expression::Input` from a "dynamicNode".
     fn build_input_source_expr(
         &mut self,
@@ -159,6 +332,11 @@ impl<'a> AstBuilder<'a> {
                 ),
             })?;
 
+        // An aliased event such as `hole#a` needs both an event type and an alias.
+        if let Some(event_type) = &source_node.input_type {
+            check_event_alias(&source_node.id, event_type)?;
+        }
+
         let source = if let Some(event_type) = &source_node.input_type {
             // This is a dedicated dynamic node (e.g., a "Hole Details" node).
             // All its outputs are dynamic properties of that event type.
@@ -204,8 +382,14 @@ impl<'a> AstBuilder<'a> {
                 source_node_id: source_node_id.to_string(),
             })
     }
+}
 
-    fn parse_handle_index(handle: &str) -> u32 {
-        handle.split('-').last().unwrap_or("0").parse().unwrap_or(0)
-    }
+/// Creates the `Expression::Input` read by a parameter node. Kept out of `build_outputs`
+/// so the recursive frame stays small.
+#[inline(never)]
+fn build_parameter_expr(node: &FlowNodeDefinition) -> Result<Expression, AstBuildError> {
+    let parameter = parameter_definition(node)?;
+    Ok(Expression::Input(InputSource::ParameterName {
+        name: parameter.name,
+    }))
 }
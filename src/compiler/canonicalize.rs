@@ -0,0 +1,175 @@
+//! Canonicalisation of commutative and associative operations, so that CSE sees
+//! `a + b` and `b + a`, or `(a AND b) AND c` and `a AND (b AND c)`, as the same node.
+//!
+//! - Chains of `AND`, `OR` and `XOR` are flattened and rebuilt as a left-deep chain.
+//!   `XOR` operands are also sorted by [`Canonicalizer::canonical_cmp`]. `AND` and `OR`
+//!   keep their source order: they skip their right operand once the left one decides the
+//!   result, so reordering them could evaluate, and fail on, an operand that was skipped.
+//! - The two operands of `+` and `*` are sorted the same way. Sums and products are not
+//!   flattened: regrouping floating-point terms can change the rounded result.
+//!
+//! Comparisons keep the orientation they were written in, since reasons show them as is.
+
+use crate::ast::{ExpressionGraph, Node, NodeId, Value};
+use ahash::AHashMap;
+use std::cmp::Ordering;
+use std::mem::{Discriminant, discriminant};
+
+/// Rewrites nodes of an expression graph into their canonical form.
+pub(super) struct Canonicalizer<'g> {
+    graph: &'g mut ExpressionGraph,
+    /// Canonical form of every node rewritten so far.
+    memo: AHashMap<NodeId, NodeId>,
+}
+
+impl<'g> Canonicalizer<'g> {
+    pub(super) fn new(graph: &'g mut ExpressionGraph) -> Self {
+        Self {
+            graph,
+            memo: AHashMap::new(),
+        }
+    }
+
+    /// Rewrites `id` into its canonical form. The result is a fixed point of this function.
+    pub(super) fn canonicalize(&mut self, id: NodeId) -> NodeId {
+        if let Some(result) = self.memo.get(&id) {
+            return *result;
+        }
+        let node = self.graph[id].clone();
+        let result = match node {
+            Node::And(..) | Node::Or(..) | Node::Xor(..) => {
+                let kind = discriminant(&node);
+                let constructor = chain_constructor(&node);
+                let mut operands = Vec::new();
+                self.flatten(id, kind, &mut operands);
+                if matches!(node, Node::Xor(..)) {
+                    operands.sort_by(|a, b| self.canonical_cmp(*a, *b));
+                }
+                operands
+                    .into_iter()
+                    .reduce(|acc, e| self.graph.add(constructor(acc, e)))
+                    .unwrap() // A chain always has at least two operands.
+            }
+            Node::Sum(l, r) => {
+                let (l, r) = self.sorted_pair(l, r);
+                self.graph.add(Node::Sum(l, r))
+            }
+            Node::Multiply(l, r) => {
+                let (l, r) = self.sorted_pair(l, r);
+                self.graph.add(Node::Multiply(l, r))
+            }
+            other => {
+                let node = other.map_children(|child| self.canonicalize(child));
+                self.graph.add(node)
+            }
+        };
+        self.memo.insert(id, result);
+        result
+    }
+
+    /// Collects the canonicalised operands of a nested chain of the operation `kind`.
+    fn flatten(&mut self, id: NodeId, kind: Discriminant<Node>, out: &mut Vec<NodeId>) {
+        let node = &self.graph[id];
+        let same_kind = discriminant(node) == kind;
+        match *node {
+            Node::And(l, r) | Node::Or(l, r) | Node::Xor(l, r) if same_kind => {
+                self.flatten(l, kind, out);
+                self.flatten(r, kind, out);
+            }
+            _ => out.push(self.canonicalize(id)),
+        }
+    }
+
+    fn sorted_pair(&mut self, l: NodeId, r: NodeId) -> (NodeId, NodeId) {
+        let (l, r) = (self.canonicalize(l), self.canonicalize(r));
+        if self.canonical_cmp(l, r) == Ordering::Greater {
+            (r, l)
+        } else {
+            (l, r)
+        }
+    }
+
+    /// A deterministic total order over expressions that depends only on their structure.
+    fn canonical_cmp(&self, a: NodeId, b: NodeId) -> Ordering {
+        if a == b {
+            return Ordering::Equal;
+        }
+        let (a, b) = (&self.graph[a], &self.graph[b]);
+        rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+            (Node::Input(a), Node::Input(b)) => a.cmp(b),
+            (Node::Literal(a), Node::Literal(b)) => value_cmp(a, b),
+            (Node::Table(x, a), Node::Table(y, b))
+            | (Node::Interpolate(x, a), Node::Interpolate(y, b)) => {
+                self.canonical_cmp(*x, *y).then_with(|| a.total_cmp(b))
+            }
+            (Node::Call(f, x), Node::Call(g, y)) => f.cmp(g).then_with(|| self.args_cmp(x, y)),
+            (Node::Spatial(p, x), Node::Spatial(q, y)) => {
+                p.cmp(q).then_with(|| self.args_cmp(x, y))
+            }
+            _ => a
+                .children()
+                .zip(b.children())
+                .map(|(a, b)| self.canonical_cmp(a, b))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal),
+        })
+    }
+
+    /// Compares two argument lists element by element, then by length.
+    fn args_cmp(&self, x: &[NodeId], y: &[NodeId]) -> Ordering {
+        x.iter()
+            .zip(y)
+            .map(|(a, b)| self.canonical_cmp(*a, *b))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| x.len().cmp(&y.len()))
+    }
+}
+
+fn chain_constructor(node: &Node) -> fn(NodeId, NodeId) -> Node {
+    match node {
+        Node::And(..) => Node::And,
+        Node::Or(..) => Node::Or,
+        _ => Node::Xor,
+    }
+}
+
+/// Orders node kinds so that operations come first, then inputs and literals last, e.g.
+/// `($x * 2) + $y + 5`. The bytecode compiler evaluates the left operand first and holds
+/// its result while the right one is evaluated, so deep operands first need fewer registers.
+fn rank(node: &Node) -> u8 {
+    match node {
+        Node::Abs(_) => 0,
+        Node::Not(_) => 1,
+        Node::Sum(..) => 2,
+        Node::Subtract(..) => 3,
+        Node::Multiply(..) => 4,
+        Node::Divide(..) => 5,
+        Node::And(..) => 6,
+        Node::Or(..) => 7,
+        Node::Xor(..) => 8,
+        Node::Equal(..) => 9,
+        Node::NotEqual(..) => 10,
+        Node::GreaterThan(..) => 11,
+        Node::GreaterThanOrEqual(..) => 12,
+        Node::SmallerThan(..) => 13,
+        Node::SmallerThanOrEqual(..) => 14,
+        Node::Table(..) => 15,
+        Node::Interpolate(..) => 16,
+        Node::Call(..) => 17,
+        Node::Spatial(..) => 18,
+        Node::Input(_) => 19,
+        Node::Literal(_) => 20,
+    }
+}
+
+fn value_cmp(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.total_cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Number(_), _) => Ordering::Less,
+        (_, Value::Number(_)) => Ordering::Greater,
+        (Value::Bool(_), _) => Ordering::Less,
+        (_, Value::Bool(_)) => Ordering::Greater,
+    }
+}
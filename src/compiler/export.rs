@@ -0,0 +1,332 @@
+//! Exporters that render `CompilationArtifacts` as JSON, Graphviz DOT or Mermaid.
+//!
+//! Every shared subexpression of the artifact's graph is emitted exactly once as a
+//! definition. All `Reference` nodes point at that single definition, so shared logic
+//! shows up as a shared node instead of being duplicated.
+
+use super::CompilationArtifacts;
+use crate::ast::{Expression, InputId, InputSource, Value};
+use ahash::{AHashMap, AHashSet};
+use serde_json::json;
+use std::fmt::Write;
+
+impl CompilationArtifacts {
+    /// Returns the artifact as a structured JSON value.
+    ///
+    /// The root expression and each reachable definition are nested trees; a
+    /// `Reference` is written as `{"op": "Reference", "id": n}` and resolved through
+    /// the `definitions` array.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        let names = InputNames::new(self);
+        let (ast, definitions) = self.graph.to_shared_expression(self.root);
+        let mut definitions: Vec<_> = definitions.into_iter().collect();
+        definitions.sort_unstable_by_key(|(id, _)| *id);
+        let definitions: Vec<_> = definitions
+            .iter()
+            .map(|(id, expr)| json!({ "id": id, "expr": expr_to_json(expr, &names) }))
+            .collect();
+        json!({
+            "name": self.name,
+            "priority": self.priority,
+            "root": expr_to_json(&ast, &names),
+            "definitions": definitions,
+            "static_map": self.static_map,
+            "dynamic_map": self.dynamic_map,
+            "parameters": self
+                .parameters
+                .iter()
+                .map(|p| json!({ "name": p.name, "default": value_to_json(&p.default) }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// Returns the artifact as pretty-printed JSON. See [`Self::to_json_value`].
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.to_json_value()).unwrap()
+    }
+
+    /// Returns the artifact as a Graphviz DOT digraph.
+    pub fn to_dot(&self) -> String {
+        let (ast, definitions) = self.graph.to_shared_expression(self.root);
+        let graph = Graph::build(self, &ast, &definitions);
+        let mut out = String::new();
+        writeln!(out, "digraph {} {{", dot_quote(&self.name)).unwrap();
+        writeln!(out, "    graph [ordering=out];").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"Helvetica\"];").unwrap();
+        for node in &graph.nodes {
+            let style = match node.kind {
+                NodeKind::Operation => "",
+                NodeKind::Leaf => ", style=rounded",
+                NodeKind::Definition => ", style=\"filled,bold\", fillcolor=lightgrey",
+            };
+            writeln!(
+                out,
+                "    {} [label={}{}];",
+                node.id,
+                dot_quote(&node.label),
+                style
+            )
+            .unwrap();
+        }
+        for (from, to) in &graph.edges {
+            writeln!(out, "    {} -> {};", from, to).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// Returns the artifact as a Mermaid flowchart.
+    pub fn to_mermaid(&self) -> String {
+        let (ast, definitions) = self.graph.to_shared_expression(self.root);
+        let graph = Graph::build(self, &ast, &definitions);
+        let mut out = String::new();
+        writeln!(out, "flowchart TD").unwrap();
+        for node in &graph.nodes {
+            let label = mermaid_escape(&node.label);
+            match node.kind {
+                NodeKind::Operation => writeln!(out, "    {}[\"{}\"]", node.id, label),
+                NodeKind::Leaf => writeln!(out, "    {}(\"{}\")", node.id, label),
+                NodeKind::Definition => writeln!(out, "    {}[[\"{}\"]]", node.id, label),
+            }
+            .unwrap();
+        }
+        for (from, to) in &graph.edges {
+            writeln!(out, "    {} --> {}", from, to).unwrap();
+        }
+        out
+    }
+}
+
+/// Reverse lookup tables for rendering interned inputs by name.
+struct InputNames {
+    static_names: AHashMap<InputId, String>,
+    dynamic_names: AHashMap<InputId, String>,
+    parameter_names: Vec<String>,
+}
+
+impl InputNames {
+    fn new(artifacts: &CompilationArtifacts) -> Self {
+        Self {
+            static_names: artifacts
+                .static_map
+                .iter()
+                .map(|(k, v)| (*v, k.clone()))
+                .collect(),
+            dynamic_names: artifacts
+                .dynamic_map
+                .iter()
+                .map(|(k, v)| (*v, k.clone()))
+                .collect(),
+            parameter_names: artifacts
+                .parameters
+                .iter()
+                .map(|p| p.name.clone())
+                .collect(),
+        }
+    }
+
+    fn label(&self, source: &InputSource) -> String {
+        match source {
+            InputSource::Static { id } => {
+                let name = self.static_names.get(id).map(|s| s.as_str());
+                format!("${}", name.unwrap_or("?"))
+            }
+            InputSource::Dynamic { id } => {
+                let name = self.dynamic_names.get(id).map(|s| s.as_str());
+                format!("${}", name.unwrap_or("?"))
+            }
+            InputSource::Parameter { id } => {
+                let name = self.parameter_names.get(*id as usize).map(|s| s.as_str());
+                format!("@{}", name.unwrap_or("?"))
+            }
+            other => other.to_string(),
+        }
+    }
+}
+
+/// The display name and operator symbol of an expression.
+fn describe(expr: &Expression) -> (&'static str, &'static str) {
+    match expr {
+        Expression::Sum(..) => ("Sum", "+"),
+        Expression::Subtract(..) => ("Subtract", "-"),
+        Expression::Multiply(..) => ("Multiply", "*"),
+        Expression::Divide(..) => ("Divide", "/"),
+        Expression::Abs(_) => ("Abs", "ABS"),
+        Expression::Not(_) => ("Not", "NOT"),
+        Expression::And(..) => ("And", "AND"),
+        Expression::Or(..) => ("Or", "OR"),
+        Expression::Xor(..) => ("Xor", "XOR"),
+        Expression::Equal(..) => ("Equal", "=="),
+        Expression::NotEqual(..) => ("NotEqual", "!="),
+        Expression::GreaterThan(..) => ("GreaterThan", ">"),
+        Expression::GreaterThanOrEqual(..) => ("GreaterThanOrEqual", ">="),
+        Expression::SmallerThan(..) => ("SmallerThan", "<"),
+        Expression::SmallerThanOrEqual(..) => ("SmallerThanOrEqual", "<="),
+        Expression::Table(..) => ("Table", "TABLE"),
+        Expression::Interpolate(..) => ("Interpolate", "INTERPOLATE"),
+        Expression::Call(..) => ("Call", "CALL"),
+        Expression::Spatial(op, _) => ("Spatial", op.symbol()),
+        Expression::Literal(_) => ("Literal", ""),
+        Expression::Input(_) => ("Input", ""),
+        Expression::Reference(_) => ("Reference", ""),
+    }
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Number(n) => json!(n),
+        Value::Bool(b) => json!(b),
+        Value::Null => serde_json::Value::Null,
+    }
+}
+
+fn expr_to_json(expr: &Expression, names: &InputNames) -> serde_json::Value {
+    match expr {
+        Expression::Literal(v) => json!({ "op": "Literal", "value": value_to_json(v) }),
+        Expression::Input(source) => {
+            let (kind, id) = match source {
+                InputSource::Static { id } => ("static", Some(*id)),
+                InputSource::Dynamic { id } => ("dynamic", Some(*id)),
+                InputSource::StaticName { .. } => ("static", None),
+                InputSource::DynamicName { .. } => ("dynamic", None),
+                InputSource::Parameter { id } => ("parameter", Some(*id)),
+                InputSource::ParameterName { .. } => ("parameter", None),
+            };
+            json!({ "op": "Input", "kind": kind, "id": id, "name": names.label(source) })
+        }
+        Expression::Reference(id) => json!({ "op": "Reference", "id": id }),
+        Expression::Table(v, table) | Expression::Interpolate(v, table) => {
+            let (name, _) = describe(expr);
+            json!({ "op": name, "args": [expr_to_json(v, names)], "rows": table.rows() })
+        }
+        Expression::Call(function, args) => {
+            let args: Vec<_> = args.iter().map(|c| expr_to_json(c, names)).collect();
+            json!({ "op": "Call", "function": function, "args": args })
+        }
+        Expression::Spatial(op, args) => {
+            let args: Vec<_> = args.iter().map(|c| expr_to_json(c, names)).collect();
+            json!({ "op": "Spatial", "operation": op.symbol(), "args": args })
+        }
+        _ => {
+            let (name, _) = describe(expr);
+            let args: Vec<_> = expr.children().map(|c| expr_to_json(c, names)).collect();
+            json!({ "op": name, "args": args })
+        }
+    }
+}
+
+enum NodeKind {
+    Operation,
+    Leaf,
+    Definition,
+}
+
+struct GraphNode {
+    id: String,
+    label: String,
+    kind: NodeKind,
+}
+
+/// A flattened node/edge view of an artifact, shared by the DOT and Mermaid writers.
+struct Graph<'a> {
+    definitions: &'a AHashMap<u64, Expression>,
+    names: InputNames,
+    nodes: Vec<GraphNode>,
+    edges: Vec<(String, String)>,
+    emitted_definitions: AHashSet<u64>,
+    next_id: usize,
+}
+
+impl<'a> Graph<'a> {
+    fn build(
+        artifacts: &CompilationArtifacts,
+        ast: &'a Expression,
+        definitions: &'a AHashMap<u64, Expression>,
+    ) -> Self {
+        let mut graph = Self {
+            definitions,
+            names: InputNames::new(artifacts),
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            emitted_definitions: AHashSet::new(),
+            next_id: 0,
+        };
+        graph.visit(ast);
+        graph
+    }
+
+    /// Emits the node for `expr` (and its subtree) and returns its graph id.
+    fn visit(&mut self, expr: &'a Expression) -> String {
+        if let Expression::Reference(id) = expr {
+            return self.visit_definition(*id);
+        }
+
+        let node_id = format!("n{}", self.next_id);
+        self.next_id += 1;
+        let (_, symbol) = describe(expr);
+        let (label, kind) = match expr {
+            Expression::Literal(v) => (v.to_string(), NodeKind::Leaf),
+            Expression::Input(source) => (self.names.label(source), NodeKind::Leaf),
+            Expression::Call(name, _) => (format!("{}()", name), NodeKind::Operation),
+            _ => (symbol.to_string(), NodeKind::Operation),
+        };
+        self.nodes.push(GraphNode {
+            id: node_id.clone(),
+            label,
+            kind,
+        });
+        for child in expr.children() {
+            let child_id = self.visit(child);
+            self.edges.push((node_id.clone(), child_id));
+        }
+        node_id
+    }
+
+    fn visit_definition(&mut self, id: u64) -> String {
+        let node_id = format!("def{}", id);
+        if !self.emitted_definitions.insert(id) {
+            return node_id;
+        }
+        let Some(def) = self.definitions.get(&id) else {
+            self.nodes.push(GraphNode {
+                id: node_id.clone(),
+                label: format!("#{} <unknown definition>", id),
+                kind: NodeKind::Definition,
+            });
+            return node_id;
+        };
+
+        let (_, symbol) = describe(def);
+        let label = match def {
+            Expression::Literal(v) => format!("#{}: {}", id, v),
+            Expression::Input(source) => format!("#{}: {}", id, self.names.label(source)),
+            Expression::Reference(inner) => format!("#{}: -> #{}", id, inner),
+            Expression::Call(name, _) => format!("#{}: {}()", id, name),
+            _ => format!("#{}: {}", id, symbol),
+        };
+        self.nodes.push(GraphNode {
+            id: node_id.clone(),
+            label,
+            kind: NodeKind::Definition,
+        });
+        if let Expression::Reference(inner) = def {
+            let child_id = self.visit_definition(*inner);
+            self.edges.push((node_id.clone(), child_id));
+        }
+        for child in def.children() {
+            let child_id = self.visit(child);
+            self.edges.push((node_id.clone(), child_id));
+        }
+        node_id
+    }
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn mermaid_escape(s: &str) -> String {
+    s.replace('"', "#quot;")
+        .replace('<', "#60;")
+        .replace('>', "#62;")
+}
@@ -0,0 +1,421 @@
+//! Interval analysis used by the optimizer to eliminate dead and redundant comparisons.
+//!
+//! The analysis walks the boolean structure of an expression while tracking, for each
+//! `InputSource`, the numeric interval its value must lie in for the current node to be
+//! evaluated at all. A comparison of an input against a numeric literal that is implied
+//! by (or contradicts) those facts is replaced by `true` (or `false`).
+//!
+//! Facts are only derived from comparisons of static inputs and parameters against
+//! literals. Such a comparison only evaluates without a type error when the input is a
+//! number, so both its true and its false outcome say something about the input. Event
+//! fields are left alone: they are null while their event is absent, and a chain operand
+//! that is null rather than false (or true) says nothing about the operands after it.
+//! `NaN` inputs are not considered.
+
+use crate::ast::{ExpressionGraph, InputSource, Node, NodeId, Value};
+use ahash::AHashMap;
+
+/// A closed, open or half-open interval over `f64`. Unbounded sides use infinities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Interval {
+    lo: f64,
+    lo_inclusive: bool,
+    hi: f64,
+    hi_inclusive: bool,
+}
+
+impl Interval {
+    fn above(value: f64, inclusive: bool) -> Self {
+        Self {
+            lo: value,
+            lo_inclusive: inclusive,
+            hi: f64::INFINITY,
+            hi_inclusive: false,
+        }
+    }
+
+    fn below(value: f64, inclusive: bool) -> Self {
+        Self {
+            lo: f64::NEG_INFINITY,
+            lo_inclusive: false,
+            hi: value,
+            hi_inclusive: inclusive,
+        }
+    }
+
+    fn point(value: f64) -> Self {
+        Self {
+            lo: value,
+            lo_inclusive: true,
+            hi: value,
+            hi_inclusive: true,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lo > self.hi || (self.lo == self.hi && !(self.lo_inclusive && self.hi_inclusive))
+    }
+
+    fn intersect(&self, other: &Self) -> Self {
+        let (lo, lo_inclusive) = if self.lo > other.lo {
+            (self.lo, self.lo_inclusive)
+        } else if other.lo > self.lo {
+            (other.lo, other.lo_inclusive)
+        } else {
+            (self.lo, self.lo_inclusive && other.lo_inclusive)
+        };
+        let (hi, hi_inclusive) = if self.hi < other.hi {
+            (self.hi, self.hi_inclusive)
+        } else if other.hi < self.hi {
+            (other.hi, other.hi_inclusive)
+        } else {
+            (self.hi, self.hi_inclusive && other.hi_inclusive)
+        };
+        Self {
+            lo,
+            lo_inclusive,
+            hi,
+            hi_inclusive,
+        }
+    }
+
+    /// The smallest interval containing both `self` and `other`.
+    fn hull(&self, other: &Self) -> Self {
+        let (lo, lo_inclusive) = if self.lo < other.lo {
+            (self.lo, self.lo_inclusive)
+        } else if other.lo < self.lo {
+            (other.lo, other.lo_inclusive)
+        } else {
+            (self.lo, self.lo_inclusive || other.lo_inclusive)
+        };
+        let (hi, hi_inclusive) = if self.hi > other.hi {
+            (self.hi, self.hi_inclusive)
+        } else if other.hi > self.hi {
+            (other.hi, other.hi_inclusive)
+        } else {
+            (self.hi, self.hi_inclusive || other.hi_inclusive)
+        };
+        Self {
+            lo,
+            lo_inclusive,
+            hi,
+            hi_inclusive,
+        }
+    }
+
+    /// Returns true if every value in `self` is also in `other`.
+    fn is_subset_of(&self, other: &Self) -> bool {
+        let lo_ok = self.lo > other.lo
+            || (self.lo == other.lo && (other.lo_inclusive || !self.lo_inclusive));
+        let hi_ok = self.hi < other.hi
+            || (self.hi == other.hi && (other.hi_inclusive || !self.hi_inclusive));
+        lo_ok && hi_ok
+    }
+
+    fn is_disjoint_from(&self, other: &Self) -> bool {
+        self.intersect(other).is_empty()
+    }
+
+    /// The complement of a half-line, which is again a half-line. Bounded intervals
+    /// have no convex complement and return `None`.
+    fn complement(&self) -> Option<Self> {
+        match (self.lo == f64::NEG_INFINITY, self.hi == f64::INFINITY) {
+            (true, false) => Some(Self::above(self.hi, !self.hi_inclusive)),
+            (false, true) => Some(Self::below(self.lo, !self.lo_inclusive)),
+            _ => None,
+        }
+    }
+}
+
+/// A comparison of an input against a numeric literal.
+enum Constraint {
+    /// True exactly when the input lies in the interval.
+    Within(Interval),
+    /// True exactly when the input is not equal to the value.
+    NotEqualTo(f64),
+}
+
+type Facts = AHashMap<InputSource, Interval>;
+
+/// Merges `other` into `facts`, intersecting the intervals of inputs present in both.
+fn merge(facts: &mut Facts, other: &Facts) {
+    for (source, interval) in other {
+        facts
+            .entry(source.clone())
+            .and_modify(|known| *known = known.intersect(interval))
+            .or_insert(*interval);
+    }
+}
+
+/// Keeps only the inputs constrained by both sides, widened to cover either one.
+fn hull(a: &Facts, b: &Facts) -> Facts {
+    a.iter()
+        .filter_map(|(source, interval)| {
+            b.get(source)
+                .map(|other| (source.clone(), interval.hull(other)))
+        })
+        .collect()
+}
+
+/// A hashable snapshot of a set of facts, used to memoize results per context.
+type FactsKey = Vec<(InputSource, [u64; 2], [bool; 2])>;
+
+fn facts_key(facts: &Facts) -> FactsKey {
+    let mut key: FactsKey = facts
+        .iter()
+        .map(|(source, i)| {
+            (
+                source.clone(),
+                [i.lo.to_bits(), i.hi.to_bits()],
+                [i.lo_inclusive, i.hi_inclusive],
+            )
+        })
+        .collect();
+    key.sort_unstable();
+    key
+}
+
+/// Rewrites comparisons that interval analysis proves always true or always false.
+pub(super) struct IntervalAnalyzer<'g> {
+    graph: &'g mut ExpressionGraph,
+    /// Results of `simplify_under`, keyed by node and context. A node shared by several
+    /// parents is only analysed again when it is reached under different facts.
+    memo: AHashMap<(NodeId, FactsKey), NodeId>,
+    /// Number of comparisons replaced by a constant.
+    pub(super) decided: usize,
+}
+
+impl<'g> IntervalAnalyzer<'g> {
+    pub(super) fn new(graph: &'g mut ExpressionGraph) -> Self {
+        Self {
+            graph,
+            memo: AHashMap::new(),
+            decided: 0,
+        }
+    }
+
+    pub(super) fn simplify(&mut self, root: NodeId) -> NodeId {
+        self.simplify_under(root, &Facts::new())
+    }
+
+    fn boolean(&mut self, value: bool) -> NodeId {
+        self.graph.add(Node::Literal(Value::Bool(value)))
+    }
+
+    /// Recognises `input <op> literal` and `literal <op> input`.
+    fn constraint(&self, id: NodeId) -> Option<(InputSource, Constraint)> {
+        let node = &self.graph[id];
+        let (source, value, flipped) = match node {
+            Node::GreaterThan(l, r)
+            | Node::GreaterThanOrEqual(l, r)
+            | Node::SmallerThan(l, r)
+            | Node::SmallerThanOrEqual(l, r)
+            | Node::Equal(l, r)
+            | Node::NotEqual(l, r) => match (&self.graph[*l], &self.graph[*r]) {
+                (Node::Input(source), Node::Literal(Value::Number(n))) => (source, *n, false),
+                (Node::Literal(Value::Number(n)), Node::Input(source)) => (source, *n, true),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if value.is_nan() || source.is_dynamic() {
+            return None;
+        }
+        let constraint = match (node, flipped) {
+            (Node::GreaterThan(..), false) | (Node::SmallerThan(..), true) => {
+                Constraint::Within(Interval::above(value, false))
+            }
+            (Node::GreaterThanOrEqual(..), false) | (Node::SmallerThanOrEqual(..), true) => {
+                Constraint::Within(Interval::above(value, true))
+            }
+            (Node::SmallerThan(..), false) | (Node::GreaterThan(..), true) => {
+                Constraint::Within(Interval::below(value, false))
+            }
+            (Node::SmallerThanOrEqual(..), false) | (Node::GreaterThanOrEqual(..), true) => {
+                Constraint::Within(Interval::below(value, true))
+            }
+            (Node::Equal(..), _) => Constraint::Within(Interval::point(value)),
+            _ => Constraint::NotEqualTo(value),
+        };
+        Some((source.clone(), constraint))
+    }
+
+    /// Facts that hold whenever `id` evaluates to true.
+    fn facts_if_true(&self, id: NodeId) -> Facts {
+        if let Some((source, constraint)) = self.constraint(id) {
+            return match constraint {
+                Constraint::Within(interval) => Facts::from([(source, interval)]),
+                Constraint::NotEqualTo(_) => Facts::new(),
+            };
+        }
+        match self.graph[id] {
+            Node::And(l, r) => {
+                let mut facts = self.facts_if_true(l);
+                merge(&mut facts, &self.facts_if_true(r));
+                facts
+            }
+            Node::Or(l, r) => hull(&self.facts_if_true(l), &self.facts_if_true(r)),
+            Node::Not(v) => self.facts_if_false(v),
+            _ => Facts::new(),
+        }
+    }
+
+    /// Facts that hold whenever `id` evaluates to false.
+    fn facts_if_false(&self, id: NodeId) -> Facts {
+        if let Some((source, constraint)) = self.constraint(id) {
+            return match constraint {
+                Constraint::Within(interval) => interval
+                    .complement()
+                    .map(|complement| Facts::from([(source, complement)]))
+                    .unwrap_or_default(),
+                Constraint::NotEqualTo(value) => Facts::from([(source, Interval::point(value))]),
+            };
+        }
+        match self.graph[id] {
+            Node::Or(l, r) => {
+                let mut facts = self.facts_if_false(l);
+                merge(&mut facts, &self.facts_if_false(r));
+                facts
+            }
+            Node::And(l, r) => hull(&self.facts_if_false(l), &self.facts_if_false(r)),
+            Node::Not(v) => self.facts_if_true(v),
+            _ => Facts::new(),
+        }
+    }
+
+    /// Decides a single comparison under `facts`, if possible.
+    fn decide(&mut self, id: NodeId, facts: &Facts) -> Option<bool> {
+        let (source, constraint) = self.constraint(id)?;
+        let known = facts.get(&source)?;
+        if known.is_empty() {
+            // Contradictory context: the node is unreachable, leave it to other passes.
+            return None;
+        }
+        let decision = match constraint {
+            Constraint::Within(interval) if known.is_subset_of(&interval) => Some(true),
+            Constraint::Within(interval) if known.is_disjoint_from(&interval) => Some(false),
+            Constraint::NotEqualTo(value) if known.is_disjoint_from(&Interval::point(value)) => {
+                Some(true)
+            }
+            Constraint::NotEqualTo(value) if known.is_subset_of(&Interval::point(value)) => {
+                Some(false)
+            }
+            _ => None,
+        };
+        if decision.is_some() {
+            self.decided += 1;
+        }
+        decision
+    }
+
+    fn simplify_under(&mut self, id: NodeId, facts: &Facts) -> NodeId {
+        let key = (id, facts_key(facts));
+        if let Some(result) = self.memo.get(&key) {
+            return *result;
+        }
+        let result = self.simplify_node(id, facts);
+        self.memo.insert(key, result);
+        result
+    }
+
+    fn simplify_node(&mut self, id: NodeId, facts: &Facts) -> NodeId {
+        if let Some(value) = self.decide(id, facts) {
+            return self.boolean(value);
+        }
+        let node = self.graph[id].clone();
+        match node {
+            Node::And(..) => self.simplify_chain(id, facts, true),
+            Node::Or(..) => self.simplify_chain(id, facts, false),
+            Node::Not(..) | Node::Xor(..) | Node::Equal(..) | Node::NotEqual(..) => {
+                let node = node.map_children(|child| self.simplify_under(child, facts));
+                self.graph.add(node)
+            }
+            _ => id,
+        }
+    }
+
+    /// Simplifies a flattened `AND` (`is_and`) or `OR` chain.
+    ///
+    /// Each operand is first simplified under the facts of the operands before it. Then,
+    /// from the back, every comparison operand is re-decided under the facts of all
+    /// other operands, which removes comparisons made redundant by later ones.
+    fn simplify_chain(&mut self, id: NodeId, facts: &Facts, is_and: bool) -> NodeId {
+        let mut operands = Vec::new();
+        flatten(self.graph, id, is_and, &mut operands);
+        let operand_facts = |this: &Self, e: NodeId| {
+            if is_and {
+                this.facts_if_true(e)
+            } else {
+                this.facts_if_false(e)
+            }
+        };
+
+        // Forward: operand i may assume the chain has not short-circuited before it.
+        let mut simplified: Vec<NodeId> = Vec::with_capacity(operands.len());
+        let mut context = facts.clone();
+        for operand in &operands {
+            let result = self.simplify_under(*operand, &context);
+            merge(&mut context, &operand_facts(self, result));
+            simplified.push(result);
+        }
+
+        // Backward: drop comparisons implied or contradicted by the other operands.
+        for i in (0..simplified.len()).rev() {
+            if self.constraint(simplified[i]).is_none() {
+                continue;
+            }
+            let mut context = facts.clone();
+            for (j, other) in simplified.iter().enumerate() {
+                if j != i {
+                    merge(&mut context, &operand_facts(self, *other));
+                }
+            }
+            if let Some(value) = self.decide(simplified[i], &context) {
+                simplified[i] = self.boolean(value);
+            }
+        }
+
+        if simplified == operands {
+            return id;
+        }
+
+        // `true` is neutral in an AND chain and absorbing in an OR chain; `false` the reverse.
+        let neutral = Value::Bool(is_and);
+        let absorbing = Value::Bool(!is_and);
+        let is_literal = |graph: &ExpressionGraph, e: NodeId, value: &Value| matches!(&graph[e], Node::Literal(v) if v == value);
+        if simplified
+            .iter()
+            .any(|e| is_literal(self.graph, *e, &absorbing))
+        {
+            return self.graph.add(Node::Literal(absorbing));
+        }
+        simplified
+            .into_iter()
+            .filter(|e| !is_literal(self.graph, *e, &neutral))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .reduce(|acc, e| {
+                if is_and {
+                    self.graph.add(Node::And(acc, e))
+                } else {
+                    self.graph.add(Node::Or(acc, e))
+                }
+            })
+            .unwrap_or_else(|| self.graph.add(Node::Literal(neutral)))
+    }
+}
+
+/// Collects the operands of a nested `AND` (`is_and`) or `OR` chain in evaluation order.
+fn flatten(graph: &ExpressionGraph, id: NodeId, is_and: bool, out: &mut Vec<NodeId>) {
+    match graph[id] {
+        Node::And(l, r) if is_and => {
+            flatten(graph, l, is_and, out);
+            flatten(graph, r, is_and, out);
+        }
+        Node::Or(l, r) if !is_and => {
+            flatten(graph, l, is_and, out);
+            flatten(graph, r, is_and, out);
+        }
+        _ => out.push(id),
+    }
+}
@@ -1,47 +1,124 @@
-use crate::ast::{Expression, InputId, InputSource, Value};
+use crate::ast::{Expression, ExpressionGraph, InputId, InputSource, Node, NodeId, Value};
 use crate::error::AstBuildError;
-use crate::recipe::{FlowDefinition, Quality};
-use ahash::AHashMap;
-
-#[cfg(feature = "debug-tools")]
-use {
-    crate::ast::DisplayExpression,
-    crate::bytecode::{compiler as bytecode_compiler, visualizer as bytecode_visualizer},
-    std::fs,
+use crate::events::{CombinationPolicy, EventPairing, PathSplit};
+use crate::recipe::{
+    Decision, FlowDefinition, FlowNodeDefinition, Quality, RecipeParameter, SubFlowDefinition,
 };
+use ahash::{AHashMap, AHashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::bytecode::compiler as bytecode_compiler;
 
 mod builder;
+mod canonicalize;
+pub mod export;
+mod interval;
+pub mod observer;
 mod optimizer;
 pub mod parsing;
+mod pushdown;
+mod validation;
 
 use builder::AstBuilder;
+pub use observer::{CompilationObserver, OptimizerPass};
 use optimizer::AstOptimizer;
+pub use optimizer::{MAX_OPTIMIZER_ITERATIONS, OptimizationLevel, OptimizerPasses, OptimizerStats};
 use parsing::*;
 
+/// The default maximum number of nested nodes between the quality node and any input.
+///
+/// The compiler and both backends recurse once per nested node. In a debug build the
+/// deepest of them, the interpreter, needs about 14 KB of stack per level, so a flow at
+/// this limit still compiles and evaluates on a thread with the default 2 MB stack.
+/// Raise the limit only together with the stack size of the threads that compile and
+/// evaluate.
+pub const DEFAULT_MAX_FLOW_DEPTH: usize = 100;
+
+/// The name of the decision formed by the qualities passed to [`Compiler::builder`].
+pub const DEFAULT_DECISION: &str = "quality";
+
+/// The node type whose data fields name numeric outputs of a recipe.
+pub const OUTPUT_NODE_TYPE: &str = "setOutputNode";
+
+/// The node type of a recipe parameter: a named value that can be overridden per evaluation.
+/// Its first data field names it, and its first literal value is its default.
+pub const PARAMETER_NODE_TYPE: &str = "parameterNode";
+
+/// What a compiled path computes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathKind {
+    /// A quality of a decision, triggered when the path evaluates to `true`.
+    Quality,
+    /// A named output value, reported whatever it evaluates to.
+    Output,
+}
+
 pub struct CompilationArtifacts {
+    pub kind: PathKind,
+    /// The name of the decision this quality path belongs to. Empty for outputs.
+    pub decision: String,
     pub priority: i32,
     pub name: String,
-    pub ast: Expression,
-    pub definitions: AHashMap<u64, Expression>,
+    /// The optimized expression of this quality path. Subexpressions shared by CSE are
+    /// single nodes with several users.
+    pub graph: ExpressionGraph,
+    /// The node of `graph` that decides the quality.
+    pub root: NodeId,
     pub static_map: AHashMap<String, InputId>,
     pub dynamic_map: AHashMap<String, InputId>,
+    /// The parameters interned so far, indexed by id, with their defaults.
+    pub parameters: Vec<RecipeParameter>,
+    /// What the optimizer did to this quality path.
+    pub stats: OptimizerStats,
+    /// How the path combines the instances of the events it reads.
+    pub combinations: CombinationPolicy,
+    /// The nodes of `graph` checked before and while combining event instances. Outputs,
+    /// and paths compiled without predicate pushdown, are evaluated whole.
+    pub split: PathSplit<NodeId>,
+}
+
+impl CompilationArtifacts {
+    /// The optimized expression as a tree, with every shared subexpression replaced by a
+    /// `Reference` into [`Self::definitions`].
+    pub fn ast(&self) -> Expression {
+        self.graph.to_shared_expression(self.root).0
+    }
+
+    /// The shared subexpressions referenced by [`Self::ast`], keyed by definition id.
+    pub fn definitions(&self) -> AHashMap<u64, Expression> {
+        self.graph.to_shared_expression(self.root).1
+    }
 }
 
 pub struct Compiler {
     flow: FlowDefinition,
     qualities: Vec<Quality>,
+    decisions: Vec<Decision>,
     registry: AHashMap<String, Box<dyn NodeParser>>,
-    ast_cache: AHashMap<String, Expression>,
+    sub_flows: AHashMap<String, SubFlowDefinition>,
+    ast_cache: AHashMap<String, NodeOutputs>,
     static_map: AHashMap<String, InputId>,
     dynamic_map: AHashMap<String, InputId>,
     next_static_id: InputId,
     next_dynamic_id: InputId,
+    parameter_defaults: AHashMap<String, Value>,
+    parameters: Vec<RecipeParameter>,
+    optimizer_passes: OptimizerPasses,
+    observers: Vec<Box<dyn CompilationObserver>>,
+    max_flow_depth: usize,
+    combinations: CombinationPolicy,
 }
 
 pub struct CompilerBuilder {
     flow: FlowDefinition,
     qualities: Vec<Quality>,
+    decisions: Vec<Decision>,
     registry: AHashMap<String, Box<dyn NodeParser>>,
+    sub_flows: AHashMap<String, SubFlowDefinition>,
+    optimizer_passes: OptimizerPasses,
+    observers: Vec<Box<dyn CompilationObserver>>,
+    max_flow_depth: usize,
+    combinations: CombinationPolicy,
 }
 
 impl CompilerBuilder {
@@ -51,7 +128,13 @@ impl CompilerBuilder {
         Self {
             flow,
             qualities,
+            decisions: Vec::new(),
             registry,
+            sub_flows: AHashMap::new(),
+            optimizer_passes: OptimizerPasses::default(),
+            observers: Vec::new(),
+            max_flow_depth: DEFAULT_MAX_FLOW_DEPTH,
+            combinations: CombinationPolicy::default(),
         }
     }
     pub fn with_type_mapping(mut self, user_type_name: &str, hantei_type_name: &str) -> Self {
@@ -64,16 +147,78 @@ impl CompilerBuilder {
         self.registry.insert(parser.node_type().to_string(), parser);
         self
     }
+    /// Adds a sub-flow to the library. Nodes whose `operation_type` is the sub-flow's name
+    /// are expanded into it; a sub-flow takes precedence over a parser of the same name.
+    pub fn with_sub_flow(mut self, sub_flow: SubFlowDefinition) -> Self {
+        self.sub_flows.insert(sub_flow.name.clone(), sub_flow);
+        self
+    }
+    /// Adds a decision named `name` that picks one of `qualities` from the inputs of the
+    /// `setQualityNode` with id `node_id`. The qualities passed to [`Compiler::builder`]
+    /// form the [`DEFAULT_DECISION`] on the first `setQualityNode` of the flow.
+    ///
+    /// Every decision needs its own name and `setQualityNode`; compilation fails otherwise.
+    pub fn with_decision(mut self, name: &str, node_id: &str, qualities: Vec<Quality>) -> Self {
+        self.decisions.push(Decision {
+            name: name.to_string(),
+            node_id: node_id.to_string(),
+            qualities,
+        });
+        self
+    }
+    /// Selects the optimizer passes from a preset level. Defaults to `OptimizationLevel::O2`.
+    pub fn with_optimization_level(mut self, level: OptimizationLevel) -> Self {
+        self.optimizer_passes = level.into();
+        self
+    }
+    /// Selects exactly which optimizer passes run, e.g. to disable only CSE.
+    pub fn with_optimizer_passes(mut self, passes: OptimizerPasses) -> Self {
+        self.optimizer_passes = passes;
+        self
+    }
+    /// Registers an observer that receives the intermediate results of every compilation stage.
+    pub fn with_observer(mut self, observer: impl CompilationObserver + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+    /// Sets how many nodes may be chained between the quality node and an input before
+    /// compilation fails with `AstBuildError::MaxDepthExceeded`. Defaults to
+    /// [`DEFAULT_MAX_FLOW_DEPTH`].
+    pub fn with_max_flow_depth(mut self, max_depth: usize) -> Self {
+        self.max_flow_depth = max_depth;
+        self
+    }
+    /// Sets whether aliases of the same event (`hole#a`, `hole#b`) are paired in both
+    /// orders or only once. Defaults to [`EventPairing::Ordered`].
+    pub fn with_event_pairing(mut self, pairing: EventPairing) -> Self {
+        self.combinations.pairing = pairing;
+        self
+    }
+    /// Sets how many combinations of event instances a path may evaluate before evaluation
+    /// fails with `EvaluationError::TooManyCombinations`. Defaults to
+    /// [`crate::events::DEFAULT_MAX_COMBINATIONS`].
+    pub fn with_max_combinations(mut self, max_combinations: usize) -> Self {
+        self.combinations.max_combinations = max_combinations;
+        self
+    }
     pub fn build(self) -> Compiler {
         Compiler {
             flow: self.flow,
             qualities: self.qualities,
+            decisions: self.decisions,
             registry: self.registry,
+            sub_flows: self.sub_flows,
             ast_cache: AHashMap::new(),
             static_map: AHashMap::new(),
             dynamic_map: AHashMap::new(),
             next_static_id: 0,
             next_dynamic_id: 0,
+            parameter_defaults: AHashMap::new(),
+            parameters: Vec::new(),
+            optimizer_passes: self.optimizer_passes,
+            observers: self.observers,
+            max_flow_depth: self.max_flow_depth,
+            combinations: self.combinations,
         }
     }
 }
@@ -101,206 +246,355 @@ impl Compiler {
         })
     }
 
-    /// Recursively transforms an AST with string-based inputs into one with ID-based inputs.
-    fn intern_ast_inputs(&mut self, expr: Expression) -> Expression {
-        match expr {
-            Expression::Input(source) => match source {
-                InputSource::StaticName { name } => {
-                    let id = self.get_static_id(&name);
-                    Expression::Input(InputSource::Static { id })
-                }
-                InputSource::DynamicName { event, field } => {
-                    let id = self.get_dynamic_id(&event, &field);
-                    Expression::Input(InputSource::Dynamic { id })
-                }
-                // Already interned
-                other => Expression::Input(other),
-            },
-            Expression::Sum(l, r) => Expression::Sum(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Subtract(l, r) => Expression::Subtract(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Multiply(l, r) => Expression::Multiply(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Divide(l, r) => Expression::Divide(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Abs(v) => Expression::Abs(Box::new(self.intern_ast_inputs(*v))),
-            Expression::Not(v) => Expression::Not(Box::new(self.intern_ast_inputs(*v))),
-            Expression::And(l, r) => Expression::And(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Or(l, r) => Expression::Or(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Xor(l, r) => Expression::Xor(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::Equal(l, r) => Expression::Equal(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::NotEqual(l, r) => Expression::NotEqual(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::GreaterThan(l, r) => Expression::GreaterThan(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::GreaterThanOrEqual(l, r) => Expression::GreaterThanOrEqual(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::SmallerThan(l, r) => Expression::SmallerThan(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            Expression::SmallerThanOrEqual(l, r) => Expression::SmallerThanOrEqual(
-                Box::new(self.intern_ast_inputs(*l)),
-                Box::new(self.intern_ast_inputs(*r)),
-            ),
-            // Leaf nodes that don't need changes
-            other => other,
+    fn get_parameter_id(&mut self, name: &str) -> InputId {
+        if let Some(id) = self.parameters.iter().position(|p| p.name == name) {
+            return id as InputId;
         }
+        self.parameters.push(RecipeParameter {
+            name: name.to_string(),
+            default: self
+                .parameter_defaults
+                .get(name)
+                .cloned()
+                .unwrap_or_default(),
+        });
+        (self.parameters.len() - 1) as InputId
+    }
+
+    /// Adds an AST with string-based inputs to a new expression graph with ID-based inputs.
+    fn intern_ast_inputs(&mut self, expr: &Expression) -> (ExpressionGraph, NodeId) {
+        let mut graph = ExpressionGraph::new();
+        let root = graph
+            .insert_with(expr, &AHashMap::new(), &mut |source| match source {
+                InputSource::StaticName { name } => InputSource::Static {
+                    id: self.get_static_id(name),
+                },
+                InputSource::DynamicName { event, field } => InputSource::Dynamic {
+                    id: self.get_dynamic_id(event, field),
+                },
+                InputSource::ParameterName { name } => InputSource::Parameter {
+                    id: self.get_parameter_id(name),
+                },
+                // Already interned
+                other => other.clone(),
+            })
+            .expect("a freshly built AST contains no references");
+        (graph, root)
     }
 
     pub fn compile(mut self) -> Result<Vec<CompilationArtifacts>, AstBuildError> {
-        let quality_node_id = self
-            .flow
-            .nodes
-            .iter()
-            .find(|n| n.operation_type == "setQualityNode")
+        let decisions = resolve_decisions(&self.flow, &self.qualities, &self.decisions)
             .ok_or_else(|| AstBuildError::InvalidNodeType {
                 node_id: "N/A".to_string(),
                 type_name: "setQualityNode not found".to_string(),
-            })?
-            .id
-            .clone();
+            })?;
+        if let Some((first, second)) = conflicting_decisions(&decisions).first() {
+            return Err(if first.name == second.name {
+                AstBuildError::DuplicateDecision(second.name.clone())
+            } else {
+                AstBuildError::SharedDecisionNode {
+                    node_id: second.node_id.clone(),
+                    first: first.name.clone(),
+                    second: second.name.clone(),
+                }
+            });
+        }
+        for decision in &decisions {
+            let node = self.flow.nodes.iter().find(|n| n.id == decision.node_id);
+            if let Some(node) = node
+                && node.operation_type != "setQualityNode"
+            {
+                return Err(AstBuildError::InvalidNodeType {
+                    node_id: node.id.clone(),
+                    type_name: node.operation_type.clone(),
+                });
+            }
+        }
 
-        let mut ast_builder = AstBuilder::new(&self.flow, &self.registry, &mut self.ast_cache);
-        let naive_ast_map = ast_builder.build_asts_for_node(&quality_node_id)?;
+        // Parameters are global, so one declared in several places must agree on its default.
+        let parameter_nodes = self
+            .flow
+            .nodes
+            .iter()
+            .chain(self.sub_flows.values().flat_map(|s| &s.flow.nodes))
+            .filter(|n| n.operation_type == PARAMETER_NODE_TYPE);
+        for node in parameter_nodes {
+            let parameter = parameter_definition(node)?;
+            if let Some(default) = self.parameter_defaults.get(&parameter.name)
+                && *default != parameter.default
+            {
+                return Err(AstBuildError::InvalidParameter {
+                    node_id: node.id.clone(),
+                    message: format!(
+                        "parameter '{}' is declared elsewhere with a different default",
+                        parameter.name
+                    ),
+                });
+            }
+            self.parameter_defaults
+                .insert(parameter.name, parameter.default);
+        }
+
+        let mut ast_builder = AstBuilder::new(
+            &self.flow,
+            &self.registry,
+            &self.sub_flows,
+            &mut self.ast_cache,
+            self.max_flow_depth,
+        );
+        // Decisions share the builder, so parts of the flow they have in common are built once.
+        let naive_ast_maps = decisions
+            .iter()
+            .map(|decision| ast_builder.build_asts_for_node(&decision.node_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Every data field of an output node names the value on the input handle of its id.
+        let outputs: Vec<(String, String, u32)> = self
+            .flow
+            .nodes
+            .iter()
+            .filter(|n| n.operation_type == OUTPUT_NODE_TYPE)
+            .flat_map(|node| {
+                node.data_fields
+                    .iter()
+                    .flatten()
+                    .map(|field| (field.name.clone(), node.id.clone(), field.id))
+            })
+            .collect();
+        let mut naive_output_asts = Vec::with_capacity(outputs.len());
+        for (name, node_id, handle) in &outputs {
+            let mut inputs = ast_builder.build_asts_for_node(node_id)?;
+            let naive_ast =
+                inputs
+                    .remove(handle)
+                    .ok_or_else(|| AstBuildError::ConnectionError {
+                        target_node_id: node_id.clone(),
+                        target_handle_index: *handle,
+                        message: format!("output '{}' has no incoming edge", name),
+                    })?;
+            naive_output_asts.push(naive_ast);
+        }
 
         let mut quality_artifacts = Vec::new();
 
-        // Clone the qualities to avoid borrowing issues during iteration
-        let qualities = self.qualities.clone();
+        // Take the observers to avoid borrowing issues during iteration
+        let mut observers = std::mem::take(&mut self.observers);
 
-        for (index, quality) in qualities.iter().enumerate() {
-            if let Some(naive_ast) = naive_ast_map.get(&(index as u32)) {
-                if let Expression::Literal(Value::Null) = naive_ast {
-                    continue;
-                }
+        for (decision, naive_ast_map) in decisions.iter().zip(&naive_ast_maps) {
+            let mut decision_artifacts = Vec::new();
+            for (index, quality) in decision.qualities.iter().enumerate() {
+                if let Some(naive_ast) = naive_ast_map.get(&(index as u32)) {
+                    if let Expression::Literal(Value::Null) = naive_ast {
+                        continue;
+                    }
 
-                // 1. Intern the strings in the naive AST to get an ID-based AST
-                let interned_ast = self.intern_ast_inputs(naive_ast.clone());
-
-                // 2. Optimize the ID-based AST
-                let mut optimizer = AstOptimizer::new();
-                let optimized_ast = optimizer.optimize(interned_ast);
-                let definitions = optimizer.definitions;
-
-                #[cfg(feature = "debug-tools")]
-                {
-                    // Create reverse maps for debugging output
-                    let static_rev_map: AHashMap<InputId, String> = self
-                        .static_map
-                        .iter()
-                        .map(|(k, v)| (*v, k.clone()))
-                        .collect();
-                    let dynamic_rev_map: AHashMap<InputId, String> = self
-                        .dynamic_map
-                        .iter()
-                        .map(|(k, v)| (*v, k.clone()))
-                        .collect();
-
-                    let sanitized_name = self.sanitize_filename(&quality.name);
-                    let naive_display = DisplayExpression {
-                        expr: naive_ast,
-                        definitions: &AHashMap::new(),
-                        static_map: &static_rev_map,
-                        dynamic_map: &dynamic_rev_map,
+                    let (mut graph, root, mut stats) =
+                        self.compile_path(quality, naive_ast, &mut observers);
+                    let dynamic_map = self.read_dynamic_map(&graph, root);
+                    let split = if self.optimizer_passes.predicate_pushdown {
+                        let (split, pushed_down) =
+                            pushdown::split_path(&mut graph, root, &dynamic_map);
+                        stats.conditions_pushed_down = pushed_down;
+                        split
+                    } else {
+                        PathSplit::whole(root)
                     };
-                    self.write_debug_file(
-                        &format!("tmp/quality_{}_naive_ast.txt", &sanitized_name),
-                        &naive_display.to_string(),
-                    )?;
-                    let optimized_display = DisplayExpression {
-                        expr: &optimized_ast,
-                        definitions: &definitions,
-                        static_map: &static_rev_map,
-                        dynamic_map: &dynamic_rev_map,
-                    };
-                    self.write_debug_file(
-                        &format!("tmp/quality_{}_optimized_ast.txt", &sanitized_name),
-                        &optimized_display.to_string(),
-                    )?;
-
-                    match bytecode_compiler::compile_to_program(
-                        &optimized_ast,
-                        &definitions,
-                        &self.static_map,
-                        &self.dynamic_map,
-                    ) {
-                        Ok(program) => {
-                            let viz = bytecode_visualizer::visualize_program(
-                                &program,
-                                &quality.name,
-                                &static_rev_map,
-                                &dynamic_rev_map,
-                            );
-                            self.write_debug_file(
-                                &format!("tmp/quality_{}_bytecode.txt", &sanitized_name),
-                                &viz,
-                            )?;
-                        }
-                        Err(e) => eprintln!(
-                            "Warning: Could not compile bytecode for debug file for quality '{}': {}",
-                            quality.name, e
-                        ),
-                    }
+                    decision_artifacts.push(CompilationArtifacts {
+                        kind: PathKind::Quality,
+                        decision: decision.name.clone(),
+                        priority: quality.priority,
+                        name: quality.name.clone(),
+                        graph,
+                        root,
+                        static_map: self.static_map.clone(),
+                        dynamic_map,
+                        parameters: self.parameters.clone(),
+                        stats,
+                        combinations: self.combinations,
+                        split,
+                    });
                 }
-
-                quality_artifacts.push(CompilationArtifacts {
-                    priority: quality.priority,
-                    name: quality.name.clone(),
-                    ast: optimized_ast,
-                    definitions,
-                    static_map: self.static_map.clone(),
-                    dynamic_map: self.dynamic_map.clone(),
-                });
             }
+
+            decision_artifacts.sort_by_key(|a| a.priority);
+            quality_artifacts.extend(decision_artifacts);
+        }
+
+        for ((name, _, _), naive_ast) in outputs.iter().zip(&naive_output_asts) {
+            // Observers identify paths by quality, so an output is passed as one without priority.
+            let as_quality = Quality {
+                name: name.clone(),
+                priority: 0,
+            };
+            let (graph, root, stats) = self.compile_path(&as_quality, naive_ast, &mut observers);
+            let dynamic_map = self.read_dynamic_map(&graph, root);
+            quality_artifacts.push(CompilationArtifacts {
+                kind: PathKind::Output,
+                decision: String::new(),
+                priority: 0,
+                name: name.clone(),
+                graph,
+                root,
+                static_map: self.static_map.clone(),
+                dynamic_map,
+                parameters: self.parameters.clone(),
+                stats,
+                combinations: self.combinations,
+                split: PathSplit::whole(root),
+            });
         }
 
-        quality_artifacts.sort_by_key(|a| a.priority);
         Ok(quality_artifacts)
     }
 
-    #[cfg(feature = "debug-tools")]
-    fn sanitize_filename(&self, name: &str) -> String {
-        name.chars()
-            .filter(|c| c.is_alphanumeric() || *c == '_')
-            .collect::<String>()
+    /// The entries of the recipe's dynamic map that the path rooted at `root` reads.
+    ///
+    /// The recipe's map holds the events of every path, but a path is only evaluated per
+    /// combination of the instances of its own events.
+    fn read_dynamic_map(&self, graph: &ExpressionGraph, root: NodeId) -> AHashMap<String, InputId> {
+        let read_events: AHashSet<InputId> = graph
+            .post_order(root)
+            .into_iter()
+            .filter_map(|node| match &graph[node] {
+                Node::Input(InputSource::Dynamic { id }) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        self.dynamic_map
+            .iter()
+            .filter(|(_, id)| read_events.contains(id))
+            .map(|(key, id)| (key.clone(), *id))
+            .collect()
     }
-    #[cfg(feature = "debug-tools")]
-    fn write_debug_file(&self, path: &str, content: &str) -> Result<(), AstBuildError> {
-        if let Some(parent) = std::path::Path::new(path).parent() {
-            fs::create_dir_all(parent).map_err(|e| {
-                AstBuildError::JsonParseError(format!("Failed to create debug directory: {}", e))
-            })?;
+
+    /// Interns and optimizes the naive AST of one path, reporting every stage to `observers`.
+    fn compile_path(
+        &mut self,
+        quality: &Quality,
+        naive_ast: &Expression,
+        observers: &mut [Box<dyn CompilationObserver>],
+    ) -> (ExpressionGraph, NodeId, OptimizerStats) {
+        for observer in observers.iter_mut() {
+            observer.on_naive_ast(quality, naive_ast);
         }
-        fs::write(path, content).map_err(|e| {
-            AstBuildError::JsonParseError(format!("Failed to write debug file: {}", e))
-        })
+
+        // 1. Intern the strings in the naive AST into an ID-based expression graph
+        let (mut graph, root) = self.intern_ast_inputs(naive_ast);
+        if !observers.is_empty() {
+            let interned_ast = graph.to_expression(root);
+            for observer in observers.iter_mut() {
+                observer.on_interned_ast(
+                    quality,
+                    &interned_ast,
+                    &self.static_map,
+                    &self.dynamic_map,
+                );
+            }
+        }
+
+        // 2. Optimize the graph
+        let mut optimizer = AstOptimizer::new(self.optimizer_passes);
+        let (graph, root) = optimizer.optimize(&mut graph, root, |pass, iteration, graph, root| {
+            if observers.is_empty() {
+                return;
+            }
+            // Sharing is only shown once CSE has run, like in the final result.
+            let (ast, definitions) = match pass {
+                OptimizerPass::CommonSubexpressionElimination => graph.to_shared_expression(root),
+                _ => (graph.to_expression(root), AHashMap::new()),
+            };
+            for observer in observers.iter_mut() {
+                observer.on_optimizer_pass(quality, pass, iteration, &ast, &definitions);
+            }
+        });
+        let stats = optimizer.stats;
+
+        if !observers.is_empty() {
+            let (optimized_ast, definitions) = graph.to_shared_expression(root);
+            for observer in observers.iter_mut() {
+                observer.on_optimized_ast(quality, &optimized_ast, &definitions);
+            }
+            // 3. Lower to bytecode so observers can inspect the final program.
+            let dynamic_map = self.read_dynamic_map(&graph, root);
+            let program =
+                bytecode_compiler::compile_graph(&graph, root, &self.static_map, &dynamic_map);
+            for observer in observers.iter_mut() {
+                observer.on_bytecode(quality, program.as_ref());
+            }
+        }
+
+        (graph, root, stats)
     }
 }
+
+/// Reads the name, type and default of a parameter node.
+fn parameter_definition(node: &FlowNodeDefinition) -> Result<RecipeParameter, AstBuildError> {
+    let invalid = |message: &str| AstBuildError::InvalidParameter {
+        node_id: node.id.clone(),
+        message: message.to_string(),
+    };
+    let field = node
+        .data_fields
+        .as_ref()
+        .and_then(|fields| fields.first())
+        .ok_or_else(|| invalid("it has no data field naming the parameter"))?;
+    let default = match node.literal_values.as_ref().and_then(|v| v.first()) {
+        Some(serde_json::Value::Number(n)) => Value::Number(n.as_f64().unwrap_or_default()),
+        Some(serde_json::Value::Bool(b)) => Value::Bool(*b),
+        _ => return Err(invalid("its default must be a number or a bool")),
+    };
+    match (field.data_type.as_deref(), &default) {
+        (None, _) | (Some("number"), Value::Number(_)) | (Some("bool"), Value::Bool(_)) => {}
+        (Some(data_type), _) => {
+            return Err(invalid(&format!(
+                "its default {} is not of type '{}'",
+                default, data_type
+            )));
+        }
+    }
+    Ok(RecipeParameter {
+        name: field.name.clone(),
+        default,
+    })
+}
+
+/// The decisions of a flow: the qualities passed to [`Compiler::builder`] on the first
+/// `setQualityNode` as the [`DEFAULT_DECISION`], followed by those added with
+/// [`CompilerBuilder::with_decision`]. The default decision is left out when it has no
+/// qualities and other decisions were added. Returns `None` if the default decision is
+/// needed but the flow has no `setQualityNode`.
+fn resolve_decisions(
+    flow: &FlowDefinition,
+    qualities: &[Quality],
+    decisions: &[Decision],
+) -> Option<Vec<Decision>> {
+    let mut resolved = Vec::with_capacity(decisions.len() + 1);
+    if decisions.is_empty() || !qualities.is_empty() {
+        let quality_node = flow
+            .nodes
+            .iter()
+            .find(|n| n.operation_type == "setQualityNode")?;
+        resolved.push(Decision {
+            name: DEFAULT_DECISION.to_string(),
+            node_id: quality_node.id.clone(),
+            qualities: qualities.to_vec(),
+        });
+    }
+    resolved.extend_from_slice(decisions);
+    Some(resolved)
+}
+
+/// Pairs of decisions that cannot be compiled together: every decision that reuses the name
+/// or the `setQualityNode` of an earlier one, with the first such earlier decision.
+fn conflicting_decisions(decisions: &[Decision]) -> Vec<(&Decision, &Decision)> {
+    decisions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, decision)| {
+            decisions[..index]
+                .iter()
+                .find(|d| d.name == decision.name || d.node_id == decision.node_id)
+                .map(|earlier| (earlier, decision))
+        })
+        .collect()
+}
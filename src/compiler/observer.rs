@@ -0,0 +1,227 @@
+use crate::ast::{Expression, InputId};
+use crate::bytecode::compiler::BytecodeProgram;
+use crate::error::BackendError;
+use crate::recipe::Quality;
+use ahash::AHashMap;
+use std::sync::{Arc, Mutex};
+
+/// Identifies an optimizer pass reported to a `CompilationObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OptimizerPass {
+    /// Constant folding, algebraic simplification, dead code elimination and De Morgan's laws.
+    FoldAndSimplify,
+    /// Interval analysis over comparisons of inputs against literals.
+    IntervalAnalysis,
+    /// Flattening and operand sorting of commutative and associative operations.
+    Canonicalization,
+    /// Common subexpression elimination.
+    CommonSubexpressionElimination,
+}
+
+/// Receives the intermediate results of `Compiler::compile` for every quality path.
+///
+/// All methods have empty default implementations, so an observer only needs to
+/// implement the stages it is interested in. Observers are registered with
+/// `CompilerBuilder::with_observer` and are called in registration order.
+pub trait CompilationObserver: Send {
+    /// Called with the unoptimized AST exactly as it was built from the flow.
+    fn on_naive_ast(&mut self, _quality: &Quality, _ast: &Expression) {}
+
+    /// Called after input interning, with the ID-based AST and the interned input maps.
+    fn on_interned_ast(
+        &mut self,
+        _quality: &Quality,
+        _ast: &Expression,
+        _static_map: &AHashMap<String, InputId>,
+        _dynamic_map: &AHashMap<String, InputId>,
+    ) {
+    }
+
+    /// Called after every optimizer pass. `iteration` counts fixed-point iterations from 0.
+    fn on_optimizer_pass(
+        &mut self,
+        _quality: &Quality,
+        _pass: OptimizerPass,
+        _iteration: usize,
+        _ast: &Expression,
+        _definitions: &AHashMap<u64, Expression>,
+    ) {
+    }
+
+    /// Called with the final optimized AST and its CSE definitions.
+    fn on_optimized_ast(
+        &mut self,
+        _quality: &Quality,
+        _ast: &Expression,
+        _definitions: &AHashMap<u64, Expression>,
+    ) {
+    }
+
+    /// Called with the result of lowering the optimized AST to bytecode.
+    fn on_bytecode(
+        &mut self,
+        _quality: &Quality,
+        _program: Result<&BytecodeProgram, &BackendError>,
+    ) {
+    }
+}
+
+/// Lets a caller keep a handle to an observer and inspect it after compilation.
+impl<T: CompilationObserver> CompilationObserver for Arc<Mutex<T>> {
+    fn on_naive_ast(&mut self, quality: &Quality, ast: &Expression) {
+        self.lock().unwrap().on_naive_ast(quality, ast)
+    }
+
+    fn on_interned_ast(
+        &mut self,
+        quality: &Quality,
+        ast: &Expression,
+        static_map: &AHashMap<String, InputId>,
+        dynamic_map: &AHashMap<String, InputId>,
+    ) {
+        self.lock()
+            .unwrap()
+            .on_interned_ast(quality, ast, static_map, dynamic_map)
+    }
+
+    fn on_optimizer_pass(
+        &mut self,
+        quality: &Quality,
+        pass: OptimizerPass,
+        iteration: usize,
+        ast: &Expression,
+        definitions: &AHashMap<u64, Expression>,
+    ) {
+        self.lock()
+            .unwrap()
+            .on_optimizer_pass(quality, pass, iteration, ast, definitions)
+    }
+
+    fn on_optimized_ast(
+        &mut self,
+        quality: &Quality,
+        ast: &Expression,
+        definitions: &AHashMap<u64, Expression>,
+    ) {
+        self.lock()
+            .unwrap()
+            .on_optimized_ast(quality, ast, definitions)
+    }
+
+    fn on_bytecode(&mut self, quality: &Quality, program: Result<&BytecodeProgram, &BackendError>) {
+        self.lock().unwrap().on_bytecode(quality, program)
+    }
+}
+
+#[cfg(feature = "debug-tools")]
+pub use debug_dump::DebugDumpObserver;
+
+#[cfg(feature = "debug-tools")]
+mod debug_dump {
+    use super::*;
+    use crate::ast::DisplayExpression;
+    use crate::bytecode::visualizer::visualize_program;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Writes human-readable dumps of the naive AST, optimized AST and bytecode of
+    /// every quality path into a directory.
+    ///
+    /// Files are named `quality_<name>_naive_ast.txt`, `quality_<name>_optimized_ast.txt`
+    /// and `quality_<name>_bytecode.txt`. Write failures are reported on stderr and
+    /// never abort the compilation.
+    pub struct DebugDumpObserver {
+        dir: PathBuf,
+        static_rev_map: AHashMap<InputId, String>,
+        dynamic_rev_map: AHashMap<InputId, String>,
+    }
+
+    impl DebugDumpObserver {
+        pub fn new(dir: impl Into<PathBuf>) -> Self {
+            Self {
+                dir: dir.into(),
+                static_rev_map: AHashMap::new(),
+                dynamic_rev_map: AHashMap::new(),
+            }
+        }
+
+        fn write(&self, quality: &Quality, stage: &str, content: &str) {
+            let sanitized_name: String = quality
+                .name
+                .chars()
+                .filter(|c| c.is_alphanumeric() || *c == '_')
+                .collect();
+            let path = self
+                .dir
+                .join(format!("quality_{}_{}.txt", sanitized_name, stage));
+            let result = fs::create_dir_all(&self.dir).and_then(|_| fs::write(&path, content));
+            if let Err(e) = result {
+                eprintln!(
+                    "Warning: Could not write debug file '{}': {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    impl CompilationObserver for DebugDumpObserver {
+        fn on_naive_ast(&mut self, quality: &Quality, ast: &Expression) {
+            let display = DisplayExpression {
+                expr: ast,
+                definitions: &AHashMap::new(),
+                static_map: &AHashMap::new(),
+                dynamic_map: &AHashMap::new(),
+            };
+            self.write(quality, "naive_ast", &display.to_string());
+        }
+
+        fn on_interned_ast(
+            &mut self,
+            _quality: &Quality,
+            _ast: &Expression,
+            static_map: &AHashMap<String, InputId>,
+            dynamic_map: &AHashMap<String, InputId>,
+        ) {
+            self.static_rev_map = static_map.iter().map(|(k, v)| (*v, k.clone())).collect();
+            self.dynamic_rev_map = dynamic_map.iter().map(|(k, v)| (*v, k.clone())).collect();
+        }
+
+        fn on_optimized_ast(
+            &mut self,
+            quality: &Quality,
+            ast: &Expression,
+            definitions: &AHashMap<u64, Expression>,
+        ) {
+            let display = DisplayExpression {
+                expr: ast,
+                definitions,
+                static_map: &self.static_rev_map,
+                dynamic_map: &self.dynamic_rev_map,
+            };
+            self.write(quality, "optimized_ast", &display.to_string());
+        }
+
+        fn on_bytecode(
+            &mut self,
+            quality: &Quality,
+            program: Result<&BytecodeProgram, &BackendError>,
+        ) {
+            match program {
+                Ok(program) => {
+                    let viz = visualize_program(
+                        program,
+                        &quality.name,
+                        &self.static_rev_map,
+                        &self.dynamic_rev_map,
+                    );
+                    self.write(quality, "bytecode", &viz);
+                }
+                Err(e) => eprintln!(
+                    "Warning: Could not compile bytecode for debug file for quality '{}': {}",
+                    quality.name, e
+                ),
+            }
+        }
+    }
+}
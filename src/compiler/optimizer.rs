@@ -1,341 +1,498 @@
-use crate::ast::{Expression, Value};
+use super::canonicalize::Canonicalizer;
+use super::interval::IntervalAnalyzer;
+use super::observer::OptimizerPass;
+use crate::ast::{ExpressionGraph, Node, NodeId, Value};
 use ahash::AHashMap;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
-/// A macro to handle simplification rules for any binary expression.
-/// It tries to apply a series of patterns and if none match, it reconstructs
-/// the expression with its (already optimized) children.
+/// A macro to handle simplification rules for any binary node.
+/// It tries to apply a series of patterns to the operand nodes and if none match,
+/// it returns the node unchanged.
 macro_rules! apply_binary_rules {
-    // The macro takes the left/right children, the default constructor,
-    // and a series of pattern arms. Each arm can now optionally have an `if` guard.
-    ($l:expr, $r:expr, $default_constructor:path, $($pattern:pat $(if $guard:expr)? => $result:expr),+ $(,)?) => {
-        match (*$l, *$r) {
+    // The macro takes the graph, the left/right operand ids, the id to return when no
+    // rule matches, the statistics counter to bump when a rule fires, and a series of
+    // pattern arms over the operand nodes. Each arm can optionally have an `if` guard
+    // and evaluates to the id of the rewritten node.
+    ($graph:expr, $l:expr, $r:expr, $unchanged:expr, $counter:expr, $($pattern:pat $(if $guard:expr)? => $result:expr),+ $(,)?) => {
+        match ($graph[$l].clone(), $graph[$r].clone()) {
             $(
-                $pattern $(if $guard)? => $result,
+                $pattern $(if $guard)? => {
+                    $counter += 1;
+                    $result
+                }
             )+
-            // Default Case: If no specific rule matches, reconstruct the expression.
-            (opt_l, opt_r) => $default_constructor(Box::new(opt_l), Box::new(opt_r)),
+            // Default Case: If no specific rule matches, keep the node.
+            _ => $unchanged,
         }
     };
 }
 
-/// A stateful optimizer that applies advanced simplification passes to an AST.
+/// Preset optimization levels, mirroring the `-O` levels of conventional compilers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OptimizationLevel {
+    /// No optimization. The interned AST is handed to the backends unchanged.
+    O0,
+    /// Constant folding, dead code elimination, interval analysis, De Morgan's laws and
+    /// predicate pushdown, without CSE.
+    O1,
+    /// Every pass, including canonicalization and common subexpression elimination.
+    #[default]
+    O2,
+}
+
+/// The set of optimizer passes that `AstOptimizer` is allowed to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OptimizerPasses {
+    /// Constant folding and algebraic simplification (e.g. `x + 0`, `x * 1`, `1 < 2`).
+    pub constant_folding: bool,
+    /// Boolean short-circuits and contradictions (e.g. `x AND false`, `x > 5 AND x < 3`).
+    pub dead_code_elimination: bool,
+    /// Double negation removal and pushing `NOT` through `AND`/`OR`.
+    pub de_morgan: bool,
+    /// Interval analysis that proves comparisons on the same input always true, always
+    /// false or redundant (e.g. `x > 5 AND x > 3` becomes `x > 5`).
+    pub interval_analysis: bool,
+    /// Flattening of associative chains, sorting of commutative operands and rewriting
+    /// `x < y` as `y > x`, so that CSE finds more duplicates.
+    pub canonicalization: bool,
+    /// Common subexpression elimination.
+    pub common_subexpression_elimination: bool,
+    /// Splitting off the conditions of a quality path that read a single event, so that
+    /// the instances of each event are filtered before they are combined.
+    pub predicate_pushdown: bool,
+}
+
+impl OptimizerPasses {
+    /// Every pass enabled.
+    pub const fn all() -> Self {
+        Self {
+            constant_folding: true,
+            dead_code_elimination: true,
+            de_morgan: true,
+            interval_analysis: true,
+            canonicalization: true,
+            common_subexpression_elimination: true,
+            predicate_pushdown: true,
+        }
+    }
+
+    /// Every pass disabled.
+    pub const fn none() -> Self {
+        Self {
+            constant_folding: false,
+            dead_code_elimination: false,
+            de_morgan: false,
+            interval_analysis: false,
+            canonicalization: false,
+            common_subexpression_elimination: false,
+            predicate_pushdown: false,
+        }
+    }
+
+    /// Returns true if any of the rules run by the fold-and-simplify pass are enabled.
+    fn any_simplification(&self) -> bool {
+        self.constant_folding || self.dead_code_elimination || self.de_morgan
+    }
+}
+
+impl Default for OptimizerPasses {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl From<OptimizationLevel> for OptimizerPasses {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::O0 => Self::none(),
+            OptimizationLevel::O1 => Self {
+                canonicalization: false,
+                common_subexpression_elimination: false,
+                ..Self::all()
+            },
+            OptimizationLevel::O2 => Self::all(),
+        }
+    }
+}
+
+/// The most fixed-point iterations `AstOptimizer::optimize` runs on one path. Every pass
+/// only shrinks or normalises the expression, so paths converge well before this.
+pub const MAX_OPTIMIZER_ITERATIONS: usize = 64;
+
+/// Counters describing what `AstOptimizer` did to one quality path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OptimizerStats {
+    /// Number of fixed-point iterations until the AST stopped changing.
+    pub iterations: usize,
+    /// Whether the AST stopped changing within [`MAX_OPTIMIZER_ITERATIONS`]. If not, the result of
+    /// the last iteration is used, which is still correct but may be less optimized.
+    pub converged: bool,
+    /// Nodes in the AST before optimization.
+    pub nodes_before: usize,
+    /// Nodes in the optimized expression graph. With CSE, shared subexpressions count once.
+    pub nodes_after: usize,
+    /// Constant folding and algebraic simplification rewrites.
+    pub constants_folded: usize,
+    /// Boolean branches removed by dead code elimination and interval analysis.
+    pub branches_eliminated: usize,
+    /// Additional uses of subexpressions that CSE shares instead of duplicating.
+    pub cse_hits: usize,
+    /// Conditions moved into per-event filters by predicate pushdown.
+    pub conditions_pushed_down: usize,
+}
+
+/// A stateful optimizer that applies advanced simplification passes to an expression graph.
+///
+/// The passes work on a hash-consed `ExpressionGraph`, so structurally equal
+/// subexpressions are a single node, a shared node is rewritten only once per pass, and
+/// comparing two subexpressions for equality is comparing their ids.
 pub struct AstOptimizer {
-    /// A cache mapping an expression's hash to a unique ID for CSE.
-    cse_cache: AHashMap<u64, u64>,
-    /// A map from a unique ID to the actual expression it represents.
-    pub definitions: AHashMap<u64, Expression>,
-    next_id: u64,
+    passes: OptimizerPasses,
+    pub stats: OptimizerStats,
 }
 
 impl AstOptimizer {
-    pub fn new() -> Self {
+    pub fn new(passes: OptimizerPasses) -> Self {
         Self {
-            cse_cache: AHashMap::new(),
-            definitions: AHashMap::new(),
-            next_id: 0,
+            passes,
+            stats: OptimizerStats::default(),
         }
     }
 
-    /// Runs optimization passes in a loop until the AST reaches a fixed point.
-    pub fn optimize(&mut self, expr: Expression) -> Expression {
-        let mut current_expr = expr;
-        loop {
-            // It's crucial to run folding/elimination before CSE to maximize cache hits.
-            let pass1 = self.fold_and_simplify(current_expr.clone());
-            let pass2 = self.eliminate_common_subexpressions(pass1);
+    /// Runs the enabled optimization passes in a loop until `root` reaches a fixed point.
+    /// `on_pass` is called with the working graph and root after every individual pass.
+    ///
+    /// Returns a compacted copy of the optimized expression. When common subexpression
+    /// elimination is enabled, equal subexpressions share one node in it; otherwise it is
+    /// a plain tree.
+    pub fn optimize<F>(
+        &mut self,
+        graph: &mut ExpressionGraph,
+        root: NodeId,
+        mut on_pass: F,
+    ) -> (ExpressionGraph, NodeId)
+    where
+        F: FnMut(OptimizerPass, usize, &ExpressionGraph, NodeId),
+    {
+        self.stats.nodes_before = graph.tree_size(root);
+        let mut current = root;
+        let mut iteration = 0;
+        while iteration < MAX_OPTIMIZER_ITERATIONS {
+            // It's crucial to run folding/elimination before CSE to maximize sharing.
+            let mut next = current;
+            if self.passes.any_simplification() {
+                next = self.fold_and_simplify(graph, next, &mut AHashMap::new());
+                on_pass(OptimizerPass::FoldAndSimplify, iteration, graph, next);
+            }
+            if self.passes.interval_analysis {
+                let mut analyzer = IntervalAnalyzer::new(graph);
+                next = analyzer.simplify(next);
+                self.stats.branches_eliminated += analyzer.decided;
+                on_pass(OptimizerPass::IntervalAnalysis, iteration, graph, next);
+            }
+            if self.passes.canonicalization {
+                next = Canonicalizer::new(graph).canonicalize(next);
+                on_pass(OptimizerPass::Canonicalization, iteration, graph, next);
+            }
+            if self.passes.common_subexpression_elimination {
+                // Hash-consing already merged equal subexpressions into one node, so this
+                // pass only decides that the sharing is kept in the result.
+                on_pass(
+                    OptimizerPass::CommonSubexpressionElimination,
+                    iteration,
+                    graph,
+                    next,
+                );
+            }
 
-            if pass2 == current_expr {
-                return pass2;
+            iteration += 1;
+            // Equal ids in a hash-consed graph mean structurally equal expressions.
+            if next == current {
+                self.stats.converged = true;
+                break;
             }
-            current_expr = pass2;
+            current = next;
         }
+        self.stats.iterations = iteration;
+        let (optimized, root) =
+            graph.extract(current, self.passes.common_subexpression_elimination);
+        self.stats.nodes_after = optimized.len();
+        self.stats.cse_hits = count_shared_uses(&optimized, root);
+        (optimized, root)
     }
 
     /// Pass 1: A combined pass for Constant Folding, Algebraic Simplification,
-    /// Dead Code Elimination (DCE), and De Morgan's Laws.
-    fn fold_and_simplify(&self, expr: Expression) -> Expression {
+    /// Dead Code Elimination (DCE), and De Morgan's Laws. Only the rule groups
+    /// enabled in `OptimizerPasses` are applied. Every node is rewritten at most once;
+    /// `memo` maps already rewritten nodes to their result.
+    fn fold_and_simplify(
+        &mut self,
+        graph: &mut ExpressionGraph,
+        id: NodeId,
+        memo: &mut AHashMap<NodeId, NodeId>,
+    ) -> NodeId {
+        if let Some(result) = memo.get(&id) {
+            return *result;
+        }
         // First, recursively optimize the children (post-order traversal).
-        let expr = match expr {
-            Expression::Sum(l, r) => Expression::Sum(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Subtract(l, r) => Expression::Subtract(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Multiply(l, r) => Expression::Multiply(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Divide(l, r) => Expression::Divide(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::And(l, r) => Expression::And(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Or(l, r) => Expression::Or(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Xor(l, r) => Expression::Xor(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Equal(l, r) => Expression::Equal(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::NotEqual(l, r) => Expression::NotEqual(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::GreaterThan(l, r) => Expression::GreaterThan(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::GreaterThanOrEqual(l, r) => Expression::GreaterThanOrEqual(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::SmallerThan(l, r) => Expression::SmallerThan(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::SmallerThanOrEqual(l, r) => Expression::SmallerThanOrEqual(
-                Box::new(self.fold_and_simplify(*l)),
-                Box::new(self.fold_and_simplify(*r)),
-            ),
-            Expression::Not(v) => Expression::Not(Box::new(self.fold_and_simplify(*v))),
-            Expression::Abs(v) => Expression::Abs(Box::new(self.fold_and_simplify(*v))),
-            other => other,
-        };
+        let node = graph[id].clone();
+        let node = node.map_children(|child| self.fold_and_simplify(graph, child, memo));
 
         // Second, apply simplification rules to the current node.
-        self.apply_simplification_rules(expr)
+        let result = self.apply_simplification_rules(graph, node);
+        memo.insert(id, result);
+        result
     }
 
-    /// Pass 2: Common Subexpression Elimination (CSE).
-    fn eliminate_common_subexpressions(&mut self, expr: Expression) -> Expression {
-        // Recursively apply to children first (post-order traversal).
-        let expr = match expr {
-            Expression::Sum(l, r) => Expression::Sum(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Subtract(l, r) => Expression::Subtract(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Multiply(l, r) => Expression::Multiply(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Divide(l, r) => Expression::Divide(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Abs(v) => {
-                Expression::Abs(Box::new(self.eliminate_common_subexpressions(*v)))
-            }
-            Expression::Not(v) => {
-                Expression::Not(Box::new(self.eliminate_common_subexpressions(*v)))
-            }
-            Expression::And(l, r) => Expression::And(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Or(l, r) => Expression::Or(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Xor(l, r) => Expression::Xor(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::Equal(l, r) => Expression::Equal(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::NotEqual(l, r) => Expression::NotEqual(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::GreaterThan(l, r) => Expression::GreaterThan(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::GreaterThanOrEqual(l, r) => Expression::GreaterThanOrEqual(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::SmallerThan(l, r) => Expression::SmallerThan(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            Expression::SmallerThanOrEqual(l, r) => Expression::SmallerThanOrEqual(
-                Box::new(self.eliminate_common_subexpressions(*l)),
-                Box::new(self.eliminate_common_subexpressions(*r)),
-            ),
-            _ => expr,
-        };
-
-        if !matches!(
-            &expr,
-            Expression::Literal(_) | Expression::Input(_) | Expression::Reference(_)
-        ) {
-            let mut hasher = DefaultHasher::new();
-            expr.hash(&mut hasher);
-            let expr_hash = hasher.finish();
-
-            if let Some(id) = self.cse_cache.get(&expr_hash) {
-                return Expression::Reference(*id);
-            } else {
-                let id = self.next_id;
-                self.next_id += 1;
-                self.cse_cache.insert(expr_hash, id);
-                self.definitions.insert(id, expr.clone());
-            }
+    /// Adds `node`, whose operands are already simplified, and applies the enabled rules.
+    fn apply_simplification_rules(&mut self, graph: &mut ExpressionGraph, node: Node) -> NodeId {
+        let mut id = graph.add(node);
+        if self.passes.constant_folding {
+            id = self.apply_folding_rules(graph, id);
         }
-        expr
+        if self.passes.dead_code_elimination {
+            id = self.apply_dead_code_rules(graph, id);
+        }
+        if self.passes.de_morgan {
+            id = self.apply_de_morgan_rules(graph, id);
+        }
+        id
     }
 
-    fn apply_simplification_rules(&self, expr: Expression) -> Expression {
-        match expr {
+    /// Constant folding and algebraic identities.
+    fn apply_folding_rules(&mut self, graph: &mut ExpressionGraph, id: NodeId) -> NodeId {
+        match graph[id].clone() {
             // --- Arithmetic ---
-            Expression::Sum(l, r) => apply_binary_rules!(l, r, Expression::Sum,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Number(lv + rv)),
-                (expr, Expression::Literal(Value::Number(n))) if n == 0.0 => expr,
-                (Expression::Literal(Value::Number(n)), expr) if n == 0.0 => expr,
-            ),
-            Expression::Subtract(l, r) => apply_binary_rules!(l, r, Expression::Subtract,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Number(lv - rv)),
-                (expr, Expression::Literal(Value::Number(n))) if n == 0.0 => expr,
-                (l_expr, r_expr) if l_expr == r_expr => Expression::Literal(Value::Number(0.0)),
-            ),
-            Expression::Multiply(l, r) => apply_binary_rules!(l, r, Expression::Multiply,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Number(lv * rv)),
-                (_, Expression::Literal(Value::Number(n))) if n == 0.0 => Expression::Literal(Value::Number(0.0)),
-                (Expression::Literal(Value::Number(n)), _) if n == 0.0 => Expression::Literal(Value::Number(0.0)),
-                (expr, Expression::Literal(Value::Number(n))) if n == 1.0 => expr,
-                (Expression::Literal(Value::Number(n)), expr) if n == 1.0 => expr,
-            ),
-            Expression::Divide(l, r) => apply_binary_rules!(l, r, Expression::Divide,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) if rv != 0.0 => Expression::Literal(Value::Number(lv / rv)),
-                (expr, Expression::Literal(Value::Number(n))) if n == 1.0 => expr,
-                (Expression::Literal(Value::Number(n)), _) if n == 0.0 => Expression::Literal(Value::Number(0.0)),
-            ),
+            Node::Sum(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => number(graph, lv + rv),
+                    (_, Node::Literal(Value::Number(n))) if n == 0.0 => l,
+                    (Node::Literal(Value::Number(n)), _) if n == 0.0 => r,
+                )
+            }
+            Node::Subtract(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => number(graph, lv - rv),
+                    (_, Node::Literal(Value::Number(n))) if n == 0.0 => l,
+                    _ if l == r && is_never_null(graph, l) => number(graph, 0.0),
+                )
+            }
+            Node::Multiply(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => number(graph, lv * rv),
+                    (_, Node::Literal(Value::Number(n))) if n == 0.0 && is_never_null(graph, l) => number(graph, 0.0),
+                    (Node::Literal(Value::Number(n)), _) if n == 0.0 && is_never_null(graph, r) => number(graph, 0.0),
+                    (_, Node::Literal(Value::Number(n))) if n == 1.0 => l,
+                    (Node::Literal(Value::Number(n)), _) if n == 1.0 => r,
+                )
+            }
+            Node::Divide(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) if rv != 0.0 => number(graph, lv / rv),
+                    (_, Node::Literal(Value::Number(n))) if n == 1.0 => l,
+                    (Node::Literal(Value::Number(n)), _) if n == 0.0 && is_never_null(graph, r) => number(graph, 0.0),
+                )
+            }
 
             // --- Unary ---
-            Expression::Abs(v) => match *v {
-                Expression::Literal(Value::Number(n)) => {
-                    Expression::Literal(Value::Number(n.abs()))
+            Node::Abs(v) => match graph[v] {
+                Node::Literal(Value::Number(n)) => {
+                    self.stats.constants_folded += 1;
+                    number(graph, n.abs())
+                }
+                _ => id,
+            },
+            Node::Not(v) => match graph[v] {
+                Node::Literal(Value::Bool(b)) => {
+                    self.stats.constants_folded += 1;
+                    boolean(graph, !b)
                 }
-                opt_v => Expression::Abs(Box::new(opt_v)),
+                _ => id,
             },
-            Expression::Not(v) => match *v {
-                Expression::Literal(Value::Bool(b)) => Expression::Literal(Value::Bool(!b)),
-                Expression::Not(inner_v) => *inner_v,
-                Expression::Or(l, r) => self.fold_and_simplify(Expression::And(
-                    Box::new(Expression::Not(l)),
-                    Box::new(Expression::Not(r)),
-                )),
-                Expression::And(l, r) => self.fold_and_simplify(Expression::Or(
-                    Box::new(Expression::Not(l)),
-                    Box::new(Expression::Not(r)),
-                )),
-                opt_v => Expression::Not(Box::new(opt_v)),
+            Node::Table(v, ref table) => match graph[v] {
+                Node::Literal(Value::Number(n)) => {
+                    self.stats.constants_folded += 1;
+                    number(graph, table.step(n).0)
+                }
+                _ => id,
+            },
+            Node::Interpolate(v, ref table) => match graph[v] {
+                Node::Literal(Value::Number(n)) => {
+                    self.stats.constants_folded += 1;
+                    number(graph, table.interpolate(n).0)
+                }
+                _ => id,
             },
+            Node::Spatial(op, ref args) => {
+                let numbers: Option<Vec<f64>> = args
+                    .iter()
+                    .map(|arg| match graph[*arg] {
+                        Node::Literal(Value::Number(n)) => Some(n),
+                        _ => None,
+                    })
+                    .collect();
+                match numbers {
+                    Some(numbers) if numbers.len() == op.arity() => {
+                        self.stats.constants_folded += 1;
+                        graph.add(Node::Literal(op.apply(&numbers)))
+                    }
+                    _ => id,
+                }
+            }
 
             // --- Logical ---
-            Expression::Or(l, r) => apply_binary_rules!(l, r, Expression::Or,
-                (_, Expression::Literal(Value::Bool(true))) | (Expression::Literal(Value::Bool(true)), _) => Expression::Literal(Value::Bool(true)),
-                (expr, Expression::Literal(Value::Bool(false))) | (Expression::Literal(Value::Bool(false)), expr) => expr,
-                (l_expr, r_expr) if l_expr == r_expr => l_expr,
-            ),
-            Expression::Xor(l, r) => apply_binary_rules!(l, r, Expression::Xor,
-                (Expression::Literal(Value::Bool(lv)), Expression::Literal(Value::Bool(rv))) => Expression::Literal(Value::Bool(lv ^ rv)),
-                (expr, Expression::Literal(Value::Bool(false))) | (Expression::Literal(Value::Bool(false)), expr) => expr,
-                (expr, Expression::Literal(Value::Bool(true))) => Expression::Not(Box::new(expr)),
-                (Expression::Literal(Value::Bool(true)), expr) => Expression::Not(Box::new(expr)),
-                (l_expr, r_expr) if l_expr == r_expr => Expression::Literal(Value::Bool(false)),
-            ),
-            Expression::And(l, r) => {
+            Node::Xor(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Bool(lv)), Node::Literal(Value::Bool(rv))) => boolean(graph, lv ^ rv),
+                    (_, Node::Literal(Value::Bool(false))) => l,
+                    (Node::Literal(Value::Bool(false)), _) => r,
+                    (_, Node::Literal(Value::Bool(true))) => graph.add(Node::Not(l)),
+                    (Node::Literal(Value::Bool(true)), _) => graph.add(Node::Not(r)),
+                    _ if l == r && is_never_null(graph, l) => boolean(graph, false),
+                )
+            }
+
+            // --- Comparisons ---
+            Node::Equal(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => boolean(graph, lv == rv),
+                    (Node::Literal(Value::Bool(lv)), Node::Literal(Value::Bool(rv))) => boolean(graph, lv == rv),
+                )
+            }
+            Node::NotEqual(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => boolean(graph, lv != rv),
+                    (Node::Literal(Value::Bool(lv)), Node::Literal(Value::Bool(rv))) => boolean(graph, lv != rv),
+                )
+            }
+            Node::GreaterThan(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => boolean(graph, lv > rv),
+                )
+            }
+            Node::GreaterThanOrEqual(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => boolean(graph, lv >= rv),
+                )
+            }
+            Node::SmallerThan(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => boolean(graph, lv < rv),
+                )
+            }
+            Node::SmallerThanOrEqual(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.constants_folded,
+                    (Node::Literal(Value::Number(lv)), Node::Literal(Value::Number(rv))) => boolean(graph, lv <= rv),
+                )
+            }
+
+            // If no top-level rule matches, return the node as is.
+            _ => id,
+        }
+    }
+
+    /// Boolean short-circuits, idempotence and contradictory range checks.
+    ///
+    /// A contradiction is only false if its comparisons cannot be null; with a missing
+    /// operand both are null, and so is their `AND`.
+    fn apply_dead_code_rules(&mut self, graph: &mut ExpressionGraph, id: NodeId) -> NodeId {
+        match graph[id].clone() {
+            Node::Or(l, r) => {
+                apply_binary_rules!(graph, l, r, id, self.stats.branches_eliminated,
+                    (_, Node::Literal(Value::Bool(true))) | (Node::Literal(Value::Bool(true)), _) => boolean(graph, true),
+                    (_, Node::Literal(Value::Bool(false))) => l,
+                    (Node::Literal(Value::Bool(false)), _) => r,
+                    _ if l == r => l,
+                )
+            }
+            Node::And(l, r) => {
                 // `And` has complex DCE rules that don't fit the simple macro, so it gets a custom match.
-                // The simple folding/identity rules are in the default arm.
-                match (&*l, &*r) {
-                    (Expression::GreaterThan(ll, lr), Expression::SmallerThan(rl, rr))
-                        if ll == rl =>
-                    {
-                        if let (
-                            Expression::Literal(Value::Number(a)),
-                            Expression::Literal(Value::Number(b)),
-                        ) = (&**lr, &**rr)
-                        {
-                            if a >= b {
-                                return Expression::Literal(Value::Bool(false));
-                            }
-                        }
-                    }
-                    (Expression::SmallerThan(ll, lr), Expression::GreaterThan(rl, rr))
-                        if ll == rl =>
-                    {
-                        if let (
-                            Expression::Literal(Value::Number(a)),
-                            Expression::Literal(Value::Number(b)),
-                        ) = (&**lr, &**rr)
-                        {
-                            if b >= a {
-                                return Expression::Literal(Value::Bool(false));
-                            }
-                        }
+                // The simple folding/identity rules are in the macro below.
+                let contradiction = match (&graph[l], &graph[r]) {
+                    (Node::GreaterThan(ll, lr), Node::SmallerThan(rl, rr)) if ll == rl => {
+                        matches!(
+                            (&graph[*lr], &graph[*rr]),
+                            (Node::Literal(Value::Number(a)), Node::Literal(Value::Number(b))) if a >= b
+                        )
                     }
-                    (Expression::Equal(ll, lr), Expression::Equal(rl, rr))
-                        if ll == rl && lr != rr =>
-                    {
-                        return Expression::Literal(Value::Bool(false));
+                    (Node::SmallerThan(ll, lr), Node::GreaterThan(rl, rr)) if ll == rl => {
+                        matches!(
+                            (&graph[*lr], &graph[*rr]),
+                            (Node::Literal(Value::Number(a)), Node::Literal(Value::Number(b))) if b >= a
+                        )
                     }
-                    _ => {} // Fall through to simple rules
+                    (Node::Equal(ll, lr), Node::Equal(rl, rr)) => ll == rl && lr != rr,
+                    _ => false,
+                } && is_never_null(graph, l)
+                    && is_never_null(graph, r);
+                if contradiction {
+                    self.stats.branches_eliminated += 1;
+                    return boolean(graph, false);
                 }
-                apply_binary_rules!(l, r, Expression::And,
-                    (_, Expression::Literal(Value::Bool(false))) | (Expression::Literal(Value::Bool(false)), _) => Expression::Literal(Value::Bool(false)),
-                    (expr, Expression::Literal(Value::Bool(true))) | (Expression::Literal(Value::Bool(true)), expr) => expr,
-                    (l_expr, r_expr) if l_expr == r_expr => l_expr,
+                apply_binary_rules!(graph, l, r, id, self.stats.branches_eliminated,
+                    (_, Node::Literal(Value::Bool(false))) | (Node::Literal(Value::Bool(false)), _) => boolean(graph, false),
+                    (_, Node::Literal(Value::Bool(true))) => l,
+                    (Node::Literal(Value::Bool(true)), _) => r,
+                    _ if l == r => l,
                 )
             }
+            _ => id,
+        }
+    }
 
-            // --- Comparisons ---
-            Expression::Equal(l, r) => apply_binary_rules!(l, r, Expression::Equal,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Bool(lv == rv)),
-                (Expression::Literal(Value::Bool(lv)), Expression::Literal(Value::Bool(rv))) => Expression::Literal(Value::Bool(lv == rv)),
-            ),
-            Expression::NotEqual(l, r) => apply_binary_rules!(l, r, Expression::NotEqual,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Bool(lv != rv)),
-                (Expression::Literal(Value::Bool(lv)), Expression::Literal(Value::Bool(rv))) => Expression::Literal(Value::Bool(lv != rv)),
-            ),
-            Expression::GreaterThan(l, r) => apply_binary_rules!(l, r, Expression::GreaterThan,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Bool(lv > rv)),
-            ),
-            Expression::GreaterThanOrEqual(l, r) => {
-                apply_binary_rules!(l, r, Expression::GreaterThanOrEqual,
-                    (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Bool(lv >= rv)),
-                )
+    /// Double negation removal and De Morgan's laws.
+    fn apply_de_morgan_rules(&mut self, graph: &mut ExpressionGraph, id: NodeId) -> NodeId {
+        let Node::Not(v) = graph[id] else {
+            return id;
+        };
+        match graph[v] {
+            Node::Not(inner) => inner,
+            Node::Or(l, r) => {
+                let not_l = self.apply_simplification_rules(graph, Node::Not(l));
+                let not_r = self.apply_simplification_rules(graph, Node::Not(r));
+                self.apply_simplification_rules(graph, Node::And(not_l, not_r))
             }
-            Expression::SmallerThan(l, r) => apply_binary_rules!(l, r, Expression::SmallerThan,
-                (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Bool(lv < rv)),
-            ),
-            Expression::SmallerThanOrEqual(l, r) => {
-                apply_binary_rules!(l, r, Expression::SmallerThanOrEqual,
-                    (Expression::Literal(Value::Number(lv)), Expression::Literal(Value::Number(rv))) => Expression::Literal(Value::Bool(lv <= rv)),
-                )
+            Node::And(l, r) => {
+                let not_l = self.apply_simplification_rules(graph, Node::Not(l));
+                let not_r = self.apply_simplification_rules(graph, Node::Not(r));
+                self.apply_simplification_rules(graph, Node::Or(not_l, not_r))
             }
+            _ => id,
+        }
+    }
+}
+
+/// Whether `id` can be proven to never evaluate to null: it reads no dynamic input, which
+/// is null when its event is absent, and calls no native function, which may return null.
+pub(super) fn is_never_null(graph: &ExpressionGraph, id: NodeId) -> bool {
+    graph
+        .post_order(id)
+        .into_iter()
+        .all(|node| match &graph[node] {
+            Node::Input(source) => !source.is_dynamic(),
+            Node::Literal(value) => *value != Value::Null,
+            Node::Call(..) => false,
+            _ => true,
+        })
+}
+
+fn number(graph: &mut ExpressionGraph, n: f64) -> NodeId {
+    graph.add(Node::Literal(Value::Number(n)))
+}
+
+fn boolean(graph: &mut ExpressionGraph, b: bool) -> NodeId {
+    graph.add(Node::Literal(Value::Bool(b)))
+}
 
-            // If no top-level rule matches, return the expression as is.
-            other => other,
+/// Counts the uses of shared operations beyond their first, i.e. the duplicates CSE saved.
+fn count_shared_uses(graph: &ExpressionGraph, root: NodeId) -> usize {
+    let mut uses = AHashMap::new();
+    for id in graph.post_order(root) {
+        for child in graph[id].children() {
+            *uses.entry(child).or_insert(0usize) += 1;
         }
     }
+    uses.into_iter()
+        .filter(|(id, count)| *count > 1 && !graph[*id].is_leaf())
+        .map(|(_, count)| count - 1)
+        .sum()
 }
@@ -1,17 +1,71 @@
 use ahash::AHashMap;
 
-use crate::ast::Expression;
+use crate::ast::spatial::{LENGTH_FIELD, WIDTH_FIELD, X_FIELD, Y_FIELD};
+use crate::ast::{Expression, InputSource, LookupTable, SpatialOp};
 use crate::error::AstBuildError;
+use crate::events::check_event_alias;
 use crate::recipe::FlowNodeDefinition;
 
+/// How the expressions of several edges connected to the same input handle are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanIn {
+    /// Logical OR, for boolean handles.
+    Or,
+    /// Logical AND, for boolean handles.
+    And,
+    /// Addition, for numeric handles.
+    Sum,
+    /// More than one edge is a compile error.
+    Reject,
+}
+
+/// The expressions produced by a node for its output handles.
+#[derive(Debug, Clone)]
+pub enum NodeOutputs {
+    /// A single output, used by every outgoing edge regardless of its source handle.
+    Single(Expression),
+    /// One expression per output handle index. Edges from any other handle are an error.
+    PerHandle(AHashMap<u32, Expression>),
+}
+
+impl NodeOutputs {
+    /// The expression of output handle `handle`, if the node has one.
+    pub fn get(&self, handle: u32) -> Option<&Expression> {
+        match self {
+            NodeOutputs::Single(expr) => Some(expr),
+            NodeOutputs::PerHandle(outputs) => outputs.get(&handle),
+        }
+    }
+}
+
 /// Defines the contract for parsing a specific `operation_type` into an `Expression`.
 pub trait NodeParser: Send + Sync {
     fn node_type(&self) -> &str;
+    /// How many leading input handles must be connected. Only used by flow validation;
+    /// the default of 0 leaves the inputs of this node unchecked.
+    fn min_inputs(&self) -> usize {
+        0
+    }
+    /// How several edges into input handle `handle` are combined. Defaults to
+    /// `FanIn::Reject`, since a single combinator cannot be right for every handle type.
+    fn fan_in(&self, _handle: u32) -> FanIn {
+        FanIn::Reject
+    }
     fn parse(
         &self,
         node: &FlowNodeDefinition,
         inputs: Vec<Expression>,
     ) -> Result<Expression, AstBuildError>;
+    /// Parses the node into the expressions of its output handles. The default is the single
+    /// output returned by [`Self::parse`]; nodes with several outputs override this, and
+    /// `parse` is then not called by the compiler.
+    fn parse_outputs(
+        &self,
+        node: &FlowNodeDefinition,
+        inputs: Vec<Expression>,
+    ) -> Result<NodeOutputs, AstBuildError> {
+        self.parse(node, inputs).map(NodeOutputs::Single)
+    }
 }
 
 /// Helper to check for at least a certain number of inputs.
@@ -37,16 +91,23 @@ fn require_inputs_at_least(
 }
 
 /// Master macro to define variadic node parsers with different chaining strategies.
+/// The last argument is the `FanIn` variant used for all input handles.
 macro_rules! define_variadic_parser {
     // ---- Strategy 1: Associative Chaining ----
     // For +, *, AND, OR, -, / where inputs are reduced left-to-right.
     // e.g., [a, b, c] -> Op(Op(a, b), c)
-    ( $struct_name:ident, $node_type:expr, $variant:path, Associative ) => {
+    ( $struct_name:ident, $node_type:expr, $variant:path, Associative, $fan_in:ident ) => {
         struct $struct_name;
         impl NodeParser for $struct_name {
             fn node_type(&self) -> &str {
                 $node_type
             }
+            fn min_inputs(&self) -> usize {
+                2
+            }
+            fn fan_in(&self, _handle: u32) -> FanIn {
+                FanIn::$fan_in
+            }
             fn parse(
                 &self,
                 node: &FlowNodeDefinition,
@@ -64,12 +125,18 @@ macro_rules! define_variadic_parser {
     // ---- Strategy 2: Chained Comparison ----
     // For >, <, == etc. where inputs are compared pairwise and ANDed.
     // e.g., [a, b, c] -> AND( Op(a,b), Op(b,c) )
-    ( $struct_name:ident, $node_type:expr, $variant:path, ChainedComparison ) => {
+    ( $struct_name:ident, $node_type:expr, $variant:path, ChainedComparison, $fan_in:ident ) => {
         struct $struct_name;
         impl NodeParser for $struct_name {
             fn node_type(&self) -> &str {
                 $node_type
             }
+            fn min_inputs(&self) -> usize {
+                2
+            }
+            fn fan_in(&self, _handle: u32) -> FanIn {
+                FanIn::$fan_in
+            }
             fn parse(
                 &self,
                 node: &FlowNodeDefinition,
@@ -93,12 +160,18 @@ macro_rules! define_variadic_parser {
 
     // ---- Strategy 3: Unary ----
     // For Not, Abs, etc.
-    ( $struct_name:ident, $node_type:expr, $variant:path, Unary ) => {
+    ( $struct_name:ident, $node_type:expr, $variant:path, Unary, $fan_in:ident ) => {
         struct $struct_name;
         impl NodeParser for $struct_name {
             fn node_type(&self) -> &str {
                 $node_type
             }
+            fn min_inputs(&self) -> usize {
+                1
+            }
+            fn fan_in(&self, _handle: u32) -> FanIn {
+                FanIn::$fan_in
+            }
             fn parse(
                 &self,
                 node: &FlowNodeDefinition,
@@ -121,65 +194,251 @@ macro_rules! define_variadic_parser {
     };
 }
 
+/// Reads the rows of a lookup node: every literal value after the input is a `[key, value]`
+/// pair.
+fn parse_lookup_table(node: &FlowNodeDefinition) -> Result<LookupTable, AstBuildError> {
+    let invalid = |message: String| AstBuildError::InvalidTable {
+        node_id: node.id.clone(),
+        message,
+    };
+    let literals = node.literal_values.as_deref().unwrap_or_default();
+    let mut rows = Vec::new();
+    for (row, literal) in literals.iter().skip(1).enumerate() {
+        let pair = match literal.as_array().map(Vec::as_slice) {
+            Some([key, value]) => key.as_f64().zip(value.as_f64()),
+            _ => None,
+        };
+        let message = || format!("row {} is not a [key, value] pair: {}", row, literal);
+        rows.push(pair.ok_or_else(|| invalid(message()))?);
+    }
+    LookupTable::new(rows).map_err(invalid)
+}
+
+/// Defines a parser for a lookup node, whose only input is handle 0 and whose table is
+/// given by its literal values. See [`parse_lookup_table`].
+macro_rules! define_lookup_parser {
+    ( $struct_name:ident, $node_type:expr, $variant:path ) => {
+        struct $struct_name;
+        impl NodeParser for $struct_name {
+            fn node_type(&self) -> &str {
+                $node_type
+            }
+            fn min_inputs(&self) -> usize {
+                1
+            }
+            fn parse(
+                &self,
+                node: &FlowNodeDefinition,
+                inputs: Vec<Expression>,
+            ) -> Result<Expression, AstBuildError> {
+                let input = require_inputs_at_least(node, inputs, 1)?.swap_remove(0);
+                Ok($variant(Box::new(input), parse_lookup_table(node)?))
+            }
+        }
+    };
+}
+
+/// Parses a `callNode` into a call of the native function named by its first data field,
+/// with its inputs, in handle order, as the arguments.
+struct CallNodeParser;
+
+impl NodeParser for CallNodeParser {
+    fn node_type(&self) -> &str {
+        "callNode"
+    }
+    fn parse(
+        &self,
+        node: &FlowNodeDefinition,
+        inputs: Vec<Expression>,
+    ) -> Result<Expression, AstBuildError> {
+        let name = node
+            .data_fields
+            .as_ref()
+            .and_then(|fields| fields.first())
+            .map(|field| field.name.clone())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| AstBuildError::InvalidFunctionCall {
+                node_id: node.id.clone(),
+                message: "it has no data field naming the function".to_string(),
+            })?;
+        Ok(Expression::Call(name, inputs))
+    }
+}
+
+/// Parses a spatial node. Its data fields name the events it reads, optionally aliased
+/// (`hole#a`), whose position and extent fields become the leading operands. Its inputs,
+/// in handle order, are the remaining operands: the bounds of a `zoneNode` and the board
+/// width and length of an `edgeDistanceNode`.
+struct SpatialNodeParser(SpatialOp);
+
+impl SpatialNodeParser {
+    /// The number of events the operation relates.
+    fn event_count(&self) -> usize {
+        match self.0 {
+            SpatialOp::Distance | SpatialOp::Overlap => 2,
+            SpatialOp::InZone | SpatialOp::EdgeDistance => 1,
+        }
+    }
+
+    /// The fields read from every event, in operand order.
+    fn event_fields(&self) -> &'static [&'static str] {
+        match self.0 {
+            SpatialOp::Distance | SpatialOp::InZone => &[X_FIELD, Y_FIELD],
+            SpatialOp::Overlap | SpatialOp::EdgeDistance => {
+                &[X_FIELD, Y_FIELD, WIDTH_FIELD, LENGTH_FIELD]
+            }
+        }
+    }
+}
+
+impl NodeParser for SpatialNodeParser {
+    fn node_type(&self) -> &str {
+        match self.0 {
+            SpatialOp::Distance => "distanceNode",
+            SpatialOp::Overlap => "overlapNode",
+            SpatialOp::InZone => "zoneNode",
+            SpatialOp::EdgeDistance => "edgeDistanceNode",
+        }
+    }
+    fn min_inputs(&self) -> usize {
+        self.0.arity() - self.event_count() * self.event_fields().len()
+    }
+    fn parse(
+        &self,
+        node: &FlowNodeDefinition,
+        inputs: Vec<Expression>,
+    ) -> Result<Expression, AstBuildError> {
+        let events = node.data_fields.as_deref().unwrap_or_default();
+        if events.len() != self.event_count() {
+            return Err(AstBuildError::InvalidSpatialNode {
+                node_id: node.id.clone(),
+                message: format!(
+                    "it needs {} data field(s) naming events, but has {}",
+                    self.event_count(),
+                    events.len()
+                ),
+            });
+        }
+        if inputs.len() != self.min_inputs() {
+            return Err(AstBuildError::ConnectionError {
+                target_node_id: node.id.clone(),
+                target_handle_index: 0,
+                message: format!(
+                    "{} requires {} inputs, but received {}",
+                    node.operation_type,
+                    self.min_inputs(),
+                    inputs.len()
+                ),
+            });
+        }
+
+        let mut operands = Vec::with_capacity(self.0.arity());
+        for event in events {
+            check_event_alias(&node.id, &event.name)?;
+            operands.extend(self.event_fields().iter().map(|field| {
+                Expression::Input(InputSource::DynamicName {
+                    event: event.name.clone(),
+                    field: field.to_string(),
+                })
+            }));
+        }
+        operands.extend(inputs);
+        Ok(Expression::Spatial(self.0, operands))
+    }
+}
+
 // --- Define all parsers using the new, powerful macro ---
 
 // Logical
-define_variadic_parser!(AndNodeParser, "andNode", Expression::And, Associative);
-define_variadic_parser!(OrNodeParser, "orNode", Expression::Or, Associative);
-define_variadic_parser!(XorNodeParser, "xorNode", Expression::Xor, Associative);
+define_variadic_parser!(AndNodeParser, "andNode", Expression::And, Associative, Or);
+define_variadic_parser!(OrNodeParser, "orNode", Expression::Or, Associative, Or);
+define_variadic_parser!(XorNodeParser, "xorNode", Expression::Xor, Associative, Or);
 
 // Comparison (note the ChainedComparison strategy)
 define_variadic_parser!(
     GtNodeParser,
     "gtNode",
     Expression::GreaterThan,
-    ChainedComparison
+    ChainedComparison,
+    Reject
 );
 define_variadic_parser!(
     StNodeParser,
     "stNode",
     Expression::SmallerThan,
-    ChainedComparison
+    ChainedComparison,
+    Reject
 );
 define_variadic_parser!(
     GteqNodeParser,
     "gteqNode",
     Expression::GreaterThanOrEqual,
-    ChainedComparison
+    ChainedComparison,
+    Reject
 );
 define_variadic_parser!(
     SteqNodeParser,
     "steqNode",
     Expression::SmallerThanOrEqual,
-    ChainedComparison
+    ChainedComparison,
+    Reject
+);
+define_variadic_parser!(
+    EqNodeParser,
+    "eqNode",
+    Expression::Equal,
+    ChainedComparison,
+    Reject
 );
-define_variadic_parser!(EqNodeParser, "eqNode", Expression::Equal, ChainedComparison);
 define_variadic_parser!(
     NeqNodeParser,
     "neqNode",
     Expression::NotEqual,
-    ChainedComparison
+    ChainedComparison,
+    Reject
 );
 
 // Arithmetic
-define_variadic_parser!(SumNodeParser, "sumNode", Expression::Sum, Associative);
-define_variadic_parser!(SubNodeParser, "subNode", Expression::Subtract, Associative);
+define_variadic_parser!(
+    SumNodeParser,
+    "sumNode",
+    Expression::Sum,
+    Associative,
+    Reject
+);
+define_variadic_parser!(
+    SubNodeParser,
+    "subNode",
+    Expression::Subtract,
+    Associative,
+    Reject
+);
 define_variadic_parser!(
     MultNodeParser,
     "multNode",
     Expression::Multiply,
-    Associative
+    Associative,
+    Reject
 );
 define_variadic_parser!(
     DivideNodeParser,
     "divideNode",
     Expression::Divide,
-    Associative
+    Associative,
+    Reject
 );
 
 // Unary
-define_variadic_parser!(NotNodeParser, "notNode", Expression::Not, Unary);
-define_variadic_parser!(AbsNodeParser, "absNode", Expression::Abs, Unary);
+define_variadic_parser!(NotNodeParser, "notNode", Expression::Not, Unary, Or);
+define_variadic_parser!(AbsNodeParser, "absNode", Expression::Abs, Unary, Reject);
+
+// Lookup tables
+define_lookup_parser!(TableNodeParser, "tableNode", Expression::Table);
+define_lookup_parser!(
+    InterpolateNodeParser,
+    "interpolateNode",
+    Expression::Interpolate
+);
 
 /// Adds all defined node parsers to the registry HashMap.
 pub(super) fn register_default_parsers(registry: &mut AHashMap<String, Box<dyn NodeParser>>) {
@@ -198,6 +457,16 @@ pub(super) fn register_default_parsers(registry: &mut AHashMap<String, Box<dyn N
     registry.insert("divideNode".to_string(), Box::new(DivideNodeParser));
     registry.insert("notNode".to_string(), Box::new(NotNodeParser));
     registry.insert("absNode".to_string(), Box::new(AbsNodeParser));
+    registry.insert("tableNode".to_string(), Box::new(TableNodeParser));
+    registry.insert(
+        "interpolateNode".to_string(),
+        Box::new(InterpolateNodeParser),
+    );
+    registry.insert("callNode".to_string(), Box::new(CallNodeParser));
+    for op in SpatialOp::ALL {
+        let parser = SpatialNodeParser(op);
+        registry.insert(parser.node_type().to_string(), Box::new(parser));
+    }
 }
 
 /// Creates a parser instance by its string name, used for type mapping.
@@ -218,6 +487,13 @@ pub(super) fn create_parser_by_name(name: &str) -> Option<Box<dyn NodeParser>> {
         "divideNode" => Some(Box::new(DivideNodeParser)),
         "notNode" => Some(Box::new(NotNodeParser)),
         "absNode" => Some(Box::new(AbsNodeParser)),
+        "tableNode" => Some(Box::new(TableNodeParser)),
+        "interpolateNode" => Some(Box::new(InterpolateNodeParser)),
+        "callNode" => Some(Box::new(CallNodeParser)),
+        "distanceNode" => Some(Box::new(SpatialNodeParser(SpatialOp::Distance))),
+        "overlapNode" => Some(Box::new(SpatialNodeParser(SpatialOp::Overlap))),
+        "zoneNode" => Some(Box::new(SpatialNodeParser(SpatialOp::InZone))),
+        "edgeDistanceNode" => Some(Box::new(SpatialNodeParser(SpatialOp::EdgeDistance))),
         _ => None,
     }
 }
@@ -0,0 +1,91 @@
+//! Predicate pushdown: splitting a quality path by the events its conditions read.
+//!
+//! A path is evaluated for every combination of event instances, so a condition that
+//! only reads one event, like `hole.Diameter > 20`, is better checked once per instance
+//! before the combinations are built. The top-level conjuncts of a path are grouped into
+//! a [`PathSplit`]: those that read no events, those that read a single event name, and
+//! the rest.
+
+use crate::ast::{ExpressionGraph, InputId, InputSource, Node, NodeId};
+use crate::events::PathSplit;
+use ahash::{AHashMap, AHashSet};
+
+/// Splits the path rooted at `root` by the event names its conjuncts read. New `And`
+/// nodes are added to `graph` where several conjuncts end up in the same part.
+///
+/// Returns the split and the number of conjuncts moved into filters.
+pub(super) fn split_path(
+    graph: &mut ExpressionGraph,
+    root: NodeId,
+    dynamic_map: &AHashMap<String, InputId>,
+) -> (PathSplit<NodeId>, usize) {
+    let event_names: AHashMap<InputId, &str> = dynamic_map
+        .iter()
+        .map(|(key, id)| (*id, key.split_once('.').map_or(key.as_str(), |(e, _)| e)))
+        .collect();
+
+    let mut conjuncts = Vec::new();
+    collect_conjuncts(graph, root, &mut conjuncts);
+
+    let mut guard = Vec::new();
+    let mut filters: AHashMap<&str, Vec<NodeId>> = AHashMap::new();
+    let mut residual = Vec::new();
+    for conjunct in conjuncts {
+        let events = read_events(graph, conjunct, &event_names);
+        match events.len() {
+            0 => guard.push(conjunct),
+            1 => filters
+                .entry(events.into_iter().next().unwrap())
+                .or_default()
+                .push(conjunct),
+            _ => residual.push(conjunct),
+        }
+    }
+
+    let pushed_down = filters.values().map(Vec::len).sum();
+    let mut filters: Vec<_> = filters
+        .into_iter()
+        .map(|(event, conjuncts)| (event.to_string(), conjoin(graph, conjuncts).unwrap()))
+        .collect();
+    filters.sort_by(|a, b| a.0.cmp(&b.0));
+    let split = PathSplit {
+        guard: conjoin(graph, guard),
+        filters,
+        residual: conjoin(graph, residual),
+    };
+    (split, pushed_down)
+}
+
+/// Flattens the `And` nodes at the top of `id` into their operands, left to right.
+fn collect_conjuncts(graph: &ExpressionGraph, id: NodeId, out: &mut Vec<NodeId>) {
+    match graph[id] {
+        Node::And(l, r) => {
+            collect_conjuncts(graph, l, out);
+            collect_conjuncts(graph, r, out);
+        }
+        _ => out.push(id),
+    }
+}
+
+/// The event names of the dynamic inputs reachable from `id`.
+fn read_events<'a>(
+    graph: &ExpressionGraph,
+    id: NodeId,
+    event_names: &AHashMap<InputId, &'a str>,
+) -> AHashSet<&'a str> {
+    graph
+        .post_order(id)
+        .into_iter()
+        .filter_map(|node| match graph[node] {
+            Node::Input(InputSource::Dynamic { id }) => event_names.get(&id).copied(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Joins `conjuncts` with `And`, in order. Returns `None` if there are none.
+fn conjoin(graph: &mut ExpressionGraph, conjuncts: Vec<NodeId>) -> Option<NodeId> {
+    conjuncts
+        .into_iter()
+        .reduce(|acc, conjunct| graph.add(Node::And(acc, conjunct)))
+}
@@ -0,0 +1,266 @@
+//! Whole-flow validation that reports every problem at once, without compiling.
+
+use super::parsing::{FanIn, NodeParser, register_default_parsers};
+use super::{
+    CompilerBuilder, OUTPUT_NODE_TYPE, PARAMETER_NODE_TYPE, conflicting_decisions,
+    parameter_definition, resolve_decisions,
+};
+use crate::error::{AstBuildError, FlowDiagnostic};
+use crate::recipe::{Decision, FlowDefinition, Quality, SubFlowDefinition};
+use ahash::{AHashMap, AHashSet};
+use std::collections::BTreeMap;
+
+impl FlowDefinition {
+    /// Checks the flow against the built-in node types and returns every problem found,
+    /// with `qualities` on the first `setQualityNode`.
+    ///
+    /// Use [`CompilerBuilder::validate`] instead when the flow relies on type mappings,
+    /// custom parsers, sub-flows or several decisions.
+    pub fn validate(&self, qualities: &[Quality]) -> Vec<FlowDiagnostic> {
+        let mut registry: AHashMap<String, Box<dyn NodeParser>> = AHashMap::new();
+        register_default_parsers(&mut registry);
+        let decisions = resolve_decisions(self, qualities, &[]);
+        validate_flow(self, decisions.as_deref(), &registry, &AHashMap::new())
+    }
+}
+
+impl CompilerBuilder {
+    /// Checks the flow against the node types registered on this builder and returns
+    /// every problem found. An empty result means the flow is expected to compile.
+    pub fn validate(&self) -> Vec<FlowDiagnostic> {
+        let decisions = resolve_decisions(&self.flow, &self.qualities, &self.decisions);
+        validate_flow(
+            &self.flow,
+            decisions.as_deref(),
+            &self.registry,
+            &self.sub_flows,
+        )
+    }
+}
+
+/// `decisions` is `None` when the flow needs a default decision but has no `setQualityNode`.
+fn validate_flow(
+    flow: &FlowDefinition,
+    decisions: Option<&[Decision]>,
+    registry: &AHashMap<String, Box<dyn NodeParser>>,
+    sub_flows: &AHashMap<String, SubFlowDefinition>,
+) -> Vec<FlowDiagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let mut node_ids = AHashSet::new();
+    let mut reported_duplicates = AHashSet::new();
+    for node in &flow.nodes {
+        if !node_ids.insert(node.id.as_str()) && reported_duplicates.insert(node.id.as_str()) {
+            diagnostics.push(FlowDiagnostic::DuplicateNodeId {
+                node_id: node.id.clone(),
+            });
+        }
+    }
+
+    // Edges between existing nodes, as (edge index, source, source handle, target, target handle).
+    let mut edges = Vec::new();
+    for (index, edge) in flow.edges.iter().enumerate() {
+        let mut valid = true;
+        for node_id in [&edge.source, &edge.target] {
+            if !node_ids.contains(node_id.as_str()) {
+                diagnostics.push(FlowDiagnostic::UnknownEdgeNode {
+                    edge: index,
+                    node_id: node_id.clone(),
+                });
+                valid = false;
+            }
+        }
+        if valid {
+            edges.push((
+                edge.source.as_str(),
+                edge.source_handle,
+                edge.target.as_str(),
+                edge.target_handle,
+            ));
+        }
+    }
+
+    for node in &flow.nodes {
+        let known = matches!(
+            node.operation_type.as_str(),
+            "dynamicNode"
+                | "setQualityNode"
+                | "subFlowInputNode"
+                | OUTPUT_NODE_TYPE
+                | PARAMETER_NODE_TYPE
+        ) || registry.contains_key(&node.operation_type)
+            || sub_flows.contains_key(&node.operation_type);
+        if !known {
+            diagnostics.push(FlowDiagnostic::UnknownOperationType {
+                node_id: node.id.clone(),
+                operation_type: node.operation_type.clone(),
+            });
+        }
+    }
+
+    let Some(decisions) = decisions else {
+        diagnostics.push(FlowDiagnostic::MissingQualityNode);
+        return diagnostics;
+    };
+    let mut quality_nodes = Vec::new();
+    for decision in decisions {
+        let is_quality_node = flow
+            .nodes
+            .iter()
+            .any(|n| n.id == decision.node_id && n.operation_type == "setQualityNode");
+        if is_quality_node {
+            quality_nodes.push(decision.node_id.as_str());
+        } else {
+            diagnostics.push(FlowDiagnostic::InvalidDecisionNode {
+                decision: decision.name.clone(),
+                node_id: decision.node_id.clone(),
+            });
+        }
+    }
+    for (first, second) in conflicting_decisions(decisions) {
+        diagnostics.push(if first.name == second.name {
+            FlowDiagnostic::DuplicateDecision {
+                decision: second.name.clone(),
+            }
+        } else {
+            FlowDiagnostic::SharedDecisionNode {
+                node_id: second.node_id.clone(),
+                first: first.name.clone(),
+                second: second.name.clone(),
+            }
+        });
+    }
+    for node in &flow.nodes {
+        if node.operation_type == "setQualityNode" && !quality_nodes.contains(&node.id.as_str()) {
+            diagnostics.push(FlowDiagnostic::ExtraQualityNode {
+                node_id: node.id.clone(),
+            });
+        }
+    }
+
+    for decision in decisions {
+        if !quality_nodes.contains(&decision.node_id.as_str()) {
+            continue;
+        }
+        for (handle, quality) in decision.qualities.iter().enumerate() {
+            let handle = handle as u32;
+            if !edges.iter().any(|(_, _, target, target_handle)| {
+                *target == decision.node_id && *target_handle == handle
+            }) {
+                diagnostics.push(FlowDiagnostic::UnconnectedQuality {
+                    node_id: decision.node_id.clone(),
+                    handle,
+                    quality: quality.name.clone(),
+                });
+            }
+        }
+    }
+
+    // Everything the quality and output nodes depend on, found by walking the edges backwards.
+    let mut pending = quality_nodes;
+    pending.extend(
+        flow.nodes
+            .iter()
+            .filter(|n| n.operation_type == OUTPUT_NODE_TYPE)
+            .map(|n| n.id.as_str()),
+    );
+    let mut reachable: AHashSet<&str> = pending.iter().copied().collect();
+    while let Some(node_id) = pending.pop() {
+        for (source, _, target, _) in &edges {
+            if *target == node_id && reachable.insert(*source) {
+                pending.push(*source);
+            }
+        }
+    }
+
+    for node in &flow.nodes {
+        if !reachable.contains(node.id.as_str()) {
+            diagnostics.push(FlowDiagnostic::UnreachableNode {
+                node_id: node.id.clone(),
+            });
+            continue;
+        }
+
+        // The required inputs of the node, and its parser if it is not a sub-flow.
+        let signature = match (
+            sub_flows.get(&node.operation_type),
+            registry.get(&node.operation_type),
+        ) {
+            (Some(sub_flow), _) => Some((sub_flow.inputs.len(), None)),
+            (None, Some(parser)) => Some((parser.min_inputs(), Some(parser))),
+            (None, None) => None,
+        };
+        if let Some((min_inputs, parser)) = signature {
+            let literals = node.literal_values.as_deref().unwrap_or_default();
+            for handle in 0..min_inputs as u32 {
+                let has_edge = edges.iter().any(|(_, _, target, target_handle)| {
+                    *target == node.id && *target_handle == handle
+                });
+                let has_literal = literals
+                    .get(handle as usize)
+                    .is_some_and(|value| !value.is_null());
+                if !has_edge && !has_literal {
+                    diagnostics.push(FlowDiagnostic::UnconnectedHandle {
+                        node_id: node.id.clone(),
+                        handle,
+                    });
+                }
+            }
+
+            let mut edges_per_handle: BTreeMap<u32, usize> = BTreeMap::new();
+            for (_, _, target, target_handle) in &edges {
+                if *target == node.id {
+                    *edges_per_handle.entry(*target_handle).or_default() += 1;
+                }
+            }
+            for (handle, count) in edges_per_handle {
+                if count > 1 && parser.is_none_or(|p| p.fan_in(handle) == FanIn::Reject) {
+                    diagnostics.push(FlowDiagnostic::IllegalFanIn {
+                        node_id: node.id.clone(),
+                        handle,
+                        edges: count,
+                    });
+                }
+            }
+        }
+
+        if node.operation_type == OUTPUT_NODE_TYPE {
+            for field in node.data_fields.iter().flatten() {
+                let connected = edges.iter().any(|(_, _, target, target_handle)| {
+                    *target == node.id && *target_handle == field.id
+                });
+                if !connected {
+                    diagnostics.push(FlowDiagnostic::UnconnectedHandle {
+                        node_id: node.id.clone(),
+                        handle: field.id,
+                    });
+                }
+            }
+        }
+
+        if node.operation_type == PARAMETER_NODE_TYPE
+            && let Err(AstBuildError::InvalidParameter { message, .. }) = parameter_definition(node)
+        {
+            diagnostics.push(FlowDiagnostic::InvalidParameter {
+                node_id: node.id.clone(),
+                message,
+            });
+        }
+
+        if node.operation_type == "dynamicNode" {
+            for field in node.data_fields.iter().flatten() {
+                let used = edges.iter().any(|(source, source_handle, _, _)| {
+                    *source == node.id && *source_handle == field.id
+                });
+                if !used {
+                    diagnostics.push(FlowDiagnostic::UnusedDataField {
+                        node_id: node.id.clone(),
+                        field: field.name.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
@@ -27,8 +27,183 @@ pub enum AstBuildError {
         message: String,
     },
 
+    #[error(
+        "Node '{node_id}' has no output handle {handle}, which is used by node '{target_node_id}'"
+    )]
+    UnknownOutputHandle {
+        node_id: String,
+        handle: u32,
+        target_node_id: String,
+    },
+
+    #[error("Sub-flow '{}' includes itself: {}", chain[0], chain.join(" -> "))]
+    RecursiveSubFlow { chain: Vec<String> },
+
+    #[error("Node '{node_id}' does not match the signature of sub-flow '{sub_flow}': {message}")]
+    SubFlowSignatureMismatch {
+        node_id: String,
+        sub_flow: String,
+        message: String,
+    },
+
+    #[error("Parameter node '{node_id}' is invalid: {message}")]
+    InvalidParameter { node_id: String, message: String },
+
+    #[error("Call node '{node_id}' is invalid: {message}")]
+    InvalidFunctionCall { node_id: String, message: String },
+
+    #[error("Spatial node '{node_id}' is invalid: {message}")]
+    InvalidSpatialNode { node_id: String, message: String },
+
+    #[error("Node '{node_id}' reads event '{event}', whose alias or event type is empty")]
+    InvalidEventAlias { node_id: String, event: String },
+
+    #[error("The table of node '{node_id}' is invalid: {message}")]
+    InvalidTable { node_id: String, message: String },
+
+    #[error("Sub-flow input node '{0}' is not bound to an input of a composite node")]
+    UnboundSubFlowInput(String),
+
+    #[error("Decision '{0}' is defined more than once")]
+    DuplicateDecision(String),
+
+    #[error("setQualityNode '{node_id}' is used by both decision '{first}' and '{second}'")]
+    SharedDecisionNode {
+        node_id: String,
+        first: String,
+        second: String,
+    },
+
     #[error("Quality trigger node '{0}' is connected, but was not found in the recipe")]
     QualityTriggerNodeNotFound(String),
+
+    /// The listed nodes feed into each other. The first and last entry are the same node.
+    #[error("The flow contains a cycle: {}", cycle.join(" -> "))]
+    CycleDetected { cycle: Vec<String> },
+
+    #[error("Node '{node_id}' is nested deeper than the maximum flow depth of {max_depth}")]
+    MaxDepthExceeded { node_id: String, max_depth: usize },
+
+    #[error(
+        "Input handle {handle} of node '{node_id}' accepts a single edge, but is connected to {}",
+        sources.join(", ")
+    )]
+    IllegalFanIn {
+        node_id: String,
+        handle: u32,
+        sources: Vec<String>,
+    },
+}
+
+/// A problem in a `FlowDefinition`, as reported by `FlowDefinition::validate`.
+///
+/// Edges are identified by their index in `FlowDefinition::edges`.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FlowDiagnostic {
+    #[error("Node id '{node_id}' is used by more than one node")]
+    DuplicateNodeId { node_id: String },
+
+    #[error("Edge {edge} references node '{node_id}', which does not exist")]
+    UnknownEdgeNode { edge: usize, node_id: String },
+
+    #[error("Node '{node_id}' has an unregistered operation type: '{operation_type}'")]
+    UnknownOperationType {
+        node_id: String,
+        operation_type: String,
+    },
+
+    #[error("Node '{node_id}' is not connected to any quality or output node")]
+    UnreachableNode { node_id: String },
+
+    #[error("Input handle {handle} of node '{node_id}' is not connected")]
+    UnconnectedHandle { node_id: String, handle: u32 },
+
+    #[error("Input handle {handle} of node '{node_id}' accepts a single edge, but has {edges}")]
+    IllegalFanIn {
+        node_id: String,
+        handle: u32,
+        edges: usize,
+    },
+
+    #[error("The flow has no setQualityNode")]
+    MissingQualityNode,
+
+    #[error("Node '{node_id}' is a setQualityNode that no decision uses and is ignored")]
+    ExtraQualityNode { node_id: String },
+
+    #[error("Decision '{decision}' refers to '{node_id}', which is not a setQualityNode")]
+    InvalidDecisionNode { decision: String, node_id: String },
+
+    #[error("Decision '{decision}' is defined more than once")]
+    DuplicateDecision { decision: String },
+
+    #[error("setQualityNode '{node_id}' is used by both decision '{first}' and '{second}'")]
+    SharedDecisionNode {
+        node_id: String,
+        first: String,
+        second: String,
+    },
+
+    #[error("Quality '{quality}' on handle {handle} of node '{node_id}' has no incoming edge")]
+    UnconnectedQuality {
+        node_id: String,
+        handle: u32,
+        quality: String,
+    },
+
+    #[error("Data field '{field}' of node '{node_id}' is never used")]
+    UnusedDataField { node_id: String, field: String },
+
+    #[error("Parameter node '{node_id}' is invalid: {message}")]
+    InvalidParameter { node_id: String, message: String },
+}
+
+/// How serious a `FlowDiagnostic` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The flow does not compile, or compiles into something other than intended.
+    Error,
+    /// The flow compiles, but contains logic or data that has no effect.
+    Warning,
+}
+
+impl FlowDiagnostic {
+    /// The node the problem is located at, if any.
+    pub fn node_id(&self) -> Option<&str> {
+        match self {
+            FlowDiagnostic::DuplicateNodeId { node_id }
+            | FlowDiagnostic::UnknownEdgeNode { node_id, .. }
+            | FlowDiagnostic::UnknownOperationType { node_id, .. }
+            | FlowDiagnostic::UnreachableNode { node_id }
+            | FlowDiagnostic::UnconnectedHandle { node_id, .. }
+            | FlowDiagnostic::IllegalFanIn { node_id, .. }
+            | FlowDiagnostic::ExtraQualityNode { node_id }
+            | FlowDiagnostic::InvalidDecisionNode { node_id, .. }
+            | FlowDiagnostic::SharedDecisionNode { node_id, .. }
+            | FlowDiagnostic::UnconnectedQuality { node_id, .. }
+            | FlowDiagnostic::UnusedDataField { node_id, .. }
+            | FlowDiagnostic::InvalidParameter { node_id, .. } => Some(node_id),
+            FlowDiagnostic::MissingQualityNode | FlowDiagnostic::DuplicateDecision { .. } => None,
+        }
+    }
+
+    /// The index of the edge the problem is located at, if any.
+    pub fn edge(&self) -> Option<usize> {
+        match self {
+            FlowDiagnostic::UnknownEdgeNode { edge, .. } => Some(*edge),
+            _ => None,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            FlowDiagnostic::UnreachableNode { .. }
+            | FlowDiagnostic::ExtraQualityNode { .. }
+            | FlowDiagnostic::UnconnectedQuality { .. }
+            | FlowDiagnostic::UnusedDataField { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
 }
 
 /// Errors that can occur when a backend compiles an AST into an executable format.
@@ -43,6 +218,20 @@ pub enum BackendError {
     #[error("Invalid logic encountered during backend compilation: {0}")]
     InvalidLogic(String),
 
+    #[error("The recipe calls the native function '{0}', which is not registered")]
+    UnknownFunction(String),
+
+    #[error("The data is not a compiled recipe, or was saved before recipes had a format version")]
+    NotACompiledRecipe,
+
+    #[error(
+        "The compiled recipe has format version {found}, but this version of hantei reads version {expected}; compile the recipe again"
+    )]
+    RecipeFormatMismatch { found: u16, expected: u16 },
+
+    #[error("The bytecode of path '{path}' is invalid: {error}")]
+    InvalidBytecode { path: String, error: ProgramError },
+
     #[error("An unexpected backend error occurred: {0}")]
     Generic(String),
 }
@@ -62,6 +251,21 @@ pub enum EvaluationError {
     #[error("Input source '{0}' not found in the provided data context")]
     InputNotFound(String),
 
+    #[error("The recipe has no parameter named '{0}'")]
+    UnknownParameter(String),
+
+    #[error("Native function '{name}' failed: {message}")]
+    NativeFunction { name: String, message: String },
+
+    #[error(
+        "Path '{path}' has {count} combinations of event instances, more than the limit of {limit}"
+    )]
+    TooManyCombinations {
+        path: String,
+        count: usize,
+        limit: usize,
+    },
+
     #[error("A backend evaluation error occurred: {0}")]
     BackendError(String),
 }
@@ -84,6 +288,15 @@ pub enum VmError {
     #[error("Invalid subroutine ID: {0}")]
     UnknownSubroutine(u64),
 
+    #[error("Invalid table ID: {0}")]
+    UnknownTable(u16),
+
+    #[error("Invalid function ID: {0}")]
+    UnknownFunction(u16),
+
+    #[error("Native function '{name}' failed: {message}")]
+    NativeFunction { name: String, message: String },
+
     #[error("Input source '{0}' not found in the provided data context")]
     InputNotFound(String),
 
@@ -92,6 +305,74 @@ pub enum VmError {
 
     #[error("Input ID '{0}' is out of bounds for the provided data vector")]
     InputIdOutOfBounds(u16),
+
+    #[error("Invalid program: {0}")]
+    InvalidProgram(#[from] ProgramError),
+}
+
+/// A reason the VM cannot run a `BytecodeProgram`, as reported by
+/// `BytecodeProgram::validate`.
+///
+/// Chunks are named as in the assembly format, `.main` or `.sub <id>`, and instructions
+/// by their index in the chunk.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ProgramError {
+    #[error("Instruction {index} of {chunk} uses register R{register}, which does not exist")]
+    InvalidRegister {
+        chunk: String,
+        index: usize,
+        register: usize,
+    },
+
+    #[error("Instruction {index} of {chunk} jumps to @{address}, outside of the chunk")]
+    JumpOutOfBounds {
+        chunk: String,
+        index: usize,
+        address: u16,
+    },
+
+    #[error("Instruction {index} of {chunk} calls subroutine #{id}, which does not exist")]
+    UnknownSubroutine {
+        chunk: String,
+        index: usize,
+        id: u64,
+    },
+
+    #[error("Instruction {index} of {chunk} reads table T{id}, which does not exist")]
+    UnknownTable {
+        chunk: String,
+        index: usize,
+        id: u16,
+    },
+
+    #[error("Instruction {index} of {chunk} calls function F{id}, which does not exist")]
+    UnknownFunction {
+        chunk: String,
+        index: usize,
+        id: u16,
+    },
+
+    #[error("{chunk} does not end with Halt or Return")]
+    MissingTerminator { chunk: String },
+}
+
+/// Errors that can occur when parsing textual bytecode assembly.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum AssemblyError {
+    #[error("Line {line}: {message}")]
+    InvalidSyntax { line: usize, message: String },
+
+    #[error("Line {line}: unknown instruction '{mnemonic}'")]
+    UnknownInstruction { line: usize, mnemonic: String },
+
+    #[error("Line {line}: section '{section}' is defined more than once")]
+    DuplicateSection { line: usize, section: String },
+
+    #[error("The program has no .main section")]
+    MissingMain,
+
+    #[error("Invalid program: {0}")]
+    InvalidProgram(#[from] ProgramError),
 }
 
 /// Errors that can occur when converting a custom user format into a Hantei `FlowDefinition`.
@@ -99,4 +380,7 @@ pub enum VmError {
 pub enum RecipeConversionError {
     #[error("Invalid custom data format: {0}")]
     ValidationError(String),
+
+    #[error("Handle '{handle}' of node '{node_id}' is not in the expected format")]
+    InvalidHandle { node_id: String, handle: String },
 }
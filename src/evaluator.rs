@@ -1,8 +1,10 @@
+use crate::ast::Value;
 use crate::backend::{BackendChoice, EvaluationBackend, ExecutableRecipe};
 use crate::compiler::CompilationArtifacts;
 use crate::error::{BackendError, EvaluationError};
-pub use crate::interpreter::EvaluationResult;
-use crate::recipe::CompiledRecipe;
+use crate::functions::FunctionRegistry;
+pub use crate::interpreter::{DecisionResult, EvaluationResult};
+use crate::recipe::{CompiledRecipe, RecipeParameter};
 use ahash::AHashMap;
 
 /// The main entry point for evaluating compiled recipes against data.
@@ -19,47 +21,69 @@ impl Evaluator {
         choice: BackendChoice,
         artifacts: Vec<CompilationArtifacts>,
     ) -> Result<Self, BackendError> {
-        let backend: Box<dyn EvaluationBackend> = match choice {
-            BackendChoice::Interpreter => Box::new(crate::interpreter::InterpreterBackend),
-            BackendChoice::Bytecode => Box::new(crate::bytecode::BytecodeBackend),
-        };
+        Self::with_functions(choice, artifacts, &FunctionRegistry::new())
+    }
 
+    /// Like [`Self::new`], for recipes that call native functions. Fails with
+    /// `BackendError::UnknownFunction` if the recipe calls a function not in `functions`.
+    pub fn with_functions(
+        choice: BackendChoice,
+        artifacts: Vec<CompilationArtifacts>,
+        functions: &FunctionRegistry,
+    ) -> Result<Self, BackendError> {
+        let backend = backend(choice);
         let compiled_recipe = backend.compile(artifacts)?;
-        let executable = backend.load(compiled_recipe)?;
+        let executable = backend.load(compiled_recipe, functions)?;
 
         Ok(Self { executable })
     }
+
     /// Creates a new evaluator from a compiled recipe loaded from a file.
     pub fn from_file(choice: BackendChoice, path: &str) -> Result<Self, BackendError> {
         let recipe = CompiledRecipe::from_file(path)?;
-        Self::from_compiled_recipe(choice, recipe)
+        Self::from_compiled_recipe(choice, recipe, &FunctionRegistry::new())
     }
 
     /// Creates a new evaluator from a compiled recipe provided as bytes.
     pub fn from_bytes(choice: BackendChoice, bytes: &[u8]) -> Result<Self, BackendError> {
         let recipe = CompiledRecipe::from_bytes(bytes)?;
-        Self::from_compiled_recipe(choice, recipe)
+        Self::from_compiled_recipe(choice, recipe, &FunctionRegistry::new())
     }
 
-    /// Internal helper to create an evaluator from a compiled recipe.
-    fn from_compiled_recipe(
+    /// Creates a new evaluator from a compiled recipe, resolving the native functions it
+    /// calls against `functions`.
+    pub fn from_compiled_recipe(
         choice: BackendChoice,
         recipe: CompiledRecipe,
+        functions: &FunctionRegistry,
     ) -> Result<Self, BackendError> {
-        let backend: Box<dyn EvaluationBackend> = match choice {
-            BackendChoice::Interpreter => Box::new(crate::interpreter::InterpreterBackend),
-            BackendChoice::Bytecode => Box::new(crate::bytecode::BytecodeBackend),
-        };
-        let executable = backend.load(recipe)?;
+        let executable = backend(choice).load(recipe, functions)?;
         Ok(Self { executable })
     }
 
+    /// The parameters of the recipe, indexed by id, with their defaults.
+    pub fn parameters(&self) -> &[RecipeParameter] {
+        self.executable.parameters()
+    }
+
     /// Evaluates the compiled recipe against the provided data.
+    ///
+    /// `parameters` overrides the defaults of recipe parameters by name; every parameter
+    /// not in it keeps its default.
     pub fn eval(
         &self,
         static_data: &AHashMap<String, f64>,
         dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>,
+        parameters: &AHashMap<String, Value>,
     ) -> Result<EvaluationResult, EvaluationError> {
-        self.executable.evaluate(static_data, dynamic_data)
+        self.executable
+            .evaluate(static_data, dynamic_data, parameters)
+    }
+}
+
+fn backend(choice: BackendChoice) -> Box<dyn EvaluationBackend> {
+    match choice {
+        BackendChoice::Interpreter => Box::new(crate::interpreter::InterpreterBackend),
+        BackendChoice::Bytecode => Box::new(crate::bytecode::BytecodeBackend),
     }
 }
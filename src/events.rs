@@ -0,0 +1,319 @@
+//! How the instances of dynamic events are combined when a path is evaluated.
+//!
+//! A path is evaluated once for every combination of one instance per event it reads.
+//! An event may be read under several aliases, written `event#alias` (e.g. `hole#a` and
+//! `hole#b`), to relate two instances of the same event type, such as two holes closer
+//! than 50 mm. Aliases of the same event never pick the same instance, and with
+//! [`EventPairing::Unordered`] each pair of instances is only tried in one order.
+//!
+//! An event without instances is absent rather than fatal: the path is still evaluated,
+//! with every field of the event reading as null. A null makes the conditions on it
+//! unknown, and an unknown condition never triggers a quality, but an `OR` with a condition
+//! that holds still does. So `$Humidity > 12 OR hole.Diameter > 30` triggers on a board
+//! without holes when the humidity is 15.
+//!
+//! Most conditions of a path only read one event, like `hole.Diameter > 20`. The compiler
+//! splits such conditions off into a [`PathSplit`], so that the instances of each event are
+//! filtered before they are combined and only the survivors are paired up. An event whose
+//! conditions were all split off is not combined at all: any instance that passed its
+//! filters will do.
+
+use crate::ast::InputId;
+use crate::error::{AstBuildError, EvaluationError};
+use ahash::{AHashMap, AHashSet};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+/// Separates an event type from its alias, as in `hole#a`.
+pub const EVENT_ALIAS_SEPARATOR: char = '#';
+
+/// The default of [`CombinationPolicy::max_combinations`].
+pub const DEFAULT_MAX_COMBINATIONS: usize = 1_000_000;
+
+/// Splits an event name into its event type and alias: `hole#a` into `("hole", Some("a"))`
+/// and `hole` into `("hole", None)`.
+pub fn split_event_alias(event: &str) -> (&str, Option<&str>) {
+    match event.split_once(EVENT_ALIAS_SEPARATOR) {
+        Some((event_type, alias)) => (event_type, Some(alias)),
+        None => (event, None),
+    }
+}
+
+/// Fails if `event` has an alias separator but an empty event type or alias.
+pub(crate) fn check_event_alias(node_id: &str, event: &str) -> Result<(), AstBuildError> {
+    match split_event_alias(event) {
+        (event_type, Some(alias)) if event_type.is_empty() || alias.is_empty() => {
+            Err(AstBuildError::InvalidEventAlias {
+                node_id: node_id.to_string(),
+                event: event.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Which combinations of instances are tried for aliases of the same event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventPairing {
+    /// Every arrangement of distinct instances, e.g. both (hole 1, hole 2) and
+    /// (hole 2, hole 1). Needed when the rule is not symmetric, like "a is larger than b".
+    #[default]
+    Ordered,
+    /// Each set of distinct instances once, with the aliases in alphabetical order taking
+    /// instances in data order. Halves the work for symmetric rules, like distances.
+    Unordered,
+}
+
+/// How a recipe combines event instances. Set with `CompilerBuilder::with_event_pairing`
+/// and `CompilerBuilder::with_max_combinations`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CombinationPolicy {
+    pub pairing: EventPairing,
+    /// The most candidate combinations (the product of the instance counts of the events
+    /// a path reads, after its single-event conditions filtered them) that a single path
+    /// may evaluate. Beyond it, evaluation fails with
+    /// `EvaluationError::TooManyCombinations` instead of running for a quadratic, or worse,
+    /// number of steps.
+    pub max_combinations: usize,
+}
+
+impl Default for CombinationPolicy {
+    fn default() -> Self {
+        Self {
+            pairing: EventPairing::default(),
+            max_combinations: DEFAULT_MAX_COMBINATIONS,
+        }
+    }
+}
+
+/// A quality path split into the conjuncts that can be evaluated before the instances of
+/// its events are combined, and the rest. The path holds exactly when every part holds;
+/// a missing part always holds.
+///
+/// `T` is the form of each part: a graph node in the compiler, an expression in the
+/// interpreter or a bytecode program in the VM.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathSplit<T> {
+    /// The conjuncts that read no events, evaluated once.
+    pub guard: Option<T>,
+    /// For every event name, aliases included, the conjuncts that read only that event,
+    /// evaluated once per instance. Sorted by event name.
+    pub filters: Vec<(String, T)>,
+    /// The conjuncts that relate several events, evaluated per combination of instances
+    /// that passed their filters.
+    pub residual: Option<T>,
+}
+
+impl<T> PathSplit<T> {
+    /// A path that is not split: everything is evaluated per combination.
+    pub fn whole(root: T) -> Self {
+        Self {
+            guard: None,
+            filters: Vec::new(),
+            residual: Some(root),
+        }
+    }
+
+    /// Converts every part with `f`.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> PathSplit<U> {
+        match self.try_map(|part| Ok::<_, std::convert::Infallible>(f(part))) {
+            Ok(split) => split,
+        }
+    }
+
+    /// Converts every part with `f`, failing on the first error.
+    pub fn try_map<U, E>(self, mut f: impl FnMut(T) -> Result<U, E>) -> Result<PathSplit<U>, E> {
+        Ok(PathSplit {
+            guard: self.guard.map(&mut f).transpose()?,
+            filters: self
+                .filters
+                .into_iter()
+                .map(|(event, filter)| Ok((event, f(filter)?)))
+                .collect::<Result<_, E>>()?,
+            residual: self.residual.map(&mut f).transpose()?,
+        })
+    }
+}
+
+/// An instance of an event, with its index in the event's data, or `None` if the event
+/// has no instances.
+type Candidate<'a> = Option<(usize, &'a AHashMap<String, f64>)>;
+
+/// The event names, aliases included, of the entries of `dynamic_map` with an id in `ids`.
+pub(crate) fn event_names(
+    dynamic_map: &AHashMap<String, InputId>,
+    ids: &AHashSet<InputId>,
+) -> AHashSet<String> {
+    dynamic_map
+        .iter()
+        .filter(|(_, id)| ids.contains(id))
+        .map(|(key, _)| key.split_once('.').unwrap().0.to_string())
+        .collect()
+}
+
+/// The instances of every event a path reads, by the event names of its dynamic map.
+pub(crate) struct EventCombinations<'a> {
+    /// Event names, aliases included, in alphabetical order.
+    names: Vec<&'a str>,
+    /// The candidate instances of each event name. An event without instances has a
+    /// single `None` candidate: it is absent.
+    instances: Vec<Vec<Candidate<'a>>>,
+    /// Pairs of indices into `names` that are aliases of the same event.
+    same_event: Vec<(usize, usize)>,
+    /// The event names that are no longer combined, with the instance that every
+    /// combination uses for them.
+    fixed: Vec<(&'a str, Candidate<'a>)>,
+    pairing: EventPairing,
+}
+
+impl<'a> EventCombinations<'a> {
+    pub(crate) fn new(
+        dynamic_map: &'a AHashMap<String, InputId>,
+        dynamic_data: &'a AHashMap<String, Vec<AHashMap<String, f64>>>,
+        pairing: EventPairing,
+    ) -> Self {
+        let mut names: Vec<&str> = dynamic_map
+            .keys()
+            .map(|key| key.split_once('.').unwrap().0)
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        let instances = names
+            .iter()
+            .map(|name| {
+                let (event_type, _) = split_event_alias(name);
+                match dynamic_data.get(event_type) {
+                    Some(instances) if !instances.is_empty() => {
+                        instances.iter().enumerate().map(Some).collect()
+                    }
+                    _ => vec![None],
+                }
+            })
+            .collect();
+        Self {
+            same_event: same_event(&names),
+            names,
+            instances,
+            fixed: Vec::new(),
+            pairing,
+        }
+    }
+
+    /// Keeps only the candidates of event `name` for which `keep` returns true, where
+    /// `None` stands for the event being absent. Does nothing if the path does not read the
+    /// event. Once every candidate of an event is rejected, there are no combinations.
+    pub(crate) fn retain<E>(
+        &mut self,
+        name: &str,
+        mut keep: impl FnMut(Option<&AHashMap<String, f64>>) -> Result<bool, E>,
+    ) -> Result<(), E> {
+        let Ok(position) = self.names.binary_search(&name) else {
+            return Ok(());
+        };
+        let mut kept = Vec::new();
+        for candidate in std::mem::take(&mut self.instances[position]) {
+            if keep(candidate.map(|(_, instance)| instance))? {
+                kept.push(candidate);
+            }
+        }
+        self.instances[position] = kept;
+        Ok(())
+    }
+
+    /// Stops combining the event names that are not in `combined`, typically those the
+    /// residual of a path does not read, once their filters have run. Each of them takes
+    /// its first remaining candidate in every combination instead.
+    ///
+    /// Events without candidates left, and aliases of events that still need distinct
+    /// instances, stay combined.
+    pub(crate) fn combine_only(&mut self, combined: &AHashSet<String>) {
+        let aliased: AHashSet<usize> = self.same_event.iter().flat_map(|&(i, j)| [i, j]).collect();
+        let mut names = Vec::new();
+        let mut instances = Vec::new();
+        for (i, (name, candidates)) in self
+            .names
+            .drain(..)
+            .zip(std::mem::take(&mut self.instances))
+            .enumerate()
+        {
+            if combined.contains(name) || aliased.contains(&i) || candidates.is_empty() {
+                names.push(name);
+                instances.push(candidates);
+            } else {
+                self.fixed.push((name, candidates[0]));
+            }
+        }
+        self.same_event = same_event(&names);
+        self.names = names;
+        self.instances = instances;
+    }
+
+    /// Fails if the path has more candidate combinations than `limit`.
+    pub(crate) fn check_limit(&self, path: &str, limit: usize) -> Result<(), EvaluationError> {
+        let count = self.instances.iter().fold(1usize, |count, instances| {
+            count.saturating_mul(instances.len())
+        });
+        if count > limit {
+            return Err(EvaluationError::TooManyCombinations {
+                path: path.to_string(),
+                count,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Every allowed combination, as the instance of each event name that is not absent.
+    /// A path that reads no events has a single, empty combination.
+    pub(crate) fn iter(
+        &self,
+    ) -> Box<dyn Iterator<Item = AHashMap<&'a str, &'a AHashMap<String, f64>>> + '_> {
+        let fixed = self
+            .fixed
+            .iter()
+            .filter_map(|(name, candidate)| candidate.map(|(_, instance)| (*name, instance)));
+        if self.names.is_empty() {
+            return Box::new(std::iter::once(fixed.collect()));
+        }
+        Box::new(
+            self.instances
+                .iter()
+                .map(|instances| 0..instances.len())
+                .multi_cartesian_product()
+                .filter(|positions| self.is_allowed(positions))
+                .map(move |positions| {
+                    self.names
+                        .iter()
+                        .zip(&self.instances)
+                        .zip(positions)
+                        .filter_map(|((name, instances), position)| {
+                            instances[position].map(|(_, instance)| (*name, instance))
+                        })
+                        .chain(fixed.clone())
+                        .collect()
+                }),
+        )
+    }
+
+    /// Aliases of the same event take distinct instances, in increasing order if the
+    /// pairing is unordered. `positions` index the candidates of every event name.
+    fn is_allowed(&self, positions: &[usize]) -> bool {
+        let index = |event: usize| self.instances[event][positions[event]].map(|(i, _)| i);
+        self.same_event
+            .iter()
+            .all(|&(i, j)| match (index(i), index(j), self.pairing) {
+                (Some(i), Some(j), EventPairing::Ordered) => i != j,
+                (Some(i), Some(j), EventPairing::Unordered) => i < j,
+                // Aliases of an absent event are all absent.
+                _ => true,
+            })
+    }
+}
+
+/// Pairs of indices into `names` that are aliases of the same event.
+fn same_event(names: &[&str]) -> Vec<(usize, usize)> {
+    (0..names.len())
+        .tuple_combinations()
+        .filter(|&(i, j)| split_event_alias(names[i]).0 == split_event_alias(names[j]).0)
+        .collect()
+}
@@ -0,0 +1,82 @@
+//! Native functions that recipes can call by name.
+//!
+//! A flow refers to a native function only by name, through a `callNode` or an
+//! `Expression::Call`. The host registers the implementations in a [`FunctionRegistry`],
+//! and every name a recipe calls is resolved against it when the `Evaluator` is loaded,
+//! so a recipe that needs a missing function fails there rather than mid-evaluation.
+//!
+//! ```rust
+//! use hantei::ast::Value;
+//! use hantei::functions::FunctionRegistry;
+//!
+//! let functions = FunctionRegistry::new().with_function("hypot", |args: &[Value]| {
+//!     match args {
+//!         [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a.hypot(*b))),
+//!         _ => Err(format!("expected two numbers, got {:?}", args)),
+//!     }
+//! });
+//! assert!(functions.get("hypot").is_some());
+//! ```
+
+use crate::ast::Value;
+use crate::error::BackendError;
+use ahash::AHashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The implementation of a native function. It receives the values of the call's
+/// arguments in order, and returns its result or a message explaining why it failed.
+///
+/// Functions must be pure: backends may evaluate a call any number of times, including
+/// not at all when its result cannot affect the outcome.
+pub type NativeFunction = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+/// A set of native functions, by name.
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: AHashMap<String, NativeFunction>,
+}
+
+impl FunctionRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `function` under `name`, replacing any function of that name.
+    pub fn with_function(
+        mut self,
+        name: impl Into<String>,
+        function: impl Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.functions.insert(name.into(), Arc::new(function));
+        self
+    }
+
+    /// The function registered under `name`.
+    pub fn get(&self, name: &str) -> Option<&NativeFunction> {
+        self.functions.get(name)
+    }
+
+    /// The names of all registered functions, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.functions.keys().map(|name| name.as_str())
+    }
+
+    /// Looks up a function a recipe calls, failing if the host did not register it.
+    pub(crate) fn resolve(&self, name: &str) -> Result<NativeFunction, BackendError> {
+        self.get(name)
+            .cloned()
+            .ok_or_else(|| BackendError::UnknownFunction(name.to_string()))
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.names().collect();
+        names.sort_unstable();
+        f.debug_struct("FunctionRegistry")
+            .field("functions", &names)
+            .finish()
+    }
+}
@@ -1,14 +1,24 @@
-use crate::ast::{EvaluationTrace, Expression, InputId, InputSource, Value};
+use crate::ast::{EvaluationTrace, Expression, InputId, InputSource, SpatialOp, Value};
 use crate::error::EvaluationError;
+use crate::functions::NativeFunction;
+use crate::recipe::RecipeParameter;
 use ahash::AHashMap;
 
 /// The core recursive engine for evaluating a single, fully-contextualized AST.
+///
+/// A null value, such as a field of an absent event, is unknown: operations on it yield
+/// null, except that `AND` with a false operand is false and `OR` with a true one is true.
 pub(super) struct AstEngine<'a> {
     expression: &'a Expression,
     static_data: &'a [Value],
     dynamic_context: &'a [Value],
     static_rev_map: &'a AHashMap<InputId, String>,
     dynamic_rev_map: &'a AHashMap<InputId, String>,
+    /// The value of every parameter by id, and the parameters for their names.
+    parameter_values: &'a [Value],
+    parameters: &'a [RecipeParameter],
+    /// The native functions the expression may call, by name.
+    functions: Option<&'a AHashMap<String, NativeFunction>>,
 }
 
 impl<'a> AstEngine<'a> {
@@ -18,6 +28,8 @@ impl<'a> AstEngine<'a> {
         dynamic_context: &'a [Value],
         static_rev_map: &'a AHashMap<InputId, String>,
         dynamic_rev_map: &'a AHashMap<InputId, String>,
+        parameter_values: &'a [Value],
+        parameters: &'a [RecipeParameter],
     ) -> Self {
         Self {
             expression,
@@ -25,9 +37,21 @@ impl<'a> AstEngine<'a> {
             dynamic_context,
             static_rev_map,
             dynamic_rev_map,
+            parameter_values,
+            parameters,
+            functions: None,
         }
     }
 
+    /// Makes `functions` available to the `Call` nodes of the expression.
+    pub(super) fn with_functions(
+        mut self,
+        functions: &'a AHashMap<String, NativeFunction>,
+    ) -> Self {
+        self.functions = Some(functions);
+        self
+    }
+
     /// Evaluates the AST and returns a trace of the execution.
     pub(super) fn evaluate(&self) -> Result<EvaluationTrace, EvaluationError> {
         self.evaluate_recursive(self.expression)
@@ -50,6 +74,7 @@ impl<'a> AstEngine<'a> {
                 let child_trace = self.evaluate_recursive(v)?;
                 let outcome = match child_trace.get_outcome() {
                     Value::Number(val) => Value::Number(val.abs()),
+                    Value::Null => Value::Null,
                     val => return Err(self.type_mismatch("ABS", "Number", val)),
                 };
                 Ok(EvaluationTrace::UnaryOp {
@@ -69,7 +94,10 @@ impl<'a> AstEngine<'a> {
             Expression::Equal(l, r) => {
                 let left_trace = self.evaluate_recursive(l)?;
                 let right_trace = self.evaluate_recursive(r)?;
-                let outcome = Value::Bool(left_trace.get_outcome() == right_trace.get_outcome());
+                let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
+                    (Value::Null, _) | (_, Value::Null) => Value::Null,
+                    (l_val, r_val) => Value::Bool(l_val == r_val),
+                };
                 Ok(EvaluationTrace::BinaryOp {
                     op_symbol: "==",
                     left: Box::new(left_trace),
@@ -80,7 +108,10 @@ impl<'a> AstEngine<'a> {
             Expression::NotEqual(l, r) => {
                 let left_trace = self.evaluate_recursive(l)?;
                 let right_trace = self.evaluate_recursive(r)?;
-                let outcome = Value::Bool(left_trace.get_outcome() != right_trace.get_outcome());
+                let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
+                    (Value::Null, _) | (_, Value::Null) => Value::Null,
+                    (l_val, r_val) => Value::Bool(l_val != r_val),
+                };
                 Ok(EvaluationTrace::BinaryOp {
                     op_symbol: "!=",
                     left: Box::new(left_trace),
@@ -103,6 +134,9 @@ impl<'a> AstEngine<'a> {
                 let right_trace = self.evaluate_recursive(r)?;
                 let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
                     (Value::Bool(lv), Value::Bool(rv)) => Value::Bool(lv && rv),
+                    // Unknown AND false is false; any other unknown operand leaves it unknown.
+                    (Value::Null, Value::Bool(false)) => Value::Bool(false),
+                    (Value::Bool(_) | Value::Null, Value::Bool(_) | Value::Null) => Value::Null,
                     (l_val, _) => return Err(self.type_mismatch("AND", "Bool", l_val)),
                 };
                 Ok(EvaluationTrace::BinaryOp {
@@ -125,6 +159,9 @@ impl<'a> AstEngine<'a> {
                 let right_trace = self.evaluate_recursive(r)?;
                 let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
                     (Value::Bool(lv), Value::Bool(rv)) => Value::Bool(lv || rv),
+                    // Unknown OR true is true; any other unknown operand leaves it unknown.
+                    (Value::Null, Value::Bool(true)) => Value::Bool(true),
+                    (Value::Bool(_) | Value::Null, Value::Bool(_) | Value::Null) => Value::Null,
                     (l_val, _) => return Err(self.type_mismatch("OR", "Bool", l_val)),
                 };
                 Ok(EvaluationTrace::BinaryOp {
@@ -138,6 +175,7 @@ impl<'a> AstEngine<'a> {
                 let child_trace = self.evaluate_recursive(v)?;
                 let outcome = match child_trace.get_outcome() {
                     Value::Bool(val) => Value::Bool(!val),
+                    Value::Null => Value::Null,
                     val => return Err(self.type_mismatch("NOT", "Bool", val)),
                 };
                 Ok(EvaluationTrace::UnaryOp {
@@ -151,6 +189,7 @@ impl<'a> AstEngine<'a> {
                 let right_trace = self.evaluate_recursive(r)?;
                 let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
                     (Value::Bool(lv), Value::Bool(rv)) => Value::Bool(lv ^ rv),
+                    (Value::Bool(_) | Value::Null, Value::Bool(_) | Value::Null) => Value::Null,
                     (Value::Bool(_), r_val) => return Err(self.type_mismatch("XOR", "Bool", r_val)),
                     (l_val, _) => return Err(self.type_mismatch("XOR", "Bool", l_val)),
                 };
@@ -162,6 +201,18 @@ impl<'a> AstEngine<'a> {
                 })
             }
 
+            // --- Lookup Tables ---
+            Expression::Table(v, table) => self.eval_lookup(v, "TABLE", |x| table.step(x)),
+            Expression::Interpolate(v, table) => {
+                self.eval_lookup(v, "INTERPOLATE", |x| table.interpolate(x))
+            }
+
+            // --- Native Functions ---
+            Expression::Call(name, args) => self.eval_call(name, args),
+
+            // --- Spatial Predicates ---
+            Expression::Spatial(op, args) => self.eval_spatial(*op, args),
+
             // --- Other Operations ---
             Expression::Literal(val) => Ok(EvaluationTrace::Leaf {
                 source: val.to_string(),
@@ -191,6 +242,18 @@ impl<'a> AstEngine<'a> {
                         })?;
                         (format!("${}", field_name), val.clone())
                     }
+                    InputSource::Parameter { id } => {
+                        let name = self
+                            .parameters
+                            .get(*id as usize)
+                            .map(|p| p.name.as_str())
+                            .unwrap_or("?Unknown?");
+                        let val = self
+                            .parameter_values
+                            .get(*id as usize)
+                            .ok_or_else(|| EvaluationError::UnknownParameter(name.to_string()))?;
+                        (format!("@{}", name), val.clone())
+                    }
                     // These should have been converted to ID-based variants during compilation
                     InputSource::StaticName { name } => {
                         return Err(EvaluationError::BackendError(format!(
@@ -204,6 +267,12 @@ impl<'a> AstEngine<'a> {
                             event, field
                         )));
                     }
+                    InputSource::ParameterName { name } => {
+                        return Err(EvaluationError::BackendError(format!(
+                            "Encountered uninterned parameter '{}' during evaluation",
+                            name
+                        )));
+                    }
                 };
                 Ok(EvaluationTrace::Leaf {
                     source: source_str,
@@ -227,6 +296,7 @@ impl<'a> AstEngine<'a> {
         let right_trace = self.evaluate_recursive(r)?;
         let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
             (Value::Number(lv), Value::Number(rv)) => Value::Number(f(lv, rv)),
+            (Value::Null, _) | (_, Value::Null) => Value::Null,
             (l_val, _) => return Err(self.type_mismatch(op, "Number", l_val)),
         };
         Ok(EvaluationTrace::BinaryOp {
@@ -251,6 +321,7 @@ impl<'a> AstEngine<'a> {
         let right_trace = self.evaluate_recursive(r)?;
         let outcome = match (left_trace.get_outcome(), right_trace.get_outcome()) {
             (Value::Number(lv), Value::Number(rv)) => Value::Bool(f(lv, rv)),
+            (Value::Null, _) | (_, Value::Null) => Value::Null,
             (l_val, _) => return Err(self.type_mismatch(op, "Number", l_val)),
         };
         Ok(EvaluationTrace::BinaryOp {
@@ -261,6 +332,91 @@ impl<'a> AstEngine<'a> {
         })
     }
 
+    fn eval_lookup<'t, F>(
+        &self,
+        v: &Expression,
+        op: &'static str,
+        lookup: F,
+    ) -> Result<EvaluationTrace, EvaluationError>
+    where
+        F: Fn(f64) -> (f64, &'t [(f64, f64)]),
+    {
+        let input_trace = self.evaluate_recursive(v)?;
+        let (outcome, rows) = match input_trace.get_outcome() {
+            Value::Number(x) => {
+                let (outcome, rows) = lookup(x);
+                (Value::Number(outcome), rows.to_vec())
+            }
+            Value::Null => (Value::Null, Vec::new()),
+            val => return Err(self.type_mismatch(op, "Number", val)),
+        };
+        Ok(EvaluationTrace::Lookup {
+            op_symbol: op,
+            input: Box::new(input_trace),
+            rows,
+            outcome,
+        })
+    }
+
+    fn eval_call(
+        &self,
+        name: &str,
+        args: &[Expression],
+    ) -> Result<EvaluationTrace, EvaluationError> {
+        let function = self
+            .functions
+            .and_then(|functions| functions.get(name))
+            .ok_or_else(|| {
+                EvaluationError::BackendError(format!(
+                    "Native function '{}' was not resolved when the recipe was loaded",
+                    name
+                ))
+            })?;
+        let arg_traces = args
+            .iter()
+            .map(|arg| self.evaluate_recursive(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let values: Vec<Value> = arg_traces.iter().map(|t| t.get_outcome()).collect();
+        let outcome = function(&values).map_err(|message| EvaluationError::NativeFunction {
+            name: name.to_string(),
+            message,
+        })?;
+        Ok(EvaluationTrace::Call {
+            function: name.to_string(),
+            args: arg_traces,
+            outcome,
+        })
+    }
+
+    fn eval_spatial(
+        &self,
+        op: SpatialOp,
+        args: &[Expression],
+    ) -> Result<EvaluationTrace, EvaluationError> {
+        let arg_traces = args
+            .iter()
+            .map(|arg| self.evaluate_recursive(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let numbers = arg_traces
+            .iter()
+            .map(|trace| match trace.get_outcome() {
+                Value::Number(n) => Ok(Some(n)),
+                Value::Null => Ok(None),
+                val => Err(self.type_mismatch(op.symbol(), "Number", val)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // A missing coordinate, such as that of an absent event, makes the result missing.
+        let outcome = match numbers.into_iter().collect::<Option<Vec<_>>>() {
+            Some(numbers) => op.apply(&numbers),
+            None => Value::Null,
+        };
+        Ok(EvaluationTrace::Call {
+            function: op.symbol().to_string(),
+            args: arg_traces,
+            outcome,
+        })
+    }
+
     fn type_mismatch(&self, op: &str, expected: &str, found: Value) -> EvaluationError {
         EvaluationError::TypeMismatch {
             operation: op.to_string(),
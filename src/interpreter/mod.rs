@@ -1,22 +1,82 @@
-use crate::ast::{Expression, InputId, InputSource, Value};
-use crate::backend::{EvaluationBackend, ExecutableRecipe};
-use crate::compiler::CompilationArtifacts;
+use crate::ast::{
+    EvaluationTrace, Expression, ExpressionVisitor, InputId, InputSource, Value, walk_expression,
+};
+use crate::backend::{
+    EvaluationBackend, ExecutableRecipe, agreed_output_value, group_by_decision,
+    recipe_combinations, recipe_parameters, resolve_parameters,
+};
+use crate::compiler::{CompilationArtifacts, PathKind};
 use crate::error::{BackendError, EvaluationError};
-use crate::recipe::{CompiledPathInterpreter, CompiledRecipe};
+use crate::events::{CombinationPolicy, EventCombinations, PathSplit, event_names};
+use crate::functions::{FunctionRegistry, NativeFunction};
+use crate::recipe::{CompiledPathInterpreter, CompiledRecipe, RecipeParameter};
 use crate::trace::TraceFormatter;
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use itertools::Itertools;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 
 mod engine;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct EvaluationResult {
     pub quality_name: Option<String>,
     pub quality_priority: Option<i32>,
     pub reason: String,
+    /// The outcome of every decision of the recipe, in compilation order. The fields above
+    /// repeat the first of them, which is the only one for single-decision recipes.
+    pub decisions: Vec<DecisionResult>,
+    /// The value of every output of the recipe, by name. An output that reads events is
+    /// null unless all combinations of their instances give it the same value.
+    pub outputs: AHashMap<String, Value>,
+}
+
+impl EvaluationResult {
+    pub(crate) fn new(decisions: Vec<DecisionResult>, outputs: AHashMap<String, Value>) -> Self {
+        let (quality_name, quality_priority, reason) = match decisions.first() {
+            Some(first) => (
+                first.quality_name.clone(),
+                first.quality_priority,
+                first.reason.clone(),
+            ),
+            None => (None, None, NO_QUALITY_TRIGGERED.to_string()),
+        };
+        Self {
+            quality_name,
+            quality_priority,
+            reason,
+            decisions,
+            outputs,
+        }
+    }
+
+    /// The outcome of the decision named `name`.
+    pub fn decision(&self, name: &str) -> Option<&DecisionResult> {
+        self.decisions.iter().find(|d| d.name == name)
+    }
 }
 
+/// The outcome of a single decision: its highest-priority quality that was triggered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionResult {
+    pub name: String,
+    pub quality_name: Option<String>,
+    pub quality_priority: Option<i32>,
+    pub reason: String,
+}
+
+impl DecisionResult {
+    pub(crate) fn not_triggered(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            quality_name: None,
+            quality_priority: None,
+            reason: NO_QUALITY_TRIGGERED.to_string(),
+        }
+    }
+}
+
+const NO_QUALITY_TRIGGERED: &str = "No quality triggered";
+
 pub struct InterpreterBackend;
 
 impl EvaluationBackend for InterpreterBackend {
@@ -24,174 +84,298 @@ impl EvaluationBackend for InterpreterBackend {
         &self,
         artifacts: Vec<CompilationArtifacts>,
     ) -> Result<CompiledRecipe, BackendError> {
+        let parameters = recipe_parameters(&artifacts);
+        let combinations = recipe_combinations(&artifacts);
         let interpreter_paths = artifacts
             .into_iter()
             .map(|artifact| {
-                let mut visited = HashMap::new();
-                let linked_ast = link_ast(&artifact.ast, &artifact.definitions, &mut visited)?;
+                // The interpreter walks a plain tree, so shared nodes are expanded here.
                 Ok(CompiledPathInterpreter {
+                    kind: artifact.kind,
+                    decision: artifact.decision,
                     priority: artifact.priority,
                     name: artifact.name,
-                    ast: linked_ast,
+                    ast: artifact.graph.to_expression(artifact.root),
                     static_map: artifact.static_map,
                     dynamic_map: artifact.dynamic_map,
+                    split: artifact
+                        .split
+                        .map(|part| artifact.graph.to_expression(part)),
                 })
             })
             .collect::<Result<_, BackendError>>()?;
 
         // Return a CompiledRecipe, which can contain interpreter paths.
-        Ok(CompiledRecipe::new(Some(interpreter_paths), None))
+        Ok(CompiledRecipe::new(
+            Some(interpreter_paths),
+            None,
+            parameters,
+            combinations,
+        ))
     }
 
     fn load(
         &self,
         recipe: crate::recipe::CompiledRecipe,
+        functions: &FunctionRegistry,
     ) -> Result<Box<dyn ExecutableRecipe>, BackendError> {
         let paths = recipe.interpreter_paths.ok_or_else(|| {
             BackendError::InvalidLogic(
                 "Recipe file does not contain interpreter artifacts".to_string(),
             )
         })?;
-        let executable_paths = paths
+        let decisions = group_by_decision(
+            paths
+                .iter()
+                .map(|p| (p.kind == PathKind::Quality).then_some(p.decision.as_str())),
+        );
+        let outputs = paths
+            .iter()
+            .positions(|p| p.kind == PathKind::Output)
+            .collect();
+        let mut calls = FunctionCollector::default();
+        for path in &paths {
+            calls.visit_expression(&path.ast);
+        }
+        let functions = calls
+            .names
             .into_iter()
-            .map(|p| (p.priority, p.name, p.ast, p.static_map, p.dynamic_map))
+            .map(|name| Ok((name.clone(), functions.resolve(&name)?)))
+            .collect::<Result<_, BackendError>>()?;
+        let combined_events = paths
+            .iter()
+            .map(|path| {
+                let mut reads = DynamicInputCollector::default();
+                if let Some(residual) = &path.split.residual {
+                    reads.visit_expression(residual);
+                }
+                event_names(&path.dynamic_map, &reads.ids)
+            })
             .collect();
         Ok(Box::new(AstExecutable {
-            paths: executable_paths,
+            paths,
+            combined_events,
+            decisions,
+            outputs,
+            parameters: recipe.parameters,
+            functions,
+            combinations: recipe.combinations,
         }))
     }
 }
 
 struct AstExecutable {
-    paths: Vec<(
-        i32,
-        String,
-        Expression,
-        AHashMap<String, InputId>,
-        AHashMap<String, InputId>,
-    )>,
+    paths: Vec<CompiledPathInterpreter>,
+    /// For every path, the events its residual relates, the only ones whose instances
+    /// are combined.
+    combined_events: Vec<AHashSet<String>>,
+    /// The indices into `paths` of each decision, in priority order.
+    decisions: Vec<(String, Vec<usize>)>,
+    /// The indices into `paths` of the outputs.
+    outputs: Vec<usize>,
+    parameters: Vec<RecipeParameter>,
+    /// The native functions the paths call, by name.
+    functions: AHashMap<String, NativeFunction>,
+    combinations: CombinationPolicy,
+}
+
+/// Collects the names of the native functions an expression calls.
+#[derive(Default)]
+struct FunctionCollector {
+    names: HashSet<String>,
+}
+
+impl ExpressionVisitor for FunctionCollector {
+    fn visit_expression(&mut self, expr: &Expression) {
+        if let Expression::Call(name, _) = expr {
+            self.names.insert(name.clone());
+        }
+        walk_expression(self, expr);
+    }
 }
 
-fn is_purely_static(expr: &Expression) -> bool {
-    match expr {
-        Expression::Input(InputSource::Dynamic { .. }) => false,
-        Expression::Sum(l, r)
-        | Expression::Subtract(l, r)
-        | Expression::Multiply(l, r)
-        | Expression::Divide(l, r)
-        | Expression::And(l, r)
-        | Expression::Or(l, r)
-        | Expression::Xor(l, r)
-        | Expression::Equal(l, r)
-        | Expression::NotEqual(l, r)
-        | Expression::GreaterThan(l, r)
-        | Expression::GreaterThanOrEqual(l, r)
-        | Expression::SmallerThan(l, r)
-        | Expression::SmallerThanOrEqual(l, r) => is_purely_static(l) && is_purely_static(r),
-        Expression::Not(v) | Expression::Abs(v) => is_purely_static(v),
-        _ => true,
+/// Collects the ids of the dynamic inputs an expression reads.
+#[derive(Default)]
+struct DynamicInputCollector {
+    ids: AHashSet<InputId>,
+}
+
+impl ExpressionVisitor for DynamicInputCollector {
+    fn visit_input(&mut self, source: &InputSource) {
+        if let InputSource::Dynamic { id } = source {
+            self.ids.insert(*id);
+        }
     }
 }
 
 impl ExecutableRecipe for AstExecutable {
+    fn parameters(&self) -> &[RecipeParameter] {
+        &self.parameters
+    }
+
     fn evaluate(
         &self,
         static_data: &AHashMap<String, f64>,
         dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>,
+        parameters: &AHashMap<String, Value>,
     ) -> Result<EvaluationResult, EvaluationError> {
-        let maybe_result =
-            self.paths
-                .iter()
-                .find_map(|(priority, name, ast, static_map, dynamic_map)| {
-                    let static_vec = match prepare_static_data(static_map, static_data) {
-                        Ok(v) => v,
-                        Err(e) => return Some(Err(e)),
-                    };
-                    let static_rev_map: AHashMap<InputId, String> =
-                        static_map.iter().map(|(k, v)| (*v, k.clone())).collect();
-                    let dynamic_rev_map: AHashMap<InputId, String> =
-                        dynamic_map.iter().map(|(k, v)| (*v, k.clone())).collect();
+        let parameter_values = resolve_parameters(&self.parameters, parameters)?;
+        let decisions = self
+            .decisions
+            .iter()
+            .map(|(decision, indices)| {
+                let maybe_result = indices.iter().find_map(|&i| {
+                    let path = &self.paths[i];
+                    let result = self.first_match(
+                        path,
+                        &self.combined_events[i],
+                        static_data,
+                        dynamic_data,
+                        &parameter_values,
+                    );
+                    result.transpose().map(|trace| {
+                        trace.map(|trace| DecisionResult {
+                            name: decision.clone(),
+                            quality_name: Some(path.name.clone()),
+                            quality_priority: Some(path.priority),
+                            reason: TraceFormatter::format_trace(&trace),
+                        })
+                    })
+                });
 
-                    if let Expression::And(l, r) = ast {
-                        for branch in [l.as_ref(), r.as_ref()] {
-                            if is_purely_static(branch) {
-                                let empty_dynamic_vec = Vec::new();
-                                let engine = engine::AstEngine::new(
-                                    branch,
-                                    &static_vec,
-                                    &empty_dynamic_vec,
-                                    &static_rev_map,
-                                    &dynamic_rev_map,
-                                );
-                                match engine.evaluate() {
-                                    Ok(trace)
-                                        if matches!(trace.get_outcome(), Value::Bool(false)) =>
-                                    {
-                                        return None;
-                                    }
-                                    Err(e) => return Some(Err(e)),
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
+                match maybe_result {
+                    Some(Ok(result)) => Ok(result),
+                    Some(Err(e)) => Err(e),
+                    None => Ok(DecisionResult::not_triggered(decision)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
-                    let (event_names, event_instances) =
-                        prepare_dynamic_events(dynamic_map, dynamic_data);
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|&i| {
+                let CompiledPathInterpreter {
+                    name,
+                    ast,
+                    static_map,
+                    dynamic_map,
+                    ..
+                } = &self.paths[i];
+                let static_vec = prepare_static_data(static_map, static_data)?;
+                let static_rev_map: AHashMap<InputId, String> =
+                    static_map.iter().map(|(k, v)| (*v, k.clone())).collect();
+                let dynamic_rev_map: AHashMap<InputId, String> =
+                    dynamic_map.iter().map(|(k, v)| (*v, k.clone())).collect();
+                let events =
+                    EventCombinations::new(dynamic_map, dynamic_data, self.combinations.pairing);
+                events.check_limit(name, self.combinations.max_combinations)?;
+                // An output has a value only if every combination of instances agrees on it.
+                let values = events.iter().map(|context_map| {
+                    let dynamic_vec = prepare_dynamic_context(dynamic_map, &context_map);
+                    let engine = engine::AstEngine::new(
+                        ast,
+                        &static_vec,
+                        &dynamic_vec,
+                        &static_rev_map,
+                        &dynamic_rev_map,
+                        &parameter_values,
+                        &self.parameters,
+                    )
+                    .with_functions(&self.functions);
+                    Ok(engine.evaluate()?.get_outcome())
+                });
+                Ok((name.clone(), agreed_output_value(values)?))
+            })
+            .collect::<Result<_, EvaluationError>>()?;
 
-                    if event_instances.iter().any(|v| v.is_empty()) {
-                        return None;
-                    }
+        Ok(EvaluationResult::new(decisions, outputs))
+    }
+}
 
-                    let combinations_iterator: Box<
-                        dyn Iterator<Item = Vec<&AHashMap<String, f64>>>,
-                    > = if event_instances.is_empty() {
-                        Box::new(std::iter::once(Vec::new()))
-                    } else {
-                        Box::new(event_instances.into_iter().multi_cartesian_product())
-                    };
+impl AstExecutable {
+    /// The trace of the first combination of event instances for which a quality path
+    /// holds, if any.
+    ///
+    /// The conjuncts of the path's split are checked first: those that read no events
+    /// once, and those that read a single event once per instance, dropping the instances
+    /// they reject. Only the events in `combined_events` are combined, and only the
+    /// remaining combinations are evaluated. The whole path is evaluated again on the one
+    /// that matches, for the trace.
+    fn first_match(
+        &self,
+        path: &CompiledPathInterpreter,
+        combined_events: &AHashSet<String>,
+        static_data: &AHashMap<String, f64>,
+        dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>,
+        parameter_values: &[Value],
+    ) -> Result<Option<EvaluationTrace>, EvaluationError> {
+        let static_vec = prepare_static_data(&path.static_map, static_data)?;
+        let static_rev_map: AHashMap<InputId, String> = path
+            .static_map
+            .iter()
+            .map(|(k, v)| (*v, k.clone()))
+            .collect();
+        let dynamic_rev_map: AHashMap<InputId, String> = path
+            .dynamic_map
+            .iter()
+            .map(|(k, v)| (*v, k.clone()))
+            .collect();
+        let evaluate = |expr: &Expression, dynamic_vec: &[Value]| {
+            engine::AstEngine::new(
+                expr,
+                &static_vec,
+                dynamic_vec,
+                &static_rev_map,
+                &dynamic_rev_map,
+                parameter_values,
+                &self.parameters,
+            )
+            .with_functions(&self.functions)
+            .evaluate()
+        };
+        let holds = |part: &Expression, dynamic_vec: &[Value]| {
+            evaluate(part, dynamic_vec).map(|trace| trace.get_outcome() == Value::Bool(true))
+        };
+        let PathSplit {
+            guard,
+            filters,
+            residual,
+        } = &path.split;
 
-                    for combination in combinations_iterator {
-                        let context_map: AHashMap<&str, &AHashMap<String, f64>> = event_names
-                            .iter()
-                            .map(|s| s.as_str())
-                            .zip(combination.into_iter())
-                            .collect();
+        if let Some(guard) = guard
+            && !holds(guard, &[])?
+        {
+            return Ok(None);
+        }
 
-                        // Now we pass a reference to the map we just built.
-                        let dynamic_vec = prepare_dynamic_context(dynamic_map, &context_map);
-                        let engine = engine::AstEngine::new(
-                            ast,
-                            &static_vec,
-                            &dynamic_vec,
-                            &static_rev_map,
-                            &dynamic_rev_map,
-                        );
-                        match engine.evaluate() {
-                            Ok(trace) if matches!(trace.get_outcome(), Value::Bool(true)) => {
-                                let reason = TraceFormatter::format_trace(&trace);
-                                return Some(Ok(EvaluationResult {
-                                    quality_name: Some(name.clone()),
-                                    quality_priority: Some(*priority),
-                                    reason,
-                                }));
-                            }
-                            Err(e) => return Some(Err(e)),
-                            _ => {} // Continue to next combination
-                        }
-                    }
-                    None // No combination triggered this quality path
-                });
+        let mut events =
+            EventCombinations::new(&path.dynamic_map, dynamic_data, self.combinations.pairing);
+        for (event, filter) in filters {
+            events.retain(event, |instance| {
+                let context = AHashMap::from_iter(instance.map(|i| (event.as_str(), i)));
+                holds(
+                    filter,
+                    &prepare_dynamic_context(&path.dynamic_map, &context),
+                )
+            })?;
+        }
+        events.combine_only(combined_events);
+        events.check_limit(&path.name, self.combinations.max_combinations)?;
 
-        match maybe_result {
-            Some(Ok(result)) => Ok(result),
-            Some(Err(e)) => Err(e),
-            None => Ok(EvaluationResult {
-                quality_name: None,
-                quality_priority: None,
-                reason: "No quality triggered".to_string(),
-            }),
+        for context_map in events.iter() {
+            let dynamic_vec = prepare_dynamic_context(&path.dynamic_map, &context_map);
+            if let Some(residual) = residual
+                && !holds(residual, &dynamic_vec)?
+            {
+                continue;
+            }
+            let trace = evaluate(&path.ast, &dynamic_vec)?;
+            if trace.get_outcome() == Value::Bool(true) {
+                return Ok(Some(trace));
+            }
         }
+        Ok(None) // No combination triggered this quality path
     }
 }
 
@@ -217,134 +401,17 @@ fn prepare_dynamic_context(
     map: &AHashMap<String, InputId>,
     context: &AHashMap<&str, &AHashMap<String, f64>>,
 ) -> Vec<Value> {
-    let mut vec = vec![Value::Null; map.len()];
+    // Ids need not be contiguous, since every path only keeps the events it reads. Those
+    // of absent events and fields must still read as null.
+    let len = map.values().max().map_or(0, |&id| id as usize + 1);
+    let mut vec = vec![Value::Null; len];
     for (key, &id) in map {
         let (event_name, field_name) = key.split_once('.').unwrap();
         if let Some(instance) = context.get(event_name) {
             if let Some(value) = instance.get(field_name) {
-                if id as usize >= vec.len() {
-                    vec.resize((id + 1) as usize, Value::Null);
-                }
                 vec[id as usize] = Value::Number(*value);
             }
         }
     }
     vec
 }
-
-fn prepare_dynamic_events<'a>(
-    dynamic_map: &AHashMap<String, InputId>,
-    dynamic_data: &'a AHashMap<String, Vec<AHashMap<String, f64>>>,
-) -> (Vec<String>, Vec<Vec<&'a AHashMap<String, f64>>>) {
-    let required_events: HashSet<&str> = dynamic_map
-        .keys()
-        .map(|k| k.split_once('.').unwrap().0)
-        .collect();
-    if required_events.is_empty() {
-        return (Vec::new(), Vec::new());
-    }
-
-    let event_names: Vec<String> = required_events.into_iter().map(|s| s.to_string()).collect();
-    let mut event_instances = Vec::with_capacity(event_names.len());
-
-    for event_name in &event_names {
-        match dynamic_data.get(event_name) {
-            Some(instances) => {
-                event_instances.push(instances.iter().collect());
-            }
-            None => {
-                event_instances.push(Vec::new());
-            }
-        }
-    }
-    (event_names, event_instances)
-}
-
-fn link_ast(
-    expr: &Expression,
-    definitions: &AHashMap<u64, Expression>,
-    visited: &mut HashMap<u64, Expression>,
-) -> Result<Expression, BackendError> {
-    match expr {
-        Expression::Reference(id) => {
-            if let Some(cached) = visited.get(id) {
-                return Ok(cached.clone());
-            }
-            let def = definitions.get(id).ok_or_else(|| {
-                BackendError::InvalidLogic(format!(
-                    "CSE Reference ID #{} not found during linking",
-                    id
-                ))
-            })?;
-            let linked_def = link_ast(def, definitions, visited)?;
-            visited.insert(*id, linked_def.clone());
-            Ok(linked_def)
-        }
-        // --- Nodes with Children ---
-        Expression::Sum(l, r) => Ok(Expression::Sum(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Subtract(l, r) => Ok(Expression::Subtract(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Multiply(l, r) => Ok(Expression::Multiply(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Divide(l, r) => Ok(Expression::Divide(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Abs(v) => Ok(Expression::Abs(Box::new(link_ast(
-            v,
-            definitions,
-            visited,
-        )?))),
-        Expression::Not(v) => Ok(Expression::Not(Box::new(link_ast(
-            v,
-            definitions,
-            visited,
-        )?))),
-        Expression::And(l, r) => Ok(Expression::And(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Or(l, r) => Ok(Expression::Or(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Xor(l, r) => Ok(Expression::Xor(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::Equal(l, r) => Ok(Expression::Equal(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::NotEqual(l, r) => Ok(Expression::NotEqual(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::GreaterThan(l, r) => Ok(Expression::GreaterThan(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::GreaterThanOrEqual(l, r) => Ok(Expression::GreaterThanOrEqual(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::SmallerThan(l, r) => Ok(Expression::SmallerThan(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-        Expression::SmallerThanOrEqual(l, r) => Ok(Expression::SmallerThanOrEqual(
-            Box::new(link_ast(l, definitions, visited)?),
-            Box::new(link_ast(r, definitions, visited)?),
-        )),
-
-        // --- Leaf Nodes (no children to link) ---
-        Expression::Literal(_) | Expression::Input(_) => Ok(expr.clone()),
-    }
-}
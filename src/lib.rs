@@ -34,7 +34,7 @@
 //!
 //!     // Evaluate the data
 //!     println!("Evaluating data...");
-//!     let result = evaluator.eval(&static_data, &dynamic_data)?;
+//!     let result = evaluator.eval(&static_data, &dynamic_data, &AHashMap::new())?;
 //!
 //!     // Print the result
 //!     if let Some(name) = result.quality_name {
@@ -55,6 +55,8 @@ pub mod compiler;
 pub mod data;
 pub mod error;
 pub mod evaluator;
+pub mod events;
+pub mod functions;
 pub mod interpreter;
 pub mod prelude;
 pub mod recipe;
@@ -26,14 +26,16 @@
 // Core compilation and evaluation
 pub use crate::compiler::{Compiler, CompilerBuilder};
 pub use crate::evaluator::Evaluator;
-pub use crate::interpreter::EvaluationResult;
+pub use crate::functions::FunctionRegistry;
+pub use crate::interpreter::{DecisionResult, EvaluationResult};
 
 // AST and expression types
 pub use crate::ast::{EvaluationTrace, Expression, InputSource, Value};
 
 // Recipe data structures and traits
 pub use crate::recipe::{
-    DataFieldDefinition, FlowDefinition, FlowEdgeDefinition, FlowNodeDefinition, IntoFlow, Quality,
+    DataFieldDefinition, Decision, FlowDefinition, FlowEdgeDefinition, FlowNodeDefinition,
+    IntoFlow, Quality, SubFlowDefinition, SubFlowOutput,
 };
 
 // Runtime data model
@@ -41,7 +43,8 @@ pub use crate::data::SampleData;
 
 // Error types
 pub use crate::error::{
-    AstBuildError, BackendError, EvaluationError, RecipeConversionError, VmError,
+    AstBuildError, BackendError, EvaluationError, FlowDiagnostic, ProgramError,
+    RecipeConversionError, VmError,
 };
 
 // Trace formatting
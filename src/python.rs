@@ -1,14 +1,16 @@
+use crate::ast::Value;
 use crate::backend::BackendChoice;
 use crate::compiler::Compiler;
 use crate::error::RecipeConversionError;
 use crate::evaluator::Evaluator;
 use crate::interpreter::EvaluationResult as RustEvaluationResult;
 use crate::recipe::{
-    DataFieldDefinition, FlowDefinition, FlowEdgeDefinition, FlowNodeDefinition, IntoFlow, Quality,
+    DataFieldDefinition, FlowDefinition, FlowEdgeDefinition, FlowNodeDefinition, IntoFlow,
+    QualifiedHandle, Quality,
 };
 use ahash::AHashMap;
 use pyo3::prelude::*;
-use pyo3::types::PyDict;
+use pyo3::types::{PyBool, PyDict};
 use std::collections::HashMap;
 
 // --- JSON Deserialization Structs ---
@@ -97,13 +99,16 @@ impl IntoFlow for json_models::RawRecipe {
         let edges = self
             .edges
             .into_iter()
-            .map(|raw_edge| FlowEdgeDefinition {
-                source: raw_edge.source,
-                source_handle: raw_edge.source_handle,
-                target: raw_edge.target,
-                target_handle: raw_edge.target_handle,
+            .map(|raw_edge| {
+                FlowEdgeDefinition::from_handle_strings(
+                    raw_edge.source,
+                    &raw_edge.source_handle,
+                    raw_edge.target,
+                    &raw_edge.target_handle,
+                    &QualifiedHandle,
+                )
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
 
         Ok(FlowDefinition { nodes, edges })
     }
@@ -118,6 +123,8 @@ struct PyEvaluationResult {
     quality_priority: Option<i32>,
     #[pyo3(get)]
     reason: String,
+    #[pyo3(get)]
+    outputs: HashMap<String, Option<f64>>,
 }
 
 #[pymethods]
@@ -133,6 +140,15 @@ impl From<RustEvaluationResult> for PyEvaluationResult {
             quality_name: res.quality_name,
             quality_priority: res.quality_priority,
             reason: res.reason,
+            // Outputs are numeric; anything else is reported as `None`.
+            outputs: res
+                .outputs
+                .into_iter()
+                .map(|(name, value)| match value {
+                    Value::Number(n) => (name, Some(n)),
+                    _ => (name, None),
+                })
+                .collect(),
         }
     }
 }
@@ -188,10 +204,14 @@ impl HanteiPy {
     }
 
     /// Evaluates the compiled recipe against the provided data.
+    ///
+    /// `parameters` optionally overrides recipe parameters by name.
+    #[pyo3(signature = (static_data_py, dynamic_data_py, parameters_py=None))]
     fn evaluate(
         &self,
         static_data_py: &Bound<'_, PyDict>,
         dynamic_data_py: &Bound<'_, PyDict>,
+        parameters_py: Option<&Bound<'_, PyDict>>,
     ) -> PyResult<PyEvaluationResult> {
         let static_data_std: HashMap<String, f64> = static_data_py.extract()?;
         let dynamic_data_std: HashMap<String, Vec<HashMap<String, f64>>> =
@@ -211,9 +231,21 @@ impl HanteiPy {
             })
             .collect();
 
+        let mut parameters: AHashMap<String, Value> = AHashMap::new();
+        if let Some(parameters_py) = parameters_py {
+            for (key, value) in parameters_py.iter() {
+                // Python bools are ints, so check for them before extracting a float.
+                let value = match value.downcast::<PyBool>() {
+                    Ok(flag) => Value::Bool(flag.is_true()),
+                    Err(_) => Value::Number(value.extract()?),
+                };
+                parameters.insert(key.extract()?, value);
+            }
+        }
+
         let result = self
             .evaluator
-            .eval(&static_data, &dynamic_data)
+            .eval(&static_data, &dynamic_data, &parameters)
             .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
 
         // Convert the internal Rust result into the Python class and return
@@ -1,5 +1,6 @@
 use crate::bytecode::compiler::BytecodeProgram;
 use crate::error::BackendError;
+use crate::events::{CombinationPolicy, PathSplit};
 use ahash::AHashMap;
 use bincode::config::standard;
 use bincode::serde::{decode_from_slice, encode_to_vec};
@@ -7,43 +8,88 @@ use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::{Read, Write};
 
+/// The bytes every saved recipe starts with, followed by its format version.
+const RECIPE_MAGIC: &[u8; 4] = b"HNTI";
+
+/// The version of the saved recipe format. Increase it whenever a change to the recipe
+/// types, like a new field or enum variant, means older recipes no longer decode.
+pub const RECIPE_FORMAT_VERSION: u16 = 1;
+
+/// A named recipe parameter and the value it has unless it is overridden when evaluating.
+/// Its id is its index in the recipe's parameter list, and its type that of `default`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecipeParameter {
+    pub name: String,
+    pub default: crate::ast::Value,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CompiledPathInterpreter {
+    pub kind: crate::compiler::PathKind,
+    pub decision: String,
     pub priority: i32,
     pub name: String,
     pub ast: crate::ast::Expression,
     pub static_map: AHashMap<String, crate::ast::InputId>,
     pub dynamic_map: AHashMap<String, crate::ast::InputId>,
+    /// The parts of `ast` checked before and while combining event instances.
+    pub split: PathSplit<crate::ast::Expression>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CompiledPathBytecode {
+    pub kind: crate::compiler::PathKind,
+    pub decision: String,
     pub priority: i32,
     pub name: String,
     pub program: BytecodeProgram,
+    /// The parts of `program` checked before and while combining event instances, each
+    /// compiled on its own.
+    pub split: PathSplit<BytecodeProgram>,
 }
 
+/// The compiled paths of a recipe. Quality paths of all decisions are stored side by side,
+/// each tagged with the name of its decision and ordered by priority within it, followed
+/// by the paths of the recipe's outputs.
 #[derive(Serialize, Deserialize)]
 pub struct CompiledRecipe {
     pub interpreter_paths: Option<Vec<CompiledPathInterpreter>>,
     pub bytecode_programs: Option<Vec<CompiledPathBytecode>>,
+    /// The parameters the paths read, with their defaults.
+    pub parameters: Vec<RecipeParameter>,
+    /// How the paths combine event instances.
+    pub combinations: CombinationPolicy,
 }
 
 impl CompiledRecipe {
     pub fn new(
         interpreter_paths: Option<Vec<CompiledPathInterpreter>>,
         bytecode_programs: Option<Vec<CompiledPathBytecode>>,
+        parameters: Vec<RecipeParameter>,
+        combinations: CombinationPolicy,
     ) -> Self {
         Self {
             interpreter_paths,
             bytecode_programs,
+            parameters,
+            combinations,
         }
     }
 
-    /// Saves the compiled recipe to a file using the bincode format.
-    pub fn save(&self, path: &str) -> Result<(), BackendError> {
-        let bytes = encode_to_vec(self, standard())
+    /// Serializes the compiled recipe using the bincode format, after a header with the
+    /// [`RECIPE_FORMAT_VERSION`].
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BackendError> {
+        let mut bytes = RECIPE_MAGIC.to_vec();
+        bytes.extend_from_slice(&RECIPE_FORMAT_VERSION.to_le_bytes());
+        let body = encode_to_vec(self, standard())
             .map_err(|e| BackendError::Generic(format!("Serialization failed: {}", e)))?;
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    /// Saves the compiled recipe to a file, as written by [`Self::to_bytes`].
+    pub fn save(&self, path: &str) -> Result<(), BackendError> {
+        let bytes = self.to_bytes()?;
         let mut file = fs::File::create(path).map_err(|e| {
             BackendError::Generic(format!("Could not create file '{}': {}", path, e))
         })?;
@@ -64,9 +110,25 @@ impl CompiledRecipe {
         Self::from_bytes(&bytes)
     }
 
-    /// Deserializes a compiled recipe from a byte slice.
+    /// Deserializes a compiled recipe from a byte slice written by [`Self::to_bytes`].
+    ///
+    /// Fails with `BackendError::RecipeFormatMismatch` if the recipe was saved with another
+    /// format version, rather than misreading it.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, BackendError> {
-        decode_from_slice(bytes, standard())
+        let body = bytes
+            .strip_prefix(RECIPE_MAGIC)
+            .ok_or(BackendError::NotACompiledRecipe)?;
+        let (version, body) = body
+            .split_first_chunk::<2>()
+            .ok_or(BackendError::NotACompiledRecipe)?;
+        let version = u16::from_le_bytes(*version);
+        if version != RECIPE_FORMAT_VERSION {
+            return Err(BackendError::RecipeFormatMismatch {
+                found: version,
+                expected: RECIPE_FORMAT_VERSION,
+            });
+        }
+        decode_from_slice(body, standard())
             .map(|(recipe, _)| recipe) // bincode 2 returns a tuple (data, bytes_read)
             .map_err(|e| BackendError::Generic(format!("Deserialization failed: {}", e)))
     }
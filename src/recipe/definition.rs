@@ -25,10 +25,33 @@ pub struct DataFieldDefinition {
 }
 
 /// Defines a connection between two nodes in the logic flow.
+///
+/// Handles are identified by index: output handles of a data node by the `id` of their
+/// `DataFieldDefinition`, input handles of an operation by their position.
 #[derive(Debug, Clone)]
 pub struct FlowEdgeDefinition {
     pub source: String,
-    pub source_handle: String,
+    pub source_handle: u32,
     pub target: String,
-    pub target_handle: String,
+    pub target_handle: u32,
+}
+
+/// A reusable flow that other flows include as a single composite node.
+///
+/// A node whose `operation_type` is `name` is expanded into a copy of `flow`. Input handle
+/// `i` of that node feeds the `subFlowInputNode` with id `inputs[i]`, and output handle `i`
+/// of that node is `outputs[i]`.
+#[derive(Debug, Clone)]
+pub struct SubFlowDefinition {
+    pub name: String,
+    pub flow: FlowDefinition,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<SubFlowOutput>,
+}
+
+/// An output of a sub-flow: output handle `handle` of the node `node_id` inside it.
+#[derive(Debug, Clone)]
+pub struct SubFlowOutput {
+    pub node_id: String,
+    pub handle: u32,
 }
@@ -0,0 +1,90 @@
+//! Conversion of string handle ids, as written by flow editors, into handle indices.
+//!
+//! `FlowEdgeDefinition` stores handle indices directly. Recipe formats that identify
+//! handles by strings convert them with [`FlowEdgeDefinition::from_handle_strings`] and a
+//! [`HandleParser`] that matches their naming scheme.
+
+use super::definition::FlowEdgeDefinition;
+use crate::error::RecipeConversionError;
+
+/// Extracts the index of a node handle from its string id.
+pub trait HandleParser {
+    /// Returns the index encoded in `handle`, a handle of the node `node_id`, or `None`
+    /// if `handle` is not in the expected format.
+    fn parse_handle(&self, handle: &str, node_id: &str) -> Option<u32>;
+}
+
+impl<F: Fn(&str, &str) -> Option<u32>> HandleParser for F {
+    fn parse_handle(&self, handle: &str, node_id: &str) -> Option<u32> {
+        self(handle, node_id)
+    }
+}
+
+/// Handles of the form `<kind>-<kind>-<node id>-<index>`, e.g. `bool-bool-0009-1`, as
+/// written by the flow editor. The node id must be the id of the handle's own node.
+pub struct QualifiedHandle;
+
+impl HandleParser for QualifiedHandle {
+    fn parse_handle(&self, handle: &str, node_id: &str) -> Option<u32> {
+        let (head, index) = handle.rsplit_once('-')?;
+        let kinds = head.strip_suffix(node_id)?.strip_suffix('-')?;
+        let (kind, data_type) = kinds.split_once('-')?;
+        if kind.is_empty() || data_type.is_empty() || data_type.contains('-') {
+            return None;
+        }
+        parse_index(index)
+    }
+}
+
+/// Handles whose index follows the last `-`, e.g. `input-1`.
+pub struct TrailingIndex;
+
+impl HandleParser for TrailingIndex {
+    fn parse_handle(&self, handle: &str, _node_id: &str) -> Option<u32> {
+        let (_, index) = handle.rsplit_once('-')?;
+        parse_index(index)
+    }
+}
+
+/// Handles that consist of the index only, e.g. `1`.
+pub struct PlainIndex;
+
+impl HandleParser for PlainIndex {
+    fn parse_handle(&self, handle: &str, _node_id: &str) -> Option<u32> {
+        parse_index(handle)
+    }
+}
+
+/// Parses a non-empty run of ASCII digits. Unlike `str::parse`, rejects a leading `+`.
+fn parse_index(index: &str) -> Option<u32> {
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    index.parse().ok()
+}
+
+impl FlowEdgeDefinition {
+    /// Creates an edge from string handle ids, using `parser` to extract their indices.
+    pub fn from_handle_strings(
+        source: String,
+        source_handle: &str,
+        target: String,
+        target_handle: &str,
+        parser: &dyn HandleParser,
+    ) -> Result<Self, RecipeConversionError> {
+        let parse = |handle: &str, node_id: &str| {
+            parser.parse_handle(handle, node_id).ok_or_else(|| {
+                RecipeConversionError::InvalidHandle {
+                    node_id: node_id.to_string(),
+                    handle: handle.to_string(),
+                }
+            })
+        };
+        Ok(Self {
+            source_handle: parse(source_handle, &source)?,
+            target_handle: parse(target_handle, &target)?,
+            source,
+            target,
+        })
+    }
+}
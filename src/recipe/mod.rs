@@ -1,9 +1,11 @@
 pub mod artifact;
 pub mod conversion;
 pub mod definition;
+pub mod handle;
 pub mod quality;
 
 pub use artifact::*;
 pub use conversion::*;
 pub use definition::*;
+pub use handle::*;
 pub use quality::*;
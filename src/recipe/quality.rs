@@ -4,3 +4,12 @@ pub struct Quality {
     pub name: String,
     pub priority: i32,
 }
+
+/// A named decision sink: the `setQualityNode` with id `node_id`, whose input handle `i`
+/// decides `qualities[i]`. Each decision independently yields its highest-priority quality.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub name: String,
+    pub node_id: String,
+    pub qualities: Vec<Quality>,
+}
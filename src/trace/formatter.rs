@@ -1,3 +1,4 @@
+use crate::ast::table::format_rows;
 use crate::ast::{EvaluationTrace, Value};
 use std::fmt::Write;
 
@@ -92,8 +93,43 @@ impl TraceFormatter {
                 let child_str = Self::format_recursive(child, current_precedence);
                 write!(result, "{} {}", op_symbol, child_str).unwrap();
             }
+            EvaluationTrace::Lookup {
+                op_symbol,
+                input,
+                rows,
+                outcome,
+            } => {
+                let input_str = Self::format_recursive(input, 0);
+                let label = if rows.len() == 1 { "row" } else { "rows" };
+                write!(
+                    result,
+                    "{}({}, {} {}) (was {})",
+                    op_symbol,
+                    input_str,
+                    label,
+                    format_rows(rows),
+                    Self::format_value(outcome)
+                )
+                .unwrap();
+            }
+            EvaluationTrace::Call {
+                function,
+                args,
+                outcome,
+            } => {
+                let args_str: Vec<String> =
+                    args.iter().map(|a| Self::format_recursive(a, 0)).collect();
+                write!(
+                    result,
+                    "{}({}) (was {})",
+                    function,
+                    args_str.join(", "),
+                    Self::format_value(outcome)
+                )
+                .unwrap();
+            }
             EvaluationTrace::Leaf { source, value } => {
-                if source.starts_with('$') {
+                if Self::is_named_source(source) {
                     write!(result, "{} (was {})", source, Self::format_value(value)).unwrap();
                 } else {
                     result.push_str(source);
@@ -108,6 +144,12 @@ impl TraceFormatter {
         result
     }
 
+    /// Whether a leaf reads a named value: `$` marks an input and `@` a parameter. Any
+    /// other leaf is a literal.
+    fn is_named_source(source: &str) -> bool {
+        source.starts_with(['$', '@'])
+    }
+
     fn format_value(value: &Value) -> String {
         value.to_string()
     }
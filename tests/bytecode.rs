@@ -1,9 +1,14 @@
 //! Unit tests for the register-based bytecode compiler and virtual machine.
 mod common;
 use ahash::AHashMap;
-use hantei::ast::{Expression, InputSource, Value};
-use hantei::bytecode::compiler::compile_to_program;
+use hantei::ast::{Expression, InputSource, SpatialOp, Value};
+use hantei::bytecode::assembly::{assemble, disassemble};
+use hantei::bytecode::compiler::{BytecodeProgram, compile_to_program};
+use hantei::bytecode::opcode::OpCode;
 use hantei::bytecode::vm::Vm;
+use hantei::error::{AssemblyError, ProgramError, VmError};
+use hantei::functions::NativeFunction;
+use std::sync::Arc;
 
 #[test]
 fn test_vm_simple_arithmetic() {
@@ -17,7 +22,7 @@ fn test_vm_simple_arithmetic() {
 
     let static_data_vec = Vec::new();
     let dynamic_data_vec = Vec::new();
-    let mut vm = Vm::new(&program, &static_data_vec, &dynamic_data_vec);
+    let mut vm = Vm::new(&program, &static_data_vec, &dynamic_data_vec).unwrap();
     let result = vm.run().unwrap();
 
     assert_eq!(result, Value::Number(5.0));
@@ -40,7 +45,403 @@ fn test_vm_data_loading() {
     let static_data = vec![Value::Number(100.0)];
     let dynamic_data = vec![Value::Number(25.0)];
 
-    let mut vm = Vm::new(&program, &static_data, &dynamic_data);
+    let mut vm = Vm::new(&program, &static_data, &dynamic_data).unwrap();
     let result = vm.run().unwrap();
     assert_eq!(result, Value::Number(125.0));
 }
+
+#[test]
+fn test_vm_reads_parameters() {
+    let ast = Expression::Sum(
+        Box::new(Expression::Input(InputSource::Static { id: 0 })),
+        Box::new(Expression::Input(InputSource::Parameter { id: 0 })),
+    );
+    let static_map = AHashMap::from([("Temp".to_string(), 0)]);
+    let program =
+        compile_to_program(&ast, &AHashMap::new(), &static_map, &AHashMap::new()).unwrap();
+
+    let text = disassemble(&program);
+    assert!(text.contains("LoadParameter"));
+    assert_eq!(assemble(&text).unwrap(), program);
+
+    let static_data = vec![Value::Number(100.0)];
+    let parameters = vec![Value::Number(5.0)];
+    let mut vm = Vm::new(&program, &static_data, &[])
+        .unwrap()
+        .with_parameters(&parameters);
+    assert_eq!(vm.run().unwrap(), Value::Number(105.0));
+}
+
+#[test]
+fn test_vm_treats_null_as_unknown() {
+    let run = |op: &str, left: &str, right: &str| {
+        let source = format!(
+            ".main\n    LoadLiteral R1, {left}\n    LoadLiteral R2, {right}\n    {op} R0, R1, R2\n    Halt\n"
+        );
+        Vm::new(&assemble(&source).unwrap(), &[], &[])
+            .unwrap()
+            .run()
+            .unwrap()
+    };
+    assert_eq!(run("GreaterThan", "null", "30.0"), Value::Null);
+    assert_eq!(run("Add", "1.0", "null"), Value::Null);
+    assert_eq!(run("Equal", "null", "null"), Value::Null);
+    assert_eq!(run("And", "null", "false"), Value::Bool(false));
+    assert_eq!(run("And", "true", "null"), Value::Null);
+    assert_eq!(run("Or", "null", "true"), Value::Bool(true));
+    assert_eq!(run("Or", "false", "null"), Value::Null);
+}
+
+#[test]
+fn test_assembly_round_trip() {
+    let flow = common::create_complex_flow();
+    let qualities = common::create_complex_qualities();
+    let artifacts = hantei::compiler::Compiler::builder(flow, qualities)
+        .build()
+        .compile()
+        .unwrap();
+    let artifact = &artifacts[0];
+
+    let program = compile_to_program(
+        &artifact.ast(),
+        &artifact.definitions(),
+        &artifact.static_map,
+        &artifact.dynamic_map,
+    )
+    .unwrap();
+
+    let text = disassemble(&program);
+    let reassembled = assemble(&text).unwrap();
+    assert_eq!(reassembled, program);
+    assert_eq!(disassemble(&reassembled), text);
+}
+
+#[test]
+fn test_assembly_hand_written_program() {
+    let source = r#"
+        .static "Leading width" 0   ; names may contain spaces
+        .dynamic "hole.Diameter" 0
+
+        .main
+            LoadStatic   R0, S0
+            LoadLiteral  R1, 0.1
+            Multiply     R0, R0, R1
+            Call         #7
+            Add          R0, R0, R1
+            Halt
+
+        .sub 7
+            LoadDynamic  R1, D0
+            Return
+    "#;
+    let program = assemble(source).unwrap();
+    assert_eq!(program.static_map.get("Leading width"), Some(&0));
+    assert_eq!(program.subroutines[&7].len(), 2);
+
+    let static_data = vec![Value::Number(1970.0)];
+    let dynamic_data = vec![Value::Number(3.0)];
+    let mut vm = Vm::new(&program, &static_data, &dynamic_data).unwrap();
+    assert_eq!(vm.run().unwrap(), Value::Number(200.0));
+}
+
+#[test]
+fn test_assembly_lookup_tables() {
+    let artifacts = hantei::compiler::Compiler::builder(
+        common::create_lookup_flow(),
+        common::create_simple_qualities()[..1].to_vec(),
+    )
+    .build()
+    .compile()
+    .unwrap();
+    for artifact in &artifacts {
+        let program = compile_to_program(
+            &artifact.ast(),
+            &artifact.definitions(),
+            &artifact.static_map,
+            &artifact.dynamic_map,
+        )
+        .unwrap();
+        assert_eq!(program.tables.len(), 1);
+        let text = disassemble(&program);
+        assert!(text.contains(".table 0 10.0:0.5 20.0:0.7 30.0:0.8"));
+        assert_eq!(assemble(&text).unwrap(), program);
+    }
+
+    let source = r#"
+        .static "Thickness" 0
+        .table 0 10:0.5 20:0.7
+
+        .main
+            LoadStatic   R0, S0
+            Interpolate  R1, R0, T0
+            Lookup       R0, R0, T0
+            Add          R0, R0, R1
+            Halt
+    "#;
+    let program = assemble(source).unwrap();
+    let static_data = vec![Value::Number(15.0)];
+    let mut vm = Vm::new(&program, &static_data, &[]).unwrap();
+    let Value::Number(result) = vm.run().unwrap() else {
+        panic!("expected a number");
+    };
+    assert!((result - 1.1).abs() < 1e-9);
+
+    let err = assemble(".table 0 20:0.7 10:0.5\n").unwrap_err();
+    assert!(matches!(err, AssemblyError::InvalidSyntax { line: 1, .. }));
+}
+
+#[test]
+fn test_assembly_reports_errors_with_line_numbers() {
+    let err = assemble(".main\n    Frobnicate R0\n").unwrap_err();
+    assert_eq!(
+        err,
+        AssemblyError::UnknownInstruction {
+            line: 2,
+            mnemonic: "Frobnicate".to_string()
+        }
+    );
+
+    let err = assemble(".main\n    Move R0\n").unwrap_err();
+    assert!(matches!(err, AssemblyError::InvalidSyntax { line: 2, .. }));
+}
+
+#[test]
+fn test_assembly_rejects_programs_the_vm_cannot_run() {
+    let invalid = |source: &str| match assemble(source).unwrap_err() {
+        AssemblyError::InvalidProgram(err) => err,
+        other => panic!("expected an invalid program, got {other:?}"),
+    };
+
+    assert_eq!(
+        invalid(".main\n    LoadLiteral R200, 7.0\n    Move R0, R200\n    Halt\n"),
+        ProgramError::InvalidRegister {
+            chunk: ".main".to_string(),
+            index: 0,
+            register: 200,
+        }
+    );
+    // The arguments of a call must fit in the register file as well.
+    assert!(matches!(
+        invalid(".function 0 \"max\"\n.main\n    CallNative R0, F0, R62, 3\n    Halt\n"),
+        ProgramError::InvalidRegister { register: 64, .. }
+    ));
+    assert!(matches!(
+        invalid(".main\n    Spatial R0, DISTANCE, R62\n    Halt\n"),
+        ProgramError::InvalidRegister { register: 65, .. }
+    ));
+}
+
+#[test]
+fn test_assembly_rejects_jumps_outside_the_chunk() {
+    assert_eq!(
+        assemble(".main\n    Jump @2\n    Halt\n").unwrap_err(),
+        AssemblyError::InvalidProgram(ProgramError::JumpOutOfBounds {
+            chunk: ".main".to_string(),
+            index: 0,
+            address: 2,
+        })
+    );
+    let err =
+        assemble(".main\n    Halt\n\n.sub 3\n    JumpIfTrue R0, @9\n    Return\n").unwrap_err();
+    assert_eq!(
+        err,
+        AssemblyError::InvalidProgram(ProgramError::JumpOutOfBounds {
+            chunk: ".sub 3".to_string(),
+            index: 0,
+            address: 9,
+        })
+    );
+}
+
+#[test]
+fn test_assembly_rejects_unknown_subroutines_tables_and_functions() {
+    assert_eq!(
+        assemble(".main\n    Call #4\n    Halt\n").unwrap_err(),
+        AssemblyError::InvalidProgram(ProgramError::UnknownSubroutine {
+            chunk: ".main".to_string(),
+            index: 0,
+            id: 4,
+        })
+    );
+    assert_eq!(
+        assemble(".table 0 10.0:0.5\n.main\n    Lookup R0, R1, T1\n    Halt\n").unwrap_err(),
+        AssemblyError::InvalidProgram(ProgramError::UnknownTable {
+            chunk: ".main".to_string(),
+            index: 0,
+            id: 1,
+        })
+    );
+    assert_eq!(
+        assemble(".main\n    CallNative R0, F0, R1, 1\n    Halt\n").unwrap_err(),
+        AssemblyError::InvalidProgram(ProgramError::UnknownFunction {
+            chunk: ".main".to_string(),
+            index: 0,
+            id: 0,
+        })
+    );
+}
+
+#[test]
+fn test_assembly_rejects_missing_main() {
+    assert_eq!(
+        assemble(".sub 0\n    Return\n").unwrap_err(),
+        AssemblyError::MissingMain
+    );
+    assert_eq!(assemble("").unwrap_err(), AssemblyError::MissingMain);
+}
+
+#[test]
+fn test_assembly_rejects_chunks_without_halt_or_return() {
+    assert_eq!(
+        assemble(".main\n    LoadLiteral R0, 1.0\n").unwrap_err(),
+        AssemblyError::InvalidProgram(ProgramError::MissingTerminator {
+            chunk: ".main".to_string(),
+        })
+    );
+    assert_eq!(
+        assemble(".main\n").unwrap_err(),
+        AssemblyError::InvalidProgram(ProgramError::MissingTerminator {
+            chunk: ".main".to_string(),
+        })
+    );
+    let err = assemble(".main\n    Call #0\n    Halt\n\n.sub 0\n    Move R0, R1\n").unwrap_err();
+    assert_eq!(
+        err,
+        AssemblyError::InvalidProgram(ProgramError::MissingTerminator {
+            chunk: ".sub 0".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_vm_rejects_invalid_programs() {
+    // Programs built in code bypass the assembler, so the VM checks them itself.
+    let program = BytecodeProgram {
+        main: vec![
+            OpCode::LoadLiteral(200, Value::Number(7.0)),
+            OpCode::Move(0, 200),
+            OpCode::Halt,
+        ],
+        ..Default::default()
+    };
+    assert!(matches!(
+        Vm::new(&program, &[], &[]).err(),
+        Some(VmError::InvalidProgram(ProgramError::InvalidRegister {
+            register: 200,
+            ..
+        }))
+    ));
+
+    let program = BytecodeProgram {
+        main: vec![OpCode::Jump(0)],
+        ..Default::default()
+    };
+    assert!(matches!(
+        Vm::new(&program, &[], &[]).err(),
+        Some(VmError::InvalidProgram(
+            ProgramError::MissingTerminator { .. }
+        ))
+    ));
+}
+
+#[test]
+fn test_assembly_native_calls() {
+    let artifacts = hantei::compiler::Compiler::builder(
+        common::create_call_flow(),
+        common::create_simple_qualities()[..1].to_vec(),
+    )
+    .build()
+    .compile()
+    .unwrap();
+    for artifact in &artifacts {
+        let program = compile_to_program(
+            &artifact.ast(),
+            &artifact.definitions(),
+            &artifact.static_map,
+            &artifact.dynamic_map,
+        )
+        .unwrap();
+        assert_eq!(program.functions, ["cylinder_volume"]);
+        let text = disassemble(&program);
+        assert!(text.contains(".function 0 \"cylinder_volume\""));
+        assert_eq!(assemble(&text).unwrap(), program);
+    }
+
+    let source = r#"
+        .function 0 "max"
+
+        .main
+            LoadLiteral  R1, 3.0
+            LoadLiteral  R2, 7.0
+            LoadLiteral  R3, 5.0
+            CallNative   R0, F0, R1, 3
+            Halt
+    "#;
+    let program = assemble(source).unwrap();
+    let max: NativeFunction = Arc::new(|args: &[Value]| {
+        args.iter()
+            .map(|v| match v {
+                Value::Number(n) => Ok(*n),
+                other => Err(format!("{other} is not a number")),
+            })
+            .try_fold(f64::MIN, |acc, n| n.map(|n| acc.max(n)))
+            .map(Value::Number)
+    });
+    let functions = [max];
+    let mut vm = Vm::new(&program, &[], &[])
+        .unwrap()
+        .with_functions(&functions);
+    assert_eq!(vm.run().unwrap(), Value::Number(7.0));
+
+    // Without the function, the VM reports the unknown id instead of calling anything.
+    let mut vm = Vm::new(&program, &[], &[]).unwrap();
+    assert_eq!(vm.run().unwrap_err(), VmError::UnknownFunction(0));
+}
+
+#[test]
+fn test_assembly_spatial_operations() {
+    let names = ["hole#a.X", "hole#a.Y", "hole#b.X", "hole#b.Y"];
+    let expr = Expression::Spatial(
+        SpatialOp::Distance,
+        (0..names.len() as u16)
+            .map(|id| Expression::Input(InputSource::Dynamic { id }))
+            .collect(),
+    );
+    let dynamic_map = names
+        .iter()
+        .enumerate()
+        .map(|(id, name)| (name.to_string(), id as u16))
+        .collect();
+    let program =
+        compile_to_program(&expr, &AHashMap::new(), &AHashMap::new(), &dynamic_map).unwrap();
+    let text = disassemble(&program);
+    assert!(text.contains("Spatial"), "{text}");
+    assert!(text.contains("DISTANCE"), "{text}");
+    assert_eq!(assemble(&text).unwrap(), program);
+
+    let dynamic_data = [0.0, 0.0, 3.0, 4.0].map(Value::Number);
+    let mut vm = Vm::new(&program, &[], &dynamic_data).unwrap();
+    assert_eq!(vm.run().unwrap(), Value::Number(5.0));
+
+    let source = r#"
+        .main
+            LoadLiteral  R1, 0.0
+            LoadLiteral  R2, true
+            LoadLiteral  R3, 3.0
+            LoadLiteral  R4, 4.0
+            Spatial      R0, DISTANCE, R1
+            Halt
+    "#;
+    let program = assemble(source).unwrap();
+    let mut vm = Vm::new(&program, &[], &[]).unwrap();
+    assert!(matches!(
+        vm.run().unwrap_err(),
+        VmError::TypeMismatch {
+            found: Value::Bool(true),
+            ..
+        }
+    ));
+
+    let err = assemble(".main\n    Spatial R0, AREA, R1\n").unwrap_err();
+    assert!(matches!(err, AssemblyError::InvalidSyntax { line: 2, .. }));
+}
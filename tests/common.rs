@@ -1,5 +1,7 @@
 //! Common test utilities for building recipe definitions and data.
 use ahash::AHashMap;
+use hantei::compiler::parsing::NodeParser;
+use hantei::compiler::{CompilationArtifacts, OptimizerPasses};
 use hantei::prelude::*;
 
 /// Creates a simple, valid `FlowDefinition` for basic tests.
@@ -42,19 +44,371 @@ pub fn create_simple_flow() -> FlowDefinition {
             FlowEdgeDefinition {
                 source: "0001".to_string(),
                 target: "0002".to_string(),
-                source_handle: "output-0".to_string(),
-                target_handle: "input-0".to_string(),
+                source_handle: 0,
+                target_handle: 0,
             },
             FlowEdgeDefinition {
                 source: "0002".to_string(),
                 target: "0003".to_string(),
-                source_handle: "output-0".to_string(), // Assumes boolean output is handle 0
-                target_handle: "input-0".to_string(),  // Connects to the first quality
+                source_handle: 0, // Assumes boolean output is handle 0
+                target_handle: 0, // Connects to the first quality
             },
         ],
     }
 }
 
+/// Extends the complex flow with an output node `outputs` that reports two values.
+///
+/// Outputs: `score` = `$Temperature * 2.0`, `diameter` = `$hole.Diameter`
+#[allow(dead_code)]
+pub fn create_output_flow() -> FlowDefinition {
+    let mut flow = create_complex_flow();
+    flow.nodes.push(FlowNodeDefinition {
+        id: "score".to_string(),
+        operation_type: "multNode".to_string(),
+        input_type: None,
+        literal_values: Some(vec![serde_json::Value::Null, serde_json::json!(2.0)]),
+        data_fields: None,
+    });
+    flow.nodes.push(FlowNodeDefinition {
+        id: "outputs".to_string(),
+        operation_type: "setOutputNode".to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: Some(
+            ["score", "diameter"]
+                .into_iter()
+                .enumerate()
+                .map(|(id, name)| DataFieldDefinition {
+                    id: id as u32,
+                    name: name.to_string(),
+                    data_type: Some("number".to_string()),
+                })
+                .collect(),
+        ),
+    });
+    for (source, target, target_handle) in [
+        ("static_source", "score", 0),
+        ("score", "outputs", 0),
+        ("hole_source", "outputs", 1),
+    ] {
+        flow.edges.push(FlowEdgeDefinition {
+            source: source.to_string(),
+            target: target.to_string(),
+            source_handle: 0,
+            target_handle,
+        });
+    }
+    flow
+}
+
+/// The simple flow with its threshold taken from a parameter node `threshold`.
+///
+/// Logic: `$Temperature > @threshold` -> Quality 0, where `threshold` defaults to 25.0
+#[allow(dead_code)]
+pub fn create_parameter_flow() -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.nodes[1].literal_values = None;
+    flow.nodes.push(FlowNodeDefinition {
+        id: "threshold".to_string(),
+        operation_type: "parameterNode".to_string(),
+        input_type: None,
+        literal_values: Some(vec![serde_json::json!(25.0)]),
+        data_fields: Some(vec![DataFieldDefinition {
+            id: 0,
+            name: "threshold".to_string(),
+            data_type: Some("number".to_string()),
+        }]),
+    });
+    flow.edges.push(FlowEdgeDefinition {
+        source: "threshold".to_string(),
+        target: "0002".to_string(),
+        source_handle: 0,
+        target_handle: 1,
+    });
+    flow
+}
+
+/// Creates a flow that looks up a moisture allowance by `$Thickness` in the rows
+/// `10 -> 0.5, 20 -> 0.7, 30 -> 0.8`.
+///
+/// Logic: `INTERPOLATE($Thickness) > 0.75` -> Quality 0. Outputs: `step` = `TABLE($Thickness)`,
+/// `allowance` = `INTERPOLATE($Thickness)`
+#[allow(dead_code)]
+pub fn create_lookup_flow() -> FlowDefinition {
+    let rows = [
+        serde_json::Value::Null,
+        serde_json::json!([10.0, 0.5]),
+        serde_json::json!([20.0, 0.7]),
+        serde_json::json!([30.0, 0.8]),
+    ];
+    let node = |id: &str, operation_type: &str, literal_values: Option<Vec<serde_json::Value>>| {
+        FlowNodeDefinition {
+            id: id.to_string(),
+            operation_type: operation_type.to_string(),
+            input_type: None,
+            literal_values,
+            data_fields: None,
+        }
+    };
+    let field = |id: u32, name: &str| DataFieldDefinition {
+        id,
+        name: name.to_string(),
+        data_type: Some("number".to_string()),
+    };
+    let mut thickness = node("thickness", "dynamicNode", None);
+    thickness.data_fields = Some(vec![field(0, "Thickness")]);
+    let mut outputs = node("outputs", "setOutputNode", None);
+    outputs.data_fields = Some(vec![field(0, "step"), field(1, "allowance")]);
+    FlowDefinition {
+        nodes: vec![
+            thickness,
+            node("step", "tableNode", Some(rows.to_vec())),
+            node("allowance", "interpolateNode", Some(rows.to_vec())),
+            node(
+                "limit",
+                "gtNode",
+                Some(vec![serde_json::Value::Null, serde_json::json!(0.75)]),
+            ),
+            node("quality", "setQualityNode", None),
+            outputs,
+        ],
+        edges: [
+            ("thickness", "step", 0),
+            ("thickness", "allowance", 0),
+            ("allowance", "limit", 0),
+            ("limit", "quality", 0),
+            ("step", "outputs", 0),
+            ("allowance", "outputs", 1),
+        ]
+        .into_iter()
+        .map(|(source, target, target_handle)| FlowEdgeDefinition {
+            source: source.to_string(),
+            target: target.to_string(),
+            source_handle: 0,
+            target_handle,
+        })
+        .collect(),
+    }
+}
+
+/// Creates a flow that calls the native function `cylinder_volume` with the static inputs
+/// `Diameter` and `Height`, and reports the result as the output `volume`.
+///
+/// Logic: `cylinder_volume($Diameter, $Height) > 1000.0` -> Quality 0
+#[allow(dead_code)]
+pub fn create_call_flow() -> FlowDefinition {
+    let node = |id: &str, operation_type: &str, fields: &[&str]| FlowNodeDefinition {
+        id: id.to_string(),
+        operation_type: operation_type.to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: (!fields.is_empty()).then(|| {
+            fields
+                .iter()
+                .enumerate()
+                .map(|(i, name)| DataFieldDefinition {
+                    id: i as u32,
+                    name: name.to_string(),
+                    data_type: Some("number".to_string()),
+                })
+                .collect()
+        }),
+    };
+    let mut limit = node("limit", "gtNode", &[]);
+    limit.literal_values = Some(vec![serde_json::Value::Null, serde_json::json!(1000.0)]);
+    FlowDefinition {
+        nodes: vec![
+            node("part", "dynamicNode", &["Diameter", "Height"]),
+            node("volume", "callNode", &["cylinder_volume"]),
+            limit,
+            node("quality", "setQualityNode", &[]),
+            node("outputs", "setOutputNode", &["volume"]),
+        ],
+        edges: [
+            ("part", 0, "volume", 0),
+            ("part", 1, "volume", 1),
+            ("volume", 0, "limit", 0),
+            ("limit", 0, "quality", 0),
+            ("volume", 0, "outputs", 0),
+        ]
+        .into_iter()
+        .map(
+            |(source, source_handle, target, target_handle)| FlowEdgeDefinition {
+                source: source.to_string(),
+                target: target.to_string(),
+                source_handle,
+                target_handle,
+            },
+        )
+        .collect(),
+    }
+}
+
+/// Creates a flow around the spatial node `operation_type` over `events`, whose remaining
+/// operands are the static inputs `statics`, in order. The result sets quality 0 directly,
+/// or through `result < limit` if a limit is given.
+///
+/// Logic, e.g. for an `edgeDistanceNode`: `EDGE_DISTANCE(knot, $Board width, $Board length) < limit`
+/// -> Quality 0
+#[allow(dead_code)]
+pub fn create_spatial_flow(
+    operation_type: &str,
+    events: &[&str],
+    statics: &[&str],
+    limit: Option<f64>,
+) -> FlowDefinition {
+    let node = |id: &str, operation_type: &str, fields: &[&str]| FlowNodeDefinition {
+        id: id.to_string(),
+        operation_type: operation_type.to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: (!fields.is_empty()).then(|| {
+            fields
+                .iter()
+                .enumerate()
+                .map(|(i, name)| DataFieldDefinition {
+                    id: i as u32,
+                    name: name.to_string(),
+                    data_type: Some("number".to_string()),
+                })
+                .collect()
+        }),
+    };
+    let edge = |source: &str, source_handle, target: &str, target_handle| FlowEdgeDefinition {
+        source: source.to_string(),
+        target: target.to_string(),
+        source_handle,
+        target_handle,
+    };
+    let mut nodes = vec![
+        node("board", "dynamicNode", statics),
+        node("spatial", operation_type, events),
+        node("quality", "setQualityNode", &[]),
+    ];
+    let mut edges: Vec<_> = (0..statics.len() as u32)
+        .map(|i| edge("board", i, "spatial", i))
+        .collect();
+    match limit {
+        Some(limit) => {
+            let mut below = node("below", "stNode", &[]);
+            below.literal_values = Some(vec![serde_json::Value::Null, serde_json::json!(limit)]);
+            nodes.push(below);
+            edges.push(edge("spatial", 0, "below", 0));
+            edges.push(edge("below", 0, "quality", 0));
+        }
+        None => edges.push(edge("spatial", 0, "quality", 0)),
+    }
+    FlowDefinition { nodes, edges }
+}
+
+/// Creates a flow that compares two instances of the `hole` event through the aliases
+/// `hole#a` and `hole#b`.
+///
+/// Logic: `$hole#a.X - $hole#b.X > threshold` -> Quality 0
+#[allow(dead_code)]
+pub fn create_event_alias_flow(threshold: f64) -> FlowDefinition {
+    let node = |id: &str, operation_type: &str, input_type: Option<&str>| FlowNodeDefinition {
+        id: id.to_string(),
+        operation_type: operation_type.to_string(),
+        input_type: input_type.map(str::to_string),
+        literal_values: None,
+        data_fields: input_type.map(|_| {
+            vec![DataFieldDefinition {
+                id: 0,
+                name: "X".to_string(),
+                data_type: Some("number".to_string()),
+            }]
+        }),
+    };
+    let mut limit = node("limit", "gtNode", None);
+    limit.literal_values = Some(vec![serde_json::Value::Null, serde_json::json!(threshold)]);
+    FlowDefinition {
+        nodes: vec![
+            node("a", "dynamicNode", Some("hole#a")),
+            node("b", "dynamicNode", Some("hole#b")),
+            node("offset", "subNode", None),
+            limit,
+            node("quality", "setQualityNode", None),
+        ],
+        edges: [
+            ("a", "offset", 0),
+            ("b", "offset", 1),
+            ("offset", "limit", 0),
+            ("limit", "quality", 0),
+        ]
+        .into_iter()
+        .map(|(source, target, target_handle)| FlowEdgeDefinition {
+            source: source.to_string(),
+            target: target.to_string(),
+            source_handle: 0,
+            target_handle,
+        })
+        .collect(),
+    }
+}
+
+/// Extends the event alias flow with a quality on another event, `knot`, which is
+/// checked first.
+///
+/// Logic: `$knot.X > 0` -> Quality 0, `$hole#a.X - $hole#b.X > threshold` -> Quality 1
+#[allow(dead_code)]
+pub fn create_knot_and_hole_flow(threshold: f64) -> FlowDefinition {
+    let mut flow = create_event_alias_flow(threshold);
+    flow.edges.last_mut().unwrap().target_handle = 1;
+    flow.nodes.push(FlowNodeDefinition {
+        id: "knot".to_string(),
+        operation_type: "dynamicNode".to_string(),
+        input_type: Some("knot".to_string()),
+        literal_values: None,
+        data_fields: Some(vec![DataFieldDefinition {
+            id: 0,
+            name: "X".to_string(),
+            data_type: Some("number".to_string()),
+        }]),
+    });
+    flow.nodes.push(FlowNodeDefinition {
+        id: "knotty".to_string(),
+        operation_type: "gtNode".to_string(),
+        input_type: None,
+        literal_values: Some(vec![serde_json::Value::Null, serde_json::json!(0.0)]),
+        data_fields: None,
+    });
+    for (source, target, target_handle) in [("knot", "knotty", 0), ("knotty", "quality", 0)] {
+        flow.edges.push(FlowEdgeDefinition {
+            source: source.to_string(),
+            target: target.to_string(),
+            source_handle: 0,
+            target_handle,
+        });
+    }
+    flow
+}
+
+/// Creates the condition of a quality relating two holes, most of whose conjuncts only
+/// read one of them.
+///
+/// Logic: `$hole#a.X > 50 AND $Limit > 0 AND $hole#b.X > 50 AND $hole#a.X - $hole#b.X > 20`
+#[allow(dead_code)]
+pub fn create_pushdown_expression() -> Expression {
+    let gt = |l, r| Box::new(Expression::GreaterThan(l, r));
+    let and = |l, r| Box::new(Expression::And(l, r));
+    let x = |event| dynamic_input(event, "X");
+    *and(
+        and(
+            and(
+                gt(x("hole#a"), number(50.0)),
+                gt(static_input("Limit"), number(0.0)),
+            ),
+            gt(x("hole#b"), number(50.0)),
+        ),
+        gt(
+            Box::new(Expression::Subtract(x("hole#a"), x("hole#b"))),
+            number(20.0),
+        ),
+    )
+}
+
 /// Creates a simple list of qualities for testing.
 #[allow(dead_code)]
 pub fn create_simple_qualities() -> Vec<Quality> {
@@ -70,6 +424,38 @@ pub fn create_simple_qualities() -> Vec<Quality> {
     ]
 }
 
+/// Extends the simple flow with a second quality node `0005` for a separate decision.
+///
+/// Logic: `$Temperature > 25.0` -> Quality 0 of `0003`, `$Temperature < 10.0` -> Quality 0
+/// of `0005`
+#[allow(dead_code)]
+pub fn create_two_decision_flow() -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.nodes.push(FlowNodeDefinition {
+        id: "0004".to_string(),
+        operation_type: "stNode".to_string(),
+        input_type: None,
+        literal_values: Some(vec![serde_json::Value::Null, serde_json::json!(10.0)]),
+        data_fields: None,
+    });
+    flow.nodes.push(FlowNodeDefinition {
+        id: "0005".to_string(),
+        operation_type: "setQualityNode".to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: None,
+    });
+    for (source, target) in [("0001", "0004"), ("0004", "0005")] {
+        flow.edges.push(FlowEdgeDefinition {
+            source: source.to_string(),
+            target: target.to_string(),
+            source_handle: 0,
+            target_handle: 0,
+        });
+    }
+    flow
+}
+
 /// Creates a more complex `FlowDefinition` involving static and dynamic data.
 ///
 /// Logic: `$Temperature > 30.0 AND $hole.Diameter < 10.0` -> Quality 0
@@ -138,32 +524,32 @@ pub fn create_complex_flow() -> FlowDefinition {
             FlowEdgeDefinition {
                 source: "static_source".to_string(),
                 target: "temp_check".to_string(),
-                source_handle: "output-0".to_string(),
-                target_handle: "input-0".to_string(),
+                source_handle: 0,
+                target_handle: 0,
             },
             FlowEdgeDefinition {
                 source: "hole_source".to_string(),
                 target: "diameter_check".to_string(),
-                source_handle: "output-0".to_string(),
-                target_handle: "input-0".to_string(),
+                source_handle: 0,
+                target_handle: 0,
             },
             FlowEdgeDefinition {
                 source: "temp_check".to_string(),
                 target: "and_gate".to_string(),
-                source_handle: "output-0".to_string(),
-                target_handle: "input-0".to_string(),
+                source_handle: 0,
+                target_handle: 0,
             },
             FlowEdgeDefinition {
                 source: "diameter_check".to_string(),
                 target: "and_gate".to_string(),
-                source_handle: "output-0".to_string(),
-                target_handle: "input-1".to_string(),
+                source_handle: 0,
+                target_handle: 1,
             },
             FlowEdgeDefinition {
                 source: "and_gate".to_string(),
                 target: "quality_sink".to_string(),
-                source_handle: "output-0".to_string(),
-                target_handle: "input-0".to_string(),
+                source_handle: 0,
+                target_handle: 0,
             },
         ],
     }
@@ -203,3 +589,85 @@ pub fn create_sample_dynamic_data() -> AHashMap<String, Vec<AHashMap<String, f64
     data.insert("hole".to_string(), hole_events);
     data
 }
+
+/// A parser for the `fixedExpression` node type that always yields the same expression.
+///
+/// Lets optimizer tests start from a hand-written AST instead of a node graph.
+#[allow(dead_code)]
+pub struct FixedExpressionParser(pub Expression);
+
+impl NodeParser for FixedExpressionParser {
+    fn node_type(&self) -> &str {
+        "fixedExpression"
+    }
+    fn parse(
+        &self,
+        _node: &FlowNodeDefinition,
+        _inputs: Vec<Expression>,
+    ) -> Result<Expression, AstBuildError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Compiles `expr` as the condition of a single quality and returns its artifact.
+#[allow(dead_code)]
+pub fn compile_fixed_expression(expr: Expression, passes: OptimizerPasses) -> CompilationArtifacts {
+    let flow = FlowDefinition {
+        nodes: vec![
+            FlowNodeDefinition {
+                id: "0001".to_string(),
+                operation_type: "fixedExpression".to_string(),
+                input_type: None,
+                literal_values: None,
+                data_fields: None,
+            },
+            FlowNodeDefinition {
+                id: "0002".to_string(),
+                operation_type: "setQualityNode".to_string(),
+                input_type: None,
+                literal_values: None,
+                data_fields: None,
+            },
+        ],
+        edges: vec![FlowEdgeDefinition {
+            source: "0001".to_string(),
+            target: "0002".to_string(),
+            source_handle: 0,
+            target_handle: 0,
+        }],
+    };
+    let qualities = vec![Quality {
+        name: "Fixed".to_string(),
+        priority: 1,
+    }];
+    Compiler::builder(flow, qualities)
+        .with_custom_parser(Box::new(FixedExpressionParser(expr)))
+        .with_optimizer_passes(passes)
+        .build()
+        .compile()
+        .expect("Failed to compile fixed expression")
+        .remove(0)
+}
+
+/// Shorthand for a static input leaf.
+#[allow(dead_code)]
+pub fn static_input(name: &str) -> Box<Expression> {
+    Box::new(Expression::Input(InputSource::StaticName {
+        name: name.to_string(),
+    }))
+}
+
+/// Shorthand for a dynamic input leaf reading `field` of `event`.
+#[allow(dead_code)]
+pub fn dynamic_input(event: &str, field: &str) -> Box<Expression> {
+    Box::new(Expression::Input(InputSource::DynamicName {
+        event: event.to_string(),
+        field: field.to_string(),
+    }))
+}
+
+/// Shorthand for a numeric literal leaf.
+#[allow(dead_code)]
+pub fn number(n: f64) -> Box<Expression> {
+    Box::new(Expression::Literal(Value::Number(n)))
+}
@@ -1,7 +1,19 @@
 //! Tests for the compilation process, AST generation, and optimization.
 mod common;
+use ahash::AHashMap;
 use common::*;
+use hantei::ast::{ExpressionGraph, InputId, Node};
+use hantei::backend::{BackendChoice, EvaluationBackend};
+use hantei::bytecode::compiler::BytecodeProgram;
+use hantei::compiler::parsing::{FanIn, NodeOutputs, NodeParser};
+use hantei::compiler::{
+    CompilationArtifacts, CompilationObserver, DEFAULT_DECISION, DEFAULT_MAX_FLOW_DEPTH,
+    OptimizationLevel, OptimizerPass, OptimizerPasses, OptimizerStats, PathKind,
+};
+use hantei::error::{FlowDiagnostic, Severity};
+use hantei::events::{CombinationPolicy, PathSplit};
 use hantei::prelude::*;
+use std::sync::{Arc, Mutex};
 
 #[test]
 fn test_compiler_builds_simple_flow() {
@@ -57,3 +69,1287 @@ fn test_compiler_fails_on_unregistered_type() {
         _ => panic!("Expected InvalidNodeType error"),
     }
 }
+
+#[test]
+fn test_compiler_reports_cycles() {
+    let mut flow = create_simple_flow();
+    flow.nodes.push(FlowNodeDefinition {
+        id: "0004".to_string(),
+        operation_type: "notNode".to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: None,
+    });
+    flow.edges.push(edge("0002", "0004", 0));
+    flow.edges.push(edge("0004", "0002", 1));
+
+    let result = Compiler::builder(flow, create_simple_qualities())
+        .build()
+        .compile();
+    match result.err() {
+        Some(AstBuildError::CycleDetected { cycle }) => {
+            assert_eq!(cycle, ["0002", "0004", "0002"]);
+        }
+        other => panic!("Expected CycleDetected error, got {:?}", other),
+    }
+}
+
+/// `$Temperature` passed through `length` chained NOT nodes into the first quality.
+fn create_not_chain_flow(length: usize) -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.edges.retain(|e| e.target != "0003");
+    let mut previous = "0002".to_string();
+    for i in 0..length {
+        let id = format!("not{}", i);
+        flow.nodes.push(FlowNodeDefinition {
+            id: id.clone(),
+            operation_type: "notNode".to_string(),
+            input_type: None,
+            literal_values: None,
+            data_fields: None,
+        });
+        flow.edges.push(edge(&previous, &id, 0));
+        previous = id;
+    }
+    flow.edges.push(edge(&previous, "0003", 0));
+    flow
+}
+
+/// `$Temperature - 1 - 1 ...` with `length` chained subtraction nodes, compared against 25
+/// for the first quality.
+fn create_subtraction_chain_flow(length: usize) -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.edges.retain(|e| e.target != "0002");
+    let mut previous = "0001".to_string();
+    for i in 0..length {
+        let id = format!("sub{}", i);
+        flow.nodes.push(FlowNodeDefinition {
+            id: id.clone(),
+            operation_type: "subNode".to_string(),
+            input_type: None,
+            literal_values: Some(vec![serde_json::Value::Null, serde_json::json!(1.0)]),
+            data_fields: None,
+        });
+        flow.edges.push(edge(&previous, &id, 0));
+        previous = id;
+    }
+    flow.edges.push(edge(&previous, "0002", 0));
+    flow
+}
+
+fn edge(source: &str, target: &str, target_handle: u32) -> FlowEdgeDefinition {
+    FlowEdgeDefinition {
+        source: source.to_string(),
+        target: target.to_string(),
+        source_handle: 0,
+        target_handle,
+    }
+}
+
+#[test]
+fn test_compiler_limits_flow_depth() {
+    let result = Compiler::builder(create_not_chain_flow(300), create_simple_qualities())
+        .build()
+        .compile();
+    match result.err() {
+        Some(AstBuildError::MaxDepthExceeded { max_depth, .. }) => {
+            assert_eq!(max_depth, DEFAULT_MAX_FLOW_DEPTH)
+        }
+        other => panic!("Expected MaxDepthExceeded error, got {:?}", other),
+    }
+
+    // A raised limit is only safe with a larger stack than the 2 MB a test thread gets.
+    let artifacts = std::thread::Builder::new()
+        .stack_size(16 * 1024 * 1024)
+        .spawn(|| {
+            Compiler::builder(create_not_chain_flow(300), create_simple_qualities())
+                .with_max_flow_depth(400)
+                .build()
+                .compile()
+                .expect("a raised depth limit should accept the chain")
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(artifacts.len(), 1);
+}
+
+#[test]
+fn test_default_flow_depth_is_safe_on_a_default_stack() {
+    // The subtraction chain plus the comparison and quality nodes.
+    let length = DEFAULT_MAX_FLOW_DEPTH - 2;
+    let too_deep = Compiler::builder(
+        create_subtraction_chain_flow(length + 1),
+        create_simple_qualities(),
+    )
+    .build()
+    .compile();
+    assert!(matches!(
+        too_deep.err(),
+        Some(AstBuildError::MaxDepthExceeded { .. })
+    ));
+
+    // Spawned threads get the default stack size, unlike the main thread.
+    std::thread::spawn(move || {
+        for level in [OptimizationLevel::O0, OptimizationLevel::O2] {
+            for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+                let artifacts = Compiler::builder(
+                    create_subtraction_chain_flow(length),
+                    create_simple_qualities(),
+                )
+                .with_optimization_level(level)
+                .build()
+                .compile()
+                .unwrap();
+                let static_data =
+                    AHashMap::from([("Temperature".to_string(), 30.0 + length as f64)]);
+                let result = Evaluator::new(choice, artifacts)
+                    .unwrap()
+                    .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+                    .unwrap();
+                assert_eq!(result.quality_name.as_deref(), Some("Hot"));
+            }
+        }
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_validate_accepts_valid_flow() {
+    let flow = create_simple_flow();
+    // Only the first quality is wired up in the simple flow.
+    let qualities = &create_simple_qualities()[..1];
+    assert_eq!(flow.validate(qualities), []);
+}
+
+#[test]
+fn test_validate_reports_all_problems() {
+    let mut flow = create_simple_flow();
+    // The second input of the comparison becomes a null literal, i.e. unconnected.
+    flow.nodes[1].literal_values = Some(vec![serde_json::json!(1.0), serde_json::Value::Null]);
+    flow.nodes.push(FlowNodeDefinition {
+        id: "0004".to_string(),
+        operation_type: "fancyNode".to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: None,
+    });
+    flow.nodes.push(FlowNodeDefinition {
+        id: "0005".to_string(),
+        operation_type: "setQualityNode".to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: None,
+    });
+    flow.nodes.push(flow.nodes[0].clone());
+    flow.edges.push(edge("0002", "missing", 0));
+
+    let diagnostics = flow.validate(&create_simple_qualities());
+    assert_eq!(
+        diagnostics,
+        [
+            FlowDiagnostic::DuplicateNodeId {
+                node_id: "0001".to_string()
+            },
+            FlowDiagnostic::UnknownEdgeNode {
+                edge: 2,
+                node_id: "missing".to_string()
+            },
+            FlowDiagnostic::UnknownOperationType {
+                node_id: "0004".to_string(),
+                operation_type: "fancyNode".to_string()
+            },
+            FlowDiagnostic::ExtraQualityNode {
+                node_id: "0005".to_string()
+            },
+            FlowDiagnostic::UnconnectedQuality {
+                node_id: "0003".to_string(),
+                handle: 1,
+                quality: "Normal".to_string()
+            },
+            FlowDiagnostic::UnconnectedHandle {
+                node_id: "0002".to_string(),
+                handle: 1
+            },
+            FlowDiagnostic::UnreachableNode {
+                node_id: "0004".to_string()
+            },
+            FlowDiagnostic::UnreachableNode {
+                node_id: "0005".to_string()
+            },
+        ]
+    );
+    assert_eq!(diagnostics[1].edge(), Some(2));
+    assert_eq!(diagnostics[2].node_id(), Some("0004"));
+    assert_eq!(diagnostics[4].severity(), Severity::Warning);
+}
+
+#[test]
+fn test_validate_checks_every_decision() {
+    let qualities = create_simple_qualities()[..1].to_vec();
+    let flow = create_two_decision_flow();
+    assert_eq!(
+        flow.validate(&qualities),
+        [
+            FlowDiagnostic::ExtraQualityNode {
+                node_id: "0005".to_string()
+            },
+            FlowDiagnostic::UnreachableNode {
+                node_id: "0004".to_string()
+            },
+            FlowDiagnostic::UnreachableNode {
+                node_id: "0005".to_string()
+            },
+        ]
+    );
+
+    let builder = Compiler::builder(flow, qualities.clone())
+        .with_decision("alert", "0005", create_simple_qualities())
+        .with_decision("broken", "0004", qualities);
+    assert_eq!(
+        builder.validate(),
+        [
+            FlowDiagnostic::InvalidDecisionNode {
+                decision: "broken".to_string(),
+                node_id: "0004".to_string()
+            },
+            FlowDiagnostic::UnconnectedQuality {
+                node_id: "0005".to_string(),
+                handle: 1,
+                quality: "Normal".to_string()
+            },
+        ]
+    );
+    match builder.build().compile().err() {
+        Some(AstBuildError::InvalidNodeType { node_id, type_name }) => {
+            assert_eq!((node_id.as_str(), type_name.as_str()), ("0004", "stNode"))
+        }
+        other => panic!("Expected InvalidNodeType error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_decisions_need_their_own_name_and_quality_node() {
+    let qualities = create_simple_qualities;
+
+    let builder = Compiler::builder(create_two_decision_flow(), qualities()).with_decision(
+        DEFAULT_DECISION,
+        "0005",
+        qualities(),
+    );
+    assert!(
+        builder
+            .validate()
+            .contains(&FlowDiagnostic::DuplicateDecision {
+                decision: DEFAULT_DECISION.to_string()
+            })
+    );
+    match builder.build().compile().err() {
+        Some(AstBuildError::DuplicateDecision(name)) => assert_eq!(name, DEFAULT_DECISION),
+        other => panic!("Expected DuplicateDecision error, got {:?}", other),
+    }
+
+    // The default decision already uses the first setQualityNode.
+    let builder = Compiler::builder(create_two_decision_flow(), qualities()).with_decision(
+        "alert",
+        "0003",
+        qualities(),
+    );
+    let shared = FlowDiagnostic::SharedDecisionNode {
+        node_id: "0003".to_string(),
+        first: DEFAULT_DECISION.to_string(),
+        second: "alert".to_string(),
+    };
+    assert!(builder.validate().contains(&shared));
+    assert_eq!(shared.node_id(), Some("0003"));
+    match builder.build().compile().err() {
+        Some(AstBuildError::SharedDecisionNode {
+            node_id,
+            first,
+            second,
+        }) => assert_eq!(
+            (node_id.as_str(), first.as_str(), second.as_str()),
+            ("0003", DEFAULT_DECISION, "alert")
+        ),
+        other => panic!("Expected SharedDecisionNode error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_validate_checks_output_nodes() {
+    // Only the first quality is wired up in the complex flow.
+    let qualities = create_complex_qualities()[..1].to_vec();
+    let mut flow = create_output_flow();
+    assert_eq!(flow.validate(&qualities), []);
+
+    flow.edges.pop();
+    assert_eq!(
+        flow.validate(&qualities),
+        [FlowDiagnostic::UnconnectedHandle {
+            node_id: "outputs".to_string(),
+            handle: 1
+        }]
+    );
+    match Compiler::builder(flow, qualities).build().compile().err() {
+        Some(AstBuildError::ConnectionError { target_node_id, .. }) => {
+            assert_eq!(target_node_id, "outputs")
+        }
+        other => panic!("Expected ConnectionError, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_validate_uses_registered_types_and_reports_unused_fields() {
+    let mut flow = create_simple_flow();
+    flow.nodes[0]
+        .data_fields
+        .as_mut()
+        .unwrap()
+        .push(DataFieldDefinition {
+            id: 1,
+            name: "Humidity".to_string(),
+            data_type: Some("number".to_string()),
+        });
+    flow.nodes[1].operation_type = "MyGreaterThan".to_string();
+    let qualities = create_simple_qualities()[..1].to_vec();
+
+    let builder = Compiler::builder(flow.clone(), qualities.clone())
+        .with_type_mapping("MyGreaterThan", "gtNode");
+    assert_eq!(
+        builder.validate(),
+        [FlowDiagnostic::UnusedDataField {
+            node_id: "0001".to_string(),
+            field: "Humidity".to_string()
+        }]
+    );
+    assert!(matches!(
+        flow.validate(&qualities)[0],
+        FlowDiagnostic::UnknownOperationType { .. }
+    ));
+}
+
+#[test]
+fn test_validation_reports_invalid_parameter_default() {
+    let qualities = create_simple_qualities()[..1].to_vec();
+    let mut flow = create_parameter_flow();
+    assert_eq!(flow.validate(&qualities), []);
+
+    flow.nodes[3].literal_values = Some(vec![serde_json::json!(true)]);
+    assert_eq!(
+        flow.validate(&qualities),
+        [FlowDiagnostic::InvalidParameter {
+            node_id: "threshold".to_string(),
+            message: "its default true is not of type 'number'".to_string()
+        }]
+    );
+    assert!(matches!(
+        Compiler::builder(flow, qualities).build().compile(),
+        Err(AstBuildError::InvalidParameter { .. })
+    ));
+}
+
+#[test]
+fn test_lookup_tables_are_checked_and_folded() {
+    let qualities = vec![Quality {
+        name: "Wet".to_string(),
+        priority: 1,
+    }];
+    let mut flow = create_lookup_flow();
+    flow.nodes[1].literal_values.as_mut().unwrap()[2] = serde_json::json!([5.0, 0.7]);
+    match Compiler::builder(flow, qualities.clone())
+        .build()
+        .compile()
+        .err()
+    {
+        Some(AstBuildError::InvalidTable { node_id, message }) => {
+            assert_eq!(node_id, "step");
+            assert!(message.contains("strictly increasing"), "{message}");
+        }
+        other => panic!("Expected InvalidTable error, got {:?}", other),
+    }
+
+    // A lookup of a constant is folded into its value.
+    let mut flow = create_lookup_flow();
+    flow.nodes[2].literal_values.as_mut().unwrap()[0] = serde_json::json!(25.0);
+    flow.edges.retain(|e| e.target != "allowance");
+    let artifacts = Compiler::builder(flow, qualities)
+        .build()
+        .compile()
+        .unwrap();
+    let outputs = artifacts
+        .iter()
+        .find(|a| a.name == "allowance")
+        .expect("an output artifact for the allowance");
+    assert_eq!(outputs.ast(), Expression::Literal(Value::Number(0.75)));
+}
+
+/// The simple flow with a second static field, `$Humidity`, also wired into the first
+/// input of the comparison.
+fn create_fan_in_flow() -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.nodes[0]
+        .data_fields
+        .as_mut()
+        .unwrap()
+        .push(DataFieldDefinition {
+            id: 1,
+            name: "Humidity".to_string(),
+            data_type: Some("number".to_string()),
+        });
+    flow.edges.push(FlowEdgeDefinition {
+        source: "0001".to_string(),
+        source_handle: 1,
+        target: "0002".to_string(),
+        target_handle: 0,
+    });
+    flow
+}
+
+#[test]
+fn test_compiler_rejects_fan_in_into_numeric_handle() {
+    let flow = create_fan_in_flow();
+    let qualities = create_simple_qualities()[..1].to_vec();
+    assert_eq!(
+        flow.validate(&qualities),
+        [FlowDiagnostic::IllegalFanIn {
+            node_id: "0002".to_string(),
+            handle: 0,
+            edges: 2
+        }]
+    );
+
+    match Compiler::builder(flow, qualities).build().compile().err() {
+        Some(AstBuildError::IllegalFanIn {
+            node_id,
+            handle,
+            sources,
+        }) => {
+            assert_eq!((node_id.as_str(), handle), ("0002", 0));
+            assert_eq!(sources, ["0001", "0001"]);
+        }
+        other => panic!("Expected IllegalFanIn error, got {:?}", other),
+    }
+}
+
+/// A `>` node that adds up all edges into the same handle.
+struct SummingGtParser;
+
+impl NodeParser for SummingGtParser {
+    fn node_type(&self) -> &str {
+        "gtNode"
+    }
+    fn fan_in(&self, _handle: u32) -> FanIn {
+        FanIn::Sum
+    }
+    fn parse(
+        &self,
+        _node: &FlowNodeDefinition,
+        mut inputs: Vec<Expression>,
+    ) -> Result<Expression, AstBuildError> {
+        let right = inputs.pop().unwrap();
+        let left = inputs.pop().unwrap();
+        Ok(Expression::GreaterThan(Box::new(left), Box::new(right)))
+    }
+}
+
+#[test]
+fn test_parser_declares_fan_in_combinator() {
+    let artifacts = Compiler::builder(create_fan_in_flow(), create_simple_qualities())
+        .with_custom_parser(Box::new(SummingGtParser))
+        .build()
+        .compile()
+        .unwrap();
+    match artifacts[0].ast() {
+        Expression::GreaterThan(left, _) => assert!(matches!(*left, Expression::Sum(..))),
+        other => panic!("Expected a comparison, got {:?}", other),
+    }
+}
+
+/// Splits `value` into `below` (handle 0), `inside` (handle 1) and `above` (handle 2)
+/// the range `[low, high]`, given as inputs `[value, low, high]`.
+struct RangeParser;
+
+impl NodeParser for RangeParser {
+    fn node_type(&self) -> &str {
+        "rangeNode"
+    }
+    fn parse(
+        &self,
+        node: &FlowNodeDefinition,
+        _inputs: Vec<Expression>,
+    ) -> Result<Expression, AstBuildError> {
+        Err(AstBuildError::InvalidNodeType {
+            node_id: node.id.clone(),
+            type_name: "rangeNode has several outputs".to_string(),
+        })
+    }
+    fn parse_outputs(
+        &self,
+        _node: &FlowNodeDefinition,
+        inputs: Vec<Expression>,
+    ) -> Result<NodeOutputs, AstBuildError> {
+        let [value, low, high] = <[Expression; 3]>::try_from(inputs).unwrap().map(Box::new);
+        let below = Expression::SmallerThan(value.clone(), low.clone());
+        let above = Expression::GreaterThan(value.clone(), high.clone());
+        let inside = Expression::And(
+            Box::new(Expression::GreaterThanOrEqual(value.clone(), low)),
+            Box::new(Expression::SmallerThanOrEqual(value, high)),
+        );
+        Ok(NodeOutputs::PerHandle(AHashMap::from([
+            (0, below),
+            (1, inside),
+            (2, above),
+        ])))
+    }
+}
+
+/// `$Temperature` split by a range node; `above` triggers quality 0, `below` quality 1.
+fn create_range_flow(below_handle: u32) -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.nodes[1].operation_type = "rangeNode".to_string();
+    flow.nodes[1].literal_values = Some(vec![
+        serde_json::Value::Null,
+        serde_json::json!(10.0),
+        serde_json::json!(20.0),
+    ]);
+    flow.edges[1].source_handle = 2;
+    flow.edges.push(FlowEdgeDefinition {
+        source: "0002".to_string(),
+        source_handle: below_handle,
+        target: "0003".to_string(),
+        target_handle: 1,
+    });
+    flow
+}
+
+#[test]
+fn test_edges_are_routed_to_output_handles() {
+    let artifacts = Compiler::builder(create_range_flow(0), create_simple_qualities())
+        .with_custom_parser(Box::new(RangeParser))
+        .build()
+        .compile()
+        .unwrap();
+    let evaluator = Evaluator::new(BackendChoice::Bytecode, artifacts).unwrap();
+    for (temperature, expected) in [(25.0, Some("Hot")), (5.0, Some("Normal")), (15.0, None)] {
+        let static_data = AHashMap::from([("Temperature".to_string(), temperature)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert_eq!(
+            result.quality_name.as_deref(),
+            expected,
+            "at {}",
+            temperature
+        );
+    }
+
+    let result = Compiler::builder(create_range_flow(7), create_simple_qualities())
+        .with_custom_parser(Box::new(RangeParser))
+        .build()
+        .compile();
+    match result.err() {
+        Some(AstBuildError::UnknownOutputHandle {
+            node_id, handle, ..
+        }) => assert_eq!((node_id.as_str(), handle), ("0002", 7)),
+        other => panic!("Expected UnknownOutputHandle error, got {:?}", other),
+    }
+}
+
+fn flow_node(id: &str, operation_type: &str) -> FlowNodeDefinition {
+    FlowNodeDefinition {
+        id: id.to_string(),
+        operation_type: operation_type.to_string(),
+        input_type: None,
+        literal_values: None,
+        data_fields: None,
+    }
+}
+
+/// A sub-flow `name` with one input and one output: `input > 25`, or `input > name(input)`
+/// when `recursive` is set.
+fn create_is_hot_sub_flow(name: &str, recursive: bool) -> SubFlowDefinition {
+    let mut gt = flow_node("gt", "gtNode");
+    gt.literal_values = Some(vec![serde_json::Value::Null, serde_json::json!(25.0)]);
+    let mut flow = FlowDefinition {
+        nodes: vec![flow_node("in", "subFlowInputNode"), gt],
+        edges: vec![edge("in", "gt", 0)],
+    };
+    if recursive {
+        flow.nodes.push(flow_node("inner", name));
+        flow.edges.push(edge("in", "inner", 0));
+        flow.edges.push(edge("inner", "gt", 1));
+    }
+    SubFlowDefinition {
+        name: name.to_string(),
+        flow,
+        inputs: vec!["in".to_string()],
+        outputs: vec![SubFlowOutput {
+            node_id: "gt".to_string(),
+            handle: 0,
+        }],
+    }
+}
+
+/// The simple flow with its comparison replaced by the composite node `isHot`.
+fn create_composite_flow() -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.nodes[1] = flow_node("0002", "isHot");
+    flow
+}
+
+#[test]
+fn test_sub_flows_are_expanded() {
+    let builder = Compiler::builder(create_composite_flow(), create_simple_qualities())
+        .with_sub_flow(create_is_hot_sub_flow("isHot", false));
+    assert_eq!(builder.validate().len(), 1); // Only the unconnected second quality.
+    let artifacts = builder.build().compile().unwrap();
+
+    let direct = Compiler::builder(create_simple_flow(), create_simple_qualities())
+        .build()
+        .compile()
+        .unwrap();
+    assert_eq!(artifacts[0].ast(), direct[0].ast());
+}
+
+#[test]
+fn test_recursive_sub_flows_are_rejected() {
+    let result = Compiler::builder(create_composite_flow(), create_simple_qualities())
+        .with_sub_flow(create_is_hot_sub_flow("isHot", true))
+        .build()
+        .compile();
+    match result.err() {
+        Some(AstBuildError::RecursiveSubFlow { chain }) => assert_eq!(chain, ["isHot", "isHot"]),
+        other => panic!("Expected RecursiveSubFlow error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_sub_flow_signature_mismatch_is_reported() {
+    let mut flow = create_composite_flow();
+    flow.edges.push(edge("0001", "0002", 1));
+    let result = Compiler::builder(flow, create_simple_qualities())
+        .with_sub_flow(create_is_hot_sub_flow("isHot", false))
+        .build()
+        .compile();
+    match result.err() {
+        Some(AstBuildError::SubFlowSignatureMismatch {
+            node_id, sub_flow, ..
+        }) => assert_eq!((node_id.as_str(), sub_flow.as_str()), ("0002", "isHot")),
+        other => panic!("Expected SubFlowSignatureMismatch error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_graph_shares_literals_only_when_their_bits_match() {
+    let mut graph = ExpressionGraph::new();
+    let zero = graph.add(Node::Literal(Value::Number(0.0)));
+    let negative_zero = graph.add(Node::Literal(Value::Number(-0.0)));
+    assert_ne!(zero, negative_zero);
+    assert_eq!(zero, graph.add(Node::Literal(Value::Number(0.0))));
+
+    let nan = graph.add(Node::Literal(Value::Number(f64::NAN)));
+    assert_eq!(nan, graph.add(Node::Literal(Value::Number(f64::NAN))));
+}
+
+/// Builds an artifact where `$Temperature > 25` is used twice, so it becomes definition #0.
+fn create_shared_artifact() -> CompilationArtifacts {
+    let shared = Expression::GreaterThan(
+        Box::new(Expression::Input(InputSource::Static { id: 0 })),
+        Box::new(Expression::Literal(Value::Number(25.0))),
+    );
+    let mut graph = ExpressionGraph::new();
+    let shared = graph.insert(&shared, &AHashMap::new()).unwrap();
+    let negated = graph.add(Node::Not(shared));
+    let root = graph.add(Node::And(shared, negated));
+    CompilationArtifacts {
+        kind: PathKind::Quality,
+        decision: DEFAULT_DECISION.to_string(),
+        priority: 1,
+        name: "Hot".to_string(),
+        graph,
+        root,
+        static_map: AHashMap::from([("Temperature".to_string(), 0)]),
+        dynamic_map: AHashMap::new(),
+        parameters: Vec::new(),
+        stats: OptimizerStats::default(),
+        combinations: CombinationPolicy::default(),
+        split: PathSplit::whole(root),
+    }
+}
+
+#[test]
+fn test_export_json_lists_shared_definitions_once() {
+    let json = create_shared_artifact().to_json_value();
+    assert_eq!(json["name"], "Hot");
+    assert_eq!(json["root"]["op"], "And");
+    assert_eq!(
+        json["root"]["args"][0],
+        serde_json::json!({ "op": "Reference", "id": 0 })
+    );
+
+    let definitions = json["definitions"].as_array().unwrap();
+    assert_eq!(definitions.len(), 1);
+    let input = &definitions[0]["expr"]["args"][0];
+    assert_eq!(input["name"], "$Temperature");
+}
+
+#[test]
+fn test_export_graphs_share_reference_nodes() {
+    let artifact = create_shared_artifact();
+
+    let dot = artifact.to_dot();
+    assert!(dot.starts_with("digraph \"Hot\" {"));
+    assert_eq!(dot.matches("def0 [label=").count(), 1);
+    assert_eq!(dot.matches("-> def0;").count(), 2);
+
+    let mermaid = artifact.to_mermaid();
+    assert!(mermaid.starts_with("flowchart TD"));
+    assert_eq!(mermaid.matches("def0[[").count(), 1);
+    assert_eq!(mermaid.matches("--> def0").count(), 2);
+    assert!(mermaid.contains("#62;"), "'>' must be escaped for Mermaid");
+}
+
+/// Records the order of observer callbacks for a compilation.
+#[derive(Default)]
+struct StageRecorder {
+    stages: Vec<String>,
+    bytecode_len: usize,
+}
+
+impl CompilationObserver for StageRecorder {
+    fn on_naive_ast(&mut self, quality: &Quality, _ast: &Expression) {
+        self.stages.push(format!("{}:naive", quality.name));
+    }
+
+    fn on_interned_ast(
+        &mut self,
+        _quality: &Quality,
+        _ast: &Expression,
+        static_map: &AHashMap<String, InputId>,
+        _dynamic_map: &AHashMap<String, InputId>,
+    ) {
+        self.stages.push(format!("interned:{}", static_map.len()));
+    }
+
+    fn on_optimizer_pass(
+        &mut self,
+        _quality: &Quality,
+        pass: OptimizerPass,
+        iteration: usize,
+        _ast: &Expression,
+        _definitions: &AHashMap<u64, Expression>,
+    ) {
+        self.stages.push(format!("{:?}#{}", pass, iteration));
+    }
+
+    fn on_optimized_ast(
+        &mut self,
+        _quality: &Quality,
+        _ast: &Expression,
+        _definitions: &AHashMap<u64, Expression>,
+    ) {
+        self.stages.push("optimized".to_string());
+    }
+
+    fn on_bytecode(
+        &mut self,
+        _quality: &Quality,
+        program: Result<&BytecodeProgram, &BackendError>,
+    ) {
+        self.bytecode_len = program.unwrap().main.len();
+        self.stages.push("bytecode".to_string());
+    }
+}
+
+#[test]
+fn test_compilation_observer_receives_all_stages() {
+    let recorder = Arc::new(Mutex::new(StageRecorder::default()));
+    let compiler = Compiler::builder(create_simple_flow(), create_simple_qualities())
+        .with_observer(recorder.clone())
+        .build();
+    compiler.compile().unwrap();
+
+    let recorder = recorder.lock().unwrap();
+    assert_eq!(
+        recorder.stages,
+        vec![
+            "Hot:naive",
+            "interned:1",
+            "FoldAndSimplify#0",
+            "IntervalAnalysis#0",
+            "Canonicalization#0",
+            "CommonSubexpressionElimination#0",
+            "optimized",
+            "bytecode",
+        ]
+    );
+    assert!(recorder.bytecode_len > 0);
+}
+
+/// Builds the simple flow with the input edge removed, so the comparison is `30 > 25`.
+fn create_constant_flow() -> FlowDefinition {
+    let mut flow = create_simple_flow();
+    flow.edges.remove(0);
+    flow.nodes[1].literal_values = Some(vec![serde_json::json!(30.0), serde_json::json!(25.0)]);
+    flow
+}
+
+#[test]
+fn test_optimization_level_o0_keeps_ast_unchanged() {
+    let artifacts = Compiler::builder(create_constant_flow(), create_simple_qualities())
+        .with_optimization_level(OptimizationLevel::O0)
+        .build()
+        .compile()
+        .unwrap();
+
+    assert_eq!(
+        artifacts[0].ast(),
+        Expression::GreaterThan(
+            Box::new(Expression::Literal(Value::Number(30.0))),
+            Box::new(Expression::Literal(Value::Number(25.0))),
+        )
+    );
+    assert!(artifacts[0].definitions().is_empty());
+}
+
+#[test]
+fn test_optimizer_passes_can_be_disabled_individually() {
+    let artifacts = Compiler::builder(create_constant_flow(), create_simple_qualities())
+        .with_optimization_level(OptimizationLevel::O1)
+        .build()
+        .compile()
+        .unwrap();
+    assert_eq!(artifacts[0].ast(), Expression::Literal(Value::Bool(true)));
+
+    // (X + Y > 5) OR (X + Y < 1) uses the sum twice.
+    let expr = || {
+        Expression::Or(
+            gt(sum(static_input("X"), static_input("Y")), number(5.0)),
+            lt(sum(static_input("X"), static_input("Y")), number(1.0)),
+        )
+    };
+    let passes = OptimizerPasses {
+        common_subexpression_elimination: false,
+        ..OptimizerPasses::all()
+    };
+    assert!(
+        compile_fixed_expression(expr(), passes)
+            .definitions()
+            .is_empty()
+    );
+    assert!(
+        !compile_fixed_expression(expr(), OptimizerPasses::all())
+            .definitions()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_optimizer_stats_are_reported_per_quality() {
+    let artifacts = Compiler::builder(create_constant_flow(), create_simple_qualities())
+        .build()
+        .compile()
+        .unwrap();
+    let stats = artifacts[0].stats;
+    assert_eq!(stats.nodes_before, 3);
+    assert_eq!(stats.nodes_after, 1);
+    assert_eq!(stats.constants_folded, 1);
+    assert_eq!(stats.branches_eliminated, 0);
+    assert!(
+        stats.iterations >= 2,
+        "needs one extra iteration to detect the fixed point"
+    );
+    assert!(stats.converged);
+
+    let artifacts = Compiler::builder(create_constant_flow(), create_simple_qualities())
+        .with_optimization_level(OptimizationLevel::O0)
+        .build()
+        .compile()
+        .unwrap();
+    let stats = artifacts[0].stats;
+    assert_eq!(stats.nodes_before, stats.nodes_after);
+    assert_eq!(stats.constants_folded, 0);
+}
+
+#[test]
+fn test_cse_definitions_are_deterministic_and_distinct() {
+    let compile = || {
+        Compiler::builder(create_complex_flow(), create_complex_qualities())
+            .build()
+            .compile()
+            .unwrap()
+    };
+    let first = compile();
+    let second = compile();
+    assert_eq!(first[0].ast(), second[0].ast());
+    assert_eq!(first[0].definitions(), second[0].definitions());
+
+    // IDs are dense and every definition is a structurally different expression.
+    let definitions = &first[0].definitions();
+    let mut ids: Vec<u64> = definitions.keys().copied().collect();
+    ids.sort_unstable();
+    assert_eq!(ids, (0..definitions.len() as u64).collect::<Vec<_>>());
+    for a in &ids {
+        for b in &ids {
+            if a != b {
+                assert_ne!(definitions[a], definitions[b]);
+            }
+        }
+    }
+}
+
+fn gt(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
+    Box::new(Expression::GreaterThan(l, r))
+}
+
+fn lt(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
+    Box::new(Expression::SmallerThan(l, r))
+}
+
+/// `$X` and `$Y` after interning, in order of first appearance.
+fn x() -> Box<Expression> {
+    Box::new(Expression::Input(InputSource::Static { id: 0 }))
+}
+
+fn y() -> Box<Expression> {
+    Box::new(Expression::Input(InputSource::Static { id: 1 }))
+}
+
+fn optimize_without_cse(expr: Expression) -> Expression {
+    compile_fixed_expression(expr, OptimizationLevel::O1.into()).ast()
+}
+
+#[test]
+fn test_interval_analysis_removes_redundant_comparisons() {
+    // x > 5 AND x > 3  ->  x > 5
+    let expr = Expression::And(
+        gt(static_input("X"), number(5.0)),
+        gt(static_input("X"), number(3.0)),
+    );
+    assert_eq!(optimize_without_cse(expr), *gt(x(), number(5.0)));
+
+    // x > 3 AND x > 5  ->  x > 5
+    let expr = Expression::And(
+        gt(static_input("X"), number(3.0)),
+        gt(static_input("X"), number(5.0)),
+    );
+    assert_eq!(optimize_without_cse(expr), *gt(x(), number(5.0)));
+
+    // x >= 10 OR x > 2  ->  x > 2
+    let expr = Expression::Or(
+        Box::new(Expression::GreaterThanOrEqual(
+            static_input("X"),
+            number(10.0),
+        )),
+        gt(static_input("X"), number(2.0)),
+    );
+    assert_eq!(optimize_without_cse(expr), *gt(x(), number(2.0)));
+}
+
+#[test]
+fn test_interval_analysis_decides_nested_conditions() {
+    // x > 5 AND (x < 3 OR y > 1)  ->  x > 5 AND y > 1
+    let expr = Expression::And(
+        gt(static_input("X"), number(5.0)),
+        Box::new(Expression::Or(
+            lt(static_input("X"), number(3.0)),
+            gt(static_input("Y"), number(1.0)),
+        )),
+    );
+    assert_eq!(
+        optimize_without_cse(expr),
+        Expression::And(gt(x(), number(5.0)), gt(y(), number(1.0)))
+    );
+
+    // (x > 10 AND y > 0) AND 4 > x  ->  false
+    let expr = Expression::And(
+        Box::new(Expression::And(
+            gt(static_input("X"), number(10.0)),
+            gt(static_input("Y"), number(0.0)),
+        )),
+        gt(number(4.0), static_input("X")),
+    );
+    assert_eq!(
+        optimize_without_cse(expr),
+        Expression::Literal(Value::Bool(false))
+    );
+
+    // Overlapping ranges on different inputs are left alone.
+    let expr = Expression::And(
+        gt(static_input("X"), number(5.0)),
+        lt(static_input("Y"), number(3.0)),
+    );
+    assert_eq!(
+        optimize_without_cse(expr),
+        Expression::And(gt(x(), number(5.0)), lt(y(), number(3.0)))
+    );
+}
+
+fn sum(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
+    Box::new(Expression::Sum(l, r))
+}
+
+fn and(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
+    Box::new(Expression::And(l, r))
+}
+
+#[test]
+fn test_canonicalization_normalises_nesting_but_keeps_operand_order() {
+    let passes = OptimizerPasses {
+        canonicalization: true,
+        ..OptimizationLevel::O1.into()
+    };
+
+    // Reasons show comparisons as written, so `3 < x` is not turned into `x > 3`.
+    let expr = lt(number(3.0), static_input("X"));
+    assert_eq!(
+        compile_fixed_expression(*expr, passes).ast(),
+        *lt(number(3.0), x())
+    );
+
+    // (a AND b) AND c  ==  a AND (b AND c), and c AND (b AND a) is rebuilt in its own order
+    let a = || gt(static_input("X"), number(1.0));
+    let b = || gt(static_input("Y"), number(1.0));
+    let c = || gt(static_input("Z"), number(1.0));
+    let left_deep = compile_fixed_expression(*and(and(a(), b()), c()), passes);
+    let right_deep = compile_fixed_expression(*and(a(), and(b(), c())), passes);
+    assert_eq!(left_deep.ast(), right_deep.ast());
+    let reversed = compile_fixed_expression(*and(c(), and(b(), a())), passes);
+    let condition = |name: &str| {
+        let id = reversed.static_map[name];
+        gt(
+            Box::new(Expression::Input(InputSource::Static { id })),
+            number(1.0),
+        )
+    };
+    assert_eq!(
+        reversed.ast(),
+        *and(and(condition("Z"), condition("Y")), condition("X"))
+    );
+}
+
+#[test]
+fn test_canonicalization_keeps_short_circuit_order() {
+    // `NOT ($X + true > 1)` fails, but only if it is evaluated: the left operand decides
+    // both. It would sort before the comparison on the left.
+    let truth = || Box::new(Expression::Literal(Value::Bool(true)));
+    let fails = || {
+        Box::new(Expression::Not(gt(
+            sum(static_input("X"), truth()),
+            number(1.0),
+        )))
+    };
+    let expressions = [
+        Expression::And(gt(static_input("X"), number(100.0)), fails()),
+        Expression::Or(lt(static_input("X"), number(100.0)), fails()),
+    ];
+    let static_data = AHashMap::from([("X".to_string(), 5.0)]);
+    for (expr, expected) in expressions.into_iter().zip([None, Some("Fixed")]) {
+        for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+            let artifact = compile_fixed_expression(expr.clone(), OptimizerPasses::all());
+            let result = Evaluator::new(choice, vec![artifact])
+                .unwrap()
+                .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+                .unwrap();
+            assert_eq!(result.quality_name.as_deref(), expected, "{expr:?}");
+        }
+    }
+}
+
+#[test]
+fn test_canonicalization_never_regroups_sums() {
+    let passes = OptimizerPasses {
+        canonicalization: true,
+        ..OptimizationLevel::O1.into()
+    };
+    let (x, y, z) = (
+        || static_input("X"),
+        || static_input("Y"),
+        || static_input("Z"),
+    );
+
+    // Y + X  ->  X + Y
+    assert_eq!(
+        compile_fixed_expression(*sum(y(), x()), passes).ast(),
+        compile_fixed_expression(*sum(x(), y()), passes).ast()
+    );
+
+    // (X + Y) + Z and X + (Y + Z) can round differently, so they stay apart.
+    let left_deep = compile_fixed_expression(*sum(sum(x(), y()), z()), passes);
+    let right_deep = compile_fixed_expression(*sum(x(), sum(y(), z())), passes);
+    assert_ne!(left_deep.ast(), right_deep.ast());
+}
+
+#[test]
+fn test_canonicalization_lets_cse_share_commuted_operands() {
+    // (X + Y > 5) OR (1 > Y + X)
+    let expr = || {
+        Expression::Or(
+            gt(sum(static_input("X"), static_input("Y")), number(5.0)),
+            gt(number(1.0), sum(static_input("Y"), static_input("X"))),
+        )
+    };
+    let count_sums = |artifact: &CompilationArtifacts| {
+        artifact
+            .definitions()
+            .values()
+            .filter(|def| matches!(def, Expression::Sum(..)))
+            .count()
+    };
+
+    let canonical = compile_fixed_expression(expr(), OptimizerPasses::all());
+    assert_eq!(count_sums(&canonical), 1);
+
+    let passes = OptimizerPasses {
+        canonicalization: false,
+        ..OptimizerPasses::all()
+    };
+    // Without canonicalization `X + Y` and `Y + X` are different nodes, so nothing is shared.
+    let raw = compile_fixed_expression(expr(), passes);
+    assert_eq!(count_sums(&raw), 0);
+}
+
+#[test]
+fn test_predicate_pushdown_splits_conditions_by_event() {
+    let artifact = compile_fixed_expression(create_pushdown_expression(), OptimizerPasses::all());
+    let split = artifact
+        .split
+        .map(|part| artifact.graph.to_expression(part));
+    let limit = Box::new(Expression::Input(InputSource::Static {
+        id: artifact.static_map["Limit"],
+    }));
+    let x = |event: &str| {
+        Box::new(Expression::Input(InputSource::Dynamic {
+            id: artifact.dynamic_map[&format!("{event}.X")],
+        }))
+    };
+
+    assert_eq!(split.guard, Some(*gt(limit, number(0.0))));
+    assert_eq!(
+        split.filters,
+        vec![
+            ("hole#a".to_string(), *gt(x("hole#a"), number(50.0))),
+            ("hole#b".to_string(), *gt(x("hole#b"), number(50.0))),
+        ]
+    );
+    assert!(split.residual.is_some());
+    assert_eq!(artifact.stats.conditions_pushed_down, 2);
+
+    let passes = OptimizerPasses {
+        predicate_pushdown: false,
+        ..OptimizerPasses::all()
+    };
+    let whole = compile_fixed_expression(create_pushdown_expression(), passes);
+    assert_eq!(whole.split, PathSplit::whole(whole.root));
+    assert_eq!(whole.stats.conditions_pushed_down, 0);
+}
+
+#[test]
+fn test_missing_native_function_is_reported_at_load() {
+    let compile = || {
+        Compiler::builder(create_call_flow(), create_simple_qualities())
+            .build()
+            .compile()
+            .unwrap()
+    };
+    let expected = BackendError::UnknownFunction("cylinder_volume".to_string());
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let err = Evaluator::new(choice, compile()).err().unwrap();
+        assert_eq!(err, expected);
+    }
+
+    // A compiled recipe names the functions it needs, so the check also covers recipe files.
+    let recipe = hantei::bytecode::BytecodeBackend
+        .compile(compile())
+        .unwrap();
+    let unrelated = FunctionRegistry::new().with_function("hypot", |_: &[Value]| Ok(Value::Null));
+    let err = Evaluator::from_compiled_recipe(BackendChoice::Bytecode, recipe, &unrelated)
+        .err()
+        .unwrap();
+    assert_eq!(err, expected);
+    assert!(err.to_string().contains("'cylinder_volume'"));
+
+    let mut flow = create_call_flow();
+    flow.nodes[1].data_fields = None;
+    let err = Compiler::builder(flow, create_simple_qualities())
+        .build()
+        .compile()
+        .err()
+        .unwrap();
+    assert!(
+        format!("{err:?}").contains("InvalidFunctionCall"),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn test_event_alias_needs_event_type_and_alias() {
+    let mut flow = create_event_alias_flow(0.0);
+    flow.nodes[1].input_type = Some("hole#".to_string());
+    let err = Compiler::builder(flow, create_simple_qualities())
+        .build()
+        .compile()
+        .err()
+        .unwrap();
+    assert!(
+        matches!(&err, AstBuildError::InvalidEventAlias { node_id, event } if node_id == "b" && event == "hole#"),
+        "{err:?}"
+    );
+
+    // Aliases are kept in the dynamic map, so each reads its own instance.
+    let artifacts = Compiler::builder(create_event_alias_flow(0.0), create_simple_qualities())
+        .build()
+        .compile()
+        .unwrap();
+    let mut keys: Vec<_> = artifacts[0].dynamic_map.keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys, ["hole#a.X", "hole#b.X"]);
+}
+
+#[test]
+fn test_spatial_nodes_read_event_geometry() {
+    let flow = create_spatial_flow("overlapNode", &["knot", "hole#a"], &[], None);
+    let artifacts = Compiler::builder(flow, create_simple_qualities())
+        .build()
+        .compile()
+        .unwrap();
+    let mut keys: Vec<_> = artifacts[0].dynamic_map.keys().cloned().collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        [
+            "hole#a.Length",
+            "hole#a.Width",
+            "hole#a.X",
+            "hole#a.Y",
+            "knot.Length",
+            "knot.Width",
+            "knot.X",
+            "knot.Y"
+        ]
+    );
+
+    let flow = create_spatial_flow("distanceNode", &["knot"], &[], Some(50.0));
+    let err = Compiler::builder(flow, create_simple_qualities())
+        .build()
+        .compile()
+        .err()
+        .unwrap();
+    assert!(
+        matches!(&err, AstBuildError::InvalidSpatialNode { node_id, .. } if node_id == "spatial"),
+        "{err:?}"
+    );
+
+    // The board size is an input of the node, so a missing one is caught.
+    let flow = create_spatial_flow("edgeDistanceNode", &["knot"], &["Board width"], None);
+    let err = Compiler::builder(flow, create_simple_qualities())
+        .build()
+        .compile()
+        .err()
+        .unwrap();
+    assert!(
+        matches!(&err, AstBuildError::ConnectionError { target_node_id, .. } if target_node_id == "spatial"),
+        "{err:?}"
+    );
+}
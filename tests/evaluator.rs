@@ -2,8 +2,14 @@
 mod common;
 use ahash::AHashMap;
 use common::*;
-use hantei::backend::BackendChoice;
+use hantei::backend::{BackendChoice, EvaluationBackend};
+use hantei::bytecode::BytecodeBackend;
+use hantei::bytecode::opcode::OpCode;
+use hantei::compiler::{OptimizationLevel, OptimizerPasses};
+use hantei::events::EventPairing;
+use hantei::interpreter::InterpreterBackend;
 use hantei::prelude::*;
+use hantei::recipe::RECIPE_FORMAT_VERSION;
 
 #[test]
 fn test_static_evaluation_trigger() {
@@ -17,7 +23,9 @@ fn test_static_evaluation_trigger() {
     static_data.insert("Temperature".to_string(), 30.0);
     let dynamic_data = AHashMap::new();
 
-    let result = evaluator.eval(&static_data, &dynamic_data).unwrap();
+    let result = evaluator
+        .eval(&static_data, &dynamic_data, &AHashMap::new())
+        .unwrap();
     assert_eq!(result.quality_name.as_deref(), Some("Hot"));
     assert!(result.reason.contains("$Temperature (was 30) > 25"));
 }
@@ -34,7 +42,9 @@ fn test_static_evaluation_no_trigger() {
     static_data.insert("Temperature".to_string(), 20.0);
     let dynamic_data = AHashMap::new();
 
-    let result = evaluator.eval(&static_data, &dynamic_data).unwrap();
+    let result = evaluator
+        .eval(&static_data, &dynamic_data, &AHashMap::new())
+        .unwrap();
     assert!(result.quality_name.is_none());
 }
 
@@ -55,7 +65,768 @@ fn test_dynamic_cross_product_evaluation() {
     hole_events.push(AHashMap::from([("Diameter".to_string(), 8.0)]));
     dynamic_data.insert("hole".to_string(), hole_events);
 
-    let result = evaluator.eval(&static_data, &dynamic_data).unwrap();
+    let result = evaluator
+        .eval(&static_data, &dynamic_data, &AHashMap::new())
+        .unwrap();
     assert_eq!(result.quality_name.as_deref(), Some("Premium"));
     assert!(result.reason.contains("$hole.Diameter (was 8)"));
 }
+
+#[test]
+fn test_each_decision_reports_its_own_quality() {
+    let alert = vec![Quality {
+        name: "Cold".to_string(),
+        priority: 1,
+    }];
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let artifacts = Compiler::builder(create_two_decision_flow(), create_simple_qualities())
+            .with_decision("alert", "0005", alert.clone())
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+
+        let static_data = AHashMap::from([("Temperature".to_string(), 5.0)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        let names: Vec<_> = result.decisions.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["quality", "alert"]);
+        assert!(result.quality_name.is_none());
+        let alert_result = result.decision("alert").unwrap();
+        assert_eq!(alert_result.quality_name.as_deref(), Some("Cold"));
+        assert_eq!(alert_result.quality_priority, Some(1));
+
+        let static_data = AHashMap::from([("Temperature".to_string(), 30.0)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Hot"));
+        assert_eq!(result.decisions[0].quality_name.as_deref(), Some("Hot"));
+        assert!(result.decision("alert").unwrap().quality_name.is_none());
+    }
+}
+
+#[test]
+fn test_outputs_are_reported_by_name() {
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let artifacts = Compiler::builder(create_output_flow(), create_complex_qualities())
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+
+        let static_data = AHashMap::from([("Temperature".to_string(), 35.0)]);
+        let mut dynamic_data = AHashMap::new();
+        dynamic_data.insert(
+            "hole".to_string(),
+            vec![
+                AHashMap::from([("Diameter".to_string(), 12.0)]),
+                AHashMap::from([("Diameter".to_string(), 8.0)]),
+            ],
+        );
+        let result = evaluator
+            .eval(&static_data, &dynamic_data, &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Premium"));
+        assert_eq!(result.outputs["score"], Value::Number(70.0));
+        // The two holes disagree on the diameter, so it has no single value.
+        assert_eq!(result.outputs["diameter"], Value::Null);
+
+        // Without any hole, the output that reads one has no value.
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert!(result.quality_name.is_none());
+        assert_eq!(result.outputs["score"], Value::Number(70.0));
+        assert_eq!(result.outputs["diameter"], Value::Null);
+    }
+}
+
+#[test]
+fn test_outputs_that_read_events_need_every_instance_to_agree() {
+    let holes = |diameters: &[f64]| {
+        AHashMap::from([(
+            "hole".to_string(),
+            diameters
+                .iter()
+                .map(|d| AHashMap::from([("Diameter".to_string(), *d)]))
+                .collect::<Vec<_>>(),
+        )])
+    };
+    let static_data = AHashMap::from([("Temperature".to_string(), 35.0)]);
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let artifacts = Compiler::builder(create_output_flow(), create_complex_qualities())
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+        let diameter = |diameters: &[f64]| {
+            evaluator
+                .eval(&static_data, &holes(diameters), &AHashMap::new())
+                .unwrap()
+                .outputs["diameter"]
+                .clone()
+        };
+
+        assert_eq!(diameter(&[12.0]), Value::Number(12.0));
+        assert_eq!(diameter(&[12.0, 12.0, 12.0]), Value::Number(12.0));
+        // The order of the instances does not matter.
+        assert_eq!(diameter(&[12.0, 8.0, 12.0]), Value::Null);
+        assert_eq!(diameter(&[8.0, 12.0, 12.0]), Value::Null);
+    }
+}
+
+#[test]
+fn test_saved_recipes_check_their_format_version() {
+    let backends: [(BackendChoice, &dyn EvaluationBackend); 2] = [
+        (BackendChoice::Interpreter, &InterpreterBackend),
+        (BackendChoice::Bytecode, &BytecodeBackend),
+    ];
+    for (choice, backend) in backends {
+        let artifacts = Compiler::builder(create_simple_flow(), create_simple_qualities())
+            .build()
+            .compile()
+            .unwrap();
+        let bytes = backend.compile(artifacts).unwrap().to_bytes().unwrap();
+
+        let evaluator = Evaluator::from_bytes(choice, &bytes).unwrap();
+        let static_data = AHashMap::from([("Temperature".to_string(), 28.0)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Hot"));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(RECIPE_FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            Evaluator::from_bytes(choice, &newer).err(),
+            Some(BackendError::RecipeFormatMismatch {
+                found: RECIPE_FORMAT_VERSION + 1,
+                expected: RECIPE_FORMAT_VERSION,
+            })
+        );
+        // A recipe saved without the header.
+        assert_eq!(
+            Evaluator::from_bytes(choice, &bytes[6..]).err(),
+            Some(BackendError::NotACompiledRecipe)
+        );
+    }
+}
+
+#[test]
+fn test_saved_recipes_with_invalid_bytecode_are_rejected() {
+    let artifacts = Compiler::builder(create_simple_flow(), create_simple_qualities())
+        .build()
+        .compile()
+        .unwrap();
+    let mut recipe = BytecodeBackend.compile(artifacts).unwrap();
+    let path = &mut recipe.bytecode_programs.as_mut().unwrap()[0];
+    let name = path.name.clone();
+    path.program.main.insert(0, OpCode::Move(0, 200));
+    let bytes = recipe.to_bytes().unwrap();
+
+    // The VM reads registers unchecked, so the program must not get as far as running.
+    let err = Evaluator::from_bytes(BackendChoice::Bytecode, &bytes)
+        .err()
+        .unwrap();
+    assert_eq!(
+        err,
+        BackendError::InvalidBytecode {
+            path: name,
+            error: ProgramError::InvalidRegister {
+                chunk: ".main".to_string(),
+                index: 0,
+                register: 200,
+            },
+        }
+    );
+}
+
+#[test]
+fn test_parameters_can_be_overridden_per_evaluation() {
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let artifacts = Compiler::builder(create_parameter_flow(), create_simple_qualities())
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+        assert_eq!(evaluator.parameters().len(), 1);
+        assert_eq!(evaluator.parameters()[0].name, "threshold");
+        assert_eq!(evaluator.parameters()[0].default, Value::Number(25.0));
+
+        let static_data = AHashMap::from([("Temperature".to_string(), 28.0)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Hot"));
+        if matches!(choice, BackendChoice::Interpreter) {
+            assert!(result.reason.contains("@threshold (was 25)"));
+        }
+
+        let overrides = AHashMap::from([("threshold".to_string(), Value::Number(30.0))]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &overrides)
+            .unwrap();
+        assert!(result.quality_name.is_none());
+
+        let unknown = AHashMap::from([("limit".to_string(), Value::Number(30.0))]);
+        assert_eq!(
+            evaluator.eval(&static_data, &AHashMap::new(), &unknown),
+            Err(EvaluationError::UnknownParameter("limit".to_string()))
+        );
+        let mistyped = AHashMap::from([("threshold".to_string(), Value::Bool(true))]);
+        assert!(matches!(
+            evaluator.eval(&static_data, &AHashMap::new(), &mistyped),
+            Err(EvaluationError::TypeMismatch { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_lookup_tables_step_and_interpolate() {
+    let qualities = vec![Quality {
+        name: "Wet".to_string(),
+        priority: 1,
+    }];
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let artifacts = Compiler::builder(create_lookup_flow(), qualities.clone())
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+
+        // Below, inside, on and beyond the rows of the table.
+        for (thickness, step, allowance) in [
+            (5.0, 0.5, 0.5),
+            (15.0, 0.5, 0.6),
+            (20.0, 0.7, 0.7),
+            (25.0, 0.7, 0.75),
+            (40.0, 0.8, 0.8),
+        ] {
+            let static_data = AHashMap::from([("Thickness".to_string(), thickness)]);
+            let result = evaluator
+                .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+                .unwrap();
+            assert_eq!(result.outputs["step"], Value::Number(step));
+            let Value::Number(value) = result.outputs["allowance"] else {
+                panic!("allowance is not a number");
+            };
+            assert!((value - allowance).abs() < 1e-9, "{thickness}: {value}");
+        }
+
+        let static_data = AHashMap::from([("Thickness".to_string(), 28.0)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Wet"));
+        if matches!(choice, BackendChoice::Interpreter) {
+            assert!(
+                result
+                    .reason
+                    .starts_with("INTERPOLATE($Thickness (was 28), rows 20 -> 0.7, 30 -> 0.8)")
+            );
+        }
+    }
+}
+
+#[test]
+fn test_native_functions_are_called_by_both_backends() {
+    let functions =
+        FunctionRegistry::new().with_function("cylinder_volume", |args: &[Value]| match args {
+            [Value::Number(d), Value::Number(h)] if *h >= 0.0 => {
+                Ok(Value::Number(std::f64::consts::PI * d * d / 4.0 * h))
+            }
+            _ => Err(format!("invalid dimensions {:?}", args)),
+        });
+    let qualities = vec![Quality {
+        name: "Large".to_string(),
+        priority: 1,
+    }];
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let artifacts = Compiler::builder(create_call_flow(), qualities.clone())
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::with_functions(choice, artifacts, &functions).unwrap();
+
+        let static_data =
+            AHashMap::from([("Diameter".to_string(), 10.0), ("Height".to_string(), 20.0)]);
+        let result = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Large"));
+        let Value::Number(volume) = result.outputs["volume"] else {
+            panic!("volume is not a number");
+        };
+        assert!((volume - 500.0 * std::f64::consts::PI).abs() < 1e-9);
+        if matches!(choice, BackendChoice::Interpreter) {
+            assert!(
+                result
+                    .reason
+                    .starts_with("cylinder_volume($Diameter (was 10), $Height (was 20))")
+            );
+        }
+
+        // A failing function is reported with its name by both backends.
+        let static_data =
+            AHashMap::from([("Diameter".to_string(), 10.0), ("Height".to_string(), -1.0)]);
+        let err = evaluator
+            .eval(&static_data, &AHashMap::new(), &AHashMap::new())
+            .unwrap_err();
+        assert!(
+            matches!(&err, EvaluationError::NativeFunction { name, .. } if name == "cylinder_volume"),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn test_event_aliases_pair_distinct_instances() {
+    let holes = |xs: &[f64]| {
+        AHashMap::from([(
+            "hole".to_string(),
+            xs.iter()
+                .map(|x| AHashMap::from([("X".to_string(), *x)]))
+                .collect::<Vec<_>>(),
+        )])
+    };
+    let qualities = vec![Quality {
+        name: "Apart".to_string(),
+        priority: 1,
+    }];
+    let triggers = |evaluator: &Evaluator, xs: &[f64]| {
+        evaluator
+            .eval(&AHashMap::new(), &holes(xs), &AHashMap::new())
+            .unwrap()
+            .quality_name
+            .is_some()
+    };
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let compile = |threshold: f64, pairing: EventPairing| {
+            let artifacts =
+                Compiler::builder(create_event_alias_flow(threshold), qualities.clone())
+                    .with_event_pairing(pairing)
+                    .build()
+                    .compile()
+                    .unwrap();
+            Evaluator::new(choice, artifacts).unwrap()
+        };
+
+        // A hole is never paired with itself, so a single hole cannot satisfy `0 > -1`.
+        let evaluator = compile(-1.0, EventPairing::Ordered);
+        assert!(!triggers(&evaluator, &[5.0]));
+        assert!(triggers(&evaluator, &[5.0, 5.0]));
+
+        // Ordered pairs try both holes as `a`; unordered ones only the first.
+        assert!(triggers(
+            &compile(20.0, EventPairing::Ordered),
+            &[0.0, 100.0]
+        ));
+        assert!(!triggers(
+            &compile(20.0, EventPairing::Unordered),
+            &[0.0, 100.0]
+        ));
+        assert!(triggers(
+            &compile(20.0, EventPairing::Unordered),
+            &[100.0, 0.0]
+        ));
+
+        // Two holes make four candidate pairs, more than the limit.
+        let artifacts = Compiler::builder(create_event_alias_flow(20.0), qualities.clone())
+            .with_max_combinations(3)
+            .build()
+            .compile()
+            .unwrap();
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+        let err = evaluator
+            .eval(&AHashMap::new(), &holes(&[0.0, 100.0]), &AHashMap::new())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            EvaluationError::TooManyCombinations {
+                path: "Apart".to_string(),
+                count: 4,
+                limit: 3,
+            }
+        );
+    }
+}
+
+#[test]
+fn test_paths_only_combine_the_events_they_read() {
+    let events = |name: &str, xs: &mut dyn Iterator<Item = f64>| {
+        (
+            name.to_string(),
+            xs.map(|x| AHashMap::from([("X".to_string(), x)]))
+                .collect::<Vec<_>>(),
+        )
+    };
+    // No knot is knotty, so "Far" is evaluated as well.
+    let dynamic_data = AHashMap::from([
+        events("knot", &mut (0..1000).map(|_| -1.0)),
+        events("hole", &mut (0..40).map(|i| i as f64 * 10.0)),
+    ]);
+    let qualities = vec![
+        Quality {
+            name: "Knotty".to_string(),
+            priority: 1,
+        },
+        Quality {
+            name: "Far".to_string(),
+            priority: 2,
+        },
+    ];
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        // The 1000 knots would make 1.6 million combinations with the 1600 pairs of holes.
+        let artifacts = Compiler::builder(create_knot_and_hole_flow(20.0), qualities.clone())
+            .with_max_combinations(2000)
+            .build()
+            .compile()
+            .unwrap();
+        let keys = |name: &str| {
+            let artifact = artifacts.iter().find(|a| a.name == name).unwrap();
+            let mut keys: Vec<_> = artifact.dynamic_map.keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys("Knotty"), ["knot.X"]);
+        assert_eq!(keys("Far"), ["hole#a.X", "hole#b.X"]);
+
+        let evaluator = Evaluator::new(choice, artifacts).unwrap();
+        let result = evaluator
+            .eval(&AHashMap::new(), &dynamic_data, &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Far"));
+
+        // Whatever ids its events have, a path reads an absent event as null.
+        for name in ["knot", "hole"] {
+            let mut dynamic_data = dynamic_data.clone();
+            dynamic_data.remove(name);
+            let result = evaluator
+                .eval(&AHashMap::new(), &dynamic_data, &AHashMap::new())
+                .unwrap();
+            let expected = (name == "knot").then_some("Far");
+            assert_eq!(result.quality_name.as_deref(), expected);
+        }
+    }
+}
+
+#[test]
+fn test_single_event_conditions_filter_instances_before_combining() {
+    // Only the holes at 60 and 100 pass `X > 50`, and only (100, 60) is 20 apart.
+    let holes = AHashMap::from([(
+        "hole".to_string(),
+        [0.0, 100.0, 5.0, 60.0, 8.0]
+            .into_iter()
+            .map(|x| AHashMap::from([("X".to_string(), x)]))
+            .collect::<Vec<_>>(),
+    )]);
+    let static_data = |limit: f64| AHashMap::from([("Limit".to_string(), limit)]);
+    let compile = |choice: BackendChoice, predicate_pushdown: bool| {
+        let passes = OptimizerPasses {
+            predicate_pushdown,
+            ..OptimizerPasses::all()
+        };
+        let mut artifact = compile_fixed_expression(create_pushdown_expression(), passes);
+        artifact.combinations.max_combinations = 4;
+        Evaluator::new(choice, vec![artifact]).unwrap()
+    };
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let evaluator = compile(choice, true);
+        let result = evaluator
+            .eval(&static_data(1.0), &holes, &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Fixed"));
+        let result = evaluator
+            .eval(&static_data(-1.0), &holes, &AHashMap::new())
+            .unwrap();
+        assert_eq!(result.quality_name, None);
+
+        // Unfiltered, the five holes make 25 candidate pairs, more than the limit.
+        let err = compile(choice, false)
+            .eval(&static_data(1.0), &holes, &AHashMap::new())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            EvaluationError::TooManyCombinations {
+                path: "Fixed".to_string(),
+                count: 25,
+                limit: 4,
+            }
+        );
+    }
+}
+
+#[test]
+fn test_events_only_read_by_filters_are_not_combined() {
+    // `knot` is only read by its own filter, so any knot that passes it will do.
+    let expr = Expression::And(
+        Box::new(create_pushdown_expression()),
+        Box::new(Expression::GreaterThan(
+            dynamic_input("knot", "X"),
+            number(0.0),
+        )),
+    );
+    let events = |name: &str, xs: &[f64]| {
+        (
+            name.to_string(),
+            xs.iter()
+                .map(|x| AHashMap::from([("X".to_string(), *x)]))
+                .collect::<Vec<_>>(),
+        )
+    };
+    let dynamic_data = |knots: &[f64]| {
+        AHashMap::from([
+            events("hole", &[0.0, 100.0, 5.0, 60.0, 8.0]),
+            events("knot", knots),
+        ])
+    };
+    let static_data = AHashMap::from([("Limit".to_string(), 1.0)]);
+    let many_knots = vec![1.0; 1000];
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        let evaluate = |max_combinations: usize, knots: &[f64]| {
+            let mut artifact = compile_fixed_expression(expr.clone(), OptimizerPasses::all());
+            artifact.combinations.max_combinations = max_combinations;
+            Evaluator::new(choice, vec![artifact]).unwrap().eval(
+                &static_data,
+                &dynamic_data(knots),
+                &AHashMap::new(),
+            )
+        };
+
+        // Only the two holes that pass `X > 50` are paired up, whatever the number of knots.
+        let result = evaluate(4, &many_knots).unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Fixed"));
+        assert_eq!(
+            evaluate(3, &many_knots).unwrap_err(),
+            EvaluationError::TooManyCombinations {
+                path: "Fixed".to_string(),
+                count: 4,
+                limit: 3,
+            }
+        );
+
+        // The path still needs a knot that passes its filter.
+        let result = evaluate(4, &[-1.0, -2.0]).unwrap();
+        assert_eq!(result.quality_name, None);
+        let result = evaluate(4, &[-1.0, 2.0]).unwrap();
+        assert_eq!(result.quality_name.as_deref(), Some("Fixed"));
+    }
+}
+
+#[test]
+fn test_events_without_instances_only_fail_their_own_conditions() {
+    let gt = |l, r| Box::new(Expression::GreaterThan(l, r));
+    let humid = || gt(static_input("Humidity"), number(12.0));
+    let wide_hole = || gt(dynamic_input("hole", "Diameter"), number(30.0));
+    let holes = |diameters: &[f64]| {
+        AHashMap::from([(
+            "hole".to_string(),
+            diameters
+                .iter()
+                .map(|d| AHashMap::from([("Diameter".to_string(), *d)]))
+                .collect::<Vec<_>>(),
+        )])
+    };
+    let no_event = AHashMap::new();
+    let no_holes = holes(&[]);
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        for level in [OptimizationLevel::O0, OptimizationLevel::O2] {
+            let triggers =
+                |expr: Expression,
+                 humidity: f64,
+                 dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>| {
+                    let artifact = compile_fixed_expression(expr, level.into());
+                    let static_data = AHashMap::from([("Humidity".to_string(), humidity)]);
+                    Evaluator::new(choice, vec![artifact])
+                        .unwrap()
+                        .eval(&static_data, dynamic_data, &AHashMap::new())
+                        .unwrap()
+                        .quality_name
+                        .is_some()
+                };
+            let either = || Expression::Or(humid(), wide_hole());
+
+            // A board without holes still triggers on humidity alone.
+            assert!(triggers(either(), 15.0, &no_event));
+            assert!(triggers(either(), 15.0, &no_holes));
+            assert!(!triggers(either(), 10.0, &no_holes));
+            assert!(triggers(either(), 10.0, &holes(&[40.0])));
+
+            // A condition on an absent hole is unknown, so neither it nor its negation holds.
+            let both = Expression::And(humid(), wide_hole());
+            assert!(!triggers(both, 15.0, &no_holes));
+            assert!(!triggers(Expression::Not(wide_hole()), 15.0, &no_holes));
+        }
+    }
+}
+
+#[test]
+fn test_optimizer_keeps_conditions_on_absent_events_unknown() {
+    let diameter = || dynamic_input("hole", "Diameter");
+    let binary = |op: fn(Box<Expression>, Box<Expression>) -> Expression, l, r| Box::new(op(l, r));
+    let wide = || binary(Expression::GreaterThan, diameter(), number(30.0));
+    let expressions = [
+        // Excluded middle: `D > 30 OR D <= 30`
+        Expression::Or(
+            wide(),
+            binary(Expression::SmallerThanOrEqual, diameter(), number(30.0)),
+        ),
+        // Contradiction: `NOT(D > 5 AND D < 3)`
+        Expression::Not(binary(
+            Expression::And,
+            binary(Expression::GreaterThan, diameter(), number(5.0)),
+            binary(Expression::SmallerThan, diameter(), number(3.0)),
+        )),
+        // `D * 0 == 0` and `D - D == 0`
+        Expression::Equal(
+            binary(Expression::Multiply, diameter(), number(0.0)),
+            number(0.0),
+        ),
+        Expression::Equal(
+            binary(Expression::Subtract, diameter(), diameter()),
+            number(0.0),
+        ),
+        // `NOT(D > 30 XOR D > 30)`
+        Expression::Not(binary(Expression::Xor, wide(), wide())),
+    ];
+    let holes = |diameters: &[f64]| {
+        AHashMap::from([(
+            "hole".to_string(),
+            diameters
+                .iter()
+                .map(|d| AHashMap::from([("Diameter".to_string(), *d)]))
+                .collect::<Vec<_>>(),
+        )])
+    };
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        for expr in &expressions {
+            let triggers = |level: OptimizationLevel, diameters: &[f64]| {
+                let artifact = compile_fixed_expression(expr.clone(), level.into());
+                Evaluator::new(choice, vec![artifact])
+                    .unwrap()
+                    .eval(&AHashMap::new(), &holes(diameters), &AHashMap::new())
+                    .unwrap()
+                    .quality_name
+                    .is_some()
+            };
+            // Every condition holds for any hole, but is unknown without one.
+            assert!(!triggers(OptimizationLevel::O0, &[]), "{:?}", expr);
+            assert!(!triggers(OptimizationLevel::O2, &[]), "{:?}", expr);
+            assert!(triggers(OptimizationLevel::O0, &[40.0]), "{:?}", expr);
+            assert!(triggers(OptimizationLevel::O2, &[40.0]), "{:?}", expr);
+        }
+    }
+}
+
+#[test]
+fn test_spatial_nodes_agree_on_both_backends() {
+    let event = |x: f64, y: f64, width: f64, length: f64| {
+        AHashMap::from([
+            ("X".to_string(), x),
+            ("Y".to_string(), y),
+            ("Width".to_string(), width),
+            ("Length".to_string(), length),
+        ])
+    };
+    let board = AHashMap::from([
+        ("Board width".to_string(), 200.0),
+        ("Board length".to_string(), 4000.0),
+        ("Zone min X".to_string(), 0.0),
+        ("Zone min Y".to_string(), 0.0),
+        ("Zone max X".to_string(), 200.0),
+        ("Zone max Y".to_string(), 500.0),
+    ]);
+    let qualities = create_simple_qualities()[..1].to_vec();
+    let triggers =
+        |flow: FlowDefinition,
+         choice: BackendChoice,
+         dynamic_data: &AHashMap<String, Vec<AHashMap<String, f64>>>| {
+            let artifacts = Compiler::builder(flow, qualities.clone())
+                .build()
+                .compile()
+                .unwrap();
+            let evaluator = Evaluator::new(choice, artifacts).unwrap();
+            let result = evaluator
+                .eval(&board, dynamic_data, &AHashMap::new())
+                .unwrap();
+            result.quality_name.is_some()
+        };
+    let knots =
+        |x: f64, y: f64| AHashMap::from([("knot".to_string(), vec![event(x, y, 10.0, 10.0)])]);
+
+    for choice in [BackendChoice::Interpreter, BackendChoice::Bytecode] {
+        // "Knot near edge": the knot's box is 15 from the left edge, or 95 from both.
+        let near_edge = || {
+            create_spatial_flow(
+                "edgeDistanceNode",
+                &["knot"],
+                &["Board width", "Board length"],
+                Some(25.0),
+            )
+        };
+        assert!(triggers(near_edge(), choice, &knots(20.0, 2000.0)));
+        assert!(!triggers(near_edge(), choice, &knots(100.0, 2000.0)));
+        assert!(triggers(near_edge(), choice, &knots(100.0, 3980.0)));
+
+        let zone = || {
+            create_spatial_flow(
+                "zoneNode",
+                &["knot"],
+                &["Zone min X", "Zone min Y", "Zone max X", "Zone max Y"],
+                None,
+            )
+        };
+        assert!(triggers(zone(), choice, &knots(100.0, 500.0)));
+        assert!(!triggers(zone(), choice, &knots(100.0, 501.0)));
+
+        // The centres of the two holes are exactly 50 apart.
+        let holes = |dy: f64| {
+            AHashMap::from([(
+                "hole".to_string(),
+                vec![event(0.0, 0.0, 10.0, 10.0), event(30.0, dy, 10.0, 10.0)],
+            )])
+        };
+        let close = || create_spatial_flow("distanceNode", &["hole#a", "hole#b"], &[], Some(50.0));
+        assert!(!triggers(close(), choice, &holes(40.0)));
+        assert!(triggers(close(), choice, &holes(39.0)));
+
+        // Boxes that only touch do not overlap.
+        let overlap = || create_spatial_flow("overlapNode", &["knot", "hole"], &[], None);
+        let mut data = knots(0.0, 0.0);
+        data.insert("hole".to_string(), vec![event(10.0, 0.0, 10.0, 10.0)]);
+        assert!(!triggers(overlap(), choice, &data));
+        data.insert("hole".to_string(), vec![event(9.0, 5.0, 10.0, 10.0)]);
+        assert!(triggers(overlap(), choice, &data));
+    }
+
+    // The trace names the operation and the fields it read.
+    let artifacts = Compiler::builder(
+        create_spatial_flow(
+            "edgeDistanceNode",
+            &["knot"],
+            &["Board width", "Board length"],
+            Some(25.0),
+        ),
+        qualities.clone(),
+    )
+    .build()
+    .compile()
+    .unwrap();
+    let evaluator = Evaluator::new(BackendChoice::Interpreter, artifacts).unwrap();
+    let result = evaluator
+        .eval(&board, &knots(20.0, 2000.0), &AHashMap::new())
+        .unwrap();
+    assert!(
+        result.reason.contains("EDGE_DISTANCE($knot.X (was 20)"),
+        "{}",
+        result.reason
+    );
+}
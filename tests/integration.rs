@@ -18,7 +18,7 @@ fn test_full_workflow_simple() {
     let dynamic_data = AHashMap::new();
 
     let result = evaluator
-        .eval(&static_data, &dynamic_data)
+        .eval(&static_data, &dynamic_data, &AHashMap::new())
         .expect("Evaluation failed");
 
     assert_eq!(result.quality_name.as_deref(), Some("Hot"));
@@ -37,7 +37,7 @@ fn test_full_workflow_complex_dynamic() {
     let dynamic_data = create_sample_dynamic_data();
 
     let result = evaluator
-        .eval(&static_data, &dynamic_data)
+        .eval(&static_data, &dynamic_data, &AHashMap::new())
         .expect("Evaluation failed");
 
     assert_eq!(result.quality_name.as_deref(), Some("Premium"));
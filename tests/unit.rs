@@ -1,7 +1,9 @@
 //! Unit tests for core Hantei functionality.
 mod common;
-use hantei::error::{AstBuildError, EvaluationError, VmError};
+use hantei::ast::{Expression, ExpressionFolder, fold_children};
+use hantei::error::{AstBuildError, EvaluationError, RecipeConversionError, VmError};
 use hantei::prelude::*;
+use hantei::recipe::{HandleParser, PlainIndex, QualifiedHandle, TrailingIndex};
 
 #[test]
 fn test_value_display() {
@@ -23,6 +25,127 @@ fn test_input_source_display() {
     assert_eq!(format!("{}", dynamic_src), "$hole.Diameter");
 }
 
+/// Rewrites every static input `$name` into the literal `defaults[name]`.
+struct InlineDefaults<'a>(&'a [(&'a str, f64)]);
+
+impl ExpressionFolder for InlineDefaults<'_> {
+    fn fold_input(&mut self, source: InputSource) -> Expression {
+        match &source {
+            InputSource::StaticName { name } => self
+                .0
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| Expression::Literal(Value::Number(*v)))
+                .unwrap_or(Expression::Input(source)),
+            _ => Expression::Input(source),
+        }
+    }
+}
+
+/// Drops double negations, relying on the default traversal for everything else.
+struct RemoveDoubleNot;
+
+impl ExpressionFolder for RemoveDoubleNot {
+    fn fold_expression(&mut self, expr: Expression) -> Expression {
+        match fold_children(self, expr) {
+            Expression::Not(inner) => match *inner {
+                Expression::Not(e) => *e,
+                other => Expression::Not(Box::new(other)),
+            },
+            other => other,
+        }
+    }
+}
+
+#[test]
+fn test_expression_folder_default_traversal() {
+    let input = |name: &str| {
+        Box::new(Expression::Input(InputSource::StaticName {
+            name: name.to_string(),
+        }))
+    };
+    let expr = Expression::Not(Box::new(Expression::Not(Box::new(
+        Expression::GreaterThan(
+            Box::new(Expression::Sum(input("A"), input("B"))),
+            input("C"),
+        ),
+    ))));
+
+    let inlined = InlineDefaults(&[("B", 2.0)]).fold_expression(expr);
+    let simplified = RemoveDoubleNot.fold_expression(inlined);
+
+    let expected = Expression::GreaterThan(
+        Box::new(Expression::Sum(
+            input("A"),
+            Box::new(Expression::Literal(Value::Number(2.0))),
+        )),
+        input("C"),
+    );
+    assert_eq!(simplified, expected);
+    assert_eq!(simplified.children().count(), 2);
+}
+
+#[test]
+fn test_handle_parsers() {
+    assert_eq!(
+        QualifiedHandle.parse_handle("bool-bool-0009-1", "0009"),
+        Some(1)
+    );
+    assert_eq!(
+        QualifiedHandle.parse_handle("hole-object-0017-12", "0017"),
+        Some(12)
+    );
+    // Missing index, wrong node id, missing kind and non-numeric index.
+    assert_eq!(QualifiedHandle.parse_handle("bool-bool-0009", "0009"), None);
+    assert_eq!(
+        QualifiedHandle.parse_handle("bool-bool-0009-1", "0010"),
+        None
+    );
+    assert_eq!(QualifiedHandle.parse_handle("bool-0009-1", "0009"), None);
+    assert_eq!(
+        QualifiedHandle.parse_handle("bool-bool-0009-x", "0009"),
+        None
+    );
+
+    assert_eq!(TrailingIndex.parse_handle("input-3", "n"), Some(3));
+    assert_eq!(TrailingIndex.parse_handle("input", "n"), None);
+    assert_eq!(TrailingIndex.parse_handle("input-+3", "n"), None);
+    assert_eq!(PlainIndex.parse_handle("3", "n"), Some(3));
+    assert_eq!(PlainIndex.parse_handle("", "n"), None);
+
+    let custom = |handle: &str, _: &str| handle.strip_prefix("in").and_then(|i| i.parse().ok());
+    assert_eq!(custom.parse_handle("in7", "n"), Some(7));
+}
+
+#[test]
+fn test_edge_from_handle_strings() {
+    let edge = FlowEdgeDefinition::from_handle_strings(
+        "0001".to_string(),
+        "number-number-0001-4",
+        "0002".to_string(),
+        "number-number-0002-1",
+        &QualifiedHandle,
+    )
+    .unwrap();
+    assert_eq!((edge.source_handle, edge.target_handle), (4, 1));
+
+    let err = FlowEdgeDefinition::from_handle_strings(
+        "0001".to_string(),
+        "number-number-0001-4",
+        "0002".to_string(),
+        "input",
+        &QualifiedHandle,
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        RecipeConversionError::InvalidHandle {
+            node_id: "0002".to_string(),
+            handle: "input".to_string(),
+        }
+    );
+}
+
 #[test]
 fn test_trace_formatter_short_circuit() {
     let trace = EvaluationTrace::BinaryOp {
@@ -39,6 +162,36 @@ fn test_trace_formatter_short_circuit() {
     assert_eq!(formatted, "true");
 }
 
+#[test]
+fn test_trace_formatter_keeps_operand_order() {
+    let leaf = |source: &str, value: f64| {
+        Box::new(EvaluationTrace::Leaf {
+            source: source.to_string(),
+            value: Value::Number(value),
+        })
+    };
+    let comparison = |left, right| EvaluationTrace::BinaryOp {
+        op_symbol: ">",
+        left,
+        right,
+        outcome: Value::Bool(true),
+    };
+
+    // Comparisons are shown the way the recipe wrote them, literal first or not.
+    let trace = comparison(leaf("10", 10.0), leaf("$Diameter", 8.5));
+    assert_eq!(
+        TraceFormatter::format_trace(&trace),
+        "10 > $Diameter (was 8.5)"
+    );
+
+    // A parameter is shown with its value, like an input.
+    let trace = comparison(leaf("@threshold", 25.0), leaf("$Temperature", 8.5));
+    assert_eq!(
+        TraceFormatter::format_trace(&trace),
+        "@threshold (was 25) > $Temperature (was 8.5)"
+    );
+}
+
 #[test]
 fn test_error_display() {
     let err = AstBuildError::NodeNotFound {
@@ -1,6 +1,11 @@
+use ahash::AHashMap;
 use clap::{Parser, ValueEnum};
+use hantei::ast::Value;
 use hantei::backend::BackendChoice;
+use hantei::compiler::{CompilationArtifacts, OptimizationLevel, OptimizerPasses};
+use hantei::error::{FlowDiagnostic, Severity};
 use hantei::prelude::*;
+use hantei::recipe::QualifiedHandle;
 use serde::Deserialize;
 use std::fs;
 use std::io::{self, Write};
@@ -68,6 +73,36 @@ enum BackendCli {
     Bytecode,
 }
 
+/// Output formats for exporting the optimized ASTs.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Json,
+    Dot,
+    Mermaid,
+}
+
+/// Individual optimizer passes that can be disabled from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OptimizerPassCli {
+    Fold,
+    Dce,
+    DeMorgan,
+    Intervals,
+    Canonicalize,
+    Cse,
+    Pushdown,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Dot => "dot",
+            ExportFormat::Mermaid => "mmd",
+        }
+    }
+}
+
 // --- Converter Implementation ---
 impl IntoFlow for RawRecipe {
     fn into_flow(self) -> Result<FlowDefinition, RecipeConversionError> {
@@ -95,13 +130,16 @@ impl IntoFlow for RawRecipe {
         let edges = self
             .edges
             .into_iter()
-            .map(|raw_edge| FlowEdgeDefinition {
-                source: raw_edge.source,
-                source_handle: raw_edge.source_handle,
-                target: raw_edge.target,
-                target_handle: raw_edge.target_handle,
+            .map(|raw_edge| {
+                FlowEdgeDefinition::from_handle_strings(
+                    raw_edge.source,
+                    &raw_edge.source_handle,
+                    raw_edge.target,
+                    &raw_edge.target_handle,
+                    &QualifiedHandle,
+                )
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
 
         Ok(FlowDefinition { nodes, edges })
     }
@@ -133,6 +171,67 @@ struct Cli {
         help = "Run a benchmark for N iterations. e.g., --benchmark 100"
     )]
     benchmark: Option<Option<usize>>,
+
+    /// Export the optimized AST of every quality path in the given format
+    #[arg(long, value_enum)]
+    export: Option<ExportFormat>,
+
+    /// Directory that exported AST files are written to
+    #[arg(long, default_value = "export")]
+    export_dir: String,
+
+    /// Optimization level: 0 = none, 1 = no canonicalization or CSE, 2 = all passes
+    #[arg(short = 'O', long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(0..=2))]
+    opt_level: u8,
+
+    /// Disable a single optimizer pass. Can be given multiple times.
+    #[arg(long, value_enum)]
+    disable_pass: Vec<OptimizerPassCli>,
+
+    /// Override a recipe parameter, e.g. --param threshold=30. Can be given multiple times.
+    #[arg(long = "param", value_name = "NAME=VALUE", value_parser = parse_parameter)]
+    params: Vec<(String, Value)>,
+}
+
+impl Cli {
+    /// Resolves `--opt-level` and `--disable-pass` into the optimizer pass set.
+    fn optimizer_passes(&self) -> OptimizerPasses {
+        let level = match self.opt_level {
+            0 => OptimizationLevel::O0,
+            1 => OptimizationLevel::O1,
+            _ => OptimizationLevel::O2,
+        };
+        let mut passes = OptimizerPasses::from(level);
+        for pass in &self.disable_pass {
+            match pass {
+                OptimizerPassCli::Fold => passes.constant_folding = false,
+                OptimizerPassCli::Dce => passes.dead_code_elimination = false,
+                OptimizerPassCli::DeMorgan => passes.de_morgan = false,
+                OptimizerPassCli::Intervals => passes.interval_analysis = false,
+                OptimizerPassCli::Canonicalize => passes.canonicalization = false,
+                OptimizerPassCli::Cse => passes.common_subexpression_elimination = false,
+                OptimizerPassCli::Pushdown => passes.predicate_pushdown = false,
+            }
+        }
+        passes
+    }
+}
+
+/// Parses a `NAME=VALUE` parameter override; `true`/`false` are booleans, anything else a number.
+fn parse_parameter(arg: &str) -> Result<(String, Value), String> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", arg))?;
+    let value = match value.trim() {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        other => Value::Number(
+            other
+                .parse()
+                .map_err(|_| format!("'{}' is not a number or boolean", other))?,
+        ),
+    };
+    Ok((name.trim().to_string(), value))
 }
 
 /// A struct to hold calculated statistics for a set of durations.
@@ -163,6 +262,9 @@ fn run_evaluation(
     qualities_path: String,
     sample_data_path: Option<String>,
     backend_choice: BackendChoice,
+    optimizer_passes: OptimizerPasses,
+    export: Option<(ExportFormat, String)>,
+    parameters: AHashMap<String, Value>,
 ) {
     let total_start = Instant::now();
 
@@ -214,7 +316,11 @@ fn run_evaluation(
     // --- 3. AST Compilation (Frontend) ---
     println!("\nStarting Hantei Recipe Compilation (AST Build)...");
     let compile_start = Instant::now();
-    let compiler = Compiler::builder(flow, qualities).build();
+    let builder = Compiler::builder(flow, qualities).with_optimizer_passes(optimizer_passes);
+    print_flow_diagnostics(&builder.validate());
+    #[cfg(feature = "debug-tools")]
+    let builder = builder.with_observer(hantei::compiler::observer::DebugDumpObserver::new("tmp"));
+    let compiler = builder.build();
 
     let compiled_paths = compiler
         .compile()
@@ -227,6 +333,12 @@ fn run_evaluation(
         compile_duration
     );
 
+    print_optimizer_report(&compiled_paths);
+
+    if let Some((format, dir)) = export {
+        export_artifacts(&compiled_paths, format, &dir);
+    }
+
     // --- 4. Backend Compilation ---
     println!("Compiling for backend: {:?}", backend_choice);
     let backend_start = Instant::now();
@@ -239,7 +351,11 @@ fn run_evaluation(
     println!("\nRunning Evaluation with Sample Data...");
     let eval_start = Instant::now();
     let result = evaluator
-        .eval(sample_data.static_data(), sample_data.dynamic_data())
+        .eval(
+            sample_data.static_data(),
+            sample_data.dynamic_data(),
+            &parameters,
+        )
         .unwrap_or_else(|e| exit_with_error(&format!("Evaluation failed: {}", e)));
     let eval_duration = eval_start.elapsed();
 
@@ -255,6 +371,11 @@ fn run_evaluation(
     } else {
         println!("  -> No quality triggered");
     }
+    let mut outputs: Vec<_> = result.outputs.iter().collect();
+    outputs.sort_by_key(|(name, _)| name.as_str());
+    for (name, value) in outputs {
+        println!("  -> Output {}: {:?}", name, value);
+    }
 
     let total_duration = total_start.elapsed();
     println!("\n--- Dataset Summary ---");
@@ -277,6 +398,7 @@ fn run_evaluation(
 
 /// Runs the CLI in non-interactive mode.
 fn run_non_interactive(cli: Cli) {
+    let optimizer_passes = cli.optimizer_passes();
     let recipe_path = cli.recipe_path.unwrap_or_else(|| {
         exit_with_error("Recipe path is required in non-interactive mode.");
     });
@@ -294,6 +416,9 @@ fn run_non_interactive(cli: Cli) {
         qualities_path,
         cli.sample_data_path,
         backend_choice,
+        optimizer_passes,
+        cli.export.map(|format| (format, cli.export_dir)),
+        cli.params.into_iter().collect(),
     );
 }
 
@@ -345,6 +470,9 @@ fn run_interactive() {
             qualities_path,
             sample_data_path,
             backend_choice,
+            OptimizerPasses::default(),
+            None,
+            AHashMap::new(),
         );
     } else {
         // mode_choice == "benchmark"
@@ -359,6 +487,11 @@ fn run_interactive() {
             backend: None,                     // Benchmark mode ignores this
             human: true,                       // We are in human mode
             benchmark: Some(Some(iterations)), // This is what triggers the mode
+            export: None,
+            export_dir: String::new(),
+            opt_level: 2,
+            disable_pass: Vec::new(),
+            params: Vec::new(),
         };
 
         run_benchmark(cli_args, iterations);
@@ -367,6 +500,8 @@ fn run_interactive() {
 
 /// Runs the full pipeline in a loop for both backends to gather performance statistics.
 fn run_benchmark(cli: Cli, iterations: usize) {
+    let optimizer_passes = cli.optimizer_passes();
+    let parameters: AHashMap<String, Value> = cli.params.iter().cloned().collect();
     let recipe_path = cli
         .recipe_path
         .unwrap_or_else(|| exit_with_error("Recipe path is required for benchmarking."));
@@ -427,7 +562,9 @@ fn run_benchmark(cli: Cli, iterations: usize) {
                 .collect();
 
             let ast_start = Instant::now();
-            let compiler = Compiler::builder(flow, qualities).build();
+            let compiler = Compiler::builder(flow, qualities)
+                .with_optimizer_passes(optimizer_passes)
+                .build();
             let compiled_paths = compiler.compile().unwrap();
             let ast_duration = ast_start.elapsed();
 
@@ -437,7 +574,11 @@ fn run_benchmark(cli: Cli, iterations: usize) {
 
             let eval_start = Instant::now();
             let _result = evaluator
-                .eval(sample_data.static_data(), sample_data.dynamic_data())
+                .eval(
+                    sample_data.static_data(),
+                    sample_data.dynamic_data(),
+                    &parameters,
+                )
                 .unwrap();
             let eval_duration = eval_start.elapsed();
 
@@ -504,6 +645,94 @@ fn run_benchmark(cli: Cli, iterations: usize) {
     println!();
 }
 
+/// Prints the problems found by flow validation, if any.
+fn print_flow_diagnostics(diagnostics: &[FlowDiagnostic]) {
+    if diagnostics.is_empty() {
+        return;
+    }
+    println!("\n--- Flow Diagnostics ---");
+    for diagnostic in diagnostics {
+        let severity = match diagnostic.severity() {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        println!("  {}: {}", severity, diagnostic);
+    }
+}
+
+/// Prints what the optimizer did to every quality path.
+fn print_optimizer_report(artifacts: &[CompilationArtifacts]) {
+    println!("\n--- Optimizer Report ---");
+    println!(
+        "| {:<20} | {:>5} | {:>12} | {:>11} | {:>6} | {:>13} | {:>8} | {:>11} |",
+        "Quality",
+        "Iters",
+        "Nodes Before",
+        "Nodes After",
+        "Folded",
+        "Dead Branches",
+        "CSE Hits",
+        "Pushed Down"
+    );
+    println!(
+        "|----------------------|-------|--------------|-------------|--------|---------------|----------|-------------|"
+    );
+    for artifact in artifacts {
+        let stats = &artifact.stats;
+        println!(
+            "| {:<20} | {:>5} | {:>12} | {:>11} | {:>6} | {:>13} | {:>8} | {:>11} |",
+            artifact.name,
+            stats.iterations,
+            stats.nodes_before,
+            stats.nodes_after,
+            stats.constants_folded,
+            stats.branches_eliminated,
+            stats.cse_hits,
+            stats.conditions_pushed_down
+        );
+    }
+    for artifact in artifacts.iter().filter(|a| !a.stats.converged) {
+        println!(
+            "Note: '{}' did not reach a fixed point within {} iterations.",
+            artifact.name,
+            hantei::compiler::MAX_OPTIMIZER_ITERATIONS
+        );
+    }
+    println!();
+}
+
+/// Writes one export file per quality path into `dir`.
+fn export_artifacts(artifacts: &[CompilationArtifacts], format: ExportFormat, dir: &str) {
+    fs::create_dir_all(dir).unwrap_or_else(|e| {
+        exit_with_error(&format!(
+            "Failed to create export directory '{}': {}",
+            dir, e
+        ))
+    });
+    for artifact in artifacts {
+        let content = match format {
+            ExportFormat::Json => artifact.to_json(),
+            ExportFormat::Dot => artifact.to_dot(),
+            ExportFormat::Mermaid => artifact.to_mermaid(),
+        };
+        let file_name: String = artifact
+            .name
+            .chars()
+            .filter(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+        let path = format!("{}/{}.{}", dir, file_name, format.extension());
+        fs::write(&path, content).unwrap_or_else(|e| {
+            exit_with_error(&format!("Failed to write export file '{}': {}", path, e))
+        });
+    }
+    println!(
+        "Exported {} quality paths as {:?} to '{}'",
+        artifacts.len(),
+        format,
+        dir
+    );
+}
+
 /// A helper function to prompt the user and read a line of input.
 fn prompt_for_input(prompt_text: &str, default: Option<&str>) -> String {
     let mut line = String::new();